@@ -0,0 +1,297 @@
+// Where to find the devices we talk to over HTTP (the Envoy, the
+// OpenEVSEs and the Powerwall), so one changing its IP address when its
+// DHCP lease runs out doesn't mean restarting with new flags.
+//
+// A device's name is looked up, in order:
+//
+// - in the --address-book file, which has one "name address..." entry
+//   per line (and # comments), and is read again whenever it changes,
+//
+// - in the --address overrides, "name=address[,address...]",
+//
+// - with the system's resolver (DNS, mDNS, /etc/hosts), and the answer
+//   kept for --dns-ttl seconds, so a device that's moved is found again
+//   without a restart, but we don't ask every request.
+//
+// ```text
+// # /etc/solar-evse/addresses
+// envoy       192.168.1.23
+// openevse    192.168.1.40
+// ```
+//
+// It also knows how to reach them: on a multi-homed host, out of the
+// --interface the devices' network is on (Linux only), or from its
+// --local-address there.  Addresses may be IPv6 ("fd00::23", or
+// "[fd00::23]:502" with a port).
+
+use std::str::FromStr;
+
+/// An --address override.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub addresses: Vec<std::net::IpAddr>,
+}
+
+impl FromStr for Entry {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, addresses)) = s.split_once('=') else {
+            return Err(eyre::eyre!(
+                "address {:?} is not of the form name=address[,address...]",
+                s
+            ));
+        };
+        Ok(Self {
+            name: name.trim().to_lowercase(),
+            addresses: parse_addresses(addresses.split(','))
+                .map_err(|e| eyre::eyre!("bad address {:?}: {}", s, e))?,
+        })
+    }
+}
+
+fn parse_addresses<'a>(
+    addresses: impl Iterator<Item = &'a str>,
+) -> Result<Vec<std::net::IpAddr>, std::net::AddrParseError> {
+    addresses
+        .map(|address| std::net::IpAddr::from_str(address.trim()))
+        .collect()
+}
+
+/// Split "host", "host:port", "fd00::23" or "[fd00::23]:port" into
+/// the host and port.
+pub fn host_port(address: &str, default_port: u16) -> Result<(String, u16), eyre::Report> {
+    if std::net::Ipv6Addr::from_str(address).is_ok() {
+        return Ok((String::from(address), default_port));
+    }
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .map_err(|e| eyre::eyre!("bad port in {address:?}: {e}"))?,
+        ),
+        None => (address, default_port),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((String::from(host), port))
+}
+
+/// `host` ("host" or "host:port"), with an IPv6 address in brackets,
+/// for putting in a URL.
+pub fn url_host(host: &str) -> String {
+    match std::net::Ipv6Addr::from_str(host) {
+        Ok(_) => format!("[{host}]"),
+        Err(_) => String::from(host),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    // The --address-book entries, and when the file was last changed
+    // when we read it.
+    file: std::collections::HashMap<String, Vec<std::net::IpAddr>>,
+    file_modified: Option<std::time::SystemTime>,
+
+    // What the system resolver said, and when.
+    resolved: std::collections::HashMap<String, (Vec<std::net::IpAddr>, std::time::Instant)>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    filename: Option<std::path::PathBuf>,
+    overrides: std::collections::HashMap<String, Vec<std::net::IpAddr>>,
+    ttl: std::time::Duration,
+    interface: Option<String>,
+    local_address: Option<std::net::IpAddr>,
+    cache: std::sync::Mutex<Cache>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AddressBook {
+    inner: std::sync::Arc<Inner>,
+}
+
+impl AddressBook {
+    pub fn new(
+        filename: Option<&std::path::Path>,
+        overrides: &[Entry],
+        ttl: std::time::Duration,
+        interface: Option<&str>,
+        local_address: Option<std::net::IpAddr>,
+    ) -> Self {
+        Self {
+            inner: std::sync::Arc::new(Inner {
+                filename: filename.map(std::path::Path::to_path_buf),
+                overrides: overrides
+                    .iter()
+                    .map(|entry| (entry.name.clone(), entry.addresses.clone()))
+                    .collect(),
+                ttl,
+                interface: interface.map(String::from),
+                local_address,
+                cache: std::sync::Mutex::new(Cache::default()),
+            }),
+        }
+    }
+
+    /// A client for talking to our devices, that finds them with the
+    /// address book.
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let builder = reqwest::Client::builder()
+            .dns_resolver(std::sync::Arc::new(self.clone()))
+            .local_address(self.inner.local_address);
+        #[cfg(target_os = "linux")]
+        let builder = match &self.inner.interface {
+            Some(interface) => builder.interface(interface),
+            None => builder,
+        };
+        builder
+    }
+
+    /// Open a TCP connection to `address` ("host" or "host:port"), for
+    /// Modbus.
+    pub async fn connect(
+        &self,
+        address: &str,
+        default_port: u16,
+    ) -> Result<tokio::net::TcpStream, eyre::Report> {
+        let (host, port) = host_port(address, default_port)?;
+        let mut last_error = eyre::eyre!("no address for {}", host);
+        for ip in self.lookup(&host).await? {
+            if self
+                .inner
+                .local_address
+                .is_some_and(|local| local.is_ipv4() != ip.is_ipv4())
+            {
+                continue;
+            }
+            match self.connect_to(std::net::SocketAddr::new(ip, port)).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = eyre::eyre!("can't connect to {}: {}", address, e),
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect_to(
+        &self,
+        address: std::net::SocketAddr,
+    ) -> Result<tokio::net::TcpStream, std::io::Error> {
+        let socket = match address {
+            std::net::SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            std::net::SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &self.inner.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(local) = self.inner.local_address {
+            socket.bind(std::net::SocketAddr::new(local, 0))?;
+        }
+        socket.connect(address).await
+    }
+
+    /// Read the --address-book file again, if it's changed.
+    fn reload(&self) {
+        let Some(filename) = &self.inner.filename else {
+            return;
+        };
+        let modified = std::fs::metadata(filename).and_then(|m| m.modified()).ok();
+        let mut cache = self.inner.cache.lock().unwrap();
+        if modified.is_some() && modified == cache.file_modified {
+            return;
+        }
+        let contents = match std::fs::read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("can't read address book {}: {}", filename.display(), e);
+                return;
+            }
+        };
+        let mut file = std::collections::HashMap::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(name) = fields.next() else {
+                continue;
+            };
+            match parse_addresses(fields) {
+                Ok(addresses) if !addresses.is_empty() => {
+                    file.insert(name.to_lowercase(), addresses);
+                }
+                Ok(_) => println!(
+                    "address book {} line {}: no address for {}",
+                    filename.display(),
+                    n + 1,
+                    name
+                ),
+                Err(e) => println!("address book {} line {}: {}", filename.display(), n + 1, e),
+            }
+        }
+        println!(
+            "read {} entries from address book {}",
+            file.len(),
+            filename.display()
+        );
+        cache.file = file;
+        cache.file_modified = modified;
+    }
+
+    /// The addresses of the device called `name`.
+    pub async fn lookup(&self, name: &str) -> Result<Vec<std::net::IpAddr>, eyre::Report> {
+        if let Ok(address) = std::net::IpAddr::from_str(name) {
+            return Ok(vec![address]);
+        }
+        self.reload();
+        let key = name.to_lowercase();
+        {
+            let cache = self.inner.cache.lock().unwrap();
+            if let Some(addresses) = cache.file.get(&key) {
+                return Ok(addresses.clone());
+            }
+            if let Some(addresses) = self.inner.overrides.get(&key) {
+                return Ok(addresses.clone());
+            }
+            if let Some((addresses, time)) = cache.resolved.get(&key) {
+                if time.elapsed() < self.inner.ttl {
+                    return Ok(addresses.clone());
+                }
+            }
+        }
+        let addresses: Vec<std::net::IpAddr> = tokio::net::lookup_host((name, 0))
+            .await
+            .map_err(|e| eyre::eyre!("can't resolve {}: {}", name, e))?
+            .map(|address| address.ip())
+            .collect();
+        let mut cache = self.inner.cache.lock().unwrap();
+        if let Some((previous, _)) = cache.resolved.get(&key) {
+            if *previous != addresses {
+                println!("{} moved from {:?} to {:?}", name, previous, addresses);
+            }
+        }
+        cache
+            .resolved
+            .insert(key, (addresses.clone(), std::time::Instant::now()));
+        Ok(addresses)
+    }
+}
+
+impl reqwest::dns::Resolve for AddressBook {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let book = self.clone();
+        Box::pin(async move {
+            let addresses = book.lookup(name.as_str()).await?;
+            // reqwest fills in the port.
+            let addrs: reqwest::dns::Addrs = Box::new(
+                addresses
+                    .into_iter()
+                    .map(|address| std::net::SocketAddr::new(address, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
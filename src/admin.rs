@@ -0,0 +1,122 @@
+// An admin console on a Unix socket (--admin-socket), for poking at the
+// controller while it runs, when debugging on site:
+//
+// ```text
+// $ socat READLINE UNIX-CONNECT:/run/solar-evse/admin.sock
+// > inject -12.5
+// ok, running a cycle with an export current of -12.500 A
+// > rapi GE
+// $OK 16 0121^2C
+// ```
+//
+// Each line is a command, which the control loop carries out between
+// cycles like the HTTP API's (see `Controller::handle_admin()` in
+// lib.rs), and the reply comes back on the socket.  Anyone who can open the
+// socket can control the EVSE, so mind its permissions.
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+pub const HELP: &str = "\
+commands:
+  help               this
+  status             the controller's status, as JSON
+  dump               the status, and the control loop's internals
+  cycle              run a control cycle now
+  inject AMPS        run a cycle now, pretending the meter read an export
+                     current of AMPS (negative when importing)
+  openevse           everything the OpenEVSE says about itself, as JSON
+  time_limit MINUTES stop the OpenEVSE charging after MINUTES this session,
+                     0 for no limit
+  delay_timer WINDOW set the OpenEVSE's own delay timer, like \"22:00-06:00\",
+                     or \"off\"
+  rapi CMD [ARG...]  send a raw RAPI command to the OpenEVSE, like \"rapi GE\"
+  reload [dry_run|confirm]
+                     read the settings again, as on SIGHUP, or only say what
+                     would change, or apply the ones held for --reload-confirm
+  mode MODE, limit AMPS, boost BUDGET, grid_limit WATTS, ev_soc PERCENT,
+  evse_min_charge_current AMPS, evse_max_charge_current AMPS,
+  target_export_current AMPS
+                     the same as the MQTT commands
+  quit               close the connection";
+
+pub struct Request {
+    pub line: String,
+    pub reply: tokio::sync::oneshot::Sender<String>,
+}
+
+/// Start listening on the socket at `path`, in the background.  Returns
+/// the requests to carry out.
+pub fn serve(path: &std::path::Path) -> Result<tokio::sync::mpsc::Receiver<Request>, eyre::Report> {
+    // A socket left over from last time would be in the way.
+    if path.exists() {
+        std::fs::remove_file(path)
+            .map_err(|e| eyre::eyre!("can't remove old admin socket {}: {}", path.display(), e))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| eyre::eyre!("can't listen on admin socket {}: {}", path.display(), e))?;
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(session(stream, tx.clone()));
+                }
+                Err(e) => {
+                    println!("admin socket failed: {e}");
+                    return;
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// The next request, or never if there's no admin socket.
+pub async fn next(rx: &mut Option<tokio::sync::mpsc::Receiver<Request>>) -> Option<Request> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Pass one connection's commands to the control loop, and its replies
+/// back.
+async fn session(stream: tokio::net::UnixStream, tx: tokio::sync::mpsc::Sender<Request>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    loop {
+        if writer.write_all(b"> ").await.is_err() {
+            return;
+        }
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            _ => return,
+        };
+        let line = line.trim();
+        let reply = match line {
+            "" => continue,
+            "quit" | "exit" => return,
+            _ => {
+                let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+                let request = Request {
+                    line: String::from(line),
+                    reply: reply_tx,
+                };
+                if tx.send(request).await.is_err() {
+                    return;
+                }
+                match reply_rx.await {
+                    Ok(reply) => reply,
+                    Err(_) => return,
+                }
+            }
+        };
+        if writer
+            .write_all(format!("{reply}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// Alerts: conditions worth telling someone about, like importing a lot
+// from the grid for a while, or no solar production in the middle of
+// the day.  Each alert is given as "name=condition [for DURATION]
+// [during HH:MM-HH:MM]", for example
+// "high_import=export_w < -8000 for 5m" or
+// "no_production=production_w_now < 10 for 30m during 10:00-14:00".
+//
+// The condition is written like a --sensor, and the alert goes off
+// once it's been true for the whole duration (right away without
+// one), and only while it's inside the time window (if any).  When it
+// goes off an "alert" notification is sent, and when the condition is
+// false again an "alert_cleared" one.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub name: String,
+    pub condition: String,
+    pub duration: std::time::Duration,
+    pub window: Option<crate::schedule::TimeWindow>,
+    expr: crate::sensor::Sensor,
+}
+
+/// What changed about an alert in a check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Raised,
+    Cleared,
+}
+
+impl Alert {
+    /// Whether the alert's condition is true now, and it's in the
+    /// alert's time window.
+    fn holds(
+        &self,
+        inputs: &std::collections::HashMap<String, f64>,
+        time: chrono::NaiveTime,
+    ) -> Result<bool, eyre::Report> {
+        if self.window.is_some_and(|window| !window.contains(time)) {
+            return Ok(false);
+        }
+        Ok(self.expr.evaluate(inputs)? != 0.0)
+    }
+}
+
+/// The alerts, and how long each one's condition has been true.
+#[derive(Debug)]
+pub struct Alerts {
+    alerts: Vec<Alert>,
+
+    // When each alert's condition became true, and whether it's gone
+    // off, by name.
+    pending: std::collections::HashMap<String, (std::time::Instant, bool)>,
+}
+
+impl Alerts {
+    pub fn new(alerts: &[Alert]) -> Self {
+        Self {
+            alerts: alerts.to_vec(),
+            pending: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Check every alert's condition, and return the ones that went
+    /// off or cleared.
+    pub fn check(
+        &mut self,
+        inputs: &std::collections::HashMap<String, f64>,
+    ) -> Vec<(Alert, Change)> {
+        let now = std::time::Instant::now();
+        let time = chrono::Local::now().time();
+        let mut changes = Vec::new();
+        for alert in &self.alerts {
+            let holds = match alert.holds(inputs, time) {
+                Ok(holds) => holds,
+                Err(e) => {
+                    println!("can't check alert {}: {:#}", alert.name, e);
+                    continue;
+                }
+            };
+            if !holds {
+                if let Some((_, true)) = self.pending.remove(&alert.name) {
+                    changes.push((alert.clone(), Change::Cleared));
+                }
+                continue;
+            }
+            let (since, raised) = self
+                .pending
+                .entry(alert.name.clone())
+                .or_insert((now, false));
+            if !*raised && now - *since >= alert.duration {
+                *raised = true;
+                changes.push((alert.clone(), Change::Raised));
+            }
+        }
+        changes
+    }
+
+    /// The names of the alerts that have gone off and not cleared.
+    pub fn active(&self) -> Vec<&str> {
+        let mut active: Vec<&str> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, raised))| *raised)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        active.sort();
+        active
+    }
+}
+
+/// Parse a duration like "30s", "5m" or "2h".  A plain number is
+/// minutes.
+fn parse_duration(s: &str) -> Result<std::time::Duration, eyre::Report> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number =
+        f64::from_str(number.trim()).map_err(|e| eyre::eyre!("bad duration {:?}: {}", s, e))?;
+    if number < 0.0 {
+        return Err(eyre::eyre!("bad duration {:?}: can't be negative", s));
+    }
+    let seconds = match unit {
+        "s" => number,
+        "" | "m" | "min" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        _ => {
+            return Err(eyre::eyre!(
+                "bad duration {:?}: expected a number of s, m or h",
+                s
+            ))
+        }
+    };
+    std::time::Duration::try_from_secs_f64(seconds)
+        .map_err(|e| eyre::eyre!("bad duration {:?}: {}", s, e))
+}
+
+impl FromStr for Alert {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, rest)) = s.split_once('=') else {
+            return Err(eyre::eyre!(
+                "alert {:?} is not of the form name=condition [for DURATION] [during HH:MM-HH:MM]",
+                s
+            ));
+        };
+        let (rest, window) = match rest.rsplit_once(" during ") {
+            Some((rest, window)) => (rest, Some(crate::schedule::TimeWindow::from_str(window)?)),
+            None => (rest, None),
+        };
+        let (condition, duration) = match rest.rsplit_once(" for ") {
+            Some((condition, duration)) => (condition, parse_duration(duration)?),
+            None => (rest, std::time::Duration::ZERO),
+        };
+        let name = name.trim();
+        let condition = condition.trim();
+        Ok(Self {
+            name: String::from(name),
+            condition: String::from(condition),
+            duration,
+            window,
+            expr: crate::sensor::Sensor::from_str(&format!("{name}={condition}"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let alert =
+            Alert::from_str("no_production=production_w_now < 10 for 30m during 10:00-14:00")
+                .unwrap();
+        assert_eq!(alert.name, "no_production");
+        assert_eq!(alert.condition, "production_w_now < 10");
+        assert_eq!(alert.duration, std::time::Duration::from_secs(30 * 60));
+        assert!(alert.window.is_some());
+
+        let alert = Alert::from_str("high_import=export_w < -8000").unwrap();
+        assert_eq!(alert.duration, std::time::Duration::ZERO);
+        assert!(alert.window.is_none());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s").unwrap().as_secs(), 90);
+        assert_eq!(parse_duration("5").unwrap().as_secs(), 300);
+        assert_eq!(parse_duration("1.5h").unwrap().as_secs(), 5400);
+        for bad in ["-5m", "5d", "m", "1000000000000000000000000s"] {
+            assert!(parse_duration(bad).is_err(), "{bad:?} parsed");
+        }
+    }
+}
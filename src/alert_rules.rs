@@ -0,0 +1,80 @@
+// Suggested Prometheus alerting rules for the --metrics-listen metrics,
+// for when the controller itself is what's gone wrong:
+//
+// ```text
+// $ solar-evse alert-rules --job solar-evse > /etc/prometheus/rules/solar-evse.yml
+// ```
+//
+// - SolarEvseDown: Prometheus can't scrape the controller, or has
+//   never heard of it.
+// - SolarEvseStaleData: the meter's last reading is older than
+//   --stale-after, so we're charging on old numbers (an Envoy that's
+//   stopped updating still answers).
+// - SolarEvseExcessiveImport: the house has been importing more than
+//   --import-amps for --import-for while the EV charges, so we're not
+//   following the surplus.
+//
+// They're a starting point: the file is meant to be edited to taste,
+// not regenerated.
+
+/// The rules file, in YAML.
+pub fn render(
+    job: &str,
+    stale_after: u64,
+    import_amps: f64,
+    import_for: u64,
+) -> Result<String, eyre::Report> {
+    if job.contains(['"', '\\', '\n']) {
+        return Err(eyre::eyre!("--job {:?} can't be put in a rule", job));
+    }
+    let selector = format!("{{job=\"{job}\"}}");
+    let rules = [
+        Rule {
+            alert: "SolarEvseDown",
+            expr: format!("up{selector} == 0 or absent(up{selector})"),
+            duration: 300,
+            severity: "critical",
+            summary: String::from("solar-evse isn't running, or can't be scraped"),
+        },
+        Rule {
+            alert: "SolarEvseStaleData",
+            expr: format!(
+                "time() - solar_evse_meter_reading_timestamp_seconds{selector} > {stale_after}"
+            ),
+            duration: 60,
+            severity: "warning",
+            summary: format!("solar-evse's meter reading is more than {stale_after} s old"),
+        },
+        Rule {
+            alert: "SolarEvseExcessiveImport",
+            expr: format!(
+                "solar_evse_export_current_amps{selector} < -{import_amps} and solar_evse_evse_current_amps{selector} > 0"
+            ),
+            duration: import_for,
+            severity: "warning",
+            summary: format!(
+                "importing more than {import_amps} A from the grid while the EV charges"
+            ),
+        },
+    ];
+
+    let mut text = String::from("groups:\n  - name: solar-evse\n    rules:\n");
+    for rule in rules {
+        text += &format!("      - alert: {}\n", rule.alert);
+        text += &format!("        expr: {:?}\n", rule.expr);
+        text += &format!("        for: {}s\n", rule.duration);
+        text += "        labels:\n";
+        text += &format!("          severity: {}\n", rule.severity);
+        text += "        annotations:\n";
+        text += &format!("          summary: {:?}\n", rule.summary);
+    }
+    Ok(text)
+}
+
+struct Rule {
+    alert: &'static str,
+    expr: String,
+    duration: u64,
+    severity: &'static str,
+    summary: String,
+}
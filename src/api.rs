@@ -0,0 +1,598 @@
+// A local HTTP API for checking on the controller and changing its
+// settings while it runs, for scripts and headless installs.
+//
+// ```text
+// $ curl http://localhost:8090/status
+// {"mode": "eco", "state": "Tracking", "export_current": 1.2, ...}
+// $ curl -d '{"mode": "fast"}' http://localhost:8090/mode
+// $ curl -d '{"minutes": 90}' http://localhost:8090/boost
+// $ curl -d '{"kwh": 10}' http://localhost:8090/boost
+// $ curl -d '{"charge_limit_cap": 16, "target_export_current": 0.5}' http://localhost:8090/limits
+// $ curl -d '{"percent": 62}' http://localhost:8090/ev_soc
+// $ curl -d '{"dry_run": true}' http://localhost:8090/reload
+// ```
+//
+// The POSTs turn into the same commands as the MQTT command topics (see
+// `Controller::handle_command()` in lib.rs), which the control loop
+// carries out between cycles and then answers, so a bad value gets a
+// 400 with the reason.  `/status` is what the control loop last
+// reported, and `/history` is what it reported each cycle for the last
+// day or so, and `/history?resolution=1m` (or `5m`, `1h`) the same
+// downsampled, going back further (see downsample.rs).
+// `/energy_flows` is where each day's energy came from and went to (see
+// energy_flow.rs), for Sankey diagrams.
+//
+// `/` is a dashboard page that charts the history and says why the EV
+// is or isn't charging.
+//
+// It can listen on more than one address (the LAN's and localhost's,
+// but not the guest network's), serve HTTPS with --http-tls-cert and
+// --http-tls-key, and ask for a username and password with
+// --http-username:
+//
+// ```text
+// $ curl --cacert cert.pem -u admin:secret https://evse.lan:8090/status
+// ```
+//
+// and take API tokens from --http-token-file, each either "read" (GETs
+// only, for a wall-mounted dashboard tablet, say) or "control"
+// (everything), given as a bearer token or, for a browser, in the URL:
+//
+// ```text
+// $ cat /etc/solar-evse/tokens
+// 6f1c9e0d2b7a read
+// 93a4d8e51fc0 control
+// $ curl -H "Authorization: Bearer 93a4d8e51fc0" -d '{"mode": "fast"}' http://evse.lan:8090/mode
+// $ firefox http://evse.lan:8090/?token=6f1c9e0d2b7a
+// ```
+//
+// (The MQTT commands are up to the broker's access control.)
+
+/// How many cycles of history to keep, a day's worth at the default
+/// --period.
+const HISTORY_LEN: usize = 24 * 60;
+
+/// What the control loop reports for the API to serve.
+#[derive(Debug, Default)]
+pub struct Published {
+    pub status: serde_json::Value,
+    pub energy_flows: serde_json::Value,
+    history: std::collections::VecDeque<serde_json::Value>,
+    downsampler: crate::downsample::Downsampler,
+}
+
+impl Published {
+    /// Add a cycle's status, taken at `time`, to the history, forgetting
+    /// the oldest if it's full, and its numbers to the downsampled
+    /// history.
+    pub fn record(&mut self, time: chrono::DateTime<chrono::Local>, entry: serde_json::Value) {
+        if let Some(fields) = entry.as_object() {
+            let values: Vec<(&str, f64)> = fields
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.as_f64()?)))
+                .collect();
+            self.downsampler.add(time, &values);
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(entry);
+    }
+}
+
+/// How to serve the API: over HTTPS, and with basic authentication or
+/// tokens.
+#[derive(Default)]
+pub struct Security {
+    pub tls: Option<tokio_rustls::TlsAcceptor>,
+
+    // The username and password.
+    pub credentials: Option<(String, String)>,
+
+    pub tokens: std::collections::HashMap<String, Scope>,
+}
+
+/// What an API token lets its holder do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    // Look, but not change anything.
+    Read,
+
+    // Everything the username and password can.
+    Control,
+}
+
+impl Scope {
+    /// Whether the scope allows a `method` request.
+    fn permits(&self, method: &axum::http::Method) -> bool {
+        match self {
+            Self::Read => matches!(*method, axum::http::Method::GET | axum::http::Method::HEAD),
+            Self::Control => true,
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "control" => Ok(Self::Control),
+            _ => Err(eyre::eyre!(
+                "unknown API token scope {:?}, expected read or control",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse an --http-token-file: a token and its scope on each line, and
+/// blank lines and #comments.
+pub fn parse_tokens(
+    contents: &str,
+) -> Result<std::collections::HashMap<String, Scope>, eyre::Report> {
+    let mut tokens = std::collections::HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((token, scope)) = line.split_once(char::is_whitespace) else {
+            return Err(eyre::eyre!("API token line {:?} has no scope", line));
+        };
+        tokens.insert(String::from(token), scope.trim().parse()?);
+    }
+    Ok(tokens)
+}
+
+/// Make an HTTPS acceptor from a PEM certificate chain and private key.
+pub fn tls_acceptor(
+    cert_filename: &std::path::Path,
+    key_filename: &std::path::Path,
+) -> Result<tokio_rustls::TlsAcceptor, eyre::Report> {
+    let read = |filename: &std::path::Path| {
+        std::fs::read(filename).map_err(|e| eyre::eyre!("can't read {}: {}", filename.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut read(cert_filename)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| eyre::eyre!("bad certificate {}: {}", cert_filename.display(), e))?;
+    let key = rustls_pemfile::private_key(&mut read(key_filename)?.as_slice())
+        .map_err(|e| eyre::eyre!("bad private key {}: {}", key_filename.display(), e))?
+        .ok_or(eyre::eyre!("no private key in {}", key_filename.display()))?;
+    let config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config)))
+}
+
+pub struct Command {
+    pub name: String,
+    pub value: String,
+    pub reply: tokio::sync::oneshot::Sender<Result<(), String>>,
+}
+
+#[derive(Clone)]
+struct Shared {
+    published: std::sync::Arc<std::sync::Mutex<Published>>,
+    commands: tokio::sync::mpsc::Sender<Command>,
+}
+
+/// Start serving the API on each of `listen`, in the background, with
+/// the history downsampled by `downsampler`.  Returns where to put what
+/// we serve, and the commands to carry out.
+pub async fn serve(
+    listen: &[String],
+    security: Security,
+    downsampler: crate::downsample::Downsampler,
+) -> Result<
+    (
+        std::sync::Arc<std::sync::Mutex<Published>>,
+        tokio::sync::mpsc::Receiver<Command>,
+    ),
+    eyre::Report,
+> {
+    let published = std::sync::Arc::new(std::sync::Mutex::new(Published {
+        downsampler,
+        ..Published::default()
+    }));
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    let shared = Shared {
+        published: published.clone(),
+        commands: tx,
+    };
+    let app = axum::Router::new()
+        .route(
+            "/",
+            axum::routing::get(|| async { axum::response::Html(include_str!("dashboard.html")) }),
+        )
+        .route("/status", axum::routing::get(get_status))
+        .route("/history", axum::routing::get(get_history))
+        .route("/energy_flows", axum::routing::get(get_energy_flows))
+        .route("/mode", axum::routing::post(post_mode))
+        .route("/boost", axum::routing::post(post_boost))
+        .route("/limits", axum::routing::post(post_limits))
+        .route("/ev_soc", axum::routing::post(post_ev_soc))
+        .route("/reload", axum::routing::post(post_reload))
+        .with_state(shared);
+    let app = match (security.credentials, security.tokens) {
+        (None, tokens) if tokens.is_empty() => app,
+        (credentials, tokens) => {
+            use base64::Engine;
+            let expected = credentials.map(|(username, password)| {
+                format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD
+                        .encode(format!("{username}:{password}"))
+                )
+            });
+            let tokens = std::sync::Arc::new(tokens);
+            app.layer(axum::middleware::from_fn(move |request, next| {
+                authenticate(expected.clone(), tokens.clone(), request, next)
+            }))
+        }
+    };
+    for listen in listen {
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .map_err(|e| eyre::eyre!("can't listen for HTTP on {listen}: {e}"))?;
+        let app = app.clone();
+        match &security.tls {
+            Some(tls) => {
+                tokio::spawn(serve_tls(listener, tls.clone(), app));
+            }
+            None => {
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(listener, app).await {
+                        println!("HTTP API server failed: {e}");
+                    }
+                });
+            }
+        }
+    }
+    Ok((published, rx))
+}
+
+/// Serve `app` over HTTPS, forever.
+async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    tls: tokio_rustls::TlsAcceptor,
+    app: axum::Router,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("HTTPS API server failed to accept: {e}");
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let service = hyper_util::service::TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("HTTPS handshake with {peer} failed: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                .await
+            {
+                println!("HTTPS connection with {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// The API token a request comes with, as a bearer token or a "token"
+/// query parameter.
+fn token(request: &axum::extract::Request) -> Option<&str> {
+    let bearer = request
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    bearer.or_else(|| {
+        request
+            .uri()
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    })
+}
+
+/// Turn away requests without the username and password, or a token
+/// whose scope allows them.
+async fn authenticate(
+    expected: Option<String>,
+    tokens: std::sync::Arc<std::collections::HashMap<String, Scope>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    let authorized = expected.is_some_and(|expected| {
+        request
+            .headers()
+            .get(axum::http::header::AUTHORIZATION)
+            .is_some_and(|value| value.as_bytes() == expected.as_bytes())
+    });
+    if authorized {
+        return next.run(request).await;
+    }
+    match token(&request).and_then(|token| tokens.get(token)) {
+        Some(scope) if scope.permits(request.method()) => return next.run(request).await,
+        Some(_) => {
+            return error(
+                axum::http::StatusCode::FORBIDDEN,
+                "this token can only read",
+            )
+            .into_response()
+        }
+        None => {}
+    }
+    (
+        axum::http::StatusCode::UNAUTHORIZED,
+        [(
+            axum::http::header::WWW_AUTHENTICATE,
+            "Basic realm=\"solar-evse\"",
+        )],
+        "unauthorized\n",
+    )
+        .into_response()
+}
+
+/// The next command, or never if there's no API.
+pub async fn next(rx: &mut Option<tokio::sync::mpsc::Receiver<Command>>) -> Option<Command> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+type Reply = (axum::http::StatusCode, axum::Json<serde_json::Value>);
+
+fn error(status: axum::http::StatusCode, message: impl std::fmt::Display) -> Reply {
+    (
+        status,
+        axum::Json(serde_json::json!({ "error": message.to_string() })),
+    )
+}
+
+/// Parse a request body as JSON, whatever its Content-Type says (so
+/// `curl -d` works).
+fn parse(body: &str) -> Result<serde_json::Value, Reply> {
+    serde_json::from_str(body).map_err(|e| {
+        error(
+            axum::http::StatusCode::BAD_REQUEST,
+            format!("bad JSON: {e}"),
+        )
+    })
+}
+
+async fn get_status(axum::extract::State(shared): axum::extract::State<Shared>) -> Reply {
+    let status = shared.published.lock().unwrap().status.clone();
+    (axum::http::StatusCode::OK, axum::Json(status))
+}
+
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    resolution: Option<crate::downsample::Resolution>,
+}
+
+async fn get_history(
+    axum::extract::State(shared): axum::extract::State<Shared>,
+    axum::extract::Query(query): axum::extract::Query<HistoryQuery>,
+) -> Reply {
+    let published = shared.published.lock().unwrap();
+    let history: Vec<serde_json::Value> = match query.resolution {
+        Some(resolution) => published.downsampler.points(resolution),
+        None => published.history.iter().cloned().collect(),
+    };
+    (
+        axum::http::StatusCode::OK,
+        axum::Json(serde_json::json!(history)),
+    )
+}
+
+async fn get_energy_flows(axum::extract::State(shared): axum::extract::State<Shared>) -> Reply {
+    let energy_flows = shared.published.lock().unwrap().energy_flows.clone();
+    (axum::http::StatusCode::OK, axum::Json(energy_flows))
+}
+
+/// Have the control loop carry out the commands, in order, stopping at
+/// the first that fails.  Reply with the status afterwards.
+async fn run_commands(shared: &Shared, commands: Vec<(&str, String)>) -> Reply {
+    for (name, value) in commands {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        let command = Command {
+            name: String::from(name),
+            value,
+            reply: reply_tx,
+        };
+        if shared.commands.send(command).await.is_err() {
+            return error(axum::http::StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+        }
+        match reply_rx.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return error(axum::http::StatusCode::BAD_REQUEST, e),
+            Err(_) => return error(axum::http::StatusCode::SERVICE_UNAVAILABLE, "shutting down"),
+        }
+    }
+    let status = shared.published.lock().unwrap().status.clone();
+    (axum::http::StatusCode::OK, axum::Json(status))
+}
+
+async fn post_mode(
+    axum::extract::State(shared): axum::extract::State<Shared>,
+    body: String,
+) -> Reply {
+    let body = match parse(&body) {
+        Ok(body) => body,
+        Err(reply) => return reply,
+    };
+    match body["mode"].as_str() {
+        Some(mode) => run_commands(&shared, vec![("mode", String::from(mode))]).await,
+        None => error(
+            axum::http::StatusCode::BAD_REQUEST,
+            "expected {\"mode\": \"eco\" | \"fast\" | \"off\" | \"pause\" | \"scheduled\"}",
+        ),
+    }
+}
+
+async fn post_boost(
+    axum::extract::State(shared): axum::extract::State<Shared>,
+    body: String,
+) -> Reply {
+    let body = match parse(&body) {
+        Ok(body) => body,
+        Err(reply) => return reply,
+    };
+    let budget = match (body["minutes"].as_f64(), body["kwh"].as_f64()) {
+        (Some(minutes), None) => format!("{minutes}m"),
+        (None, Some(kwh)) => format!("{kwh}kWh"),
+        _ => {
+            return error(
+                axum::http::StatusCode::BAD_REQUEST,
+                "expected {\"minutes\": <minutes>} or {\"kwh\": <kWh>}, 0 to cancel",
+            )
+        }
+    };
+    run_commands(&shared, vec![("boost", budget)]).await
+}
+
+async fn post_ev_soc(
+    axum::extract::State(shared): axum::extract::State<Shared>,
+    body: String,
+) -> Reply {
+    let body = match parse(&body) {
+        Ok(body) => body,
+        Err(reply) => return reply,
+    };
+    let Some(percent) = body["percent"].as_f64() else {
+        return error(
+            axum::http::StatusCode::BAD_REQUEST,
+            "expected {\"percent\": <state of charge>}",
+        );
+    };
+    run_commands(&shared, vec![("ev_soc", percent.to_string())]).await
+}
+
+async fn post_limits(
+    axum::extract::State(shared): axum::extract::State<Shared>,
+    body: String,
+) -> Reply {
+    let body = match parse(&body) {
+        Ok(body) => body,
+        Err(reply) => return reply,
+    };
+    let Some(fields) = body.as_object() else {
+        return error(
+            axum::http::StatusCode::BAD_REQUEST,
+            "expected a JSON object",
+        );
+    };
+    let mut commands = Vec::new();
+    for (name, value) in fields {
+        let command = match name.as_str() {
+            "charge_limit_cap" => "limit",
+            "grid_limit_w" => "grid_limit",
+            "evse_min_charge_current" | "evse_max_charge_current" | "target_export_current" => {
+                name.as_str()
+            }
+            _ => {
+                return error(
+                    axum::http::StatusCode::BAD_REQUEST,
+                    format!("unknown limit {name:?}"),
+                )
+            }
+        };
+        let value = match value {
+            serde_json::Value::Null => String::from("none"),
+            serde_json::Value::Number(n) => n.to_string(),
+            _ => {
+                return error(
+                    axum::http::StatusCode::BAD_REQUEST,
+                    format!("{name} should be a number"),
+                )
+            }
+        };
+        commands.push((command, value));
+    }
+    run_commands(&shared, commands).await
+}
+
+/// Read the settings again, or only say what would change with
+/// `{"dry_run": true}`, or apply the ones waiting for --reload-confirm
+/// with `{"confirm": true}` (see reload.rs).
+async fn post_reload(
+    axum::extract::State(shared): axum::extract::State<Shared>,
+    body: String,
+) -> Reply {
+    let body = match body.trim() {
+        "" => serde_json::json!({}),
+        body => match parse(body) {
+            Ok(body) => body,
+            Err(reply) => return reply,
+        },
+    };
+    let how = match (body["dry_run"].as_bool(), body["confirm"].as_bool()) {
+        (Some(true), Some(true)) => {
+            return error(
+                axum::http::StatusCode::BAD_REQUEST,
+                "a reload can't be a dry run and confirmed",
+            )
+        }
+        (Some(true), _) => "dry_run",
+        (_, Some(true)) => "confirm",
+        _ => "",
+    };
+    run_commands(&shared, vec![("reload", String::from(how))]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let tokens = parse_tokens(
+            "# the tablet in the hall\n6f1c9e0d2b7a read\n\n93a4d8e51fc0   control # me\n",
+        )
+        .unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens["6f1c9e0d2b7a"], Scope::Read);
+        assert_eq!(tokens["93a4d8e51fc0"], Scope::Control);
+        assert!(parse_tokens("6f1c9e0d2b7a").is_err());
+        assert!(parse_tokens("6f1c9e0d2b7a write").is_err());
+    }
+
+    #[test]
+    fn scopes() {
+        use axum::http::Method;
+        assert!(Scope::Read.permits(&Method::GET));
+        assert!(!Scope::Read.permits(&Method::POST));
+        assert!(Scope::Control.permits(&Method::GET));
+        assert!(Scope::Control.permits(&Method::POST));
+    }
+
+    #[test]
+    fn request_token() {
+        let request = |uri: &str, authorization: Option<&str>| {
+            let mut request = axum::http::Request::builder().uri(uri);
+            if let Some(authorization) = authorization {
+                request = request.header(axum::http::header::AUTHORIZATION, authorization);
+            }
+            request.body(axum::body::Body::empty()).unwrap()
+        };
+        assert_eq!(token(&request("/status", Some("Bearer abc"))), Some("abc"));
+        assert_eq!(
+            token(&request("/history?resolution=1m&token=abc", None)),
+            Some("abc")
+        );
+        assert_eq!(token(&request("/status", Some("Basic abc"))), None);
+        assert_eq!(token(&request("/status", None)), None);
+    }
+}
@@ -0,0 +1,90 @@
+// Keep track of changes made to the EVSE by someone other than us (the
+// OpenEVSE web UI, the phone app, a button press on the unit, etc), and
+// report them in a single aggregated notice per hour rather than one
+// message per change.
+
+// Pilot reports that arrive this soon after we changed the limit may
+// have been published before the EVSE saw our change.
+const GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(15);
+
+#[derive(Debug)]
+pub struct OverrideAudit {
+    interval: std::time::Duration,
+    last_report: std::time::Instant,
+    events: Vec<OverrideEvent>,
+
+    // The charge current limit we last sent to the EVSE, and when.
+    commanded: Option<(isize, std::time::Instant)>,
+}
+
+#[derive(Debug)]
+struct OverrideEvent {
+    time: chrono::DateTime<chrono::Local>,
+    reported_limit: f64,
+    commanded_limit: isize,
+}
+
+impl OverrideAudit {
+    pub fn new(interval: std::time::Duration) -> Self {
+        Self {
+            interval,
+            last_report: std::time::Instant::now(),
+            events: Vec::new(),
+            commanded: None,
+        }
+    }
+
+    /// Remember the charge current limit we just sent to the EVSE.
+    pub fn commanded(&mut self, limit: isize) {
+        self.commanded = Some((limit, std::time::Instant::now()));
+    }
+
+    /// Check a charge current limit reported by the EVSE against the
+    /// limit we last commanded, and remember it if they disagree.
+    pub fn check_pilot(&mut self, reported_limit: f64) {
+        let Some((commanded_limit, commanded_at)) = self.commanded else {
+            // We haven't set anything yet, so there's nothing to override.
+            return;
+        };
+        if commanded_at.elapsed() < GRACE_PERIOD {
+            return;
+        }
+        if reported_limit as isize == commanded_limit {
+            return;
+        }
+        if let Some(last) = self.events.last() {
+            // The EVSE re-publishes its pilot, don't count the same
+            // override more than once.
+            if last.reported_limit == reported_limit && last.commanded_limit == commanded_limit {
+                return;
+            }
+        }
+        self.events.push(OverrideEvent {
+            time: chrono::Local::now(),
+            reported_limit,
+            commanded_limit,
+        });
+    }
+
+    /// If it's been long enough since the last report and there's
+    /// something to report, return the aggregated notice (for the
+    /// "override_report" template) and start collecting again.
+    pub fn take_report(&mut self) -> Option<serde_json::Value> {
+        if self.events.is_empty() || self.last_report.elapsed() < self.interval {
+            return None;
+        }
+        let events: Vec<serde_json::Value> = self
+            .events
+            .drain(..)
+            .map(|event| {
+                serde_json::json!({
+                    "time": event.time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    "reported_limit": event.reported_limit,
+                    "commanded_limit": event.commanded_limit,
+                })
+            })
+            .collect();
+        self.last_report = std::time::Instant::now();
+        Some(serde_json::json!({ "events": events }))
+    }
+}
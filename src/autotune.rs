@@ -0,0 +1,279 @@
+// Auto-tuning the control loop.  `solar-evse autotune` holds the charge
+// current limit at what the EV's drawing, steps it by --step Amps (up,
+// or down if there's no room), and watches the meter's export current
+// follow:
+//
+// ```text
+// $ solar-evse --config solar-evse.toml autotune
+// ...
+// export current moved -5.82 A for a 6 A step: dead time 9 s, time constant 14 s, noise 0.31 A
+// suggested: --kp 0.30 --ki 0 --kd 0 --export-smoothing 0
+// ```
+//
+// The step stays within --evse-min-charge-current and
+// --evse-max-charge-current, and the EVSE is given back (or set to
+// charge at full blast) afterwards, as when the controller exits.
+//
+// The response is taken to be a first-order lag after a dead time: the
+// dead time is how long the export took to move a tenth of the way,
+// and the time constant how much longer it took to get 63% of the way.
+// Each cycle the controller corrects for all of the error it sees
+// times --kp, and it sees none of the last correction until the
+// response has mostly happened, so with a response time (dead time
+// plus time constant) of n cycles, --kp is cut to 1/(n+1) so the
+// corrections it stacks up in the meantime don't overshoot.  The
+// controller already adds its correction to what the EV's drawing,
+// which takes the error to zero on its own, so --ki is 0.  If the
+// export current is noisy (more than half an Amp standard deviation
+// while holding steady), --export-smoothing filters it over two
+// cycles.
+
+/// How far the export has to move for it to count as having started
+/// responding, and as having mostly responded.
+const STARTED: f64 = 0.1;
+const MOSTLY: f64 = 1.0 - 1.0 / std::f64::consts::E;
+
+/// The export current's standard deviation, in Amps, above which to
+/// smooth it.
+const NOISY: f64 = 0.5;
+
+/// A step test: the export current while holding the charge current
+/// limit at `from`, and then, with the time since the step in seconds,
+/// after stepping it to `to`.
+#[derive(Debug, Clone, Default)]
+pub struct Step {
+    pub from: f64,
+    pub to: f64,
+    pub before: Vec<f64>,
+    pub after: Vec<(f64, f64)>,
+}
+
+/// How the export current responded to a step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Response {
+    // Amps of export per Amp of charge current limit, about -1.
+    pub gain: f64,
+
+    // Seconds.
+    pub dead_time: f64,
+    pub time_constant: f64,
+
+    // The export current's standard deviation before the step, in
+    // Amps.
+    pub noise: f64,
+}
+
+/// Suggested control loop settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Suggestion {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub smoothing: f64,
+}
+
+impl std::fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "--kp {:.2} --ki {} --kd {} --export-smoothing {}",
+            self.kp, self.ki, self.kd, self.smoothing
+        )
+    }
+}
+
+fn mean(values: impl Iterator<Item = f64> + Clone) -> f64 {
+    let n = values.clone().count();
+    values.sum::<f64>() / n as f64
+}
+
+impl Step {
+    /// A step test of `step` Amps from `current`, what the EV's
+    /// drawing, up or down if there's no room up, staying within whole
+    /// Amps from `min` to `max`.
+    pub fn new(current: f64, step: f64, min: f64, max: f64) -> Result<Self, eyre::Report> {
+        if !step.is_finite() || step <= 0.0 {
+            return Err(eyre::eyre!("--step {} isn't a current to step by", step));
+        }
+        let (min, max) = (min.ceil(), max.floor());
+        if min.is_nan() || max.is_nan() || min > max {
+            return Err(eyre::eyre!(
+                "there's no whole Amp between the charge current range's {} A and {} A",
+                min,
+                max
+            ));
+        }
+        if current.is_nan() || current < min - 1.0 {
+            return Err(eyre::eyre!(
+                "the EV has to be charging to auto-tune, it's drawing {:.1} A",
+                current
+            ));
+        }
+        let from = current.round().clamp(min, max);
+        let to = if from + step <= max {
+            from + step
+        } else {
+            (from - step).max(min)
+        };
+        if (to - from).abs() < 1.0 {
+            return Err(eyre::eyre!(
+                "no room for a {} A step between {} A and {} A",
+                step,
+                min,
+                max
+            ));
+        }
+        Ok(Self {
+            from,
+            to,
+            ..Default::default()
+        })
+    }
+
+    /// Fit the response to the step.
+    pub fn response(&self) -> Result<Response, eyre::Report> {
+        let step = self.to - self.from;
+        if step == 0.0 || self.before.len() < 2 || self.after.len() < 3 {
+            return Err(eyre::eyre!("not enough of a step test to go on"));
+        }
+        let start = mean(self.before.iter().copied());
+        let noise = mean(self.before.iter().map(|x| (x - start).powi(2))).sqrt();
+        // Where it settled: the last third of the readings after the
+        // step.
+        let settled = &self.after[self.after.len() * 2 / 3..];
+        let end = mean(settled.iter().map(|(_, x)| *x));
+        let moved = end - start;
+        let gain = moved / step;
+        if !(-2.0..=-0.3).contains(&gain) {
+            return Err(eyre::eyre!(
+                "the export current moved {:.2} A for a {:.0} A step, was the EV drawing what it was offered?",
+                moved,
+                step
+            ));
+        }
+        let reached = |fraction: f64| {
+            self.after
+                .iter()
+                .find(|(_, x)| (x - start) / moved >= fraction)
+                .map(|(t, _)| *t)
+        };
+        let (Some(started), Some(mostly)) = (reached(STARTED), reached(MOSTLY)) else {
+            return Err(eyre::eyre!(
+                "the export current never settled after the step"
+            ));
+        };
+        Ok(Response {
+            gain,
+            dead_time: started,
+            time_constant: (mostly - started).max(0.0),
+            noise,
+        })
+    }
+}
+
+impl Response {
+    /// The control loop settings for this response, running every
+    /// `period` seconds.
+    pub fn suggest(&self, period: f64) -> Suggestion {
+        let cycles = ((self.dead_time + self.time_constant) / period).floor();
+        Suggestion {
+            kp: 1.0 / (cycles + 1.0) / self.gain.abs(),
+            ki: 0.0,
+            kd: 0.0,
+            smoothing: if self.noise > NOISY {
+                2.0 * period
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A step test with the export responding `dead_time` seconds after
+    /// the step, with a time constant of `tau`, read every 5 s.
+    fn step(dead_time: f64, tau: f64, noise: f64) -> Step {
+        Step {
+            from: 10.0,
+            to: 16.0,
+            before: vec![4.0 - noise, 4.0 + noise, 4.0 - noise, 4.0 + noise],
+            after: (1..=40)
+                .map(|i| {
+                    let t = 5.0 * i as f64;
+                    let x = if t < dead_time {
+                        0.0
+                    } else {
+                        1.0 - (-(t - dead_time) / tau).exp()
+                    };
+                    (t, 4.0 - 6.0 * x)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn new() {
+        let test = Step::new(9.6, 6.0, 6.0, 32.0).unwrap();
+        assert_eq!((test.from, test.to), (10.0, 16.0));
+        // Down if there's no room up.
+        let test = Step::new(30.0, 6.0, 6.0, 32.0).unwrap();
+        assert_eq!((test.from, test.to), (30.0, 24.0));
+        // Within whole Amps of the range.
+        let test = Step::new(9.0, 6.0, 6.5, 15.5).unwrap();
+        assert_eq!((test.from, test.to), (9.0, 15.0));
+    }
+
+    #[test]
+    fn new_bad() {
+        for step in [0.0, -6.0, f64::NAN, f64::INFINITY] {
+            assert!(Step::new(10.0, step, 6.0, 32.0).is_err(), "{step}");
+        }
+        // No whole Amp in the range.
+        assert!(Step::new(6.6, 6.0, 6.5, 6.8).is_err());
+        // Not charging.
+        assert!(Step::new(0.0, 6.0, 6.0, 32.0).is_err());
+        assert!(Step::new(f64::NAN, 6.0, 6.0, 32.0).is_err());
+        // No room for the step.
+        assert!(Step::new(6.0, 6.0, 6.0, 6.5).is_err());
+    }
+
+    #[test]
+    fn fast_meter() {
+        let response = step(0.0, 1.0, 0.0).response().unwrap();
+        assert!((response.gain + 1.0).abs() < 0.01, "{response:?}");
+        assert_eq!(response.dead_time, 5.0);
+        assert_eq!(response.time_constant, 0.0);
+        let suggestion = response.suggest(10.0);
+        assert!((suggestion.kp - 1.0).abs() < 0.01, "{suggestion:?}");
+        assert_eq!(suggestion.smoothing, 0.0);
+    }
+
+    #[test]
+    fn slow_meter() {
+        let response = step(12.0, 20.0, 1.0).response().unwrap();
+        assert_eq!(response.dead_time, 15.0);
+        assert_eq!(response.time_constant, 20.0);
+        assert_eq!(response.noise, 1.0);
+        // A 35 s response is 3 cycles of 10 s.
+        let suggestion = response.suggest(10.0);
+        assert!((suggestion.kp - 0.25).abs() < 0.01, "{suggestion:?}");
+        assert_eq!(suggestion.smoothing, 20.0);
+        assert_eq!(
+            suggestion.to_string(),
+            "--kp 0.25 --ki 0 --kd 0 --export-smoothing 20"
+        );
+    }
+
+    #[test]
+    fn no_response() {
+        let mut test = step(0.0, 1.0, 0.0);
+        for (_, x) in &mut test.after {
+            *x = 4.0;
+        }
+        assert!(test.response().is_err());
+        assert!(Step::default().response().is_err());
+    }
+}
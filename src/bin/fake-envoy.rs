@@ -0,0 +1,219 @@
+// A pretend Enphase Envoy, for demos and for trying out changes without
+// a real solar system.  It serves `production.json` with production and
+// consumption following a scenario, plus whatever a fake-openevse is
+// charging at.
+//
+// The scenario file has one line per point in time: seconds since
+// start, production in Watts, and house consumption (not counting the
+// EV) in Watts.  Power is interpolated between points, and the scenario
+// starts over after the last one.  For example a cloud passing over:
+//
+// ```text
+// # seconds production_w consumption_w
+// 0    6000 800
+// 60   6000 800
+// 90   1500 800
+// 150  1500 1200
+// 180  6000 800
+// ```
+//
+// ```
+// $ cargo run --features fakes --bin fake-envoy -- --scenario cloud.txt --openevse 127.0.0.1:8081
+// $ cargo run -- --envoy http://127.0.0.1:8080 --openevse 127.0.0.1:8081 --auth-token-filename /dev/null ...
+// ```
+
+use clap::Parser;
+
+#[path = "fake/http.rs"]
+mod http;
+
+/// Pretend to be an Enphase Envoy.
+#[derive(clap::Parser, Debug)]
+#[command(version, about, long_about=None)]
+struct Args {
+    /// The address to listen on for HTTP requests.
+    #[arg(long, default_value_t = String::from("127.0.0.1:8080"))]
+    listen: String,
+
+    /// The scenario file.  Without one, production and consumption are
+    /// constant.
+    #[arg(long)]
+    scenario: Option<std::path::PathBuf>,
+
+    /// Production in Watts when there's no scenario.
+    #[arg(long, default_value_t = 6000.0)]
+    production: f64,
+
+    /// House consumption in Watts when there's no scenario.
+    #[arg(long, default_value_t = 800.0)]
+    consumption: f64,
+
+    /// The fake-openevse (hostname:port) whose charge current to add to
+    /// the house consumption.
+    #[arg(long)]
+    openevse: Option<String>,
+
+    /// The grid voltage.
+    #[arg(long, default_value_t = 240.0)]
+    voltage: f64,
+}
+
+// (seconds, production_w, consumption_w)
+type Scenario = Vec<(f64, f64, f64)>;
+
+fn read_scenario(path: &std::path::Path) -> Result<Scenario, eyre::Report> {
+    let text = std::fs::read_to_string(path)?;
+    let mut scenario = Scenario::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields = line
+            .split_whitespace()
+            .map(|field| field.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| eyre::eyre!("{}:{}: {}", path.display(), i + 1, e))?;
+        let [seconds, production_w, consumption_w] = fields[..] else {
+            return Err(eyre::eyre!(
+                "{}:{}: expected \"seconds production_w consumption_w\"",
+                path.display(),
+                i + 1
+            ));
+        };
+        if scenario.last().is_some_and(|(prev, _, _)| seconds <= *prev) {
+            return Err(eyre::eyre!(
+                "{}:{}: time doesn't increase",
+                path.display(),
+                i + 1
+            ));
+        }
+        scenario.push((seconds, production_w, consumption_w));
+    }
+    if scenario.is_empty() {
+        return Err(eyre::eyre!("{} has no points", path.display()));
+    }
+    Ok(scenario)
+}
+
+/// Production and consumption `t` seconds into the scenario.
+fn scenario_at(scenario: &Scenario, t: f64) -> (f64, f64) {
+    let (end, _, _) = scenario[scenario.len() - 1];
+    let t = if end > 0.0 { t % end } else { 0.0 };
+    for pair in scenario.windows(2) {
+        let ((t0, p0, c0), (t1, p1, c1)) = (pair[0], pair[1]);
+        if t <= t1 {
+            let f = ((t - t0) / (t1 - t0)).clamp(0.0, 1.0);
+            return (p0 + f * (p1 - p0), c0 + f * (c1 - c0));
+        }
+    }
+    let (_, p, c) = scenario[scenario.len() - 1];
+    (p, c)
+}
+
+#[derive(Debug, Default)]
+struct Meters {
+    production_w: f64,
+    consumption_w: f64,
+    production_wh: f64,
+    consumption_wh: f64,
+    // Export counts negative here, like the Envoy's net-consumption.
+    net_wh: f64,
+}
+
+/// Ask the fake-openevse what it's charging at, in Watts.
+async fn ev_power(openevse: &str, voltage: f64) -> Result<f64, eyre::Report> {
+    let reply: serde_json::Value = reqwest::get(format!("http://{openevse}/r?json=1&rapi=%24GG"))
+        .await?
+        .json()
+        .await?;
+    let milliamps = reply["ret"]
+        .as_str()
+        .and_then(|ret| ret.split_whitespace().nth(1))
+        .and_then(|ma| ma.parse::<f64>().ok())
+        .ok_or(eyre::eyre!("bad $GG reply {reply}"))?;
+    Ok(milliamps / 1000.0 * voltage)
+}
+
+fn production_json(meters: &Meters, voltage: f64) -> String {
+    let now = chrono::Utc::now().timestamp();
+    let net_w = meters.consumption_w - meters.production_w;
+    let eim = |measurement_type: &str, w: f64, wh: f64| {
+        serde_json::json!({
+            "type": "eim",
+            "activeCount": 1,
+            "measurementType": measurement_type,
+            "readingTime": now,
+            "wNow": w,
+            "whLifetime": wh,
+            "rmsVoltage": voltage,
+            "rmsCurrent": w / voltage,
+        })
+    };
+    serde_json::json!({
+        "production": [
+            {
+                "type": "inverters",
+                "activeCount": 20,
+                "readingTime": now,
+                "wNow": meters.production_w,
+                "whLifetime": meters.production_wh,
+            },
+            eim("production", meters.production_w, meters.production_wh),
+        ],
+        "consumption": [
+            eim("total-consumption", meters.consumption_w, meters.consumption_wh),
+            eim("net-consumption", net_w, meters.net_wh),
+        ],
+        "storage": [{"type": "acb", "activeCount": 0, "wNow": 0}],
+    })
+    .to_string()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), eyre::Report> {
+    let args = Args::parse();
+    let scenario = match &args.scenario {
+        Some(path) => read_scenario(path)?,
+        None => vec![(0.0, args.production, args.consumption)],
+    };
+    let meters = std::sync::Arc::new(std::sync::Mutex::new(Meters::default()));
+
+    // Advance the meters once a second.
+    let start = std::time::Instant::now();
+    let ticker_meters = meters.clone();
+    let voltage = args.voltage;
+    let openevse = args.openevse.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let (production_w, house_w) = scenario_at(&scenario, start.elapsed().as_secs_f64());
+            let ev_w = match &openevse {
+                Some(openevse) => match ev_power(openevse, voltage).await {
+                    Ok(w) => w,
+                    Err(e) => {
+                        println!("can't read fake-openevse: {e}");
+                        0.0
+                    }
+                },
+                None => 0.0,
+            };
+            let mut meters = ticker_meters.lock().unwrap();
+            meters.production_w = production_w;
+            meters.consumption_w = house_w + ev_w;
+            meters.production_wh += production_w / 3600.0;
+            meters.consumption_wh += meters.consumption_w / 3600.0;
+            meters.net_wh += (meters.consumption_w - production_w) / 3600.0;
+        }
+    });
+
+    http::serve(&args.listen, move |request| {
+        let (route, _) = request.path.split_once('?').unwrap_or((request.path, ""));
+        match route {
+            "/production.json" => Some(production_json(&meters.lock().unwrap(), voltage)),
+            _ => None,
+        }
+    })
+    .await
+}
@@ -0,0 +1,294 @@
+// A pretend OpenEVSE with an EV plugged in, for demos and for trying
+// out changes without a real charger.  It answers the RAPI-over-HTTP
+// requests solar-evse makes, and the EV draws whatever the pilot
+// offers, up to its own maximum.
+//
+// ```
+// $ cargo run --features fakes --bin fake-openevse -- --listen 127.0.0.1:8081
+// $ curl 'http://127.0.0.1:8081/r?json=1&rapi=%24GG%5E24'
+// {"cmd":"$GG^24","ret":"$OK 16000 -1^0B"}
+// ```
+//
+// Like the real one, it checks the checksum on a command if there is
+// one, and puts one on every reply.  It also answers the WiFi
+// firmware's JSON /status and /config, and takes claims on /claims,
+// unless it's pretending to be older firmware with --rapi-only.
+
+use clap::Parser;
+
+#[path = "fake/http.rs"]
+mod http;
+
+/// Pretend to be an OpenEVSE charging an EV.
+#[derive(clap::Parser, Debug)]
+#[command(version, about, long_about=None)]
+struct Args {
+    /// The address to listen on for RAPI requests.
+    #[arg(long, default_value_t = String::from("127.0.0.1:8081"))]
+    listen: String,
+
+    /// The most current the EV will draw, in Amps.  0 means no EV is
+    /// plugged in.
+    #[arg(long, default_value_t = 32.0)]
+    ev_max_current: f64,
+
+    /// Trip the GFCI this many seconds after starting, and stay tripped
+    /// for --fault-for seconds.
+    #[arg(long)]
+    fault_after: Option<u64>,
+
+    #[arg(long, default_value_t = 30)]
+    fault_for: u64,
+
+    /// Only answer RAPI, like WiFi firmware before the JSON API.
+    #[arg(long)]
+    rapi_only: bool,
+}
+
+#[derive(Debug)]
+struct Evse {
+    // The pilot current limit, in Amps.
+    pilot: f64,
+    enabled: bool,
+
+    // When the EV started charging.  The fake EV is plugged in when
+    // we start.
+    session_start: std::time::Instant,
+
+    // When the GFCI trips, and when it's reset, with --fault-after.
+    fault: Option<(std::time::Instant, std::time::Instant)>,
+
+    // The claims on it, by client, in the order they were made.  The
+    // last one made wins, there's no ranking by priority.
+    claims: Vec<(String, serde_json::Value)>,
+}
+
+/// Make, change (with just the properties given) or release a claim,
+/// and do what the claims say, or go back to full current.
+fn claim(evse: &mut Evse, method: &str, client: &str, body: &str) -> Option<String> {
+    match method {
+        "POST" => {
+            let properties: serde_json::Value = serde_json::from_str(body).ok()?;
+            match evse.claims.iter_mut().find(|(c, _)| c == client) {
+                Some((_, claim)) => {
+                    for (key, value) in properties.as_object()? {
+                        claim[key] = value.clone();
+                    }
+                }
+                None => evse.claims.push((String::from(client), properties)),
+            }
+        }
+        "DELETE" => evse.claims.retain(|(c, _)| c != client),
+        _ => return None,
+    }
+    println!("claims: {:?}", evse.claims);
+    let claim = evse.claims.last().map(|(_, claim)| claim);
+    evse.enabled = claim.and_then(|claim| claim["state"].as_str()) != Some("disabled");
+    evse.pilot = claim
+        .and_then(|claim| claim["charge_current"].as_f64())
+        .unwrap_or(32.0)
+        .clamp(6.0, 32.0);
+    Some(String::from(r#"{"msg": "done"}"#))
+}
+
+fn rapi(evse: &mut Evse, ev_max_current: f64, command: &str) -> String {
+    let mut tokens = command.trim_start_matches('$').split_whitespace();
+    let now = std::time::Instant::now();
+    let tripped = evse
+        .fault
+        .is_some_and(|(start, end)| start <= now && now < end);
+    let vehicle = ev_max_current > 0.0;
+    match (tokens.next(), tokens.next()) {
+        (Some("GG"), _) => {
+            let current = if evse.enabled && vehicle && !tripped {
+                evse.pilot.min(ev_max_current)
+            } else {
+                0.0
+            };
+            format!("$OK {:.0} -1", current * 1000.0)
+        }
+        (Some("GE"), _) => format!("$OK {:.0} 0121", evse.pilot),
+        (Some("SC"), Some(amps)) => match amps.parse::<f64>() {
+            Ok(amps) => {
+                evse.pilot = amps.clamp(6.0, 32.0);
+                println!("pilot: {} A", evse.pilot);
+                format!("$OK {:.0}", evse.pilot)
+            }
+            Err(_) => String::from("$NK"),
+        },
+        (Some("FE"), _) => {
+            evse.enabled = true;
+            println!("enabled");
+            String::from("$OK")
+        }
+        (Some("FS"), _) => {
+            evse.enabled = false;
+            println!("sleeping");
+            String::from("$OK")
+        }
+        (Some("GS"), _) => {
+            let state = match (tripped, evse.enabled, vehicle) {
+                (true, _, _) => 6,
+                (false, false, _) => 254,
+                (false, true, false) => 1,
+                (false, true, true) => 3,
+            };
+            let elapsed = evse.session_start.elapsed().as_secs();
+            // The pilot state (2 for B, EV connected) and the vflags,
+            // with ECVF_EV_CONNECTED.
+            let (pilot, vflags) = if vehicle { (2, 0x0100) } else { (1, 0) };
+            format!("$OK {state} {elapsed} {pilot} {vflags:04X}")
+        }
+        (Some("GU"), _) => {
+            // Roughly, as if it had been charging at the pilot current
+            // the whole time.
+            let ws = if vehicle {
+                evse.pilot.min(ev_max_current) * 240.0 * evse.session_start.elapsed().as_secs_f64()
+            } else {
+                0.0
+            };
+            format!("$OK {ws:.0} {ws:.0}")
+        }
+        (Some("S1"), Some(_)) => {
+            println!("clock set: {}", command);
+            String::from("$OK")
+        }
+        (Some("GF"), _) => {
+            let trips = evse.fault.is_some_and(|(start, _)| start <= now) as u8;
+            format!("$OK {trips:X} 0 0")
+        }
+        (Some("GV"), _) => String::from("$OK 7.1.3 5.0.1"),
+        (Some("GC"), _) => String::from("$OK 6 32"),
+        (Some("GP"), _) => String::from("$OK 230 -2560 -2560"),
+        (Some("GD"), _) => String::from("$OK 0 0 0 0"),
+        (Some("GT"), _) => String::from("$OK 165 165 165 165 165 85"),
+        (Some("ST" | "S3"), Some(_)) => {
+            println!("timer set: {}", command);
+            String::from("$OK")
+        }
+        _ => String::from("$NK"),
+    }
+}
+
+/// The WiFi firmware's /status, put together from what RAPI says so
+/// the two agree.
+fn json_status(evse: &mut Evse, ev_max_current: f64) -> String {
+    let mut values = |command| -> Vec<f64> {
+        rapi(evse, ev_max_current, command)
+            .split_whitespace()
+            .skip(1)
+            .map(|value| value.parse().unwrap_or_default())
+            .collect()
+    };
+    let charging = values("GG");
+    let capacity = values("GE");
+    let state = values("GS");
+    let energy = values("GU");
+    serde_json::json!({
+        "amp": charging[0],
+        "pilot": capacity[0],
+        "state": state[0] as u8,
+        "vehicle": (ev_max_current > 0.0) as u8,
+        "elapsed": state[1] as u64,
+        "session_energy": energy[0] / 3600.0,
+    })
+    .to_string()
+}
+
+/// The RAPI checksum of `text`, the XOR of its bytes.
+fn checksum(text: &str) -> u8 {
+    text.bytes().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// Undo the %XX and + escaping in a query string value.
+fn unescape(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut chars = s.bytes();
+    while let Some(c) = chars.next() {
+        match c {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = chars.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => bytes.push(byte),
+                    None => bytes.extend_from_slice(&hex),
+                }
+            }
+            c => bytes.push(c),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), eyre::Report> {
+    let args = Args::parse();
+    let evse = std::sync::Mutex::new(Evse {
+        pilot: 32.0,
+        enabled: true,
+        session_start: std::time::Instant::now(),
+        fault: args.fault_after.map(|after| {
+            let start = std::time::Instant::now() + std::time::Duration::from_secs(after);
+            (
+                start,
+                start + std::time::Duration::from_secs(args.fault_for),
+            )
+        }),
+        claims: Vec::new(),
+    });
+    let ev_max_current = args.ev_max_current;
+    let rapi_only = args.rapi_only;
+    http::serve(&args.listen, move |request| {
+        let (route, query) = request.path.split_once('?').unwrap_or((request.path, ""));
+        match route {
+            "/r" => {}
+            "/override" if !rapi_only => return Some(String::from("{}")),
+            "/divertmode" if !rapi_only && request.method == "POST" => {
+                println!("divert mode: {}", request.body);
+                return Some(String::from(r#"{"msg": "done"}"#));
+            }
+            _ if route.starts_with("/claims/") && !rapi_only => {
+                let client = route.trim_start_matches("/claims/");
+                return claim(
+                    &mut evse.lock().unwrap(),
+                    request.method,
+                    client,
+                    request.body,
+                );
+            }
+            "/status" if !rapi_only => {
+                return Some(json_status(&mut evse.lock().unwrap(), ev_max_current))
+            }
+            "/config" if !rapi_only => {
+                return Some(
+                    serde_json::json!({
+                        "firmware": "7.1.3",
+                        "protocol": "5.0.1",
+                        "version": "4.1.2",
+                        "min_current_hard": 6,
+                        "max_current_hard": 32,
+                    })
+                    .to_string(),
+                )
+            }
+            _ => return None,
+        }
+        let command = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("rapi="))
+            .map(unescape)?;
+        let ret = match command.rsplit_once('^') {
+            Some((text, sum)) if u8::from_str_radix(sum, 16).ok() != Some(checksum(text)) => {
+                String::from("$NK")
+            }
+            Some((text, _)) => rapi(&mut evse.lock().unwrap(), ev_max_current, text),
+            None => rapi(&mut evse.lock().unwrap(), ev_max_current, &command),
+        };
+        let ret = format!("{ret}^{:02X}", checksum(&ret));
+        Some(serde_json::json!({ "cmd": command, "ret": ret }).to_string())
+    })
+    .await
+}
@@ -0,0 +1,70 @@
+// Just enough of an HTTP/1.1 server for the fake devices: one request
+// per connection, JSON replies.
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+pub struct Request<'a> {
+    // Not every fake cares about these.
+    #[allow(dead_code)]
+    pub method: &'a str,
+    pub path: &'a str,
+    #[allow(dead_code)]
+    pub body: &'a str,
+}
+
+/// Serve requests on `listen` forever, answering each with
+/// `handler(request)`, which returns the JSON body or None for 404.
+pub async fn serve(
+    listen: &str,
+    handler: impl Fn(&Request) -> Option<String> + Send + Sync + 'static,
+) -> Result<(), eyre::Report> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    println!("listening on {listen}");
+    let handler = std::sync::Arc::new(handler);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut request_line = String::new();
+            if stream.read_line(&mut request_line).await.is_err() {
+                return;
+            }
+            // Skip the headers, except for the body's length.
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                match stream.read_line(&mut line).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) if line.trim().is_empty() => break,
+                    Ok(_) => {
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap_or(0);
+                            }
+                        }
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            if stream.read_exact(&mut body).await.is_err() {
+                return;
+            }
+            let mut words = request_line.split_whitespace();
+            let request = Request {
+                method: words.next().unwrap_or("GET"),
+                path: words.next().unwrap_or("/"),
+                body: &String::from_utf8_lossy(&body),
+            };
+            let (status, body) = match handler(&request) {
+                Some(body) => ("200 OK", body),
+                None => ("404 Not Found", String::from("{}")),
+            };
+            let reply = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.get_mut().write_all(reply.as_bytes()).await;
+        });
+    }
+}
@@ -0,0 +1,190 @@
+// Boosting: charging at --evse-max-charge-current, surplus or not, until
+// a budget of time or energy is used up, and then going back to
+// whatever mode we were in.  A budget is given like "90m", "2h" or
+// "10kWh"; a plain number is minutes.
+
+use std::str::FromStr;
+
+/// The longest boost, a week.
+const MAX_TIME: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The most energy to boost by, in Watt-hours, well over any EV's
+/// battery.
+const MAX_ENERGY_WH: f64 = 1_000_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Budget {
+    Time(std::time::Duration),
+
+    // Watt-hours.
+    Energy(f64),
+}
+
+impl Budget {
+    /// True for a budget of nothing, which cancels a boost.
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Self::Time(duration) => duration.is_zero(),
+            Self::Energy(wh) => *wh <= 0.0,
+        }
+    }
+}
+
+impl std::fmt::Display for Budget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Time(duration) => write!(f, "{:.0} minutes", duration.as_secs_f64() / 60.0),
+            Self::Energy(wh) => write!(f, "{:.3} kWh", wh / 1000.0),
+        }
+    }
+}
+
+impl FromStr for Budget {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let split = lower
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(lower.len());
+        let (number, unit) = lower.split_at(split);
+        let number =
+            f64::from_str(number.trim()).map_err(|e| eyre::eyre!("bad boost {:?}: {}", s, e))?;
+        if !number.is_finite() {
+            return Err(eyre::eyre!("bad boost {:?}: not a number", s));
+        }
+        if number < 0.0 {
+            return Err(eyre::eyre!("bad boost {:?}: can't be negative", s));
+        }
+        let minutes = |m: f64| -> Result<Self, eyre::Report> {
+            let duration = std::time::Duration::try_from_secs_f64(m * 60.0)
+                .map_err(|e| eyre::eyre!("bad boost {:?}: {}", s, e))?;
+            if duration > MAX_TIME {
+                return Err(eyre::eyre!("bad boost {:?}: longer than a week", s));
+            }
+            Ok(Self::Time(duration))
+        };
+        let energy = |wh: f64| -> Result<Self, eyre::Report> {
+            if wh > MAX_ENERGY_WH {
+                return Err(eyre::eyre!(
+                    "bad boost {:?}: more than {:.0} kWh",
+                    s,
+                    MAX_ENERGY_WH / 1000.0
+                ));
+            }
+            Ok(Self::Energy(wh))
+        };
+        match unit {
+            "" | "m" | "min" | "minutes" => minutes(number),
+            "h" | "hours" => minutes(number * 60.0),
+            "kwh" => energy(number * 1000.0),
+            "wh" => energy(number),
+            _ => Err(eyre::eyre!(
+                "bad boost {:?}: expected minutes, or a number of m, h, kWh or Wh",
+                s
+            )),
+        }
+    }
+}
+
+/// A boost in progress.
+#[derive(Debug, Clone, Copy)]
+pub struct Boost {
+    pub budget: Budget,
+    started: std::time::Instant,
+
+    // The session energy when the boost started, in Watt-hours.
+    start_energy_wh: f64,
+}
+
+impl Boost {
+    pub fn new(budget: Budget, session_energy_wh: f64) -> Self {
+        Self {
+            budget,
+            started: std::time::Instant::now(),
+            start_energy_wh: session_energy_wh,
+        }
+    }
+
+    /// How long is left of a time budget.
+    pub fn remaining_time(&self) -> Option<std::time::Duration> {
+        match self.budget {
+            Budget::Time(duration) => Some(duration.saturating_sub(self.started.elapsed())),
+            Budget::Energy(_) => None,
+        }
+    }
+
+    /// How many Watt-hours are left of an energy budget.
+    pub fn remaining_energy_wh(&self, session_energy_wh: f64) -> Option<f64> {
+        match self.budget {
+            Budget::Time(_) => None,
+            Budget::Energy(wh) => Some((wh - (session_energy_wh - self.start_energy_wh)).max(0.0)),
+        }
+    }
+
+    /// Whether the budget's been used up.
+    pub fn is_over(&self, session_energy_wh: f64) -> bool {
+        self.remaining_time().is_some_and(|t| t.is_zero())
+            || self
+                .remaining_energy_wh(session_energy_wh)
+                .is_some_and(|wh| wh <= 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let minutes = |m: u64| Budget::Time(std::time::Duration::from_secs(m * 60));
+        assert_eq!(Budget::from_str("90").unwrap(), minutes(90));
+        assert_eq!(Budget::from_str("90m").unwrap(), minutes(90));
+        assert_eq!(Budget::from_str(" 2 Hours ").unwrap(), minutes(120));
+        assert_eq!(Budget::from_str("10kWh").unwrap(), Budget::Energy(10000.0));
+        assert_eq!(Budget::from_str("500 wh").unwrap(), Budget::Energy(500.0));
+        assert!(Budget::from_str("0").unwrap().is_zero());
+        assert!(Budget::from_str("0kWh").unwrap().is_zero());
+    }
+
+    #[test]
+    fn parse_bad() {
+        for s in [
+            "",
+            "-5",
+            "ten",
+            "10 furlongs",
+            "nan",
+            "inf",
+            "-inf",
+            "1e30",
+            "1e30h",
+            "8d",
+            "10081",
+            "1e30kWh",
+            "2000kWh",
+        ] {
+            assert!(Budget::from_str(s).is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn parse_huge() {
+        // What POST /boost makes of {"minutes": 1e30}, and of payloads
+        // like that over MQTT.
+        for minutes in [1e30, f64::MAX] {
+            assert!(Budget::from_str(&format!("{minutes}m")).is_err());
+            assert!(Budget::from_str(&format!("{minutes}")).is_err());
+        }
+    }
+
+    #[test]
+    fn energy_budget() {
+        let boost = Boost::new(Budget::Energy(1000.0), 2500.0);
+        assert_eq!(boost.remaining_time(), None);
+        assert_eq!(boost.remaining_energy_wh(3000.0), Some(500.0));
+        assert!(!boost.is_over(3000.0));
+        assert!(boost.is_over(3500.0));
+        assert_eq!(boost.remaining_energy_wh(4000.0), Some(0.0));
+    }
+}
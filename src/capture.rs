@@ -0,0 +1,90 @@
+// Write the raw data we get from the devices to files, so that when
+// something fails to parse, the actual payload can be attached to a
+// bug report.
+//
+// Each device gets its own file in the capture directory (`envoy.log`,
+// `openevse.log`), rotated to `envoy.log.1`, `envoy.log.2` etc when it
+// gets too big.  Secrets like the Envoy auth token are replaced with
+// "<redacted>" before anything is written.
+
+use std::io::Write;
+
+#[derive(Debug)]
+pub struct Capture {
+    dir: std::path::PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    secrets: Vec<String>,
+    lock: std::sync::Mutex<()>,
+}
+
+impl Capture {
+    pub fn new(
+        dir: &std::path::Path,
+        max_bytes: u64,
+        max_files: usize,
+        secrets: &[&str],
+    ) -> Result<Self, eyre::Report> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            max_files,
+            secrets: secrets
+                .iter()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            lock: std::sync::Mutex::new(()),
+        })
+    }
+
+    /// Append a raw payload to the named capture file.  Failures are
+    /// reported but otherwise ignored, capturing is just a debugging
+    /// aid.
+    pub fn record(&self, name: &str, request: &str, payload: &str) {
+        if let Err(e) = self.try_record(name, request, payload) {
+            println!("failed to capture {} payload: {:#}", name, e);
+        }
+    }
+
+    fn try_record(&self, name: &str, request: &str, payload: &str) -> Result<(), eyre::Report> {
+        let mut record = format!(
+            "--- {} {}\n{}\n",
+            chrono::Local::now().to_rfc3339(),
+            request,
+            payload
+        );
+        for secret in &self.secrets {
+            record = record.replace(secret, "<redacted>");
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        let path = self.dir.join(format!("{name}.log"));
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            if metadata.len() + record.len() as u64 > self.max_bytes {
+                self.rotate(name)?;
+            }
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        file.write_all(record.as_bytes())?;
+        Ok(())
+    }
+
+    fn rotate(&self, name: &str) -> Result<(), eyre::Report> {
+        let path = |n: usize| match n {
+            0 => self.dir.join(format!("{name}.log")),
+            n => self.dir.join(format!("{name}.log.{n}")),
+        };
+        let _ = std::fs::remove_file(path(self.max_files));
+        for n in (0..self.max_files).rev() {
+            if path(n).exists() {
+                std::fs::rename(path(n), path(n + 1))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+// Trickle charging on freezing nights, whatever the sun's doing, so
+// the EV can keep its battery warm from the grid instead of from
+// itself.  With --cold-temperature-topic, while the outdoor temperature
+// published there is below --cold-below and it's in the --cold-window
+// (the off-peak hours, say), the EV gets at least
+// --cold-charge-current, until --cold-kwh has been delivered that way.
+// The budget starts afresh each time the window opens.
+
+/// Ignore a temperature reading older than this, the sensor's probably
+/// gone away.
+const STALE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub struct ColdCharging {
+    below: f64,
+    window: crate::schedule::TimeWindow,
+    budget_wh: f64,
+
+    // The last outdoor temperature, and when we got it.
+    temperature: Option<(f64, std::time::Instant)>,
+
+    // Energy trickle charged since the window opened.
+    used_wh: f64,
+}
+
+impl ColdCharging {
+    pub fn new(below: f64, window: crate::schedule::TimeWindow, budget_kwh: f64) -> Self {
+        Self {
+            below,
+            window,
+            budget_wh: budget_kwh * 1000.0,
+            temperature: None,
+            used_wh: 0.0,
+        }
+    }
+
+    pub fn set_temperature(&mut self, celsius: f64) {
+        self.temperature = Some((celsius, std::time::Instant::now()));
+    }
+
+    /// The outdoor temperature, if it's recent.
+    pub fn temperature(&self) -> Option<f64> {
+        self.temperature
+            .filter(|(_, time)| time.elapsed() < STALE)
+            .map(|(celsius, _)| celsius)
+    }
+
+    /// Whether to trickle charge right now.
+    pub fn active(&self) -> bool {
+        self.window.now()
+            && self.used_wh < self.budget_wh
+            && self
+                .temperature()
+                .is_some_and(|celsius| celsius < self.below)
+    }
+
+    /// Count energy delivered to the EV, and start the budget afresh
+    /// outside the window.
+    pub fn add(&mut self, wh: f64) {
+        if !self.window.now() {
+            self.used_wh = 0.0;
+        } else if self.active() {
+            self.used_wh += wh;
+        }
+    }
+
+    pub fn used_wh(&self) -> f64 {
+        self.used_wh
+    }
+}
@@ -0,0 +1,382 @@
+// Settings can come from a TOML config file as well as from the command
+// line.  The file's keys are the long command-line option names, with
+// either dashes or underscores:
+//
+// ```
+// version = 2
+// envoy = "envoy.local"
+// openevse = "openevse"
+// mqtt_broker = "mqtt.local"
+// auth_token_filename = "/etc/solar-evse/envoy-token"
+// evse_max_charge_current = 32
+// dither = true
+// webhook = ["https://example.com/hook/{event}"]
+// ```
+//
+// The file's settings are turned into command-line options and put in
+// front of the real command-line options, and the command-line parser
+// lets later options override earlier ones, so options given on the
+// command line win.
+//
+// `solar-evse config-schema` prints a JSON Schema for the file, made
+// from the command-line options, for editors to autocomplete and check
+// it with (for example with taplo's `#:schema` directive).  It spells
+// the keys with underscores.
+//
+// `version` is the file's format, 1 if it's not there.  A file in an
+// older format is migrated to the current one when it's read (at
+// startup, or on a reload), renaming the options that have been
+// renamed since, and written back, comments and all, with the old one
+// kept next to it:
+//
+// ```text
+// config: migrated /etc/solar-evse.toml from version 1 to 2 (keys are spelled with underscores), the old one is in /etc/solar-evse.toml.v1.bak
+// ```
+//
+// If it can't be written back it's migrated again each time it's read.
+
+/// The config file format.
+pub const VERSION: i64 = 2;
+
+/// How to migrate a config file to a version from the one before.
+struct Migration {
+    to: i64,
+    change: &'static str,
+    apply: fn(&mut toml_edit::Table),
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    to: 2,
+    change: "keys are spelled with underscores",
+    apply: underscores,
+}];
+
+/// A config file brought up to the current version.
+#[derive(Debug)]
+pub struct Migrated {
+    pub contents: String,
+    pub from: i64,
+    pub changes: Vec<&'static str>,
+}
+
+/// Version 2: spell the keys with underscores, like `config-schema`.
+fn underscores(table: &mut toml_edit::Table) {
+    let entries: Vec<(toml_edit::Key, toml_edit::Item)> = table
+        .iter()
+        .filter_map(|(key, _)| {
+            let (key, item) = table.get_key_value(key)?;
+            Some((key.clone(), item.clone()))
+        })
+        .collect();
+    table.clear();
+    for (key, item) in entries {
+        let renamed = toml_edit::Key::new(key.get().replace('-', "_"))
+            .with_leaf_decor(key.leaf_decor().clone());
+        table.insert_formatted(&renamed, item);
+    }
+}
+
+/// Bring a config file's `contents` up to the current version, or None
+/// if it's already there.
+pub fn migrate(contents: &str) -> Result<Option<Migrated>, eyre::Report> {
+    let mut document: toml_edit::DocumentMut = contents.parse()?;
+    let version = match document.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_integer()
+            .ok_or(eyre::eyre!("version {} isn't a number", version))?,
+    };
+    if version > VERSION {
+        return Err(eyre::eyre!(
+            "it's version {}, newer than this solar-evse's version {}",
+            version,
+            VERSION
+        ));
+    }
+    if version == VERSION {
+        return Ok(None);
+    }
+    let mut changes = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.to > version) {
+        (migration.apply)(document.as_table_mut());
+        changes.push(migration.change);
+    }
+    // The version goes first, the rest in the order they were.
+    let table = document.as_table_mut();
+    table.insert("version", toml_edit::value(VERSION));
+    table.sort_values_by(|a, _, b, _| (b.get() == "version").cmp(&(a.get() == "version")));
+    Ok(Some(Migrated {
+        contents: document.to_string(),
+        from: version,
+        changes,
+    }))
+}
+
+/// Migrate the config file `filename`, with `contents`, to the current
+/// version if it's older, writing it back and keeping the old one as a
+/// backup.  Returns the contents to use.
+fn migrate_file(filename: &std::path::Path, contents: String) -> Result<String, eyre::Report> {
+    let Some(migrated) = migrate(&contents)
+        .map_err(|e| eyre::eyre!("config file {}: {:#}", filename.display(), e))?
+    else {
+        return Ok(contents);
+    };
+    let mut backup = filename.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", migrated.from));
+    let backup = std::path::PathBuf::from(backup);
+    let written = std::fs::write(&backup, &contents)
+        .and_then(|()| std::fs::write(filename, &migrated.contents));
+    match written {
+        Ok(()) => println!(
+            "config: migrated {} from version {} to {} ({}), the old one is in {}",
+            filename.display(),
+            migrated.from,
+            VERSION,
+            migrated.changes.join(", "),
+            backup.display()
+        ),
+        Err(e) => println!(
+            "config: can't write {} migrated from version {} to {} ({}): {}",
+            filename.display(),
+            migrated.from,
+            VERSION,
+            migrated.changes.join(", "),
+            e
+        ),
+    }
+    Ok(migrated.contents)
+}
+
+/// The command-line arguments, with the settings from the `--config`
+/// file (if any) put in front of them.
+pub fn args() -> Result<Vec<std::ffi::OsString>, eyre::Report> {
+    expand(std::env::args_os().collect())
+}
+
+/// `args`, a command line starting with the program name, with the
+/// settings from its `--config` file (if any) put in front of them.
+pub fn expand(mut args: Vec<std::ffi::OsString>) -> Result<Vec<std::ffi::OsString>, eyre::Report> {
+    let Some(filename) = config_filename(&args) else {
+        return Ok(args);
+    };
+    let contents = std::fs::read_to_string(&filename)
+        .map_err(|e| eyre::eyre!("can't read config file {}: {}", filename.display(), e))?;
+    let contents = migrate_file(&filename, contents)?;
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e| eyre::eyre!("can't parse config file {}: {}", filename.display(), e))?;
+
+    let mut file_args = Vec::<std::ffi::OsString>::new();
+    for (key, value) in &table {
+        if key == "version" {
+            continue;
+        }
+        let option = format!("--{}", key.replace('_', "-"));
+        if option == "--config" {
+            return Err(eyre::eyre!(
+                "config file {} can't include another config file",
+                filename.display()
+            ));
+        }
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => file_args.push(option.clone().into()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(s) => file_args.push(format!("{option}={s}").into()),
+                toml::Value::Integer(i) => file_args.push(format!("{option}={i}").into()),
+                toml::Value::Float(f) => file_args.push(format!("{option}={f}").into()),
+                _ => {
+                    return Err(eyre::eyre!(
+                        "config file {}: unsupported value for {}",
+                        filename.display(),
+                        key
+                    ))
+                }
+            }
+        }
+    }
+
+    // Right after the program name, so they come before everything on
+    // the command line.
+    args.splice(1..1, file_args);
+    Ok(args)
+}
+
+/// A JSON Schema for the config file, with a key for each of
+/// `command`'s long options.
+pub fn schema(command: &clap::Command) -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    for arg in command.get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches!(long, "config" | "help" | "version") {
+            continue;
+        }
+
+        let parser = arg.get_value_parser();
+        let kind = if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+            "boolean"
+        } else if parser.type_id() == std::any::TypeId::of::<f64>() {
+            "number"
+        } else if [
+            std::any::TypeId::of::<u8>(),
+            std::any::TypeId::of::<u16>(),
+            std::any::TypeId::of::<u32>(),
+            std::any::TypeId::of::<u64>(),
+            std::any::TypeId::of::<usize>(),
+            std::any::TypeId::of::<i64>(),
+        ]
+        .iter()
+        .any(|id| parser.type_id() == *id)
+        {
+            "integer"
+        } else {
+            "string"
+        };
+
+        let mut value = serde_json::json!({ "type": kind });
+        if kind == "string" {
+            let names: Vec<String> = arg
+                .get_possible_values()
+                .iter()
+                .flat_map(|value| value.get_name_and_aliases())
+                .map(String::from)
+                .collect();
+            if !names.is_empty() {
+                value["enum"] = serde_json::json!(names);
+            }
+        }
+        if let Some(default) = arg.get_default_values().first().and_then(|d| d.to_str()) {
+            value["default"] = match kind {
+                "number" | "integer" => match serde_json::from_str(default) {
+                    Ok(number) => number,
+                    Err(_) => serde_json::json!(default),
+                },
+                _ => serde_json::json!(default),
+            };
+        }
+        // Options that can be given more than once can be a list, or
+        // just one.
+        if matches!(arg.get_action(), clap::ArgAction::Append) {
+            value = serde_json::json!({
+                "anyOf": [value, { "type": "array", "items": value }],
+            });
+        }
+        if let Some(help) = arg.get_long_help().or(arg.get_help()) {
+            value["description"] = serde_json::json!(help.to_string());
+        }
+        properties.insert(long.replace('-', "_"), value);
+    }
+
+    properties.insert(
+        String::from("version"),
+        serde_json::json!({
+            "type": "integer",
+            "maximum": VERSION,
+            "default": 1,
+            "description": "The config file format.",
+        }),
+    );
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "solar-evse config file",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+/// Find the `--config` option on the command line, if there is one.
+fn config_filename(args: &[std::ffi::OsString]) -> Option<std::path::PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.to_str() else {
+            continue;
+        };
+        if arg == "--" {
+            return None;
+        }
+        if arg == "--config" {
+            return args.next().map(std::path::PathBuf::from);
+        }
+        if let Some(filename) = arg.strip_prefix("--config=") {
+            return Some(std::path::PathBuf::from(filename));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_v1() {
+        let migrated = migrate(
+            "# the house\nenvoy = \"envoy.local\"\nevse-max-charge-current = 24  # breaker\nwebhook = [\"a\", \"b\"]\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(migrated.from, 1);
+        assert_eq!(migrated.changes, ["keys are spelled with underscores"]);
+        assert_eq!(
+            migrated.contents,
+            "version = 2\n# the house\nenvoy = \"envoy.local\"\nevse_max_charge_current = 24  # breaker\nwebhook = [\"a\", \"b\"]\n"
+        );
+        // Once is enough.
+        assert!(migrate(&migrated.contents).unwrap().is_none());
+    }
+
+    #[test]
+    fn migrate_bad() {
+        assert!(migrate("version = 3\n").is_err());
+        assert!(migrate("version = \"2\"\n").is_err());
+        assert!(migrate("envoy = \n").is_err());
+    }
+
+    #[test]
+    fn expand_file() {
+        let filename = std::env::temp_dir().join(format!(
+            "solar-evse-config-test-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &filename,
+            "envoy = \"envoy.local\"\nevse-max-charge-current = 24\ndither = true\nsensor = [\"a=1\", \"b=2\"]\n",
+        )
+        .unwrap();
+        let args = expand(vec![
+            "solar-evse".into(),
+            "--config".into(),
+            filename.clone().into(),
+            "--period=5".into(),
+        ])
+        .unwrap();
+        let mut backup = filename.clone().into_os_string();
+        backup.push(".v1.bak");
+        let migrated = std::fs::read_to_string(&filename);
+        let _ = std::fs::remove_file(&filename);
+        let _ = std::fs::remove_file(&backup);
+        assert_eq!(
+            args,
+            [
+                "solar-evse",
+                "--dither",
+                "--envoy=envoy.local",
+                "--evse-max-charge-current=24",
+                "--sensor=a=1",
+                "--sensor=b=2",
+                "--config",
+                filename.to_str().unwrap(),
+                "--period=5",
+            ]
+        );
+        assert!(migrated.unwrap().starts_with("version = 2\n"));
+    }
+}
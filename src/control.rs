@@ -0,0 +1,78 @@
+// The control loop's update rule.  By default the new charge current
+// limit is just what the EV is drawing plus the surplus over the target
+// (`limit = charge current + export - target`), which goes straight to
+// the right answer when the surplus is steady but chases every passing
+// cloud.  To calm it down:
+//
+// - --export-smoothing low-pass filters the export current, with a time
+//   constant in seconds, so short dips and spikes count for less.
+//
+// - --kp, --ki and --kd weight the error (the filtered export current
+//   minus the target), its integral over time and its rate of change.
+//   --kp 1 with the others 0 is the default rule.
+//
+// - --deadband leaves the charge current limit alone while the new one
+//   would be within that many Amps of the old one, so small wobbles in
+//   the surplus don't mean an EVSE write every cycle.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Gains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+
+    // Seconds, 0 for no filtering.
+    pub smoothing: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Controller {
+    // The low-pass filtered export current.
+    filtered: Option<f64>,
+
+    // The error integrated over time, in Amp-seconds.
+    integral: f64,
+
+    // The error last cycle.
+    previous_error: Option<f64>,
+}
+
+impl Controller {
+    /// Filter the export current measured over the last `dt` seconds.
+    pub fn filter(&mut self, gains: &Gains, export_current: f64, dt: f64) -> f64 {
+        let filtered = match self.filtered {
+            Some(filtered) if gains.smoothing > 0.0 => {
+                let alpha = 1.0 - (-dt / gains.smoothing).exp();
+                filtered + alpha * (export_current - filtered)
+            }
+            _ => export_current,
+        };
+        self.filtered = Some(filtered);
+        filtered
+    }
+
+    /// How much to change the EV's charge current by, for an error of
+    /// `error` Amps more export than the target, `dt` seconds after the
+    /// last update.  The integral is kept to no more than `max_current`
+    /// worth of correction.
+    pub fn correction(&mut self, gains: &Gains, error: f64, dt: f64, max_current: f64) -> f64 {
+        if gains.ki != 0.0 {
+            let bound = max_current / gains.ki.abs();
+            self.integral = (self.integral + error * dt).clamp(-bound, bound);
+        }
+        let derivative = match self.previous_error {
+            Some(previous) if dt > 0.0 => (error - previous) / dt,
+            _ => 0.0,
+        };
+        self.previous_error = Some(error);
+        gains.kp * error + gains.ki * self.integral + gains.kd * derivative
+    }
+
+    /// Forget the integral and derivative, for when we stop tracking
+    /// the surplus.  The filter carries on, the export current still
+    /// means the same thing.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.previous_error = None;
+    }
+}
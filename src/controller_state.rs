@@ -0,0 +1,47 @@
+// What the controller is doing, and why.  Each cycle the controller
+// decides which state it should be in (see `Controller::next_state()`
+// in lib.rs), announces the transition if it changed, and then acts
+// according to the state.  The state diagram is in the README.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControllerState {
+    /// No EV plugged in.  What we do with the EVSE is up to
+    /// `--unplugged`.
+    Idle,
+
+    /// EV plugged in, but not enough surplus to charge it.  The EVSE is
+    /// asleep.
+    Standby,
+
+    /// Charging the EV with surplus solar.
+    Tracking,
+
+    /// Charging the EV at the maximum charge current, whatever the
+    /// surplus, until the boost's time or energy budget runs out.
+    Boost,
+
+    /// Charging the EV with whatever keeps grid import under the
+    /// peak-shaving limit.
+    GridAssist,
+
+    /// The EVSE is unreachable or reports a fault.  We leave it alone
+    /// until it recovers.
+    Fault,
+}
+
+impl ControllerState {
+    pub const ALL: [ControllerState; 6] = [
+        ControllerState::Idle,
+        ControllerState::Standby,
+        ControllerState::Tracking,
+        ControllerState::Boost,
+        ControllerState::GridAssist,
+        ControllerState::Fault,
+    ];
+}
+
+impl std::fmt::Display for ControllerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
@@ -0,0 +1,150 @@
+// Making sure the EV gets at least --deadline-kwh by --deadline every
+// day, even when the sun doesn't cooperate.  We count the energy
+// delivered to the EV since the last deadline, solar or not, and if
+// there's still some to go, work out when charging at full power would
+// have to start to finish in time, and charge from the grid from then
+// on.  With --ev-target-soc, what's needed is whatever it takes to get
+// the EV's estimated SoC (see soc.rs) to the target instead.
+
+/// Start grid charging this much earlier than the estimate says, since
+/// EVs slow down as they fill up.
+const MARGIN: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+
+#[derive(Debug)]
+pub struct Deadline {
+    time: chrono::NaiveTime,
+    energy_wh: f64,
+
+    // Energy delivered to the EV since the last deadline.
+    delivered_wh: f64,
+
+    // The energy still needed to reach the target SoC, if we know it.
+    needed_wh: Option<f64>,
+
+    // The next time the energy is due.
+    next: chrono::DateTime<chrono::Local>,
+}
+
+impl Deadline {
+    pub fn new(time: chrono::NaiveTime, energy_kwh: f64) -> Self {
+        Self {
+            time,
+            energy_wh: energy_kwh * 1000.0,
+            delivered_wh: 0.0,
+            needed_wh: None,
+            next: next_deadline(time, chrono::Local::now()),
+        }
+    }
+
+    /// Count energy delivered to the EV.
+    pub fn add(&mut self, wh: f64) {
+        self.delivered_wh += wh;
+    }
+
+    /// Set how much more energy the EV needs to reach its target SoC,
+    /// None if we don't know and it's --deadline-kwh.
+    pub fn set_needed(&mut self, wh: Option<f64>) {
+        self.needed_wh = wh;
+    }
+
+    /// Start counting afresh once the deadline has passed.
+    pub fn update(&mut self, now: chrono::DateTime<chrono::Local>) {
+        if now < self.next {
+            return;
+        }
+        println!(
+            "deadline {}: delivered {:.3} of {:.3} kWh",
+            self.next.format("%Y-%m-%d %H:%M"),
+            self.delivered_wh / 1000.0,
+            self.energy_wh / 1000.0
+        );
+        self.delivered_wh = 0.0;
+        self.next = next_deadline(self.time, now);
+    }
+
+    pub fn next(&self) -> chrono::DateTime<chrono::Local> {
+        self.next
+    }
+
+    pub fn delivered_wh(&self) -> f64 {
+        self.delivered_wh
+    }
+
+    /// How much more energy the EV needs by the deadline.
+    pub fn remaining_wh(&self) -> f64 {
+        if let Some(needed_wh) = self.needed_wh {
+            return needed_wh;
+        }
+        (self.energy_wh - self.delivered_wh).max(0.0)
+    }
+
+    /// How long it would take to deliver the rest at `power_w`.
+    pub fn time_needed(&self, power_w: f64) -> std::time::Duration {
+        if power_w <= 0.0 {
+            return std::time::Duration::MAX;
+        }
+        std::time::Duration::try_from_secs_f64(3600.0 * self.remaining_wh() / power_w)
+            .unwrap_or(std::time::Duration::MAX)
+    }
+
+    /// When to start charging at `power_w` to finish by the deadline,
+    /// or None if there's nothing left to deliver.
+    pub fn start_time(&self, power_w: f64) -> Option<chrono::DateTime<chrono::Local>> {
+        if self.remaining_wh() <= 0.0 {
+            return None;
+        }
+        // If we can't charge at all, we should have started long ago.
+        let needed = chrono::Duration::from_std(self.time_needed(power_w).saturating_add(MARGIN))
+            .unwrap_or(chrono::Duration::days(365));
+        Some(self.next - needed)
+    }
+
+    /// Whether we have to charge at `power_w` from now on to make the
+    /// deadline.
+    pub fn must_charge(&self, now: chrono::DateTime<chrono::Local>, power_w: f64) -> bool {
+        self.start_time(power_w).is_some_and(|start| now >= start)
+    }
+}
+
+/// The first time after `now` that it's `time` of day.
+fn next_deadline(
+    time: chrono::NaiveTime,
+    now: chrono::DateTime<chrono::Local>,
+) -> chrono::DateTime<chrono::Local> {
+    let mut date = now.date_naive();
+    loop {
+        if let Some(deadline) = date
+            .and_time(time)
+            .and_local_timezone(chrono::Local)
+            .earliest()
+        {
+            if deadline > now {
+                return deadline;
+            }
+        }
+        date = date.succ_opt().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_needed() {
+        let mut deadline = Deadline::new(chrono::NaiveTime::from_hms_opt(7, 0, 0).unwrap(), 10.0);
+        assert_eq!(
+            deadline.time_needed(5000.0),
+            std::time::Duration::from_secs(2 * 60 * 60)
+        );
+        deadline.add(5000.0);
+        assert_eq!(
+            deadline.time_needed(5000.0),
+            std::time::Duration::from_secs(60 * 60)
+        );
+        assert_eq!(deadline.time_needed(0.0), std::time::Duration::MAX);
+        // Too long to say is as good as forever.
+        assert_eq!(deadline.time_needed(1e-300), std::time::Duration::MAX);
+        assert!(deadline.start_time(1e-300).is_some());
+    }
+}
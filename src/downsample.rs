@@ -0,0 +1,460 @@
+// Downsampled history, for charting the last day, week or month on the
+// dashboard without sending (and drawing) a point for every cycle: each
+// cycle's numbers are summed up into 1 minute, 5 minute and 1 hour
+// buckets, and a day, a week and a month of those are kept.
+//
+// ```text
+// $ curl http://localhost:8090/history?resolution=1h
+// [{"time": "2026-03-01T17:00:00+01:00", "export_current": 4.21, "charge_limit": 12.6, ...}, ...]
+// ```
+//
+// How a bucket's samples become one point is up to an `Aggregate`: the
+// mean by default, or the min, max or last value (--history-aggregate).
+// With --history-file the finished buckets are saved every hour and
+// read back at startup, so a restart doesn't lose the month.
+
+/// Sums up one bucket's samples of one number.
+pub trait Aggregate: std::fmt::Debug + Send {
+    fn add(&mut self, value: f64);
+
+    /// None if there were no samples.
+    fn value(&self) -> Option<f64>;
+}
+
+#[derive(Debug, Default)]
+struct Mean {
+    sum: f64,
+    count: u32,
+}
+
+impl Aggregate for Mean {
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn value(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Min(Option<f64>);
+
+impl Aggregate for Min {
+    fn add(&mut self, value: f64) {
+        self.0 = Some(self.0.map_or(value, |min| min.min(value)));
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Max(Option<f64>);
+
+impl Aggregate for Max {
+    fn add(&mut self, value: f64) {
+        self.0 = Some(self.0.map_or(value, |max| max.max(value)));
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Last(Option<f64>);
+
+impl Aggregate for Last {
+    fn add(&mut self, value: f64) {
+        self.0 = Some(value);
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.0
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum Method {
+    #[default]
+    Mean,
+    Min,
+    Max,
+    Last,
+}
+
+impl Method {
+    fn aggregate(&self) -> Box<dyn Aggregate> {
+        match self {
+            Self::Mean => Box::<Mean>::default(),
+            Self::Min => Box::<Min>::default(),
+            Self::Max => Box::<Max>::default(),
+            Self::Last => Box::<Last>::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum Resolution {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+}
+
+impl Resolution {
+    const ALL: [Self; 3] = [Self::OneMinute, Self::FiveMinutes, Self::OneHour];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::OneMinute => "1m",
+            Self::FiveMinutes => "5m",
+            Self::OneHour => "1h",
+        }
+    }
+
+    fn seconds(&self) -> i64 {
+        match self {
+            Self::OneMinute => 60,
+            Self::FiveMinutes => 5 * 60,
+            Self::OneHour => 60 * 60,
+        }
+    }
+
+    /// How many buckets to keep: a day, a week, and a month.
+    fn len(&self) -> usize {
+        match self {
+            Self::OneMinute => 24 * 60,
+            Self::FiveMinutes => 7 * 24 * 12,
+            Self::OneHour => 31 * 24,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Seconds since the epoch.
+    start: i64,
+    values: std::collections::BTreeMap<String, Box<dyn Aggregate>>,
+}
+
+#[derive(Debug)]
+struct Tier {
+    resolution: Resolution,
+    points: std::collections::VecDeque<serde_json::Value>,
+    bucket: Option<Bucket>,
+}
+
+impl Tier {
+    /// Add a sample, finishing the bucket if it's from the next one.
+    /// Returns whether it did.
+    fn add(&mut self, method: Method, time: i64, values: &[(&str, f64)]) -> bool {
+        let start = time - time.rem_euclid(self.resolution.seconds());
+        let mut finished = false;
+        if self
+            .bucket
+            .as_ref()
+            .is_some_and(|bucket| bucket.start != start)
+        {
+            if let Some(bucket) = self.bucket.take() {
+                self.finish(bucket);
+                finished = true;
+            }
+        }
+        let bucket = self.bucket.get_or_insert_with(|| Bucket {
+            start,
+            values: std::collections::BTreeMap::new(),
+        });
+        for (name, value) in values {
+            bucket
+                .values
+                .entry(String::from(*name))
+                .or_insert_with(|| method.aggregate())
+                .add(*value);
+        }
+        finished
+    }
+
+    fn finish(&mut self, bucket: Bucket) {
+        let mut point = serde_json::Map::new();
+        let time = chrono::DateTime::from_timestamp(bucket.start, 0)
+            .map(|time| time.with_timezone(&chrono::Local).to_rfc3339());
+        point.insert(String::from("time"), serde_json::json!(time));
+        for (name, aggregate) in bucket.values {
+            point.insert(name, serde_json::json!(aggregate.value()));
+        }
+        if self.points.len() == self.resolution.len() {
+            self.points.pop_front();
+        }
+        self.points.push_back(serde_json::Value::Object(point));
+    }
+}
+
+#[derive(Debug)]
+pub struct Downsampler {
+    method: Method,
+    filename: Option<std::path::PathBuf>,
+    tiers: Vec<Tier>,
+}
+
+impl Default for Downsampler {
+    fn default() -> Self {
+        Self {
+            method: Method::default(),
+            filename: None,
+            tiers: Resolution::ALL
+                .iter()
+                .map(|resolution| Tier {
+                    resolution: *resolution,
+                    points: std::collections::VecDeque::new(),
+                    bucket: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Downsampler {
+    /// Downsample with `method`, and save to `filename` if given, after
+    /// reading what was saved there last time.
+    pub fn new(method: Method, filename: Option<&std::path::Path>) -> Result<Self, eyre::Report> {
+        let mut downsampler = Self {
+            method,
+            filename: filename.map(std::path::Path::to_path_buf),
+            ..Self::default()
+        };
+        let Some(filename) = filename else {
+            return Ok(downsampler);
+        };
+        let mut saved: std::collections::HashMap<String, Vec<serde_json::Value>> =
+            match std::fs::read_to_string(filename) {
+                Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+                    eyre::eyre!("can't parse history file {}: {}", filename.display(), e)
+                })?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+                Err(e) => {
+                    return Err(eyre::eyre!(
+                        "can't read history file {}: {}",
+                        filename.display(),
+                        e
+                    ))
+                }
+            };
+        for tier in &mut downsampler.tiers {
+            let mut points = saved.remove(tier.resolution.name()).unwrap_or_default();
+            let excess = points.len().saturating_sub(tier.resolution.len());
+            tier.points = points.drain(excess..).collect();
+        }
+        Ok(downsampler)
+    }
+
+    /// Add a cycle's numbers, taken at `time`.
+    pub fn add(&mut self, time: chrono::DateTime<chrono::Local>, values: &[(&str, f64)]) {
+        let mut finished_hour = false;
+        for tier in &mut self.tiers {
+            let finished = tier.add(self.method, time.timestamp(), values);
+            finished_hour |= finished && tier.resolution == Resolution::OneHour;
+        }
+        if finished_hour {
+            if let Err(e) = self.save() {
+                println!("{e:#}");
+            }
+        }
+    }
+
+    /// The finished buckets at `resolution`, oldest first.
+    pub fn points(&self, resolution: Resolution) -> Vec<serde_json::Value> {
+        self.tiers
+            .iter()
+            .find(|tier| tier.resolution == resolution)
+            .map(|tier| tier.points.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<(), eyre::Report> {
+        let Some(filename) = &self.filename else {
+            return Ok(());
+        };
+        let saved: std::collections::HashMap<&str, &std::collections::VecDeque<serde_json::Value>> =
+            self.tiers
+                .iter()
+                .map(|tier| (tier.resolution.name(), &tier.points))
+                .collect();
+        // Write it next to the old one and then replace it, so we
+        // don't lose the lot if we die halfway through.
+        let temporary = filename.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_string(&saved)?)
+            .and_then(|()| std::fs::rename(&temporary, filename))
+            .map_err(|e| eyre::eyre!("can't write history file {}: {}", filename.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The start of an hour, in seconds since the epoch.
+    const HOUR: i64 = 1_772_380_800;
+
+    fn add(downsampler: &mut Downsampler, time: i64, values: &[(&str, f64)]) {
+        let time = chrono::DateTime::from_timestamp(time, 0)
+            .unwrap()
+            .with_timezone(&chrono::Local);
+        downsampler.add(time, values);
+    }
+
+    // The start of each finished bucket, and its value of `name`.
+    fn points(
+        downsampler: &Downsampler,
+        resolution: Resolution,
+        name: &str,
+    ) -> Vec<(i64, Option<f64>)> {
+        downsampler
+            .points(resolution)
+            .iter()
+            .map(|point| {
+                let time = chrono::DateTime::parse_from_rfc3339(point["time"].as_str().unwrap())
+                    .unwrap()
+                    .timestamp();
+                (time, point.get(name).and_then(serde_json::Value::as_f64))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn boundaries() {
+        let mut downsampler = Downsampler::default();
+        add(&mut downsampler, HOUR, &[("x", 1.0)]);
+        add(&mut downsampler, HOUR + 59, &[("x", 3.0)]);
+        // Nothing's finished until a sample from the next bucket.
+        assert_eq!(points(&downsampler, Resolution::OneMinute, "x"), []);
+
+        // A sample right on the boundary starts the next bucket.
+        add(&mut downsampler, HOUR + 60, &[("x", 10.0)]);
+        assert_eq!(
+            points(&downsampler, Resolution::OneMinute, "x"),
+            [(HOUR, Some(2.0))]
+        );
+        assert_eq!(points(&downsampler, Resolution::FiveMinutes, "x"), []);
+
+        add(&mut downsampler, HOUR + 299, &[("x", 10.0)]);
+        add(&mut downsampler, HOUR + 300, &[("x", 0.0)]);
+        assert_eq!(
+            points(&downsampler, Resolution::OneMinute, "x"),
+            [
+                (HOUR, Some(2.0)),
+                (HOUR + 60, Some(10.0)),
+                (HOUR + 240, Some(10.0))
+            ]
+        );
+        assert_eq!(
+            points(&downsampler, Resolution::FiveMinutes, "x"),
+            [(HOUR, Some(6.0))]
+        );
+
+        // A gap doesn't leave empty buckets.
+        add(&mut downsampler, HOUR + 3600, &[("x", 0.0)]);
+        assert_eq!(
+            points(&downsampler, Resolution::FiveMinutes, "x"),
+            [(HOUR, Some(6.0)), (HOUR + 300, Some(0.0))]
+        );
+        assert_eq!(
+            points(&downsampler, Resolution::OneHour, "x"),
+            [(HOUR, Some(4.8))]
+        );
+    }
+
+    #[test]
+    fn methods() {
+        for (method, value) in [
+            (Method::Mean, 2.0),
+            (Method::Min, -1.0),
+            (Method::Max, 5.0),
+            (Method::Last, 2.0),
+        ] {
+            let mut downsampler = Downsampler::new(method, None).unwrap();
+            for (time, x) in [(0, 2.0), (10, -1.0), (20, 5.0), (59, 2.0), (60, 100.0)] {
+                add(&mut downsampler, HOUR + time, &[("x", x)]);
+            }
+            assert_eq!(
+                points(&downsampler, Resolution::OneMinute, "x"),
+                [(HOUR, Some(value))],
+                "{method:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_values() {
+        // Each number is summed up over the samples that had it.
+        let mut downsampler = Downsampler::default();
+        add(&mut downsampler, HOUR, &[("x", 1.0)]);
+        add(&mut downsampler, HOUR + 10, &[("x", 3.0), ("y", 7.0)]);
+        add(&mut downsampler, HOUR + 20, &[]);
+        add(&mut downsampler, HOUR + 60, &[("y", 1.0)]);
+        add(&mut downsampler, HOUR + 120, &[]);
+        assert_eq!(
+            points(&downsampler, Resolution::OneMinute, "x"),
+            [(HOUR, Some(2.0)), (HOUR + 60, None)]
+        );
+        assert_eq!(
+            points(&downsampler, Resolution::OneMinute, "y"),
+            [(HOUR, Some(7.0)), (HOUR + 60, Some(1.0))]
+        );
+    }
+
+    #[test]
+    fn oldest_dropped() {
+        // A day of 1 minute buckets is kept.
+        let mut downsampler = Downsampler::default();
+        for minute in 0..24 * 60 + 3 {
+            add(
+                &mut downsampler,
+                HOUR + minute * 60,
+                &[("x", minute as f64)],
+            );
+        }
+        let points = points(&downsampler, Resolution::OneMinute, "x");
+        assert_eq!(points.len(), 24 * 60);
+        assert_eq!(points[0], (HOUR + 2 * 60, Some(2.0)));
+        assert_eq!(
+            points[24 * 60 - 1],
+            (HOUR + (24 * 60 + 1) * 60, Some(1441.0))
+        );
+    }
+
+    #[test]
+    fn history_file() {
+        let filename = std::env::temp_dir().join(format!(
+            "solar-evse-history-test-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&filename);
+
+        let mut downsampler = Downsampler::new(Method::Mean, Some(&filename)).unwrap();
+        add(&mut downsampler, HOUR, &[("x", 1.0)]);
+        add(&mut downsampler, HOUR + 60, &[("x", 2.0)]);
+        // Not saved until an hour's finished.
+        assert!(!filename.exists());
+        add(&mut downsampler, HOUR + 3600, &[("x", 3.0)]);
+
+        let downsampler = Downsampler::new(Method::Mean, Some(&filename)).unwrap();
+        assert_eq!(
+            points(&downsampler, Resolution::OneMinute, "x"),
+            [(HOUR, Some(1.0)), (HOUR + 60, Some(2.0))]
+        );
+        assert_eq!(
+            points(&downsampler, Resolution::OneHour, "x"),
+            [(HOUR, Some(1.5))]
+        );
+
+        std::fs::write(&filename, "not json").unwrap();
+        assert!(Downsampler::new(Method::Mean, Some(&filename)).is_err());
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
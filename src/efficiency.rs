@@ -0,0 +1,78 @@
+// The EV onboard charger's efficiency as a function of charge current,
+// for example "6=0.82,10=0.88,16=0.91,32=0.90" for 82% at 6 A rising to
+// 91% at 16 A.  Efficiency between the given currents is interpolated,
+// and outside them it's the nearest given value.
+//
+// Many onboard chargers waste a lot more of their input at low
+// currents, so a kWh of surplus diverted to the EV at 6 A puts less
+// into the battery than the same kWh at 16 A.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct EfficiencyCurve {
+    // (current, efficiency), sorted by current.
+    points: Vec<(f64, f64)>,
+}
+
+impl EfficiencyCurve {
+    /// The charger's efficiency at the specified charge current.
+    pub fn at(&self, current: f64) -> f64 {
+        let first = self.points.first().unwrap();
+        let last = self.points.last().unwrap();
+        if current <= first.0 {
+            return first.1;
+        }
+        if current >= last.0 {
+            return last.1;
+        }
+        let i = self.points.iter().position(|(c, _)| *c >= current).unwrap();
+        let (c0, e0) = self.points[i - 1];
+        let (c1, e1) = self.points[i];
+        e0 + (e1 - e0) * (current - c0) / (c1 - c0)
+    }
+
+    /// The highest charge current no higher than `limit` and no lower
+    /// than `floor` at which the charger is at least `min_efficiency`
+    /// efficient, looking at `limit` and the whole Amps below it.
+    pub fn efficient_limit(&self, limit: f64, floor: f64, min_efficiency: f64) -> Option<f64> {
+        std::iter::once(limit)
+            .chain(
+                (floor.ceil() as i64..=limit.floor() as i64)
+                    .rev()
+                    .map(|a| a as f64),
+            )
+            .filter(|current| *current >= floor)
+            .find(|current| self.at(*current) >= min_efficiency)
+    }
+}
+
+impl FromStr for EfficiencyCurve {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut points = Vec::new();
+        for point in s.split(',') {
+            let Some((current, efficiency)) = point.trim().split_once('=') else {
+                return Err(eyre::eyre!(
+                    "efficiency curve point {:?} is not of the form current=efficiency",
+                    point
+                ));
+            };
+            let current = f64::from_str(current.trim())
+                .map_err(|e| eyre::eyre!("bad current {:?} in efficiency curve: {}", current, e))?;
+            let efficiency = f64::from_str(efficiency.trim()).map_err(|e| {
+                eyre::eyre!("bad efficiency {:?} in efficiency curve: {}", efficiency, e)
+            })?;
+            if !(0.0..=1.0).contains(&efficiency) {
+                return Err(eyre::eyre!(
+                    "efficiency {} in efficiency curve is not between 0 and 1",
+                    efficiency
+                ));
+            }
+            points.push((current, efficiency));
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Self { points })
+    }
+}
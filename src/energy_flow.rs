@@ -0,0 +1,87 @@
+// Where the house's energy came from and went to, day by day, for
+// drawing Sankey diagrams: solar to the house, the EV and the grid, and
+// the grid to the house and the EV.
+//
+// Solar goes to the house's own loads first, then to the EV, and the
+// rest is exported.  A home battery counts as part of the house.  It
+// needs a meter that reports solar production (the Envoy and the
+// Powerwall do).
+
+/// How many days to remember.
+const DAYS: usize = 31;
+
+/// Energy in Watt-hours.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Flows {
+    pub solar_to_house: f64,
+    pub solar_to_ev: f64,
+    pub solar_to_grid: f64,
+    pub grid_to_house: f64,
+    pub grid_to_ev: f64,
+}
+
+impl Flows {
+    /// The flows while producing `production_w`, exporting `export_w`
+    /// (negative when importing) and charging the EV at `ev_w`, for
+    /// `hours`.
+    pub fn split(production_w: f64, export_w: f64, ev_w: f64, hours: f64) -> Self {
+        let production_w = production_w.max(0.0);
+        let ev_w = ev_w.max(0.0);
+        let solar_used = (production_w - export_w.max(0.0)).max(0.0);
+        let house_w = (production_w - export_w - ev_w).max(0.0);
+        let solar_to_house = solar_used.min(house_w);
+        let solar_to_ev = (solar_used - solar_to_house).min(ev_w);
+        Self {
+            solar_to_house: solar_to_house * hours,
+            solar_to_ev: solar_to_ev * hours,
+            solar_to_grid: export_w.max(0.0) * hours,
+            grid_to_house: (house_w - solar_to_house) * hours,
+            grid_to_ev: (ev_w - solar_to_ev) * hours,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.solar_to_house += other.solar_to_house;
+        self.solar_to_ev += other.solar_to_ev;
+        self.solar_to_grid += other.solar_to_grid;
+        self.grid_to_house += other.grid_to_house;
+        self.grid_to_ev += other.grid_to_ev;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EnergyFlows {
+    // Oldest first.
+    days: std::collections::VecDeque<(chrono::NaiveDate, Flows)>,
+}
+
+impl EnergyFlows {
+    pub fn add(&mut self, date: chrono::NaiveDate, flows: &Flows) {
+        if self.days.back().map(|(d, _)| *d) != Some(date) {
+            if self.days.len() == DAYS {
+                self.days.pop_front();
+            }
+            self.days.push_back((date, Flows::default()));
+        }
+        self.days.back_mut().unwrap().1.add(flows);
+    }
+
+    /// Each day's flows, in kWh.
+    pub fn to_json(&self) -> serde_json::Value {
+        let days: Vec<serde_json::Value> = self
+            .days
+            .iter()
+            .map(|(date, flows)| {
+                serde_json::json!({
+                    "date": date.to_string(),
+                    "solar_to_house_kwh": flows.solar_to_house / 1000.0,
+                    "solar_to_ev_kwh": flows.solar_to_ev / 1000.0,
+                    "solar_to_grid_kwh": flows.solar_to_grid / 1000.0,
+                    "grid_to_house_kwh": flows.grid_to_house / 1000.0,
+                    "grid_to_ev_kwh": flows.grid_to_ev / 1000.0,
+                })
+            })
+            .collect();
+        serde_json::json!(days)
+    }
+}
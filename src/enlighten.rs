@@ -0,0 +1,227 @@
+// Reading the CSV exports of a site's energy that Enphase's Enlighten
+// (and the Envoy's own reports) offer, for `solar-evse import-csv`
+// (see sessions.rs):
+//
+// ```text
+// Date/Time,Energy Produced (Wh),Energy Consumed (Wh),Exported to Grid (Wh),Imported from Grid (Wh)
+// 2024-06-01,31250,18730,19840,7320
+// 2024-06-02,28410,21175,14820,7585
+// ```
+//
+// The columns are found by name, whatever order they're in: the date
+// (or date and time) the row starts at, the energy produced, the
+// energy consumed, and the energy exported or imported (or both).  The
+// one of those two that's missing is worked out from the others.
+// Energies are in Wh, or kWh if the column name says "(kWh)".  Rows can
+// be whole days or shorter intervals, with times in local time.
+
+/// One row of an export: the energy that flowed from `time` until the
+/// next row, in Watt-hours.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub time: chrono::NaiveDateTime,
+    pub produced_wh: f64,
+    pub consumed_wh: f64,
+    pub exported_wh: f64,
+    pub imported_wh: f64,
+}
+
+/// The fields of a CSV line, without their quotes.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    for c in line.trim_end_matches('\r').chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.into_iter().map(|f| f.trim().to_string()).collect()
+}
+
+fn parse_time(s: &str) -> Option<chrono::NaiveDateTime> {
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%m/%d/%Y %H:%M:%S",
+        "%m/%d/%Y %H:%M",
+    ] {
+        if let Ok(time) = chrono::NaiveDateTime::parse_from_str(s, format) {
+            return Some(time);
+        }
+    }
+    // With a UTC offset, in local time like the rest.
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(time.with_timezone(&chrono::Local).naive_local());
+    }
+    for format in ["%Y-%m-%d", "%m/%d/%Y"] {
+        if let Ok(date) = chrono::NaiveDate::parse_from_str(s, format) {
+            return date.and_hms_opt(0, 0, 0);
+        }
+    }
+    None
+}
+
+/// Read an export.
+pub fn parse(contents: &str) -> Result<Vec<Row>, eyre::Report> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let Some((_, header)) = lines.next() else {
+        return Err(eyre::eyre!("the CSV file is empty"));
+    };
+    let header: Vec<String> = fields(header).iter().map(|f| f.to_lowercase()).collect();
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.iter().any(|name| h.contains(name)))
+            .map(|i| {
+                (
+                    i,
+                    if header[i].contains("kwh") {
+                        1000.0
+                    } else {
+                        1.0
+                    },
+                )
+            })
+    };
+    let Some((time_column, _)) = column(&["date", "time"]) else {
+        return Err(eyre::eyre!("the CSV file has no date column"));
+    };
+    let Some(produced) = column(&["produc"]) else {
+        return Err(eyre::eyre!("the CSV file has no energy produced column"));
+    };
+    let Some(consumed) = column(&["consum"]) else {
+        return Err(eyre::eyre!("the CSV file has no energy consumed column"));
+    };
+    let (exported, imported) = (column(&["export"]), column(&["import"]));
+    if exported.is_none() && imported.is_none() {
+        return Err(eyre::eyre!(
+            "the CSV file has no energy exported or imported column"
+        ));
+    }
+
+    let mut rows = Vec::new();
+    for (n, line) in lines {
+        let fields = fields(line);
+        let field = |i: usize| fields.get(i).map(String::as_str).unwrap_or_default();
+        let energy = |(i, scale): (usize, f64)| {
+            field(i)
+                .replace(',', "")
+                .parse::<f64>()
+                .ok()
+                .filter(|wh| wh.is_finite())
+                .map(|wh| wh * scale)
+                .ok_or(eyre::eyre!(
+                    "line {}: {:?} isn't an energy for {:?}",
+                    n + 1,
+                    field(i),
+                    header[i]
+                ))
+        };
+        let time = parse_time(field(time_column)).ok_or(eyre::eyre!(
+            "line {}: {:?} isn't a date",
+            n + 1,
+            field(time_column)
+        ))?;
+        let (produced_wh, consumed_wh) = (energy(produced)?, energy(consumed)?);
+        // What's consumed is what's produced, less what's exported, plus
+        // what's imported.
+        let (exported_wh, imported_wh) = match (exported, imported) {
+            (Some(exported), Some(imported)) => (energy(exported)?, energy(imported)?),
+            (Some(exported), None) => {
+                let exported_wh = energy(exported)?;
+                (exported_wh, consumed_wh - produced_wh + exported_wh)
+            }
+            (None, Some(imported)) => {
+                let imported_wh = energy(imported)?;
+                (produced_wh - consumed_wh + imported_wh, imported_wh)
+            }
+            (None, None) => unreachable!(),
+        };
+        rows.push(Row {
+            time,
+            produced_wh,
+            consumed_wh,
+            exported_wh: exported_wh.max(0.0),
+            imported_wh: imported_wh.max(0.0),
+        });
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> chrono::NaiveDateTime {
+        parse_time(s).unwrap()
+    }
+
+    #[test]
+    fn days() {
+        let rows = parse(
+            "Date/Time,Energy Produced (Wh),Energy Consumed (Wh),Exported to Grid (Wh),Imported from Grid (Wh)\n\
+             2024-06-01,31250,18730,19840,7320\n\
+             \"2024-06-02\",\"28,410\",21175,14820,7585\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [
+                Row {
+                    time: time("2024-06-01"),
+                    produced_wh: 31250.0,
+                    consumed_wh: 18730.0,
+                    exported_wh: 19840.0,
+                    imported_wh: 7320.0,
+                },
+                Row {
+                    time: time("2024-06-02"),
+                    produced_wh: 28410.0,
+                    consumed_wh: 21175.0,
+                    exported_wh: 14820.0,
+                    imported_wh: 7585.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn intervals() {
+        // In kWh, in another order, and without an imported column.
+        let rows = parse(
+            "Consumption (kWh),Date,Production (kWh),Export (kWh)\r\n\
+             0.5,06/01/2024 12:15,2.0,1.75\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [Row {
+                time: time("2024-06-01 12:15"),
+                produced_wh: 2000.0,
+                consumed_wh: 500.0,
+                exported_wh: 1750.0,
+                imported_wh: 250.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn bad() {
+        for csv in [
+            "",
+            "Energy Produced (Wh),Energy Consumed (Wh),Exported to Grid (Wh)\n",
+            "Date,Energy Consumed (Wh),Exported to Grid (Wh)\n",
+            "Date,Energy Produced (Wh),Energy Consumed (Wh)\n",
+            "Date,Energy Produced (Wh),Energy Consumed (Wh),Exported (Wh)\nJune 1st,1,1,1\n",
+            "Date,Energy Produced (Wh),Energy Consumed (Wh),Exported (Wh)\n2024-06-01,1,,1\n",
+        ] {
+            assert!(parse(csv).is_err(), "{csv:?}");
+        }
+    }
+}
@@ -0,0 +1,498 @@
+// Client for the parts of the Enphase Envoy local API that we use.
+//
+// We make the requests ourselves so we can tune the HTTP connection
+// pool.  The Envoy's TLS handshake takes seconds, so it's worth a lot
+// to keep a connection open from one cycle to the next instead of
+// reconnecting.
+//
+// We also pick the meter readings out of the JSON by hand instead of
+// deserializing it into strict types, because different Envoy firmware
+// versions leave out or rename fields, and one missing field shouldn't
+// make the whole reply unusable.
+
+/// One Envoy Integrated Meter's reading.
+#[derive(Debug, Clone)]
+pub struct MeterReading {
+    /// When the Envoy took the reading.
+    pub reading_time: chrono::DateTime<chrono::Utc>,
+
+    /// Power right now, in Watts.
+    pub w_now: f64,
+
+    /// Lifetime energy, in Watt-hours.
+    pub wh_lifetime: Option<f64>,
+
+    pub rms_voltage: Option<f64>,
+    pub rms_current: Option<f64>,
+
+    /// The same readings for each phase, if the meter's CTs are on more
+    /// than one line.
+    pub lines: Vec<MeterReading>,
+}
+
+/// The Envoy Integrated Meter readings from `production.json`.
+#[derive(Debug, Default)]
+pub struct Readings {
+    pub production: Option<MeterReading>,
+    pub total_consumption: Option<MeterReading>,
+    pub net_consumption: Option<MeterReading>,
+
+    /// AC battery power in Watts (positive when discharging) and state
+    /// of charge in percent, if the Envoy reports an active AC battery.
+    pub storage: Option<(f64, Option<f64>)>,
+
+    // Why we couldn't use some of the meters in the reply, for the
+    // error message if we end up needing them.
+    problems: Vec<String>,
+}
+
+impl MeterReading {
+    fn from_json(device: &serde_json::Value, path: &str) -> Result<Self, eyre::Report> {
+        let field = |name: &str| device.get(name).and_then(|v| v.as_f64());
+        let w_now = field("wNow").ok_or(eyre::eyre!("{path} has no \"wNow\" field"))?;
+        let reading_time = match device.get("readingTime").and_then(|v| v.as_i64()) {
+            Some(t) if t > 0 => chrono::DateTime::from_timestamp(t, 0)
+                .ok_or(eyre::eyre!("{path} has bad \"readingTime\" {t}"))?,
+            // Fall back to our own clock.
+            _ => chrono::Utc::now(),
+        };
+        Ok(Self {
+            reading_time,
+            w_now,
+            wh_lifetime: field("whLifetime"),
+            rms_voltage: field("rmsVoltage").filter(|v| *v > 0.0),
+            rms_current: field("rmsCurrent"),
+            // A line that can't be read makes the others meaningless,
+            // they have to add up to the whole.
+            lines: device
+                .get("lines")
+                .and_then(|v| v.as_array())
+                .map(|lines| {
+                    lines
+                        .iter()
+                        .enumerate()
+                        .map(|(i, line)| Self::from_json(line, &format!("{path}.lines[{i}]")))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl Readings {
+    pub fn from_json(body: &str) -> Result<Self, eyre::Report> {
+        let json: serde_json::Value = serde_json::from_str(body)?;
+        let mut readings = Self::default();
+        for section in ["production", "consumption"] {
+            let Some(devices) = json.get(section).and_then(|v| v.as_array()) else {
+                readings
+                    .problems
+                    .push(format!("reply has no \"{section}\" list"));
+                continue;
+            };
+            for (i, device) in devices.iter().enumerate() {
+                let path = format!("{section}[{i}]");
+                if device.get("type").and_then(|v| v.as_str()) != Some("eim") {
+                    continue;
+                }
+                let measurement_type = match device.get("measurementType").and_then(|v| v.as_str())
+                {
+                    Some(measurement_type) => measurement_type,
+                    // The production list only ever has the one meter.
+                    None if section == "production" => "production",
+                    None => {
+                        readings
+                            .problems
+                            .push(format!("{path} has no \"measurementType\" field"));
+                        continue;
+                    }
+                };
+                let reading = match MeterReading::from_json(device, &path) {
+                    Ok(reading) => reading,
+                    Err(e) => {
+                        readings.problems.push(format!("{e}"));
+                        continue;
+                    }
+                };
+                match measurement_type {
+                    "production" => readings.production = Some(reading),
+                    "total-consumption" => readings.total_consumption = Some(reading),
+                    "net-consumption" => readings.net_consumption = Some(reading),
+                    other => readings
+                        .problems
+                        .push(format!("{path} has unknown measurementType {other:?}")),
+                }
+            }
+        }
+
+        // Older AC batteries show up in production.json.  IQ Batteries
+        // don't, see `Envoy::battery()`.
+        if let Some(storage) = json
+            .get("storage")
+            .and_then(|v| v.as_array())
+            .and_then(|v| v.first())
+        {
+            let active = storage.get("activeCount").and_then(|v| v.as_i64()) > Some(0);
+            if let (true, Some(w_now)) = (active, storage.get("wNow").and_then(|v| v.as_f64())) {
+                readings.storage =
+                    Some((w_now, storage.get("percentFull").and_then(|v| v.as_f64())));
+            }
+        }
+        Ok(readings)
+    }
+
+    /// The net consumption (import minus export), measured directly if
+    /// the Envoy reports it, otherwise computed from total consumption
+    /// minus production.
+    pub fn net_consumption(&self) -> Result<MeterReading, eyre::Report> {
+        if let Some(net) = &self.net_consumption {
+            return Ok(net.clone());
+        }
+        if let (Some(total), Some(production)) = (&self.total_consumption, &self.production) {
+            return Ok(MeterReading {
+                reading_time: total.reading_time,
+                w_now: total.w_now - production.w_now,
+                wh_lifetime: total
+                    .wh_lifetime
+                    .zip(production.wh_lifetime)
+                    .map(|(total, production)| total - production),
+                rms_voltage: total.rms_voltage.or(production.rms_voltage),
+                rms_current: None,
+                lines: if total.lines.len() == production.lines.len() {
+                    total
+                        .lines
+                        .iter()
+                        .zip(&production.lines)
+                        .map(|(total, production)| MeterReading {
+                            reading_time: total.reading_time,
+                            w_now: total.w_now - production.w_now,
+                            wh_lifetime: None,
+                            rms_voltage: total.rms_voltage.or(production.rms_voltage),
+                            rms_current: None,
+                            lines: Vec::new(),
+                        })
+                        .collect()
+                } else {
+                    Vec::new()
+                },
+            });
+        }
+        let mut message =
+            String::from("Envoy reports no net-consumption meter, and not enough to compute it");
+        for problem in &self.problems {
+            message += &format!("; {problem}");
+        }
+        Err(eyre::Report::msg(message))
+    }
+
+    /// The total consumption, measured directly if the Envoy reports it,
+    /// otherwise computed from net consumption plus production.
+    pub fn total_consumption(&self) -> Option<MeterReading> {
+        if let Some(total) = &self.total_consumption {
+            return Some(total.clone());
+        }
+        let (net, production) = (self.net_consumption.as_ref()?, self.production.as_ref()?);
+        Some(MeterReading {
+            reading_time: net.reading_time,
+            w_now: net.w_now + production.w_now,
+            wh_lifetime: None,
+            rms_voltage: net.rms_voltage.or(production.rms_voltage),
+            rms_current: None,
+            lines: if net.lines.len() == production.lines.len() {
+                net.lines
+                    .iter()
+                    .zip(&production.lines)
+                    .map(|(net, production)| MeterReading {
+                        reading_time: net.reading_time,
+                        w_now: net.w_now + production.w_now,
+                        wh_lifetime: None,
+                        rms_voltage: net.rms_voltage.or(production.rms_voltage),
+                        rms_current: None,
+                        lines: Vec::new(),
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        })
+    }
+
+    /// All the meters we got readings from, by their Envoy names.
+    pub fn meters(&self) -> Vec<(&'static str, &MeterReading)> {
+        [
+            ("production", &self.production),
+            ("total-consumption", &self.total_consumption),
+            ("net-consumption", &self.net_consumption),
+        ]
+        .into_iter()
+        .filter_map(|(name, reading)| reading.as_ref().map(|reading| (name, reading)))
+        .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Envoy {
+    base_url: reqwest::Url,
+    auth_token: String,
+    client: reqwest::Client,
+    capture: Option<std::sync::Arc<crate::capture::Capture>>,
+
+    // Whether to read IQ Battery power and state of charge.
+    battery: bool,
+}
+
+impl Envoy {
+    pub fn new(
+        base_url: reqwest::Url,
+        address_book: &crate::address_book::AddressBook,
+        auth_token: &str,
+        pool_idle_timeout: std::time::Duration,
+        tcp_keepalive: std::time::Duration,
+        capture: Option<std::sync::Arc<crate::capture::Capture>>,
+        battery: bool,
+    ) -> Result<Self, eyre::Report> {
+        let client = address_book
+            .client_builder()
+            // The Envoy uses a self-signed certificate.
+            .danger_accept_invalid_certs(true)
+            .pool_idle_timeout(pool_idle_timeout)
+            .pool_max_idle_per_host(1)
+            .tcp_keepalive(tcp_keepalive)
+            .build()?;
+        Ok(Self {
+            base_url,
+            auth_token: String::from(auth_token),
+            client,
+            capture,
+            battery,
+        })
+    }
+
+    async fn get(&self, path: &str) -> Result<String, eyre::Report> {
+        let url = self.base_url.join(path)?;
+        let body = self
+            .client
+            .get(url.clone())
+            .bearer_auth(&self.auth_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if let Some(capture) = &self.capture {
+            capture.record("envoy", url.as_str(), &body);
+        }
+        Ok(body)
+    }
+
+    /// Read current and cumulative production and consumption.
+    pub async fn production(&self) -> Result<Readings, eyre::Report> {
+        let start = std::time::Instant::now();
+        let body = self.get("production.json?details=1").await?;
+        let production = Readings::from_json(&body)?;
+        println!(
+            "Envoy production request took {:.3} s",
+            start.elapsed().as_secs_f64()
+        );
+        Ok(production)
+    }
+
+    /// Read the IQ Batteries' total power in Watts (positive when
+    /// discharging) and state of charge in percent.
+    ///
+    /// ```text
+    /// $ curl -k -H "Authorization: Bearer $TOKEN" https://envoy.local/ivp/livedata/status
+    /// {"meters": {"soc": 54, "storage": {"agg_p_mw": -2500000, ...}, ...}, ...}
+    /// ```
+    pub async fn battery(&self) -> Result<(f64, Option<f64>), eyre::Report> {
+        let body = self.get("ivp/livedata/status").await?;
+        let json: serde_json::Value = serde_json::from_str(&body)?;
+        let meters = &json["meters"];
+        let milliwatts = meters["storage"]["agg_p_mw"]
+            .as_f64()
+            .ok_or(eyre::eyre!("ivp/livedata/status has no battery power"))?;
+        Ok((milliwatts / 1000.0, meters["soc"].as_f64()))
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::meter::Meter for Envoy {
+    async fn export_power(&mut self) -> Result<crate::meter::PowerReading, eyre::Report> {
+        let readings = self.production().await?;
+        let net = readings.net_consumption()?;
+        let total = readings.total_consumption();
+        let battery = match self.battery {
+            true => Some(self.battery().await?),
+            false => readings.storage,
+        };
+        let mut details = Vec::new();
+        for (name, reading) in readings.meters() {
+            let lines = reading
+                .lines
+                .iter()
+                .enumerate()
+                .map(|(i, line)| (format!("{name}/line{}", i + 1), line));
+            for (name, reading) in std::iter::once((String::from(name), reading)).chain(lines) {
+                details.push((format!("{name}/w_now"), reading.w_now));
+                for (field, value) in [
+                    ("wh_lifetime", reading.wh_lifetime),
+                    ("rms_voltage", reading.rms_voltage),
+                    ("rms_current", reading.rms_current),
+                ] {
+                    if let Some(value) = value {
+                        details.push((format!("{name}/{field}"), value));
+                    }
+                }
+            }
+        }
+        Ok(crate::meter::PowerReading {
+            reading_time: net.reading_time,
+            export_w: -net.w_now,
+            export_wh_lifetime: net.wh_lifetime.map(|wh| -wh),
+            rms_voltage: net.rms_voltage,
+            phases: net
+                .lines
+                .iter()
+                .enumerate()
+                .map(|(i, line)| crate::meter::Phase {
+                    export_w: -line.w_now,
+                    rms_voltage: line.rms_voltage,
+                    consumption_w: total
+                        .as_ref()
+                        .and_then(|total| total.lines.get(i))
+                        .map(|line| line.w_now),
+                })
+                .collect(),
+            consumption_w: total.as_ref().map(|total| total.w_now),
+            production_w: readings.production.as_ref().map(|reading| reading.w_now),
+            battery_w: battery.map(|(w, _)| w),
+            battery_soc: battery.and_then(|(_, soc)| soc),
+            details,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // production.json?details=1 from firmware that names each meter.
+    const NEW: &str = r#"{
+        "production": [
+            {"type": "inverters", "activeCount": 20, "readingTime": 1717236000, "wNow": 3900},
+            {"type": "eim", "activeCount": 1, "measurementType": "production", "readingTime": 1717236001,
+             "wNow": 4000.5, "whLifetime": 12000000, "rmsCurrent": 16.6, "rmsVoltage": 241.2,
+             "lines": [{"wNow": 2000.25, "rmsVoltage": 120.6}, {"wNow": 2000.25, "rmsVoltage": 120.6}]}
+        ],
+        "consumption": [
+            {"type": "eim", "activeCount": 1, "measurementType": "total-consumption", "readingTime": 1717236001,
+             "wNow": 1500, "whLifetime": 9000000, "rmsVoltage": 241.2},
+            {"type": "eim", "activeCount": 1, "measurementType": "net-consumption", "readingTime": 1717236001,
+             "wNow": -2500.5, "whLifetime": -3000000, "rmsVoltage": 241.2}
+        ],
+        "storage": [{"type": "acb", "activeCount": 1, "wNow": -800, "percentFull": 64}]
+    }"#;
+
+    // Older firmware: no measurementType on the production meter, no
+    // net-consumption meter, and no readingTime.
+    const OLD: &str = r#"{
+        "production": [
+            {"type": "inverters", "activeCount": 20, "wNow": 3900},
+            {"type": "eim", "activeCount": 1, "wNow": 4000, "whLifetime": 12000000}
+        ],
+        "consumption": [
+            {"type": "eim", "activeCount": 1, "measurementType": "total-consumption",
+             "wNow": 1500, "whLifetime": 9000000, "rmsVoltage": 241.2}
+        ],
+        "storage": [{"type": "acb", "activeCount": 0, "wNow": 0, "percentFull": 0}]
+    }"#;
+
+    #[test]
+    fn new_firmware() {
+        let readings = Readings::from_json(NEW).unwrap();
+        let production = readings.production.as_ref().unwrap();
+        assert_eq!(production.w_now, 4000.5);
+        assert_eq!(production.reading_time.timestamp(), 1717236001);
+        assert_eq!(production.rms_current, Some(16.6));
+        assert_eq!(production.lines.len(), 2);
+        assert_eq!(production.lines[0].rms_voltage, Some(120.6));
+        let net = readings.net_consumption().unwrap();
+        assert_eq!(net.w_now, -2500.5);
+        assert_eq!(net.wh_lifetime, Some(-3000000.0));
+        assert_eq!(readings.total_consumption().unwrap().w_now, 1500.0);
+        assert_eq!(readings.storage, Some((-800.0, Some(64.0))));
+        assert_eq!(readings.meters().len(), 3);
+    }
+
+    #[test]
+    fn old_firmware() {
+        let before = chrono::Utc::now();
+        let readings = Readings::from_json(OLD).unwrap();
+        let production = readings.production.as_ref().unwrap();
+        assert_eq!(production.w_now, 4000.0);
+        // Our own clock, without a readingTime.
+        assert!(production.reading_time >= before);
+        assert_eq!(production.rms_voltage, None);
+        // Worked out from the total consumption and the production.
+        let net = readings.net_consumption().unwrap();
+        assert_eq!(net.w_now, -2500.0);
+        assert_eq!(net.wh_lifetime, Some(-3000000.0));
+        assert_eq!(net.rms_voltage, Some(241.2));
+        // The AC battery isn't active.
+        assert_eq!(readings.storage, None);
+
+        // The other way around: no total-consumption meter.
+        let readings = Readings::from_json(&NEW.replace("total-consumption", "other")).unwrap();
+        assert_eq!(readings.total_consumption().unwrap().w_now, 1500.0);
+    }
+
+    #[test]
+    fn missing_fields() {
+        // A consumption meter that doesn't say which it is can't be
+        // used, and the error says why.
+        let no_type = OLD.replace(r#""measurementType": "total-consumption","#, "");
+        let readings = Readings::from_json(&no_type).unwrap();
+        assert!(readings.total_consumption.is_none());
+        let e = readings.net_consumption().unwrap_err().to_string();
+        assert!(
+            e.contains(r#"consumption[0] has no "measurementType" field"#),
+            "{e}"
+        );
+
+        // Nor can one without a reading.
+        let no_reading = NEW.replace(r#""wNow": -2500.5,"#, "");
+        let readings = Readings::from_json(&no_reading).unwrap();
+        assert!(readings.net_consumption.is_none());
+        // But there's enough to work it out.
+        assert_eq!(readings.net_consumption().unwrap().w_now, -2500.5);
+        let e = Readings::from_json(&no_reading.replace("total-consumption", "other"))
+            .unwrap()
+            .net_consumption()
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains(r#"consumption[1] has no "wNow" field"#), "{e}");
+        assert!(e.contains(r#"unknown measurementType "other""#), "{e}");
+
+        // A line without a reading spoils the whole meter.
+        let e = Readings::from_json(
+            &OLD.replace(r#""wNow": 1500,"#, r#""wNow": 1500, "lines": [{}],"#),
+        )
+        .unwrap()
+        .net_consumption()
+        .unwrap_err()
+        .to_string();
+        assert!(
+            e.contains(r#"consumption[0].lines[0] has no "wNow" field"#),
+            "{e}"
+        );
+
+        let e = Readings::from_json(r#"{"production": []}"#)
+            .unwrap()
+            .net_consumption()
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains(r#"reply has no "consumption" list"#), "{e}");
+        assert!(Readings::from_json("<html>").is_err());
+    }
+}
@@ -0,0 +1,52 @@
+// What the controller's doing, as it happens, for a program embedding
+// it to react to without scraping the logs:
+//
+// ```text
+// let controller = solar_evse::ControllerBuilder::new().build().await?;
+// let mut events = controller.subscribe();
+// tokio::spawn(async move {
+//     while let Ok(event) = events.recv().await {
+//         if let solar_evse::events::Event::SessionStarted { start } = event {
+//             println!("charging session started at {start}");
+//         }
+//     }
+// });
+// controller.run_until_stopped().await?;
+// ```
+//
+// It's a broadcast channel: every subscriber gets every event, and one
+// that falls more than `CAPACITY` events behind misses the oldest (its
+// `recv()` says how many) rather than holding up the control loop.
+
+pub const CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A control cycle finished, with what we measured and decided.
+    CycleCompleted {
+        /// Amps, negative when importing.
+        export_current: f64,
+        charge_limit: f64,
+        charge_current: f64,
+        state: crate::controller_state::ControllerState,
+        reason: String,
+    },
+
+    /// An EV was plugged in, or was already charging when we started.
+    SessionStarted {
+        start: chrono::DateTime<chrono::Local>,
+    },
+
+    /// The EV was unplugged.
+    SessionEnded {
+        start: chrono::DateTime<chrono::Local>,
+        energy_wh: f64,
+    },
+
+    /// We set the EVSE's charge current limit, in Amps.
+    SetpointChanged { amps: isize },
+
+    /// Something went wrong: the EVSE stopped answering, the meter
+    /// failed, and so on.
+    Error { message: String },
+}
@@ -0,0 +1,90 @@
+// The charger, as far as the control loop is concerned.  OpenEVSE is
+// one implementation, other chargers (OCPP wallboxes, ...) can be
+// supported by implementing this trait, without changing the control
+// loop.
+
+/// A charging session the EVSE is keeping track of.
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    // How long ago the EV started charging.
+    pub elapsed: std::time::Duration,
+
+    // Energy delivered to the EV so far, in Watt-hours.
+    pub energy_wh: f64,
+}
+
+/// What the EVSE says about itself, when it has to be asked rather
+/// than telling us over MQTT.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    // Whether an EV is plugged in, if the EVSE says.
+    pub vehicle_connected: Option<bool>,
+
+    // The fault the EVSE is in (a GFCI trip, over-temperature, ...),
+    // if any.
+    pub fault: Option<String>,
+}
+
+#[async_trait::async_trait]
+pub trait Evse: Send + Sync {
+    /// Let the EVSE charge the EV.
+    async fn enable(&self) -> Result<(), eyre::Report>;
+
+    /// Stop charging the EV, without forgetting any settings.
+    async fn sleep(&self) -> Result<(), eyre::Report>;
+
+    /// Read amount of current currently being drawn by the EV, in amps.
+    async fn get_active_charging_current(&self) -> Result<f64, eyre::Report>;
+
+    /// Read amount of current currently being offered by the EVSE to
+    /// the EV, in amps, or that it would offer if it were enabled.
+    async fn get_current_capacity(&self) -> Result<f64, eyre::Report>;
+
+    /// Set the amount of current the EVSE offers to the EV, in amps.
+    /// Negative is discharging the EV into the house, for bidirectional
+    /// EVSEs.
+    async fn set_current_capacity(&self, charge_current_limit: isize) -> Result<(), eyre::Report>;
+
+    /// The session in progress, if the EV is charging and the EVSE
+    /// keeps count of it.
+    async fn get_session(&self) -> Result<Option<Session>, eyre::Report> {
+        Ok(None)
+    }
+
+    /// Whether an EV is plugged in and whether the EVSE is faulted, if
+    /// it has to be asked.
+    async fn status(&self) -> Result<Status, eyre::Report> {
+        Ok(Status::default())
+    }
+
+    /// Whether the EVSE can discharge the EV into the house (V2H) as
+    /// well as charge it.  If it can, the active charging current is
+    /// negative while discharging.
+    fn is_bidirectional(&self) -> bool {
+        false
+    }
+
+    /// Whether the EVSE can switch between charging on one phase and
+    /// on three.
+    fn can_switch_phases(&self) -> bool {
+        false
+    }
+
+    /// Charge on `phases` phases from now on, for EVSEs that can switch.
+    async fn set_phases(&self, phases: u8) -> Result<(), eyre::Report> {
+        Err(eyre::eyre!("this EVSE can't switch to {} phases", phases))
+    }
+
+    /// Hand the EVSE back to its own settings, for when we stop.
+    /// Returns false if it hasn't got any to go back to, and should be
+    /// left charging at full blast.
+    async fn release(&self) -> Result<bool, eyre::Report> {
+        Ok(false)
+    }
+
+    /// The RFID card (or other ID token) the latest session was started
+    /// with, if the EVSE knows.
+    fn id_tag(&self) -> Option<String> {
+        None
+    }
+}
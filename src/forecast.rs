@@ -0,0 +1,198 @@
+// Solar production forecasts, from forecast.solar or Solcast, so that
+// with a --deadline we can tell whether the sun is likely to deliver
+// the EV's energy in time, or whether to top up from the grid early
+// (in the cheapest slots, or the --charge-window) because it won't.
+//
+// forecast.solar's estimate gives the energy produced in each period
+// up to a (site local) time:
+//
+// ```text
+// $ curl https://api.forecast.solar/estimate/40.0/-105.2/30/0/6.5
+// {"result": {"watt_hours_period": {"2024-06-01 06:00:00": 12, "2024-06-01 07:00:00": 240, ...}, ...}, ...}
+// ```
+//
+// Solcast's rooftop site forecasts give the average power in kW over
+// each period:
+//
+// ```text
+// $ curl -H "Authorization: Bearer $KEY" https://api.solcast.com.au/rooftop_sites/abcd-1234/forecasts?format=json
+// {"forecasts": [{"pv_estimate": 1.23, "period_end": "2024-06-01T06:30:00.0000000Z", "period": "PT30M"}, ...]}
+// ```
+
+/// How often to fetch the forecast.  Solcast's free tier allows ten
+/// requests a day.
+const REFRESH: std::time::Duration = std::time::Duration::from_secs(3 * 60 * 60);
+
+#[derive(Debug, Clone)]
+struct Period {
+    start: chrono::DateTime<chrono::Local>,
+    end: chrono::DateTime<chrono::Local>,
+    energy_wh: f64,
+}
+
+#[derive(Debug)]
+pub struct Forecast {
+    url: reqwest::Url,
+    api_key: Option<String>,
+    client: reqwest::Client,
+
+    // Sorted by start time.
+    periods: Vec<Period>,
+    fetched: Option<std::time::Instant>,
+}
+
+impl Forecast {
+    pub fn new(url: reqwest::Url, api_key: Option<String>) -> Self {
+        Self {
+            url,
+            api_key,
+            client: reqwest::Client::new(),
+            periods: Vec::new(),
+            fetched: None,
+        }
+    }
+
+    /// Fetch the forecast, if it's been a while.
+    pub async fn refresh(&mut self) -> Result<(), eyre::Report> {
+        if self.fetched.is_some_and(|t| t.elapsed() < REFRESH) {
+            return Ok(());
+        }
+        let mut request = self
+            .client
+            .get(self.url.clone())
+            .timeout(std::time::Duration::from_secs(30));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let json: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        let mut periods = if let Some(forecasts) = json["forecasts"].as_array() {
+            solcast_periods(forecasts)
+        } else if let Some(energy) = json["result"]["watt_hours_period"].as_object() {
+            forecast_solar_periods(energy)
+        } else {
+            return Err(eyre::eyre!(
+                "solar forecast from {} has neither \"forecasts\" nor \"result.watt_hours_period\"",
+                self.url
+            ));
+        };
+        periods.sort_by_key(|period| period.start);
+        println!(
+            "fetched a solar forecast of {:.3} kWh, up to {}",
+            periods.iter().map(|period| period.energy_wh).sum::<f64>() / 1000.0,
+            periods
+                .last()
+                .map(|period| period.end.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        );
+        self.periods = periods;
+        self.fetched = Some(std::time::Instant::now());
+        Ok(())
+    }
+
+    /// The solar energy forecast to be produced between `from` and
+    /// `to`, in Watt-hours.  Periods partly in that time count in
+    /// proportion.
+    pub fn energy_wh(
+        &self,
+        from: chrono::DateTime<chrono::Local>,
+        to: chrono::DateTime<chrono::Local>,
+    ) -> f64 {
+        self.periods
+            .iter()
+            .filter(|period| period.end > from && period.start < to)
+            .map(|period| {
+                let length = (period.end - period.start).num_seconds() as f64;
+                let overlap = (period.end.min(to) - period.start.max(from)).num_seconds() as f64;
+                if length <= 0.0 {
+                    period.energy_wh
+                } else {
+                    period.energy_wh * overlap / length
+                }
+            })
+            .sum()
+    }
+
+    /// The solar power forecast for right now, in Watts.
+    pub fn power_w(&self, now: chrono::DateTime<chrono::Local>) -> Option<f64> {
+        let period = self
+            .periods
+            .iter()
+            .find(|period| period.start <= now && now < period.end)?;
+        let hours = (period.end - period.start).num_seconds() as f64 / 3600.0;
+        (hours > 0.0).then(|| period.energy_wh / hours)
+    }
+
+    /// Whether we have a forecast at all.
+    pub fn is_known(&self) -> bool {
+        !self.periods.is_empty()
+    }
+}
+
+/// Solcast's forecasts, each the average power over the period ending
+/// at "period_end".
+fn solcast_periods(forecasts: &[serde_json::Value]) -> Vec<Period> {
+    forecasts
+        .iter()
+        .filter_map(|forecast| {
+            let kw = forecast["pv_estimate"].as_f64()?;
+            let end = chrono::DateTime::parse_from_rfc3339(forecast["period_end"].as_str()?)
+                .ok()?
+                .with_timezone(&chrono::Local);
+            let minutes = forecast["period"]
+                .as_str()
+                .and_then(parse_iso_minutes)
+                .unwrap_or(30);
+            let start = end - chrono::Duration::minutes(minutes);
+            Some(Period {
+                start,
+                end,
+                energy_wh: kw * 1000.0 * minutes as f64 / 60.0,
+            })
+        })
+        .collect()
+}
+
+/// forecast.solar's energy in each period, keyed by the (site local)
+/// time the period ends.  Each period starts where the one before it
+/// ends, except the first of the day, which is taken to be an hour
+/// long.
+fn forecast_solar_periods(energy: &serde_json::Map<String, serde_json::Value>) -> Vec<Period> {
+    let mut ends: Vec<(chrono::DateTime<chrono::Local>, f64)> = energy
+        .iter()
+        .filter_map(|(time, wh)| {
+            let time = chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")
+                .ok()?
+                .and_local_timezone(chrono::Local)
+                .earliest()?;
+            Some((time, wh.as_f64()?))
+        })
+        .collect();
+    ends.sort_by_key(|(time, _)| *time);
+    let mut periods = Vec::new();
+    let mut previous: Option<chrono::DateTime<chrono::Local>> = None;
+    for (end, energy_wh) in ends {
+        let start = match previous {
+            Some(previous) if previous.date_naive() == end.date_naive() => previous,
+            _ => end - chrono::Duration::hours(1),
+        };
+        periods.push(Period {
+            start,
+            end,
+            energy_wh,
+        });
+        previous = Some(end);
+    }
+    periods
+}
+
+/// The minutes in an ISO 8601 duration like "PT30M" or "PT1H".
+fn parse_iso_minutes(s: &str) -> Option<i64> {
+    let s = s.strip_prefix("PT")?;
+    if let Some(minutes) = s.strip_suffix('M') {
+        return minutes.parse().ok();
+    }
+    if let Some(hours) = s.strip_suffix('H') {
+        return hours.parse::<i64>().ok().map(|hours| hours * 60);
+    }
+    None
+}
@@ -0,0 +1,60 @@
+// A consumption limit from the grid operator, for example the German
+// §14a EnWG "steuerbare Verbrauchseinrichtungen" rules, where the DSO
+// can dim the EV charger down to 4.2 kW when the local grid is
+// congested.  The signal reaches the house over EEBUS (the LPC use
+// case, "limitation of power consumption"), which we don't speak
+// ourselves: an EEBUS gateway (or the HEMS) passes the limit on, in
+// Watts, over MQTT (--grid-limit-topic), or as the "grid_limit"
+// command from MQTT, the HTTP API or the admin console.
+//
+// Whatever the solar algorithm (or the mode, or a boost) wants, the EV
+// charges at no more than the limit.  Like EEBUS LPC, if the gateway
+// goes quiet for too long we fall back to a failsafe limit, if there
+// is one, until it speaks up again.
+
+#[derive(Debug)]
+pub struct GridLimit {
+    // The limit in Watts, None if there isn't one, and when we last
+    // heard from the gateway.
+    limit_w: Option<f64>,
+    updated: Option<std::time::Instant>,
+
+    failsafe_w: Option<f64>,
+    timeout: std::time::Duration,
+}
+
+impl GridLimit {
+    pub fn new(failsafe_w: Option<f64>, timeout: std::time::Duration) -> Self {
+        Self {
+            limit_w: None,
+            updated: None,
+            failsafe_w,
+            timeout,
+        }
+    }
+
+    /// A new limit from the gateway, or None to lift it.
+    pub fn set(&mut self, limit_w: Option<f64>) {
+        if limit_w != self.limit_w {
+            match limit_w {
+                Some(w) => println!("grid operator limits the EV to {:.0} W", w),
+                None => println!("grid operator lifted the EV's limit"),
+            }
+        }
+        self.limit_w = limit_w;
+        self.updated = Some(std::time::Instant::now());
+    }
+
+    /// The limit in effect right now, in Watts.
+    pub fn current(&self) -> Option<f64> {
+        match self.updated {
+            Some(t) if self.failsafe_w.is_some() && t.elapsed() > self.timeout => self.failsafe_w,
+            _ => self.limit_w,
+        }
+    }
+
+    /// Whether we've fallen back to the failsafe limit.
+    pub fn is_failsafe(&self) -> bool {
+        self.failsafe_w.is_some() && self.updated.is_some_and(|t| t.elapsed() > self.timeout)
+    }
+}
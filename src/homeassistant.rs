@@ -0,0 +1,153 @@
+// Home Assistant MQTT discovery: describe the telemetry we publish (see
+// --mqtt-telemetry-prefix) so Home Assistant shows the controller as a
+// device with sensors, without anyone writing YAML.
+//
+// Each entity gets a retained config message at
+// `<discovery prefix>/<component>/solar_evse/<object>/config`, pointing
+// at the telemetry topic with its value.  Home Assistant shows the
+// device as unavailable when `<telemetry prefix>/availability` says
+// "offline", which the broker publishes for us (as our MQTT last will)
+// if we go away.
+//
+// With --mqtt-commands there are controls too: a select for the mode,
+// and a switch that boosts for an hour (or cancels a boost).
+
+struct Sensor {
+    // The telemetry topic under the prefix, and the entity's object id.
+    object: &'static str,
+    name: &'static str,
+    unit: Option<&'static str>,
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+}
+
+const SENSORS: &[Sensor] = &[
+    Sensor {
+        object: "export_current",
+        name: "Surplus current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        state_class: Some("measurement"),
+    },
+    Sensor {
+        object: "charge_limit",
+        name: "Charge limit",
+        unit: Some("A"),
+        device_class: Some("current"),
+        state_class: Some("measurement"),
+    },
+    Sensor {
+        object: "evse_charge_current",
+        name: "EV charge current",
+        unit: Some("A"),
+        device_class: Some("current"),
+        state_class: Some("measurement"),
+    },
+    Sensor {
+        object: "session_energy",
+        name: "Session energy",
+        unit: Some("kWh"),
+        device_class: Some("energy"),
+        state_class: Some("total_increasing"),
+    },
+    Sensor {
+        object: "state",
+        name: "Controller state",
+        unit: None,
+        device_class: None,
+        state_class: None,
+    },
+    Sensor {
+        object: "state_reason",
+        name: "Controller state reason",
+        unit: None,
+        device_class: None,
+        state_class: None,
+    },
+];
+
+/// The availability topic under the telemetry prefix.
+pub fn availability_topic(telemetry_prefix: &str) -> String {
+    format!("{telemetry_prefix}/availability")
+}
+
+/// The discovery config messages, as (topic, payload).
+pub fn discovery_messages(
+    discovery_prefix: &str,
+    telemetry_prefix: &str,
+    commands: bool,
+) -> Vec<(String, String)> {
+    let device = serde_json::json!({
+        "identifiers": ["solar_evse"],
+        "name": "Solar EVSE",
+        "model": "solar-evse",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let mut messages: Vec<(String, String)> = SENSORS
+        .iter()
+        .map(|sensor| {
+            let object = sensor.object;
+            let mut config = serde_json::json!({
+                "name": sensor.name,
+                "unique_id": format!("solar_evse_{object}"),
+                "object_id": format!("solar_evse_{object}"),
+                "state_topic": format!("{telemetry_prefix}/{object}"),
+                "availability_topic": availability_topic(telemetry_prefix),
+                "device": device,
+            });
+            for (key, value) in [
+                ("unit_of_measurement", sensor.unit),
+                ("device_class", sensor.device_class),
+                ("state_class", sensor.state_class),
+            ] {
+                if let Some(value) = value {
+                    config[key] = serde_json::json!(value);
+                }
+            }
+            (
+                format!("{discovery_prefix}/sensor/solar_evse/{object}/config"),
+                config.to_string(),
+            )
+        })
+        .collect();
+
+    if commands {
+        use clap::ValueEnum;
+        let modes: Vec<String> = crate::mode::Mode::value_variants()
+            .iter()
+            .map(|mode| mode.to_string())
+            .collect();
+        messages.push((
+            format!("{discovery_prefix}/select/solar_evse/mode/config"),
+            serde_json::json!({
+                "name": "Mode",
+                "unique_id": "solar_evse_mode",
+                "object_id": "solar_evse_mode",
+                "state_topic": format!("{telemetry_prefix}/mode"),
+                "command_topic": format!("{telemetry_prefix}/cmd/mode"),
+                "options": modes,
+                "availability_topic": availability_topic(telemetry_prefix),
+                "device": device,
+            })
+            .to_string(),
+        ));
+        messages.push((
+            format!("{discovery_prefix}/switch/solar_evse/boost/config"),
+            serde_json::json!({
+                "name": "Boost",
+                "unique_id": "solar_evse_boost",
+                "object_id": "solar_evse_boost",
+                "state_topic": format!("{telemetry_prefix}/boost"),
+                "command_topic": format!("{telemetry_prefix}/cmd/boost"),
+                "payload_on": "60",
+                "payload_off": "0",
+                "state_on": "ON",
+                "state_off": "OFF",
+                "availability_topic": availability_topic(telemetry_prefix),
+                "device": device,
+            })
+            .to_string(),
+        ));
+    }
+    messages
+}
@@ -0,0 +1,143 @@
+// Measure how long it takes for a change to the EVSE's charge current
+// limit to show up in the charge current it reports, so the controller
+// can hold off on further adjustments until the last one has taken
+// effect.  Otherwise it sees the old charge current, decides the last
+// adjustment wasn't enough, and corrects again.
+
+// If the reported current hasn't responded to a change by now, it's
+// probably not going to (for example the EV was already drawing less
+// than the new limit), so stop waiting for it.
+const MAX_LATENCY: std::time::Duration = std::time::Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy)]
+struct PendingChange {
+    time: std::time::Instant,
+    old_current: f64,
+    new_limit: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct SetpointLatency {
+    pending: Option<PendingChange>,
+    estimate: Option<std::time::Duration>,
+}
+
+impl SetpointLatency {
+    /// Remember that we just changed the charge current limit, if it's
+    /// a change we expect to see in the EV's charge current: either the
+    /// limit was lowered below what the EV is drawing, or it was raised
+    /// while the EV was drawing all it was allowed to.
+    pub fn commanded(&mut self, old_current: f64, old_limit: Option<isize>, new_limit: isize) {
+        let new_limit = new_limit as f64;
+        let lowered = new_limit < old_current - 1.0;
+        let raised = match old_limit {
+            Some(old_limit) => {
+                old_current >= old_limit as f64 - 1.0 && new_limit > old_current + 1.0
+            }
+            None => false,
+        };
+        self.pending = if lowered || raised {
+            Some(PendingChange {
+                time: std::time::Instant::now(),
+                old_current,
+                new_limit,
+            })
+        } else {
+            None
+        };
+    }
+
+    /// Look at a newly reported charge current, and if it's moved at
+    /// least half way from where it was to the limit we set, take that
+    /// as the EVSE's response and update the latency estimate.
+    pub fn observe(&mut self, current: f64) {
+        let Some(pending) = self.pending else {
+            return;
+        };
+        let elapsed = pending.time.elapsed();
+        if elapsed > MAX_LATENCY {
+            self.pending = None;
+            return;
+        }
+        let expected = pending.new_limit - pending.old_current;
+        let actual = current - pending.old_current;
+        if actual / expected < 0.5 {
+            return;
+        }
+        self.pending = None;
+        self.estimate = Some(match self.estimate {
+            None => elapsed,
+            // Smooth out the jitter in when the EVSE happens to publish.
+            Some(estimate) => (estimate * 3 + elapsed) / 4,
+        });
+        println!(
+            "EVSE responded to charge limit change after {:.1} s (estimated latency {:.1} s)",
+            elapsed.as_secs_f64(),
+            self.estimate.unwrap().as_secs_f64()
+        );
+    }
+
+    /// True if we've recently changed the charge current limit and
+    /// shouldn't expect to see the effect yet.
+    pub fn settling(&self) -> bool {
+        let Some(pending) = self.pending else {
+            return false;
+        };
+        pending.time.elapsed() < self.estimate.unwrap_or(MAX_LATENCY).min(MAX_LATENCY)
+    }
+}
+
+// The EV's charge current has to change by at least this much for the
+// change to stand out from the rest of the house in the meter data.
+const MIN_METER_STEP: f64 = 2.0;
+
+/// Estimate how long it takes for a change in the EV's charge current to
+/// show up in the Envoy's meter data, end to end.
+///
+/// If the EV's current changes by `dI` right after one meter reading,
+/// and the meter sees it `L` seconds later, then the average export over
+/// the `P` seconds until the next reading reflects the old current for
+/// `L` seconds and the new current for the rest, so it differs from the
+/// instantaneous export at the end of the interval by `dI * L / P`.
+#[derive(Debug, Default)]
+pub struct MeterLatency {
+    estimate: Option<f64>,
+}
+
+impl MeterLatency {
+    /// Update the estimate from one interval between meter readings.
+    pub fn observe(
+        &mut self,
+        interval_s: f64,
+        average_export: f64,
+        instantaneous_export: f64,
+        delta_current: f64,
+    ) {
+        if delta_current.abs() < MIN_METER_STEP {
+            return;
+        }
+        // A rising EV current lowers the export.
+        let latency = interval_s * (instantaneous_export - average_export) / delta_current;
+        if !(-0.5 * interval_s..=1.5 * interval_s).contains(&latency) {
+            // Something else in the house changed too, ignore this one.
+            return;
+        }
+        let latency = latency.clamp(0.0, interval_s);
+        self.estimate = Some(match self.estimate {
+            None => latency,
+            Some(estimate) => (estimate * 3.0 + latency) / 4.0,
+        });
+        println!("estimated meter latency: {:.1} s", self.estimate.unwrap());
+    }
+
+    /// How much the average export over the last interval overstates
+    /// what we'd be exporting with the EV at its present charge current,
+    /// because part of the interval went by before the meter saw the
+    /// change.
+    pub fn compensation(&self, interval_s: f64, delta_current: f64) -> f64 {
+        match self.estimate {
+            Some(latency) => delta_current * (latency / interval_s).min(1.0),
+            None => 0.0,
+        }
+    }
+}
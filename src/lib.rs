@@ -0,0 +1,5169 @@
+// Divert a house's solar surplus into an EV: read the grid export from
+// the meter every --period, and set the EVSE's charge current limit to
+// soak it up.  The `solar-evse` command is a thin wrapper around `cli()`,
+// and other programs can embed the controller with `ControllerBuilder`,
+// giving it their own meter, EVSE, source of EV data or charging
+// policies:
+//
+// ```text
+// let controller = solar_evse::ControllerBuilder::new()
+//     .args(["--openevse", "garage-evse", "--mqtt-broker", "mqtt.lan"])?
+//     .period(std::time::Duration::from_secs(10))
+//     .meter(MyMeter::new(), "my meter")
+//     .policy(NoChargingAtNight)
+//     .build()
+//     .await?;
+// controller.run_until_stopped().await?;
+// ```
+//
+// Options not set with the builder are at their command line defaults,
+// and `build()` checks them the same way the command line does.  The
+// controller's `subscribe()` tells the embedder what it's doing as it
+// happens (see events.rs).
+
+use clap::Parser;
+use std::str::FromStr;
+
+pub mod address_book;
+pub mod admin;
+pub mod alert;
+pub mod alert_rules;
+pub mod api;
+pub mod audit;
+pub mod autotune;
+pub mod boost;
+pub mod capture;
+pub mod cold;
+pub mod config;
+pub mod control;
+pub mod controller_state;
+pub mod deadline;
+pub mod downsample;
+pub mod efficiency;
+pub mod energy_flow;
+pub mod enlighten;
+pub mod envoy;
+pub mod events;
+pub mod evse;
+pub mod forecast;
+pub mod grid_limit;
+pub mod homeassistant;
+pub mod latency;
+pub mod limits;
+pub mod meter;
+pub mod metrics;
+pub mod mode;
+pub mod mqtt;
+pub mod multi_evse;
+pub mod ocpp;
+pub mod openevse;
+pub mod openevse_events;
+pub mod phase_switch;
+pub mod policy;
+pub mod powerwall;
+pub mod prices;
+pub mod probe;
+pub mod profile;
+pub mod quasar;
+pub mod reload;
+pub mod restarts;
+pub mod schedule;
+pub mod sensor;
+pub mod sessions;
+pub mod snapshot;
+pub mod soc;
+pub mod sunspec;
+pub mod templates;
+pub mod vehicle;
+pub mod webhook;
+
+/// Read energy consumption & generation information from Enphase Envoy,
+/// allow any surplus to be used by OpenEVSE to charge an EV.
+#[derive(clap::Parser, Debug)]
+#[command(version, about, long_about=None, args_override_self = true, subcommand_negates_reqs = true)]
+#[command(group(clap::ArgGroup::new("mqtt").multiple(true).args(["mqtt_broker", "mqtt_publish_broker"])))]
+struct Args {
+    /// Read settings from this TOML file.  The keys are the long option
+    /// names, for example `mqtt_broker = "mqtt.local"`.  Options given
+    /// on the command line override the file.
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
+    /// The hostname or IP address of the Enphase Envoy to connect to,
+    /// or a full URL like "http://127.0.0.1:8080" (for fake-envoy).
+    #[arg(long, default_value_t = String::from("envoy.local"))]
+    envoy: String,
+
+    /// The hostname or IP address of the OpenEVSE to connect to.
+    #[arg(long, default_value_t = String::from("openevse"))]
+    openevse: String,
+
+    /// Another OpenEVSE to share the surplus with, as "host" or
+    /// "host/MIN-MAX" (its charge current range in Amps).  May be given
+    /// more than once.  --evse-min-charge-current and
+    /// --evse-max-charge-current are the --openevse's range, and the
+    /// control loop's maximum becomes the total of all the units'.
+    #[arg(long, conflicts_with_all = ["ocpp_listen", "quasar"])]
+    extra_openevse: Vec<multi_evse::UnitSpec>,
+
+    /// How to split the surplus between more than one OpenEVSE:
+    /// "priority" gives it to them in the order given, "round-robin"
+    /// evenly, taking turns when there isn't enough for all of them.
+    #[arg(long, value_enum, default_value_t = multi_evse::Sharing::Priority)]
+    load_sharing: multi_evse::Sharing,
+
+    /// Where to find a device, as "name=address[,address...]", instead
+    /// of asking DNS.  May be given more than once.
+    #[arg(long)]
+    address: Vec<address_book::Entry>,
+
+    /// A file of "name address..." lines, one per device, that's read
+    /// again whenever it changes, so a device's address can be changed
+    /// without a restart.  It comes before --address.
+    #[arg(long)]
+    address_book: Option<std::path::PathBuf>,
+
+    /// How long to keep a device's address from DNS before looking it
+    /// up again, in seconds.
+    #[arg(long, default_value_t = 300)]
+    dns_ttl: u64,
+
+    /// The network interface to reach the devices and the MQTT brokers
+    /// out of, on a multi-homed host (Linux only).
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// Our address to reach the devices from, on a multi-homed host.
+    /// (MQTT goes by --interface only.)
+    #[arg(long)]
+    local_address: Option<std::net::IpAddr>,
+
+    /// A URL where the OpenEVSE pushes its state changes, as
+    /// Server-Sent Events or by long-polling, for hearing about them
+    /// between cycles without MQTT.  Each event is a JSON object with
+    /// fields named like the OpenEVSE's MQTT topics ("amp", "pilot",
+    /// "state", "vehicle", "status").
+    #[arg(long)]
+    openevse_events: Option<reqwest::Url>,
+
+    /// Instead of an OpenEVSE, drive an OCPP 1.6J or 2.0.1 charger,
+    /// listening on this address (for example "0.0.0.0:8887") for it to
+    /// connect.  Configure the charger's central system URL to point
+    /// here, and (for OCPP 1.6J) have it send Current.Import meter
+    /// values at least every --period.
+    #[arg(long)]
+    ocpp_listen: Option<String>,
+
+    /// Only let the OCPP charger start a session for this RFID card (or
+    /// other ID token), and log each session with its card.  May be
+    /// given more than once.  By default any card may.
+    #[arg(long, requires = "ocpp_listen")]
+    ocpp_id_tag: Vec<String>,
+
+    /// Settings for the vehicle whose RFID card (or other ID token)
+    /// started the OCPP charger's session, as "TAG:setting=value,...",
+    /// for example "CAFE0001:max_current=16,stop_soc=80" or
+    /// "CAFE0002:ready_by=07:00,ready_kwh=20".  max_current caps the
+    /// charge current limit, stop_soc replaces --ev-stop-soc, and
+    /// ready_by with ready_kwh replace --deadline and --deadline-kwh,
+    /// until the EV is unplugged.  May be given more than once.
+    #[arg(long, requires = "ocpp_listen")]
+    vehicle_profile: Vec<profile::Profile>,
+
+    /// Instead of an OpenEVSE, drive a Wallbox Quasar bidirectional
+    /// charger over Modbus TCP ("host" or "host:port").
+    #[arg(long, conflicts_with = "ocpp_listen")]
+    quasar: Option<String>,
+
+    /// The Modbus unit ID of the Quasar.
+    #[arg(long, default_value_t = 1)]
+    quasar_unit_id: u8,
+
+    /// The MQTT broker to connect to for OpenEVSE telemetry, as "host"
+    /// or "host:port".  Without one, we poll the EVSE for the EV's
+    /// charge current every cycle.
+    #[arg(long)]
+    mqtt_broker: Option<String>,
+
+    /// The username to log in to --mqtt-broker with.
+    #[arg(long, requires = "mqtt_broker")]
+    mqtt_username: Option<String>,
+
+    /// Filename of the password to log in to --mqtt-broker with.
+    #[arg(long, requires = "mqtt_username")]
+    mqtt_password_filename: Option<String>,
+
+    /// Every this many cycles, poll the EVSE for the EV's charge current
+    /// ($GG) even though it's reporting it over MQTT (or
+    /// --openevse-events), to check that the reports are right.  0 never
+    /// checks.
+    #[arg(long, default_value_t = 10)]
+    charge_current_check_cycles: u64,
+
+    /// If the reported charge current and the polled one differ by more
+    /// than this many Amps, stop trusting the reports and poll the EVSE
+    /// every cycle, until they agree again.
+    #[arg(long, default_value_t = 2.0)]
+    charge_current_tolerance: f64,
+
+    /// A different MQTT broker to publish our own telemetry, sensors and
+    /// Home Assistant discovery to, as "host" or "host:port".  By
+    /// default we publish to --mqtt-broker.
+    #[arg(long)]
+    mqtt_publish_broker: Option<String>,
+
+    /// The username to log in to --mqtt-publish-broker with.
+    #[arg(long, requires = "mqtt_publish_broker")]
+    mqtt_publish_username: Option<String>,
+
+    /// Filename of the password to log in to --mqtt-publish-broker with.
+    #[arg(long, requires = "mqtt_publish_username")]
+    mqtt_publish_password_filename: Option<String>,
+
+    /// Filename of the Envoy local auth token to use, uuencoded.  Needed
+    /// unless the meter is --sunspec or --powerwall.
+    #[arg(short, long)]
+    auth_token_filename: Option<String>,
+
+    /// Instead of the Envoy, read grid import/export and the home
+    /// battery's power and state of charge from this Tesla Energy
+    /// Gateway (Powerwall).
+    #[arg(
+        long,
+        conflicts_with = "sunspec",
+        requires = "powerwall_password_filename"
+    )]
+    powerwall: Option<String>,
+
+    /// Filename of the Powerwall gateway's customer password.
+    #[arg(long)]
+    powerwall_password_filename: Option<String>,
+
+    /// Instead of the Envoy, read grid import/export from this SunSpec
+    /// meter over Modbus TCP ("host" or "host:port").
+    #[arg(long)]
+    sunspec: Option<String>,
+
+    /// The Modbus unit ID of the SunSpec meter.
+    #[arg(long, default_value_t = 1)]
+    sunspec_unit_id: u8,
+
+    /// The SunSpec meter reports exporting as positive power, instead
+    /// of importing.
+    #[arg(long)]
+    sunspec_invert: bool,
+
+    /// The most current the grid lets us export, in Amps.  With
+    /// --curtail-inverter, production is curtailed to stay under it
+    /// once the EVSE can't take any more.
+    #[arg(long)]
+    export_limit: Option<f64>,
+
+    /// SunSpec inverter ("host" or "host:port") to curtail over Modbus
+    /// TCP when export is over --export-limit and the EVSE can't use
+    /// the surplus.
+    #[arg(long, requires = "export_limit")]
+    curtail_inverter: Option<String>,
+
+    /// The Modbus unit ID of the inverter to curtail.
+    #[arg(long, default_value_t = 1)]
+    curtail_inverter_unit_id: u8,
+
+    /// How many seconds to keep an idle connection to the Envoy open,
+    /// so the next cycle can reuse it instead of doing a slow TLS
+    /// handshake.  Should be longer than --period.
+    #[arg(long, default_value_t = 300)]
+    envoy_pool_idle_timeout: u64,
+
+    /// Interval in seconds between TCP keep-alive probes on the Envoy
+    /// connection.
+    #[arg(long, default_value_t = 30)]
+    envoy_tcp_keepalive: u64,
+
+    /// Read IQ Battery power and state of charge from the Envoy.  (Older
+    /// AC batteries are read without this.)
+    #[arg(long)]
+    envoy_battery: bool,
+
+    /// Set the OpenEVSE's clock to our local time at startup and then
+    /// every this many hours, so its timers and session logs line up
+    /// with ours.  0 to leave its clock alone.
+    #[arg(long, default_value_t = 24)]
+    openevse_clock_sync: u64,
+
+    /// Set the OpenEVSE's charge current with a claim on its WiFi
+    /// firmware's claims API (firmware 4 and up), rather than with RAPI,
+    /// so its own timers and manual override win over us.  The claim
+    /// is released when we stop, instead of leaving it charging at full
+    /// blast.
+    #[arg(long)]
+    openevse_claims: bool,
+
+    /// The priority of our claim, with --openevse-claims.  The
+    /// firmware's timers and manual override are higher than the
+    /// default.
+    #[arg(long, default_value_t = 10)]
+    openevse_claim_priority: u32,
+
+    /// How many seconds to wait for each device to respond at startup.
+    #[arg(long, default_value_t = 30)]
+    startup_timeout: u64,
+
+    /// The grid voltage to assume if the Envoy doesn't report it.  With
+    /// --phases 3 this is the line-to-neutral voltage.
+    #[arg(long, default_value_t = 240.0)]
+    nominal_voltage: f64,
+
+    /// How many phases the house's grid connection has.  With 3, the
+    /// surplus is worked out for each phase from the meter's per-phase
+    /// readings, and a single-phase EVSE can only have as much as its
+    /// phase is exporting (or the house as a whole, if that's less).
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+    phases: u8,
+
+    /// Which phase (1, 2 or 3) a single-phase EVSE is wired to, with
+    /// --phases 3.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+    evse_phase: u8,
+
+    /// How many phases the EVSE charges on.  A three-phase EVSE's
+    /// charge current is per phase, so it gets the house's total
+    /// surplus divided between the phases.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+    evse_phases: u8,
+
+    /// Switch a three-phase EVSE to charging on one phase while the
+    /// surplus is too small for the minimum charge current on three,
+    /// and back.  The EVSE has to be able to switch, or there has to
+    /// be a --phase-switch-topic.
+    #[arg(long)]
+    phase_switching: bool,
+
+    /// Switch phases by publishing "1" or "3" to this MQTT topic, for
+    /// an external contactor.  Implies --phase-switching.
+    #[arg(long)]
+    phase_switch_topic: Option<String>,
+
+    /// How many seconds the surplus has to call for a phase switch
+    /// before we switch.
+    #[arg(long, default_value_t = 300)]
+    phase_switch_delay: u64,
+
+    /// How many Watts more than the three-phase minimum the surplus
+    /// has to be to switch from one phase to three.
+    #[arg(long, default_value_t = 500.0)]
+    phase_switch_hysteresis: f64,
+
+    /// The number of seconds between updates.
+    #[arg(short, long, default_value_t = 60)]
+    period: u64,
+
+    /// Average the export current over this many seconds of meter
+    /// readings, instead of just the time since the last one, so a
+    /// passing cloud doesn't put the EVSE to sleep.  0 averages over
+    /// the last --period.
+    #[arg(long, default_value_t = 0)]
+    export_window: u64,
+
+    /// The target amount of current to be exporting.  Anything above
+    /// this surplus will be directed to the EVSE.
+    #[arg(short = 't', long, default_value_t = 1.0)]
+    target_export_current: f64,
+
+    /// Vary the target export current by time of day, for example
+    /// "06:00=5,12:00=1" to keep 5 A of headroom in the morning and 1 A
+    /// from noon on.  Overrides --target-export-current.
+    #[arg(long)]
+    target_export_schedule: Option<schedule::Schedule<f64>>,
+
+    /// Peak-shaving mode: instead of charging from surplus solar,
+    /// charge the EV with whatever keeps the house's import from the
+    /// grid at or below this many Amps.  Overrides
+    /// --target-export-current and --target-export-schedule.
+    #[arg(long, conflicts_with_all = ["target_export_current", "target_export_schedule"])]
+    peak_shaving_max_import: Option<f64>,
+
+    /// How to charge the EV when we start.
+    #[arg(long, value_enum, default_value_t = mode::Mode::Eco)]
+    mode: mode::Mode,
+
+    /// The time of day to charge at full power in scheduled mode, for
+    /// example "23:00-06:00" for off-peak electricity.
+    #[arg(long, required_if_eq("mode", "scheduled"))]
+    charge_window: Option<schedule::TimeWindow>,
+
+    /// Start out boosting: charging at --evse-max-charge-current until
+    /// this budget is used up, like "90m", "2h" or "10kWh" (a plain
+    /// number is minutes), then going back to --mode.
+    #[arg(long)]
+    boost: Option<boost::Budget>,
+
+    /// Make sure the EV gets at least --deadline-kwh (or gets to
+    /// --ev-target-soc) by this time of day (for example "07:00"),
+    /// charging from the grid at --evse-max-charge-current before then
+    /// (in the cheapest slots, with --octopus-agile-url) if the sun
+    /// didn't deliver it (or, with --solar-forecast-url, won't).
+    #[arg(long)]
+    deadline: Option<chrono::NaiveTime>,
+
+    /// How much energy the EV needs every day by --deadline, in kWh.
+    #[arg(long, requires = "deadline")]
+    deadline_kwh: Option<f64>,
+
+    /// The EV battery's usable capacity in kWh, for estimating its state
+    /// of charge from the energy delivered to it.
+    #[arg(long)]
+    ev_battery_kwh: Option<f64>,
+
+    /// The state of charge in percent to assume the EV has when it's
+    /// plugged in, until it's told otherwise with the "ev_soc" command.
+    #[arg(long, default_value_t = 20.0, requires = "ev_battery_kwh")]
+    ev_plug_in_soc: f64,
+
+    /// How many percentage points --ev-plug-in-soc could be off by.
+    #[arg(long, default_value_t = 20.0, requires = "ev_battery_kwh")]
+    ev_plug_in_soc_uncertainty: f64,
+
+    /// Make sure the EV's estimated state of charge is at least this
+    /// many percent by --deadline, even at the low end of the estimate.
+    /// Replaces --deadline-kwh while the EV's plugged in.
+    #[arg(long, requires_all = ["deadline", "ev_battery_kwh"])]
+    ev_target_soc: Option<f64>,
+
+    /// MQTT topic the outdoor temperature is published to, in degrees
+    /// Celsius.  While it's below --cold-below in the --cold-window, the
+    /// EV is trickle charged at --cold-charge-current from the grid if
+    /// need be, so it can keep its battery warm.
+    #[arg(long, requires = "cold_window")]
+    cold_temperature_topic: Option<String>,
+
+    /// Trickle charge below this outdoor temperature, in degrees
+    /// Celsius.
+    #[arg(long, default_value_t = 0.0)]
+    cold_below: f64,
+
+    /// When cold-weather trickle charging is allowed, like
+    /// "00:00-06:00" for the off-peak hours.
+    #[arg(long, requires = "cold_temperature_topic")]
+    cold_window: Option<schedule::TimeWindow>,
+
+    /// The most energy to trickle charge each time the --cold-window
+    /// opens, in kWh.
+    #[arg(long, default_value_t = 2.0)]
+    cold_kwh: f64,
+
+    /// The charge current for cold-weather trickle charging, in Amps.
+    /// Defaults to --evse-min-charge-current.
+    #[arg(long)]
+    cold_charge_current: Option<f64>,
+
+    /// TeslaMate's MQTT topic for the EV, like "teslamate/cars/1", to
+    /// hear its state of charge and charge limit from.
+    #[arg(long, requires = "mqtt_broker", conflicts_with = "tesla_vehicle_id")]
+    teslamate_topic: Option<String>,
+
+    /// The Tesla API id of the EV, to ask it for its state of charge
+    /// and charge limit.  It's only asked while it's awake.
+    #[arg(long, requires = "tesla_token_filename")]
+    tesla_vehicle_id: Option<String>,
+
+    /// File containing a Tesla API access token.
+    #[arg(long, requires = "tesla_vehicle_id")]
+    tesla_token_filename: Option<std::path::PathBuf>,
+
+    /// The Tesla API to use, the owner API or a Fleet API region.
+    #[arg(long, default_value = "https://owner-api.teslamotors.com/")]
+    tesla_api_url: reqwest::Url,
+
+    /// How often to ask the Tesla API about the EV, in seconds.
+    #[arg(long, default_value_t = 600)]
+    tesla_poll_interval: u64,
+
+    /// Stop charging the EV once it says it's at this state of charge,
+    /// in percent.  Defaults to the charge limit set in the car.
+    #[arg(long)]
+    ev_stop_soc: Option<f64>,
+
+    /// Octopus Energy Agile half-hourly prices, as the tariff's
+    /// "standard-unit-rates" API URL.  With --deadline, grid charging
+    /// happens in the cheapest slots before the deadline.  When the
+    /// price is negative, the EV charges at full power.
+    #[arg(long)]
+    octopus_agile_url: Option<reqwest::Url>,
+
+    /// What importing from the grid costs per kWh, as a schedule like
+    /// --export-price, for working out what charging sessions cost
+    /// without --octopus-agile-url.
+    #[arg(long)]
+    import_price: Option<schedule::Schedule<f64>>,
+
+    /// What exporting to the grid earns per kWh, as a schedule like
+    /// --target-export-schedule (for example "00:00=4,16:00=25,19:00=4",
+    /// or "00:00=15" for a flat rate), in the same currency as
+    /// --ev-energy-value.  While exporting earns more than the surplus
+    /// is worth in the EV, eco mode exports it instead.
+    #[arg(long, requires = "ev_energy_value")]
+    export_price: Option<schedule::Schedule<f64>>,
+
+    /// What a kWh put into the EV is worth, usually what it would cost
+    /// to charge it from the grid otherwise (for example the off-peak
+    /// price).  With --charger-efficiency, the charger's losses are
+    /// taken into account.
+    #[arg(long, requires = "export_price")]
+    ev_energy_value: Option<f64>,
+
+    /// A solar production forecast, as a forecast.solar estimate URL
+    /// (like "https://api.forecast.solar/estimate/LAT/LON/DEC/AZ/KWP")
+    /// or a Solcast rooftop site forecasts URL.  With --deadline, grid
+    /// charging only makes up what the sun isn't expected to deliver
+    /// in time, and starts early (in the cheapest slots, or in
+    /// --charge-window) when the sun won't.  The forecast surplus
+    /// (see --solar-forecast-surplus-fraction) for today, and for the
+    /// rest of today, are the sensor inputs "forecast_surplus_today_kwh"
+    /// and "forecast_surplus_left_today_kwh".
+    #[arg(long)]
+    solar_forecast_url: Option<reqwest::Url>,
+
+    /// Filename of the Solcast API key to fetch --solar-forecast-url
+    /// with.
+    #[arg(long, requires = "solar_forecast_url")]
+    solcast_api_key_filename: Option<String>,
+
+    /// How much of the forecast solar production to count on as
+    /// surplus for the EV, the rest going to the house.
+    #[arg(long, default_value_t = 0.5)]
+    solar_forecast_surplus_fraction: f64,
+
+    /// Minimum EVSE charge current.  If there's less than this available,
+    /// the EVSE will be put to sleep, where it won't charge the EV.
+    #[arg(short = 'i', long, default_value_t = 6.0)]
+    evse_min_charge_current: f64,
+
+    /// Maximum EVSE charge current.  If there's more than this available,
+    /// the surplus will be exported instead of used by the EVSE.
+    #[arg(short = 'x', long, default_value_t = 30.0)]
+    evse_max_charge_current: f64,
+
+    /// With a bidirectional EVSE (--quasar), when the house is importing
+    /// more than the target, discharge the EV into the house at up to
+    /// this many Amps (vehicle-to-home).  0 never discharges.
+    #[arg(long, default_value_t = 0.0)]
+    max_discharge_current: f64,
+
+    /// MQTT topic where an EEBUS gateway (or HEMS) publishes the grid
+    /// operator's limit on the EV's power in Watts (for example §14a
+    /// dimming to 4200), or "none" when it's lifted.  The EV charges at
+    /// no more than the limit, whatever the mode.  The limit can also be
+    /// set with the "grid_limit" command.
+    #[arg(long, requires = "mqtt_broker")]
+    grid_limit_topic: Option<String>,
+
+    /// If the gateway doesn't publish to --grid-limit-topic for
+    /// --grid-limit-timeout seconds, limit the EV to this many Watts
+    /// until it does.
+    #[arg(long)]
+    grid_limit_failsafe: Option<f64>,
+
+    /// How long to wait for the gateway before falling back to
+    /// --grid-limit-failsafe, in seconds.
+    #[arg(long, default_value_t = 120)]
+    grid_limit_timeout: u64,
+
+    /// A current limit on a level of wiring the EVSE is downstream of,
+    /// as "name=amps:load", where load is an expression (written like a
+    /// --sensor) for the current through that level now.  "circuit=32"
+    /// and "service=100" have built-in loads (the EV's charge current,
+    /// and the grid import current).  The EV's charge current is kept
+    /// under all of them, whatever the mode.  May be given more than
+    /// once.
+    #[arg(long)]
+    current_limit: Vec<limits::CurrentLimit>,
+
+    /// The main breaker's rating in Amps.  The EV's charge current plus
+    /// the rest of the house's load, from the meter's consumption CTs
+    /// (each phase's, with --phases 3), is kept under it whatever the
+    /// mode, and the EV's charge current is cut right away if it's
+    /// over.
+    #[arg(long)]
+    house_max_current: Option<f64>,
+
+    /// The EV onboard charger's efficiency at different charge
+    /// currents, for example "6=0.82,10=0.88,16=0.91,32=0.90".
+    #[arg(long)]
+    charger_efficiency: Option<efficiency::EfficiencyCurve>,
+
+    /// Only charge at currents where --charger-efficiency is at least
+    /// this, lowering the charge current to get there if it helps, and
+    /// exporting the surplus if no current in reach is efficient
+    /// enough.
+    #[arg(long, default_value_t = 0.0, requires = "charger_efficiency")]
+    min_charger_efficiency: f64,
+
+    /// The EVSE only accepts whole Amps.  When the ideal charge current
+    /// is fractional, alternate between the neighboring whole Amp values
+    /// from cycle to cycle so the average matches the surplus.
+    #[arg(long)]
+    dither: bool,
+
+    /// Low-pass filter the export current with this time constant, in
+    /// seconds, so passing clouds move the charge current less.  0
+    /// doesn't filter.
+    #[arg(long, default_value_t = 0.0)]
+    export_smoothing: f64,
+
+    /// How much to change the charge current by for each Amp of export
+    /// over the target.
+    #[arg(long, default_value_t = 1.0)]
+    kp: f64,
+
+    /// How much to change the charge current by for each Amp-second of
+    /// export over the target, added up over time.
+    #[arg(long, default_value_t = 0.0)]
+    ki: f64,
+
+    /// How much to change the charge current by for each Amp per second
+    /// that the export over the target is changing by.
+    #[arg(long, default_value_t = 0.0)]
+    kd: f64,
+
+    /// Leave the charge current limit alone unless the new one is at
+    /// least this many Amps away from it, to save EVSE writes.
+    #[arg(long, default_value_t = 0.0)]
+    deadband: f64,
+
+    /// After waking the EVSE, start charging at the minimum charge
+    /// current and ramp up to the full available current over this
+    /// many cycles.  0 disables the soft start.
+    #[arg(long, default_value_t = 0)]
+    soft_start_cycles: u32,
+
+    /// Don't raise or lower the charge current limit faster than this
+    /// many Amps per minute, so the EV's onboard charger doesn't see big
+    /// steps and export changes smoothly, including going into and out
+    /// of a boost.  Lowering it to stay under a --current-limit or a
+    /// cap, or for off or pause mode, isn't slowed down.
+    #[arg(long)]
+    max_ramp_rate: Option<f64>,
+
+    /// Don't change the EVSE's charge current limit more often than
+    /// once every this many seconds, whatever --period is.  Changes
+    /// asked for in between are dropped in favor of the latest one.
+    /// Some EVs complain when the pilot changes too often.
+    #[arg(long, default_value_t = 0)]
+    min_pilot_change_interval: u64,
+
+    /// Once the EVSE is woken up, keep it awake (at least at the minimum
+    /// charge current) for this many seconds before putting it to sleep
+    /// again, to spare its relay.
+    #[arg(long, default_value_t = 0)]
+    min_on_seconds: u64,
+
+    /// Once the EVSE is put to sleep, leave it asleep for this many
+    /// seconds before waking it up again, to spare its relay.
+    #[arg(long, default_value_t = 0)]
+    min_off_seconds: u64,
+
+    /// URL to POST a JSON notification to when an EV is plugged in or
+    /// unplugged, when the EVSE faults, or on error.  "{event}" in the
+    /// URL is replaced by the event name.  May be given more than once.
+    #[arg(long)]
+    webhook: Vec<String>,
+
+    /// Directory of notification text templates (`session_start.txt`,
+    /// `session_end.txt`, `state_change.txt`, `error.txt`,
+    /// `trigger.txt`, `alert.txt`, `alert_cleared.txt`,
+    /// `override_report.txt`, `evse_fault.txt`, `evse_fault_cleared.txt`)
+    /// to use instead of the built-in ones, for customizing or
+    /// translating the messages.
+    #[arg(long)]
+    template_dir: Option<std::path::PathBuf>,
+
+    /// What to do with the EVSE while no EV is plugged in.  Solar
+    /// tracking resumes when one is plugged in.  Note that an EVSE
+    /// that's asleep can only report the EV being plugged in if its
+    /// firmware publishes the `openevse/vehicle` MQTT topic.
+    #[arg(long, value_enum, default_value_t = UnpluggedAction::MinCurrent)]
+    unplugged: UnpluggedAction,
+
+    /// How long to wait after the EVSE's fault (a GFCI trip,
+    /// over-temperature, ...) clears before charging again, in seconds.
+    #[arg(long, default_value_t = 300)]
+    fault_hold: u64,
+
+    /// How often to check on the EVSE while there's no EV plugged in,
+    /// in seconds, instead of every --period.
+    #[arg(long, default_value_t = 60)]
+    idle_period: u64,
+
+    /// Republish the Envoy's meter readings to MQTT topics under this
+    /// prefix, for example "envoy" gives "envoy/production/w_now",
+    /// "envoy/net-consumption/wh_lifetime", "envoy/export_current",
+    /// etc.
+    #[arg(long, requires = "mqtt")]
+    mqtt_envoy_prefix: Option<String>,
+
+    /// Leave the charging to the OpenEVSE's own solar divert, and just
+    /// publish the grid import (positive) or export (negative) in Watts
+    /// to this MQTT topic every cycle, for the OpenEVSE's "grid_ie"
+    /// setting.  The OpenEVSE is put in Eco divert mode at startup, and
+    /// otherwise left alone.
+    #[arg(long, requires = "mqtt")]
+    grid_ie_topic: Option<String>,
+
+    /// Publish what the controller is doing, and why, as retained MQTT
+    /// messages under this prefix, for example "solar-evse" gives
+    /// "solar-evse/export_current", "solar-evse/charge_limit",
+    /// "solar-evse/state", "solar-evse/state_reason",
+    /// "solar-evse/last_error", etc.
+    #[arg(long, requires = "mqtt")]
+    mqtt_telemetry_prefix: Option<String>,
+
+    /// Publish Home Assistant MQTT discovery messages under this prefix
+    /// (usually "homeassistant"), so the controller and its telemetry
+    /// show up in Home Assistant by themselves.
+    #[arg(long, requires = "mqtt_telemetry_prefix")]
+    homeassistant_discovery_prefix: Option<String>,
+
+    /// Take commands from MQTT topics under --mqtt-telemetry-prefix:
+    /// "<prefix>/cmd/mode" (eco, fast, off, pause or scheduled),
+    /// "<prefix>/cmd/limit" (a charge current cap in Amps until the EV
+    /// is unplugged, or "none"), "<prefix>/cmd/boost" (how long
+    /// or how much to charge at full power, like "90m", "2h" or "10kWh",
+    /// a plain number is minutes, 0 to cancel), "<prefix>/cmd/grid_limit"
+    /// (the grid operator's limit in Watts, or "none"),
+    /// "<prefix>/cmd/ev_soc" (the EV's state of charge in percent, with
+    /// --ev-battery-kwh), and "<prefix>/cmd/evse_min_charge_current",
+    /// "<prefix>/cmd/evse_max_charge_current" and
+    /// "<prefix>/cmd/target_export_current" (Amps).
+    #[arg(long, requires = "mqtt_telemetry_prefix")]
+    mqtt_commands: bool,
+
+    /// Compute a sensor from other values each cycle, for example
+    /// "house_load=production_w_now - export_w", and publish it under
+    /// --mqtt-envoy-prefix.  May be given more than once.
+    #[arg(long)]
+    sensor: Vec<sensor::Sensor>,
+
+    /// Adjust the charge current limit each cycle with a policy, for
+    /// example "quiet=schedule(22:00-07:00, min(limit, 10), limit)".
+    /// Policies are put together with min(), schedule() and override()
+    /// out of --sensor expressions, which can use "limit", the limit
+    /// chosen so far.  May be given more than once, and they're applied
+    /// in order.
+    #[arg(long)]
+    policy: Vec<policy::Spec>,
+
+    /// Send a "trigger" webhook, and publish 1 under
+    /// --mqtt-envoy-prefix, when a condition becomes true, for example
+    /// "precool=vehicle_connected && evse_charge_current < 1 &&
+    /// export_current > 10" to start the AC when the EV is full and
+    /// there's surplus to spare, or with
+    /// "&& forecast_surplus_today_kwh > 20" only on days with a big
+    /// --solar-forecast-url surplus.  The condition is written like a
+    /// --sensor.  May be given more than once.
+    #[arg(long)]
+    trigger: Vec<sensor::Sensor>,
+
+    /// Send an "alert" notification when a condition has been true for
+    /// a while, and an "alert_cleared" one when it's false again, as
+    /// "name=condition [for DURATION] [during HH:MM-HH:MM]", for example
+    /// "high_import=export_w < -8000 for 5m" or
+    /// "no_production=production_w_now < 10 for 30m during 10:00-14:00".
+    /// The condition is written like a --sensor, and the duration like
+    /// "30s", "5m" or "2h".  May be given more than once.
+    #[arg(long)]
+    alert: Vec<alert::Alert>,
+
+    /// A zigbee2mqtt smart plug, by its friendly name, whose power
+    /// reading is the sensor input "plug_<name>_w" (with anything but
+    /// letters and digits in the name turned into "_").  A --trigger
+    /// with the same name switches the plug on and off with its
+    /// condition, making it a dump load for surplus.  May be given more
+    /// than once.
+    #[arg(long, requires = "mqtt_broker")]
+    zigbee_plug: Vec<String>,
+
+    /// A sensor input read from an MQTT topic on --mqtt-broker, as
+    /// "name=topic" for a numeric payload or "name=topic,/json/pointer"
+    /// for a number in a JSON one, for example a water heater's tank
+    /// temperature "tank_temp=shelly1pm/status/temperature:100,/tC", so
+    /// the dump load's --trigger can stop at a setpoint with "&&
+    /// tank_temp < 60".  Readings older than 15 minutes are dropped,
+    /// which switches such a trigger off.  May be given more than once.
+    #[arg(long, requires = "mqtt_broker")]
+    mqtt_input: Vec<mqtt::Input>,
+
+    /// zigbee2mqtt's base MQTT topic.
+    #[arg(long, default_value_t = String::from("zigbee2mqtt"))]
+    zigbee2mqtt_prefix: String,
+
+    /// MQTT topic that reports an AC-coupled home battery's power in
+    /// Watts, positive when discharging and negative when charging.
+    /// Battery discharge is not counted as surplus.
+    #[arg(long, requires = "mqtt_broker")]
+    battery_power_topic: Option<String>,
+
+    /// Multiply the values on --battery-power-topic by this, for
+    /// example -1 if the battery reports charging as positive, or 1000
+    /// if it reports kW.
+    #[arg(long, default_value_t = 1.0)]
+    battery_power_scale: f64,
+
+    /// Which gets the solar surplus first when the home battery wants to
+    /// charge.  With "ev", power the battery is charging with counts as
+    /// surplus for the EV.  With "battery", the EV only gets what the
+    /// battery doesn't take.
+    #[arg(long, value_enum, default_value_t = BatteryPriority::Ev)]
+    battery_priority: BatteryPriority,
+
+    /// While the home battery's state of charge is below this many
+    /// percent, it gets the surplus first, whatever --battery-priority
+    /// says, and EV charging doesn't start or speed up.  Needs a meter
+    /// that reports the state of charge.
+    #[arg(long, default_value_t = 0.0)]
+    battery_reserve_soc: f64,
+
+    /// Write the raw Envoy JSON and OpenEVSE RAPI replies to files in
+    /// this directory, for attaching to bug reports.  The Envoy auth
+    /// token is redacted.
+    #[arg(long)]
+    capture_dir: Option<std::path::PathBuf>,
+
+    /// Rotate a capture file when it reaches this many bytes.
+    #[arg(long, default_value_t = 1_000_000)]
+    capture_max_bytes: u64,
+
+    /// How many rotated capture files to keep for each device.
+    #[arg(long, default_value_t = 5)]
+    capture_max_files: usize,
+
+    /// Serve Prometheus metrics at /metrics on this address, for
+    /// example "0.0.0.0:9477".
+    #[arg(long)]
+    metrics_listen: Option<String>,
+
+    /// Append a record of each charging session (its energy, how much
+    /// was solar, and what the rest cost) to this file, as a line of
+    /// JSON.  See `solar-evse sessions` for a yearly report.
+    #[arg(long)]
+    session_log: Option<std::path::PathBuf>,
+
+    /// Keep count of restarts, and why the last run stopped, in this
+    /// file (for example "/var/lib/solar-evse/state.json"), and report
+    /// them in the metrics and the status API.
+    #[arg(long)]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Serve the HTTP API (GET /status, /history and /energy_flows, POST
+    /// /mode, /boost and /limits) and a dashboard page (/) on this address, for
+    /// example "127.0.0.1:8090", or "[::]:8090" for IPv6.  Anyone who can
+    /// reach it can change the settings, without --http-username or
+    /// --http-token-file.
+    ///
+    /// May be given more than once, to listen on several addresses.
+    #[arg(long)]
+    http_listen: Vec<String>,
+
+    /// Serve the HTTP API over HTTPS, with this PEM certificate (chain).
+    #[arg(long, requires = "http_tls_key")]
+    http_tls_cert: Option<std::path::PathBuf>,
+
+    /// The PEM private key for --http-tls-cert.
+    #[arg(long, requires = "http_tls_cert")]
+    http_tls_key: Option<std::path::PathBuf>,
+
+    /// Ask for this username, and the password in
+    /// --http-password-filename, to use the HTTP API and dashboard.
+    #[arg(long, requires = "http_password_filename")]
+    http_username: Option<String>,
+
+    /// File containing the HTTP API's password.
+    #[arg(long, requires = "http_username")]
+    http_password_filename: Option<std::path::PathBuf>,
+
+    /// File of tokens for the HTTP API and dashboard, one per line with
+    /// its scope: "read" for GETs only (a dashboard tablet, say), or
+    /// "control" for everything.  Send one as "Authorization: Bearer
+    /// TOKEN", or add "?token=TOKEN" to the URL.
+    #[arg(long)]
+    http_token_file: Option<std::path::PathBuf>,
+
+    /// How the HTTP API's 1 minute, 5 minute and 1 hour history sums up
+    /// each interval's numbers.
+    #[arg(long, value_enum, default_value_t = downsample::Method::Mean)]
+    history_aggregate: downsample::Method,
+
+    /// Save the HTTP API's downsampled history to this file every hour,
+    /// and read it back at startup, so a restart doesn't lose it.
+    #[arg(long)]
+    history_file: Option<std::path::PathBuf>,
+
+    /// Listen on a Unix socket at this path for an admin console, with
+    /// commands to dump the controller's state, run a cycle now, inject
+    /// a meter reading, or send the OpenEVSE a raw RAPI command.  Try
+    /// "socat READLINE UNIX-CONNECT:<path>" and "help".
+    #[arg(long)]
+    admin_socket: Option<std::path::PathBuf>,
+
+    /// When the settings are reloaded (on SIGHUP, or with POST /reload)
+    /// and the current limits or another safety-relevant option
+    /// changed, don't apply them until they're confirmed with POST
+    /// /reload {"confirm": true}.
+    #[arg(long)]
+    reload_confirm: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Instead of running the controller, record the Envoy's meter
+    /// readings, the EVSE's replies to some read-only queries, and the
+    /// configuration to files in DIR, then exit.  The Envoy auth token
+    /// is redacted.  Useful for attaching to bug reports.
+    Snapshot {
+        /// The directory to write the snapshot files to.
+        dir: std::path::PathBuf,
+    },
+
+    /// Print a JSON Schema for the --config file, for editors to
+    /// autocomplete and check it with, then exit.
+    ConfigSchema,
+
+    /// Print the charging sessions in the --session-log, with their
+    /// energy, solar fraction and cost, then exit.
+    Sessions {
+        /// Only the sessions that started in this year.
+        #[arg(long)]
+        year: Option<i32>,
+
+        #[arg(long, value_enum, default_value_t = sessions::Format::Json)]
+        format: sessions::Format,
+    },
+
+    /// Print the energy and cost of the --session-log's sessions in a
+    /// month for each RFID card they were started with, as CSV for
+    /// invoicing, then exit.
+    Invoice {
+        /// The month, like "2026-03".
+        #[arg(long)]
+        month: sessions::Month,
+    },
+
+    /// Print each month's charging from the --session-log (sessions,
+    /// energy, solar fraction, savings and average session length) as
+    /// CSV, then exit.
+    Analytics,
+
+    /// Add the days in an Enphase Enlighten CSV export of the house's
+    /// energy to the --session-log, so the analytics go back to before
+    /// we were charging the EV, then exit.
+    ImportCsv {
+        /// The CSV file.
+        file: std::path::PathBuf,
+    },
+
+    /// Instead of running the controller, step the charge current limit
+    /// of an EV that's charging and watch the meter respond, print
+    /// suggested --kp, --ki, --kd and --export-smoothing, then exit.
+    Autotune {
+        /// How far to step the charge current limit, in Amps.
+        #[arg(long, default_value_t = 6.0)]
+        step: f64,
+
+        /// How long to hold the limit before the step and after it, in
+        /// seconds.
+        #[arg(long, default_value_t = 120)]
+        settle: u64,
+    },
+
+    /// Print suggested Prometheus alerting rules for the
+    /// --metrics-listen metrics, then exit.
+    AlertRules {
+        /// The Prometheus job that scrapes solar-evse.
+        #[arg(long, default_value = "solar-evse")]
+        job: String,
+
+        /// Alert when the meter's last reading is older than this, in
+        /// seconds.
+        #[arg(long, default_value_t = 300)]
+        stale_after: u64,
+
+        /// Alert when the house imports more than this many amps while
+        /// the EV charges...
+        #[arg(long, default_value_t = 10.0)]
+        import_amps: f64,
+
+        /// ...for this many seconds.
+        #[arg(long, default_value_t = 900)]
+        import_for: u64,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum UnpluggedAction {
+    /// Put the EVSE to sleep.
+    Sleep,
+
+    /// Leave the EVSE enabled at the minimum charge current.
+    MinCurrent,
+
+    /// Don't send the EVSE any commands at all.
+    Release,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum BatteryPriority {
+    Ev,
+    Battery,
+}
+
+pub struct Controller {
+    args: Args,
+
+    meter: Box<dyn meter::Meter>,
+    evse: Box<dyn evse::Evse>,
+
+    // The OpenEVSE again, for raw RAPI commands from the admin console.
+    // None for OCPP chargers.
+    rapi: Option<openevse::OpenEVSE>,
+
+    // When we last set the OpenEVSE's clock.
+    clock_synced: Option<std::time::Instant>,
+
+    ctrl_c_rx: tokio::sync::mpsc::Receiver<()>,
+    // The MQTT connection, if there's a broker.
+    mqtt_client: Option<rumqttc::AsyncClient>,
+    mqtt_eventloop: Option<rumqttc::EventLoop>,
+
+    // The connection to the broker we publish to, if it's a different
+    // one.
+    mqtt_publish_client: Option<rumqttc::AsyncClient>,
+    mqtt_publish_eventloop: Option<rumqttc::EventLoop>,
+
+    // The OpenEVSE's state changes pushed over HTTP, if enabled.
+    openevse_events: Option<tokio::sync::mpsc::Receiver<openevse_events::Event>>,
+
+    // The last reading from the meter.
+    last_reading: Option<meter::PowerReading>,
+
+    // The time and lifetime export energy of the readings in the
+    // --export-window, oldest first.
+    recent_readings: std::collections::VecDeque<(chrono::DateTime<chrono::Utc>, f64)>,
+
+    // How many Amps we're currently exporting to the grid.
+    export_current: f64,
+
+    // How many Amps we were exporting at the moment of the last meter
+    // reading (as opposed to `export_current`, which is usually the
+    // average since the reading before).
+    export_current_now: f64,
+
+    // The voltage of the EVSE's phase(s) at the last meter reading.
+    rms_voltage: f64,
+
+    // The home battery's power in Watts as last reported, positive when
+    // discharging, and when it was reported.
+    battery_power: Option<(f64, std::time::Instant)>,
+
+    // The --mqtt-input readings, and when they came in.
+    mqtt_inputs: std::collections::HashMap<String, (f64, std::time::Instant)>,
+
+    // The home battery's state of charge in percent, as last reported.
+    battery_soc: Option<f64>,
+
+    // Seconds between the last two meter readings, if `export_current`
+    // is an average over them.
+    reading_interval: Option<f64>,
+
+    // The EVSE Pilot current, how much it's advertising to the EV that
+    // it's willing to supply.
+    evse_charge_limit: f64,
+
+    // The EVSE actual charge current.  How much the EV is currently
+    // drawing.
+    evse_charge_current: f64,
+
+    // False if the EVSE is not (or no longer) reachable.
+    evse_attached: bool,
+
+    // When `evse_charge_current` was last updated, from MQTT or by
+    // polling the EVSE.
+    evse_charge_current_time: Option<std::time::Instant>,
+
+    // The EV's charge current as last reported over MQTT, and when.
+    // Kept apart from `evse_charge_current` so it can be checked
+    // against polling.
+    reported_charge_current: Option<(f64, std::time::Instant)>,
+
+    // True if the reported charge current disagreed with polling, so we
+    // poll every cycle instead of using it.
+    reported_charge_current_unreliable: bool,
+
+    // Cycles since we last checked the reported charge current against
+    // polling.
+    cycles_since_charge_current_check: u64,
+
+    // The EVSE state as last reported over MQTT.
+    evse_state: Option<openevse::EvseState>,
+
+    // The fault the EVSE is in, if any, when the last one cleared, and
+    // how many times it's tripped (for OpenEVSEs).
+    evse_fault: Option<String>,
+    fault_cleared: Option<std::time::Instant>,
+    fault_counts: Option<openevse::FaultCounts>,
+
+    // Whether the EVSE is enabled (true) or asleep (false), as last
+    // reported over MQTT or set by us.  None if we don't know.
+    evse_enabled: Option<bool>,
+
+    // When we last woke up the EVSE or put it to sleep.
+    evse_enabled_time: Option<std::time::Instant>,
+
+    // The charge current limit we last sent to the EVSE, if any.
+    commanded_charge_limit: Option<isize>,
+
+    // When we last sent the EVSE a new charge current limit.
+    commanded_charge_limit_time: Option<std::time::Instant>,
+
+    // The state of the control loop's update rule.
+    controller: control::Controller,
+
+    // The part of the ideal charge current limit that's been rounded
+    // away so far, when dithering.
+    dither_error: f64,
+
+    // The charge current limit after the last --max-ramp-rate step, and
+    // when that was.
+    ramp_base: Option<(f64, std::time::Instant)>,
+
+    // Whether --house-max-current is holding the charge current limit
+    // down.
+    house_limited: bool,
+
+    // How many phases the EVSE is charging on, and when to switch.
+    active_phases: u8,
+    phase_switch: phase_switch::PhaseSwitch,
+
+    // How many cycles ago we woke up the EVSE, while soft-starting.
+    soft_start_cycle: Option<u32>,
+
+    // How long the EVSE takes to respond to a new charge current limit.
+    setpoint_latency: latency::SetpointLatency,
+
+    // How long a change in the EV's charge current takes to show up in
+    // the meter data.
+    meter_latency: latency::MeterLatency,
+
+    // Whether an EV is plugged in, if we know.
+    vehicle_connected: Option<bool>,
+
+    // When the EV was plugged in, if it is.
+    session_start: Option<chrono::DateTime<chrono::Local>>,
+
+    // Energy delivered to the EV since it was plugged in (or since we
+    // started, if we don't know), in Watt-hours, and when we last added
+    // to it.
+    session_energy_wh: f64,
+    session_energy_time: Option<std::time::Instant>,
+
+    // How much of the session's energy came from solar, in Watt-hours,
+    // what the rest cost, and what the solar saved, if we know.
+    session_solar_wh: f64,
+    session_cost: Option<f64>,
+    session_savings: Option<f64>,
+
+    webhooks: webhook::Webhooks,
+
+    // Which of the triggers' conditions were true last time we checked.
+    active_triggers: std::collections::HashMap<String, bool>,
+
+    // The alerts, and which of them have gone off.
+    alerts: alert::Alerts,
+
+    // The zigbee2mqtt plugs' power in Watts, as last reported, by
+    // friendly name.
+    plug_power: std::collections::HashMap<String, f64>,
+
+    // The inverter to curtail when export is over the limit.
+    inverter: Option<sunspec::SunSpecInverter>,
+
+    templates: std::sync::Arc<templates::Templates>,
+
+    // What the controller is doing, and why.
+    controller_state: controller_state::ControllerState,
+    controller_state_reason: String,
+
+    // The last thing that went wrong, if anything has.
+    last_error: Option<String>,
+
+    // How the user wants the EV charged.
+    mode: mode::Mode,
+
+    // The grid operator's limit on the EV's power.
+    grid_limit: grid_limit::GridLimit,
+
+    // The user's cap on the charge current limit, until the EV is
+    // unplugged.
+    charge_limit_cap: Option<f64>,
+
+    // Charge at full power until its budget is used up, whatever the
+    // mode.
+    boost: Option<boost::Boost>,
+
+    // Each day's energy flows between solar, the house, the EV and the
+    // grid.
+    energy_flows: energy_flow::EnergyFlows,
+
+    // The energy the EV needs by --deadline, and how it's going.
+    deadline: Option<deadline::Deadline>,
+
+    // The ID tag of the --vehicle-profile in use, and the --deadline it
+    // set aside for one of its own, if it has one.
+    vehicle_profile: Option<String>,
+    set_aside_deadline: Option<Option<deadline::Deadline>>,
+
+    // The EV's estimated state of charge, with --ev-battery-kwh.
+    soc: Option<soc::SocEstimate>,
+
+    // What the EV says about its battery, with --teslamate-topic or
+    // --tesla-vehicle-id (or the builder's `vehicle()`), and where to
+    // ask.
+    vehicle: Option<vehicle::Vehicle>,
+    vehicle_source: Option<Box<dyn vehicle::Source>>,
+
+    // Cold-weather trickle charging, with --cold-temperature-topic.
+    cold: Option<cold::ColdCharging>,
+
+    // Electricity prices, if we know them.
+    prices: Option<prices::Prices>,
+
+    // The solar production forecast, if there is one.
+    forecast: Option<forecast::Forecast>,
+
+    // Changes to the EVSE settings that we didn't make.
+    override_audit: audit::OverrideAudit,
+
+    // The --policy ones, then the builder's `policy()`s, in order.
+    policies: Vec<Box<dyn policy::Policy>>,
+
+    // Where `subscribe()`rs hear what we're doing.
+    events: tokio::sync::broadcast::Sender<events::Event>,
+
+    // The numbers we serve to Prometheus.
+    metrics: std::sync::Arc<std::sync::Mutex<metrics::Metrics>>,
+
+    // Uptime, and how many times we've restarted and why.
+    restarts: restarts::Restarts,
+
+    // What we serve at the HTTP API's /status, and the commands that
+    // come in over it, if it's enabled.
+    api_published: Option<std::sync::Arc<std::sync::Mutex<api::Published>>>,
+    api_commands: Option<tokio::sync::mpsc::Receiver<api::Command>>,
+
+    // Requests from the admin console, if enabled.
+    admin_requests: Option<tokio::sync::mpsc::Receiver<admin::Request>>,
+
+    // An export current from the admin console to use instead of the
+    // meter's next reading.
+    injected_export_current: Option<f64>,
+
+    // Set to run the next cycle without waiting out the --period.
+    cycle_now: bool,
+
+    // The command line we were started with, before the --config file
+    // was read, to read it again on a reload; and with it, what the
+    // settings in effect were parsed from.
+    command_line: Vec<std::ffi::OsString>,
+    options: Vec<std::ffi::OsString>,
+
+    // SIGHUPs, unless whoever's embedding us has their own signals.
+    hangups: Option<tokio::signal::unix::Signal>,
+
+    // A reload waiting for --reload-confirm, what it was parsed from,
+    // and what came of the last reload, for the status.
+    pending_reload: Option<(Vec<std::ffi::OsString>, Box<Args>)>,
+    last_reload: Option<serde_json::Value>,
+}
+
+impl Controller {
+    /// Publish a retained message, if we have an MQTT broker.
+    fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>) {
+        let Some(mqtt_client) = self
+            .mqtt_publish_client
+            .as_ref()
+            .or(self.mqtt_client.as_ref())
+        else {
+            return;
+        };
+        if let Err(e) = mqtt_client.try_publish(topic, rumqttc::QoS::AtMostOnce, true, payload) {
+            println!("failed to publish {}: {:?}", topic, e);
+        }
+    }
+
+    /// Republish the Envoy's meter readings to MQTT, if enabled.
+    fn publish_envoy_data(&self, reading: &meter::PowerReading) {
+        let Some(prefix) = &self.args.mqtt_envoy_prefix else {
+            return;
+        };
+        let mut values = vec![(format!("{prefix}/export_current"), self.export_current)];
+        for (name, value) in &reading.details {
+            values.push((format!("{prefix}/{name}"), *value));
+        }
+        for (topic, value) in values {
+            self.publish(&topic, format!("{value:.3}"));
+        }
+    }
+
+    /// Tell the OpenEVSE's own solar divert the grid import/export, with
+    /// --grid-ie-topic.
+    fn publish_grid_ie(&self, topic: &str) {
+        let export_w = match &self.last_reading {
+            Some(reading) => reading.export_w,
+            None => self.export_current * self.watts_per_amp(),
+        };
+        println!(
+            "grid import/export for the OpenEVSE's divert: {:.0} W",
+            -export_w
+        );
+        self.publish(topic, format!("{:.0}", -export_w));
+    }
+
+    /// Put the OpenEVSE's own solar divert in charge, with
+    /// --grid-ie-topic.
+    async fn start_divert(&self, topic: &str) {
+        println!(
+            "leaving the charging to the OpenEVSE's divert, set its grid_ie MQTT topic to {topic:?}"
+        );
+        let Some(rapi) = &self.rapi else {
+            return;
+        };
+        match rapi.set_divert_mode(true).await {
+            Ok(()) => println!("put the OpenEVSE in Eco divert mode"),
+            Err(e) => println!("can't put the OpenEVSE in Eco divert mode: {e:#}"),
+        }
+    }
+
+    async fn update_current_surplus(&mut self) -> Result<(), eyre::Report> {
+        if let Some(amps) = self.injected_export_current.take() {
+            println!("using the injected export current instead of the meter's");
+            self.export_current = amps;
+            self.export_current_now = amps;
+            self.reading_interval = None;
+            return Ok(());
+        }
+        let reading = self.meter.export_power().await?;
+        let rms_voltage = self.evse_voltage(&reading);
+
+        // Keep the readings going back --export-window, plus the one
+        // before that to average from.
+        match reading.export_wh_lifetime {
+            Some(wh) => {
+                if self.recent_readings.back().map(|(time, _)| *time) != Some(reading.reading_time)
+                {
+                    self.recent_readings.push_back((reading.reading_time, wh));
+                }
+            }
+            None => self.recent_readings.clear(),
+        }
+        let window = chrono::Duration::seconds(self.args.export_window as i64);
+        while self.recent_readings.len() > 2
+            && reading.reading_time - self.recent_readings[1].0 >= window
+        {
+            self.recent_readings.pop_front();
+        }
+
+        // The average power over the window (or the time since the last
+        // reading), and how long that was, if we can compute it.
+        let average = match (self.recent_readings.front(), self.recent_readings.back()) {
+            (Some((first_time, first_wh)), Some((last_time, last_wh))) => {
+                // Enphase reports second-resolution timestamps, it'd
+                // be nice if it had higher resolution.
+                let time_delta_s = (*last_time - *first_time).num_seconds() as f64;
+                let previous_time = self
+                    .last_reading
+                    .as_ref()
+                    .map(|last_reading| last_reading.reading_time);
+                if time_delta_s <= 0.0 || previous_time == Some(reading.reading_time) {
+                    None
+                } else {
+                    let ws = (last_wh - first_wh) * 60.0 * 60.0;
+                    Some((ws / time_delta_s, time_delta_s))
+                }
+            }
+            _ => None,
+        };
+        self.rms_voltage = rms_voltage;
+        let watts_per_amp = rms_voltage * self.active_phases as f64;
+        self.export_current_now = reading.export_w / watts_per_amp;
+        if let Some(w) = reading.battery_w {
+            self.battery_power = Some((w, std::time::Instant::now()));
+        }
+        if let Some(soc) = reading.battery_soc {
+            self.battery_soc = Some(soc);
+        }
+        self.reading_interval = average.map(|(_, time_delta_s)| time_delta_s);
+
+        match average {
+            None => {
+                println!(
+                    "no previous reading to compare to, using instantaneous data for this cycle"
+                );
+                self.export_current = self.export_current_now;
+            }
+            Some((w, _)) => {
+                // Average current exported to the grid during the time
+                // interval from the old reading to now.  If this is
+                // negative, it means we imported energy from the grid.
+                self.export_current = w / watts_per_amp;
+            }
+        }
+        if let Some(phase_current) = self.evse_phase_export_current(&reading) {
+            if phase_current < self.export_current {
+                println!(
+                    "phase {} is only exporting {:.3} A, less than the house's {:.3} A",
+                    self.args.evse_phase, phase_current, self.export_current
+                );
+                self.export_current = phase_current;
+            }
+            self.export_current_now = self.export_current_now.min(phase_current);
+        }
+        self.publish_envoy_data(&reading);
+        self.last_reading = Some(reading);
+        Ok(())
+    }
+
+    /// The voltage of the phase(s) the EVSE charges on.  A three-phase
+    /// meter's overall voltage may be the sum of the phases, so with
+    /// --phases it comes from the per-phase readings, or is taken to be
+    /// --nominal-voltage if there aren't any.
+    fn evse_voltage(&self, reading: &meter::PowerReading) -> f64 {
+        if self.args.phases == 1 {
+            return reading.rms_voltage.unwrap_or(self.args.nominal_voltage);
+        }
+        let voltages: Vec<f64> = match self.active_phases {
+            1 => reading
+                .phases
+                .get(self.args.evse_phase as usize - 1)
+                .and_then(|phase| phase.rms_voltage)
+                .into_iter()
+                .collect(),
+            _ => reading
+                .phases
+                .iter()
+                .filter_map(|phase| phase.rms_voltage)
+                .collect(),
+        };
+        if voltages.is_empty() {
+            return self.args.nominal_voltage;
+        }
+        voltages.iter().sum::<f64>() / voltages.len() as f64
+    }
+
+    /// How many Amps the single-phase EVSE's phase is exporting right
+    /// now, if the house has more than one phase and the meter measures
+    /// them.
+    fn evse_phase_export_current(&self, reading: &meter::PowerReading) -> Option<f64> {
+        if self.args.phases == 1 || self.active_phases != 1 {
+            return None;
+        }
+        if reading.phases.len() != self.args.phases as usize {
+            return None;
+        }
+        let phase = &reading.phases[self.args.evse_phase as usize - 1];
+        Some(phase.export_w / phase.rms_voltage.unwrap_or(self.args.nominal_voltage))
+    }
+
+    /// Watts for each Amp of EVSE charge current.
+    fn watts_per_amp(&self) -> f64 {
+        self.rms_voltage * self.active_phases as f64
+    }
+
+    /// The values user-defined sensors can be computed from.
+    fn sensor_inputs(&self) -> std::collections::HashMap<String, f64> {
+        let mut inputs = std::collections::HashMap::from([
+            (String::from("export_current"), self.export_current),
+            (String::from("export_current_now"), self.export_current_now),
+            (String::from("rms_voltage"), self.rms_voltage),
+            (
+                String::from("evse_charge_current"),
+                self.evse_charge_current,
+            ),
+            (String::from("evse_charge_limit"), self.evse_charge_limit),
+            (
+                String::from("vehicle_connected"),
+                if self.vehicle_connected == Some(true) {
+                    1.0
+                } else {
+                    0.0
+                },
+            ),
+            (
+                String::from("target_export_current"),
+                self.target_export_current(),
+            ),
+            (
+                String::from("boost"),
+                if self.boost.is_some() { 1.0 } else { 0.0 },
+            ),
+        ]);
+        if let Some(limit_w) = self.grid_limit.current() {
+            inputs.insert(String::from("grid_limit_w"), limit_w);
+        }
+        for (name, w) in &self.plug_power {
+            let name = name.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+            inputs.insert(format!("plug_{name}_w"), *w);
+        }
+        for (name, (value, time)) in &self.mqtt_inputs {
+            if time.elapsed() <= mqtt::MAX_INPUT_AGE {
+                inputs.insert(name.clone(), *value);
+            }
+        }
+        if let Some(percent) = self
+            .vehicle
+            .as_ref()
+            .and_then(|vehicle| vehicle.soc())
+            .or(self
+                .soc
+                .as_ref()
+                .and_then(|soc| soc.estimate())
+                .map(|(percent, _)| percent))
+        {
+            inputs.insert(String::from("ev_soc"), percent);
+        }
+        if self.forecast.as_ref().is_some_and(|f| f.is_known()) {
+            let now = chrono::Local::now();
+            let midnight = |date: chrono::NaiveDate| {
+                date.and_time(chrono::NaiveTime::MIN)
+                    .and_local_timezone(chrono::Local)
+                    .earliest()
+            };
+            let today = now.date_naive();
+            if let (Some(start), Some(end)) = (midnight(today), today.succ_opt().and_then(midnight))
+            {
+                inputs.insert(
+                    String::from("forecast_surplus_today_kwh"),
+                    self.forecast_surplus_wh(start, end) / 1000.0,
+                );
+                inputs.insert(
+                    String::from("forecast_surplus_left_today_kwh"),
+                    self.forecast_surplus_wh(now, end) / 1000.0,
+                );
+            }
+        }
+        if let Some(reading) = &self.last_reading {
+            inputs.insert(String::from("export_w"), reading.export_w);
+            for (name, value) in &reading.details {
+                inputs.insert(name.replace(['/', '-'], "_"), *value);
+            }
+        }
+        inputs
+    }
+
+    /// Compute the user-defined sensors, and publish them.
+    fn publish_sensors(&self) {
+        if self.args.sensor.is_empty() {
+            return;
+        }
+        let inputs = self.sensor_inputs();
+        for sensor in &self.args.sensor {
+            let value = match sensor.evaluate(&inputs) {
+                Ok(value) => value,
+                Err(e) => {
+                    println!("can't compute sensor {}: {:#}", sensor.name, e);
+                    continue;
+                }
+            };
+            println!("sensor {}: {:.3}", sensor.name, value);
+            if let Some(prefix) = &self.args.mqtt_envoy_prefix {
+                let topic = format!("{prefix}/{}", sensor.name);
+                self.publish(&topic, format!("{value:.3}"));
+            }
+        }
+    }
+
+    /// Add the energy delivered to the EV since last time to the
+    /// session's total, and count the day's energy flows.
+    fn update_session_energy(&mut self) {
+        let now = std::time::Instant::now();
+        if let Some(last) = self.session_energy_time {
+            let hours = (now - last).as_secs_f64() / 3600.0;
+            let wh = self.evse_charge_current * self.watts_per_amp() * hours;
+            self.session_energy_wh += wh;
+            let ev_w = self.evse_charge_current * self.watts_per_amp();
+            let export_w = self.export_current * self.watts_per_amp();
+            let solar_wh = match self.last_reading.as_ref().and_then(|r| r.production_w) {
+                Some(production_w) => {
+                    let flows = energy_flow::Flows::split(production_w, export_w, ev_w, hours);
+                    self.energy_flows
+                        .add(chrono::Local::now().date_naive(), &flows);
+                    flows.solar_to_ev
+                }
+                // Without a production reading, take whatever surplus
+                // there'd be if the EV weren't charging as solar.
+                None => (ev_w + export_w).clamp(0.0, ev_w.max(0.0)) * hours,
+            };
+            self.session_solar_wh += solar_wh;
+            let grid_wh = (wh - solar_wh).max(0.0);
+            self.session_cost = match (self.session_cost, self.import_price()) {
+                (Some(cost), Some(price)) => Some(cost + price * grid_wh / 1000.0),
+                (Some(cost), None) if grid_wh <= 0.0 => Some(cost),
+                _ => None,
+            };
+            // Solar the EV took would otherwise have been exported.
+            self.session_savings = match (self.session_savings, self.import_price()) {
+                (Some(savings), Some(price)) => {
+                    let export_price = self.export_price().unwrap_or(0.0);
+                    Some(savings + (price - export_price) * solar_wh / 1000.0)
+                }
+                (Some(savings), None) if solar_wh <= 0.0 => Some(savings),
+                _ => None,
+            };
+            if let Some(deadline) = &mut self.deadline {
+                deadline.add(wh);
+            }
+            if let Some(cold) = &mut self.cold {
+                cold.add(wh);
+            }
+            let battery_wh = wh * self.charger_efficiency(self.evse_charge_current);
+            if let Some(soc) = &mut self.soc {
+                soc.add(battery_wh);
+            }
+        }
+        self.session_energy_time = Some(now);
+        let efficiency = self.charger_efficiency(self.args.evse_max_charge_current);
+        if let Some(deadline) = &mut self.deadline {
+            // --ev-target-soc doesn't apply to a --vehicle-profile's
+            // deadline.
+            if let (Some(target), None) = (self.args.ev_target_soc, &self.set_aside_deadline) {
+                let needed_wh = self.soc.as_ref().and_then(|soc| soc.needed_wh(target));
+                deadline.set_needed(needed_wh.map(|wh| wh / efficiency));
+            }
+            deadline.update(chrono::Local::now());
+        }
+    }
+
+    /// The EV onboard charger's efficiency at `current`.
+    fn charger_efficiency(&self, current: f64) -> f64 {
+        self.args
+            .charger_efficiency
+            .as_ref()
+            .map_or(soc::DEFAULT_EFFICIENCY, |curve| curve.at(current))
+    }
+
+    /// Tell MQTT we're here, and Home Assistant what we publish, if
+    /// enabled.  Called each time we connect to the broker.
+    fn announce(&self) {
+        let Some(prefix) = &self.args.mqtt_telemetry_prefix else {
+            return;
+        };
+        self.publish(&homeassistant::availability_topic(prefix), "online");
+        if let Some(discovery_prefix) = &self.args.homeassistant_discovery_prefix {
+            for (topic, config) in
+                homeassistant::discovery_messages(discovery_prefix, prefix, self.args.mqtt_commands)
+            {
+                self.publish(&topic, config);
+            }
+        }
+    }
+
+    /// Publish what the controller is doing, if enabled.
+    fn publish_telemetry(&self) {
+        let Some(prefix) = &self.args.mqtt_telemetry_prefix else {
+            return;
+        };
+        for (name, value) in [
+            ("export_current", self.export_current),
+            ("target_export_current", self.target_export_current()),
+            ("evse_charge_current", self.evse_charge_current),
+            ("charge_limit", self.evse_charge_limit),
+            ("session_energy", self.session_energy_wh / 1000.0),
+        ] {
+            self.publish(&format!("{prefix}/{name}"), format!("{value:.3}"));
+        }
+        if let Some(limit) = self.commanded_charge_limit {
+            self.publish(
+                &format!("{prefix}/commanded_charge_limit"),
+                limit.to_string(),
+            );
+        }
+        self.publish(
+            &format!("{prefix}/state"),
+            self.controller_state.to_string(),
+        );
+        self.publish(&format!("{prefix}/mode"), self.mode.to_string());
+        self.publish(
+            &format!("{prefix}/boost"),
+            if self.boost.is_some() { "ON" } else { "OFF" },
+        );
+        let boost_remaining = self
+            .boost
+            .and_then(|boost| boost.remaining_time())
+            .unwrap_or_default();
+        self.publish(
+            &format!("{prefix}/boost_remaining_s"),
+            boost_remaining.as_secs().to_string(),
+        );
+        let boost_remaining_wh = self
+            .boost
+            .and_then(|boost| boost.remaining_energy_wh(self.session_energy_wh))
+            .unwrap_or_default();
+        self.publish(
+            &format!("{prefix}/boost_remaining_kwh"),
+            format!("{:.3}", boost_remaining_wh / 1000.0),
+        );
+        self.publish(
+            &format!("{prefix}/state_reason"),
+            self.controller_state_reason.as_str(),
+        );
+        if let Some(error) = &self.last_error {
+            self.publish(&format!("{prefix}/last_error"), error.as_str());
+        }
+    }
+
+    /// What the controller is doing, for the HTTP API.
+    fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mode": self.mode.to_string(),
+            "state": self.controller_state.to_string(),
+            "state_reason": self.controller_state_reason,
+            "export_current": self.export_current,
+            "target_export_current": self.target_export_current(),
+            "rms_voltage": self.rms_voltage,
+            "evse_attached": self.evse_attached,
+            "reload": self.last_reload,
+            "evse_charge_current": self.evse_charge_current,
+            "charge_current_source":
+                if self.reported_charge_current.is_none() || self.reported_charge_current_unreliable {
+                    "polled"
+                } else {
+                    "reported"
+                },
+            "charge_limit": self.evse_charge_limit,
+            "commanded_charge_limit": self.commanded_charge_limit,
+            "charge_limit_cap": self.charge_limit_cap,
+            "vehicle_profile": self.vehicle_profile,
+            "grid_limit_w": self.grid_limit.current(),
+            "phases": self.active_phases,
+            "cold_charging": self.cold.as_ref().map(|cold| serde_json::json!({
+                "temperature": cold.temperature(),
+                "active": cold.active(),
+                "used_kwh": cold.used_wh() / 1000.0,
+            })),
+            "ev_soc": self.soc.as_ref().and_then(|soc| soc.estimate()).map(|(percent, uncertainty)| {
+                serde_json::json!({ "percent": percent, "uncertainty": uncertainty })
+            }),
+            "vehicle": self.vehicle.as_ref().map(|vehicle| serde_json::json!({
+                "soc": vehicle.soc(),
+                "charge_limit": vehicle.charge_limit(),
+                "state": vehicle.state(),
+                "full": self.ev_full(),
+            })),
+            "evse_fault": self.evse_fault,
+            "evse_fault_counts": self.fault_counts,
+            "evse_min_charge_current": self.args.evse_min_charge_current,
+            "evse_max_charge_current": self.args.evse_max_charge_current,
+            "vehicle_connected": self.vehicle_connected,
+            "session_start": self.session_start.map(|t| t.to_rfc3339()),
+            "session_energy_kwh": self.session_energy_wh / 1000.0,
+            "boost": self.boost.map(|boost| boost.budget.to_string()),
+            "boost_remaining_s": self
+                .boost
+                .and_then(|boost| boost.remaining_time())
+                .map(|t| t.as_secs()),
+            "boost_remaining_kwh": self
+                .boost
+                .and_then(|boost| boost.remaining_energy_wh(self.session_energy_wh))
+                .map(|wh| wh / 1000.0),
+            "battery_soc": self.battery_soc,
+            "last_error": self.last_error,
+            "alerts": self.alerts.active(),
+            "deadline": self.deadline.as_ref().map(|deadline| serde_json::json!({
+                "time": deadline.next().to_rfc3339(),
+                "delivered_kwh": deadline.delivered_wh() / 1000.0,
+                "remaining_kwh": deadline.remaining_wh() / 1000.0,
+                "grid_charging_from": deadline
+                    .start_time(self.args.evse_max_charge_current * self.watts_per_amp())
+                    .map(|t| t.to_rfc3339()),
+            })),
+            "price": self.current_price(),
+            "export_price": self.export_price(),
+            "solar_forecast": self.forecast.as_ref().filter(|f| f.is_known()).map(|forecast| {
+                let now = chrono::Local::now();
+                serde_json::json!({
+                    "power_w": forecast.power_w(now),
+                    "next_24h_kwh": forecast.energy_wh(now, now + chrono::Duration::days(1)) / 1000.0,
+                    "until_deadline_kwh": self
+                        .deadline
+                        .as_ref()
+                        .map(|deadline| forecast.energy_wh(now, deadline.next()) / 1000.0),
+                })
+            }),
+            "uptime_s": self.restarts.uptime().as_secs(),
+            "restarts": self.restarts.restarts,
+            "last_exit_cause": self.restarts.last_exit_cause,
+            "last_exit_message": self.restarts.last_exit_message,
+        })
+    }
+
+    /// Tell the `subscribe()`rs.
+    fn emit(&self, event: events::Event) {
+        // It's fine if nobody's listening.
+        let _ = self.events.send(event);
+    }
+
+    /// Note what went wrong, for the status API and the subscribers.
+    fn set_error(&mut self, message: String) {
+        self.emit(events::Event::Error {
+            message: message.clone(),
+        });
+        self.last_error = Some(message);
+    }
+
+    fn update_api_status(&self) {
+        if let Some(api_published) = &self.api_published {
+            api_published.lock().unwrap().status = self.status();
+        }
+    }
+
+    /// Add this cycle to the HTTP API's history.
+    fn record_api_history(&self) {
+        if let Some(api_published) = &self.api_published {
+            let mut api_published = api_published.lock().unwrap();
+            api_published.energy_flows = self.energy_flows.to_json();
+            let now = chrono::Local::now();
+            api_published.record(
+                now,
+                serde_json::json!({
+                    "time": now.to_rfc3339(),
+                    "export_current": self.export_current,
+                    "target_export_current": self.target_export_current(),
+                    "charge_limit": self.evse_charge_limit,
+                    "evse_charge_current": self.evse_charge_current,
+                    "session_energy_kwh": self.session_energy_wh / 1000.0,
+                    "mode": self.mode.to_string(),
+                    "state": self.controller_state.to_string(),
+                    "state_reason": self.controller_state_reason,
+                }),
+            );
+        }
+    }
+
+    /// Check the triggers' conditions, and fire the ones that just
+    /// became true.
+    fn check_triggers(&mut self) {
+        if self.args.trigger.is_empty() {
+            return;
+        }
+        let inputs = self.sensor_inputs();
+        for trigger in &self.args.trigger {
+            // A trigger that can't be checked (say its temperature
+            // input went quiet) is off, so a dump load doesn't run
+            // unattended.
+            let active = match trigger.evaluate(&inputs) {
+                Ok(value) => value != 0.0,
+                Err(e) => {
+                    if self.active_triggers.get(&trigger.name) == Some(&true) {
+                        println!("can't check trigger {}: {:#}", trigger.name, e);
+                    }
+                    false
+                }
+            };
+            let was_active = self
+                .active_triggers
+                .insert(trigger.name.clone(), active)
+                .unwrap_or(false);
+            if active == was_active {
+                continue;
+            }
+            println!(
+                "trigger {}: {}",
+                trigger.name,
+                if active { "on" } else { "off" }
+            );
+            if active {
+                self.webhooks
+                    .fire("trigger", serde_json::json!({ "name": trigger.name }));
+            }
+            if let Some(prefix) = &self.args.mqtt_envoy_prefix {
+                let topic = format!("{prefix}/trigger/{}", trigger.name);
+                self.publish(&topic, if active { "1" } else { "0" });
+            }
+            if self.args.zigbee_plug.contains(&trigger.name) {
+                self.switch_plug(&trigger.name, active);
+            }
+        }
+    }
+
+    /// Check the alerts' conditions, and send notifications for the
+    /// ones that went off or cleared.
+    fn check_alerts(&mut self) {
+        let inputs = self.sensor_inputs();
+        for (alert, change) in self.alerts.check(&inputs) {
+            let (event, on) = match change {
+                alert::Change::Raised => ("alert", true),
+                alert::Change::Cleared => ("alert_cleared", false),
+            };
+            println!("{event} {}: {}", alert.name, alert.condition);
+            self.webhooks.fire(
+                event,
+                serde_json::json!({
+                    "name": alert.name,
+                    "condition": alert.condition,
+                    "duration_s": alert.duration.as_secs(),
+                    "window": alert.window.map(|window| window.to_string()),
+                }),
+            );
+            if let Some(prefix) = &self.args.mqtt_telemetry_prefix {
+                let topic = format!("{prefix}/alert/{}", alert.name);
+                self.publish(&topic, if on { "ON" } else { "OFF" });
+            }
+        }
+    }
+
+    /// Switch a zigbee2mqtt plug on or off.
+    fn switch_plug(&self, name: &str, on: bool) {
+        let Some(mqtt_client) = &self.mqtt_client else {
+            return;
+        };
+        let topic = format!("{}/{}/set", self.args.zigbee2mqtt_prefix, name);
+        let payload = serde_json::json!({ "state": if on { "ON" } else { "OFF" } });
+        println!("switching plug {} {}", name, if on { "on" } else { "off" });
+        if let Err(e) = mqtt_client.try_publish(
+            &topic,
+            rumqttc::QoS::AtLeastOnce,
+            false,
+            payload.to_string(),
+        ) {
+            println!("failed to publish {}: {:?}", topic, e);
+        }
+    }
+
+    /// Whether the EV has to charge at full power from now on to get
+    /// --deadline-kwh by --deadline.
+    fn must_charge_for_deadline(&self) -> bool {
+        let Some(deadline) = &self.deadline else {
+            return false;
+        };
+        let now = chrono::Local::now();
+        let power_w = self.args.evse_max_charge_current * self.watts_per_amp();
+        if deadline.must_charge(now, power_w) {
+            return true;
+        }
+        let grid_wh = deadline.remaining_wh() - self.forecast_surplus_wh(now, deadline.next());
+        if grid_wh <= 0.0 || power_w <= 0.0 {
+            return false;
+        }
+        // Charge earlier than we have to if it's one of the cheapest
+        // times to.
+        if let Some(prices) = &self.prices {
+            let needed = std::time::Duration::try_from_secs_f64(3600.0 * grid_wh / power_w)
+                .unwrap_or(std::time::Duration::MAX);
+            return prices.is_cheapest(now, deadline.next(), needed);
+        }
+        // The sun won't make it, so top up while it's off-peak.
+        self.forecast.as_ref().is_some_and(|f| f.is_known()) && self.in_charge_window()
+    }
+
+    /// How much solar surplus the forecast says the EV can expect
+    /// between `from` and `to`, in Watt-hours.
+    fn forecast_surplus_wh(
+        &self,
+        from: chrono::DateTime<chrono::Local>,
+        to: chrono::DateTime<chrono::Local>,
+    ) -> f64 {
+        self.forecast.as_ref().map_or(0.0, |forecast| {
+            forecast.energy_wh(from, to) * self.args.solar_forecast_surplus_fraction
+        })
+    }
+
+    /// The electricity price right now, if we know it.
+    fn current_price(&self) -> Option<f64> {
+        self.prices
+            .as_ref()
+            .and_then(|prices| prices.current(chrono::Local::now()))
+            .map(|slot| slot.price)
+    }
+
+    /// What importing costs per kWh right now, if we know.
+    fn import_price(&self) -> Option<f64> {
+        self.current_price().or_else(|| {
+            self.args
+                .import_price
+                .as_ref()
+                .map(|schedule| schedule.now())
+        })
+    }
+
+    /// What exporting earns per kWh right now, if we know.
+    fn export_price(&self) -> Option<f64> {
+        self.args
+            .export_price
+            .as_ref()
+            .map(|schedule| schedule.now())
+    }
+
+    /// Whether to trickle charge for the cold right now.
+    fn cold_trickle(&self) -> bool {
+        self.cold.as_ref().is_some_and(|cold| cold.active())
+    }
+
+    /// Whether it's --charge-window right now.
+    fn in_charge_window(&self) -> bool {
+        self.args.charge_window.is_some_and(|window| window.now())
+    }
+
+    /// The target export current right now.
+    fn target_export_current(&self) -> f64 {
+        if let Some(max_import) = self.args.peak_shaving_max_import {
+            // Importing is just negative exporting.
+            return -max_import;
+        }
+        match &self.args.target_export_schedule {
+            Some(schedule) => schedule.now(),
+            None => self.args.target_export_current,
+        }
+    }
+
+    async fn charge_at_full_blast(&mut self) -> Result<(), eyre::Report> {
+        println!("charging at full blast!");
+        self.evse
+            .set_current_capacity(self.args.evse_max_charge_current as isize)
+            .await?;
+        self.evse.get_current_capacity().await?;
+        self.evse.enable().await?;
+        Ok(())
+    }
+
+    /// Convert the (fractional) charge current limit to the whole Amps
+    /// that the EVSE accepts.
+    fn charge_limit_setpoint(&mut self) -> isize {
+        if !self.args.dither || self.evse_charge_limit < 0.0 {
+            return self.evse_charge_limit as isize;
+        }
+        let ideal = self.evse_charge_limit + self.dither_error;
+        let setpoint = ideal.floor().clamp(
+            self.args.evse_min_charge_current.ceil(),
+            self.args.evse_max_charge_current.floor(),
+        );
+        self.dither_error = ideal - setpoint;
+        setpoint as isize
+    }
+
+    /// While soft-starting, limit the charge current to a ramp from
+    /// the minimum up to the maximum charge current.
+    fn apply_soft_start(&mut self) {
+        let Some(cycle) = self.soft_start_cycle else {
+            return;
+        };
+        if cycle >= self.args.soft_start_cycles {
+            self.soft_start_cycle = None;
+            return;
+        }
+        let cap = self.args.evse_min_charge_current
+            + (self.args.evse_max_charge_current - self.args.evse_min_charge_current)
+                * cycle as f64
+                / self.args.soft_start_cycles as f64;
+        if self.evse_charge_limit > cap {
+            println!(
+                "soft start cycle {}/{}, limiting charge current to {:.3} A",
+                cycle + 1,
+                self.args.soft_start_cycles,
+                cap
+            );
+            self.evse_charge_limit = cap;
+        }
+        self.soft_start_cycle = Some(cycle + 1);
+    }
+
+    /// The EV's told us its state of charge, which beats our estimate.
+    fn vehicle_soc(&mut self, percent: f64) {
+        if let Some(soc) = &mut self.soc {
+            if self.vehicle_connected == Some(true) {
+                soc.set(percent);
+            }
+        }
+    }
+
+    /// Whether the EV says it's charged as far as it should be.
+    fn ev_full(&self) -> bool {
+        self.boost.is_none()
+            && self.vehicle.as_ref().is_some_and(|vehicle| {
+                vehicle.is_full(
+                    self.vehicle_profile()
+                        .and_then(|profile| profile.stop_soc)
+                        .or(self.args.ev_stop_soc),
+                )
+            })
+    }
+
+    /// The --vehicle-profile in use, if any.
+    fn vehicle_profile(&self) -> Option<&profile::Profile> {
+        let id_tag = self.vehicle_profile.as_ref()?;
+        self.args
+            .vehicle_profile
+            .iter()
+            .find(|profile| &profile.id_tag == id_tag)
+    }
+
+    /// Switch to the --vehicle-profile for the card the EV's session
+    /// was started with, or back from it when the EV's unplugged.
+    fn update_vehicle_profile(&mut self) {
+        let id_tag = match self.vehicle_connected {
+            Some(true) => self.evse.id_tag().filter(|id_tag| {
+                self.args
+                    .vehicle_profile
+                    .iter()
+                    .any(|profile| &profile.id_tag == id_tag)
+            }),
+            _ => None,
+        };
+        if id_tag == self.vehicle_profile {
+            return;
+        }
+        if let Some(deadline) = self.set_aside_deadline.take() {
+            self.deadline = deadline;
+        }
+        self.vehicle_profile = id_tag;
+        let Some(profile) = self.vehicle_profile().cloned() else {
+            println!("no vehicle profile");
+            return;
+        };
+        println!("vehicle profile {}", profile.id_tag);
+        if let (Some(time), Some(kwh)) = (profile.ready_by, profile.ready_kwh) {
+            let deadline = deadline::Deadline::new(time, kwh);
+            self.set_aside_deadline = Some(self.deadline.replace(deadline));
+        }
+    }
+
+    /// Notice when an EV gets plugged in or unplugged.
+    fn set_vehicle_connected(&mut self, connected: bool) {
+        self.vehicle_connected = Some(connected);
+        self.update_vehicle_profile();
+        match (connected, self.session_start) {
+            (true, None) => {
+                println!("EV plugged in, starting session");
+                let start = chrono::Local::now();
+                self.session_start = Some(start);
+                self.webhooks.fire("session_start", serde_json::json!({}));
+                self.emit(events::Event::SessionStarted { start });
+
+                if let Some(soc) = &mut self.soc {
+                    soc.plugged_in(
+                        self.args.ev_plug_in_soc,
+                        self.args.ev_plug_in_soc_uncertainty,
+                    );
+                }
+                if let Some(source) = &mut self.vehicle_source {
+                    source.poll_soon();
+                }
+
+                // Start tracking the surplus afresh.
+                self.session_energy_wh = 0.0;
+                self.session_solar_wh = 0.0;
+                self.session_cost = Some(0.0);
+                self.session_savings = Some(0.0);
+                self.controller.reset();
+                self.dither_error = 0.0;
+                self.soft_start_cycle = None;
+                self.setpoint_latency = latency::SetpointLatency::default();
+            }
+            (false, Some(start)) => {
+                let duration = chrono::Local::now() - start;
+                println!(
+                    "EV unplugged, ending session after {} minutes",
+                    duration.num_minutes()
+                );
+                self.session_start = None;
+                self.emit(events::Event::SessionEnded {
+                    start,
+                    energy_wh: self.session_energy_wh,
+                });
+                if self.mode == mode::Mode::Pause {
+                    println!("unpaused, back to eco mode");
+                    self.mode = mode::Mode::Eco;
+                }
+                self.charge_limit_cap = None;
+                self.boost = None;
+                if let Some(soc) = &mut self.soc {
+                    soc.unplugged();
+                }
+                if let Some(vehicle) = &mut self.vehicle {
+                    vehicle.unplugged();
+                }
+                if let Some(filename) = &self.args.session_log {
+                    let record = sessions::Record::new(
+                        start,
+                        chrono::Local::now(),
+                        self.session_energy_wh,
+                        self.session_solar_wh,
+                        self.session_cost,
+                        self.session_savings,
+                        self.evse.id_tag(),
+                    );
+                    if let Err(e) = sessions::append(filename, &record) {
+                        println!("{e:#}");
+                    }
+                }
+                self.webhooks.fire(
+                    "session_end",
+                    serde_json::json!({
+                        "session_start": start.to_rfc3339(),
+                        "duration_s": duration.num_seconds(),
+                    }),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Charge at full power until `budget` is used up.
+    fn start_boost(&mut self, budget: boost::Budget) {
+        println!("boosting for {budget}");
+        self.boost = Some(boost::Boost::new(budget, self.session_energy_wh));
+    }
+
+    /// Carry out a command from the admin console, and say how it went.
+    async fn handle_admin(&mut self, line: &str) -> String {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "help" => String::from(admin::HELP),
+            "status" => serde_json::to_string_pretty(&self.status()).unwrap_or_default(),
+            "dump" => {
+                let mut dump = self.status();
+                dump["internals"] = serde_json::json!({
+                    "export_current_now": self.export_current_now,
+                    "reading_interval": self.reading_interval,
+                    "evse_state": self.evse_state.map(|s| format!("{s:?}")),
+                    "evse_enabled": self.evse_enabled,
+                    "dither_error": self.dither_error,
+                    "soft_start_cycle": self.soft_start_cycle,
+                    "reported_charge_current_unreliable": self.reported_charge_current_unreliable,
+                    "plug_power": self.plug_power,
+                    "active_triggers": self.active_triggers.keys().collect::<Vec<_>>(),
+                });
+                serde_json::to_string_pretty(&dump).unwrap_or_default()
+            }
+            "cycle" => {
+                self.cycle_now = true;
+                String::from("ok, running a cycle")
+            }
+            "inject" => match f64::from_str(rest) {
+                Ok(amps) => {
+                    self.injected_export_current = Some(amps);
+                    self.cycle_now = true;
+                    format!("ok, running a cycle with an export current of {amps:.3} A")
+                }
+                Err(e) => format!("error: failed to parse f64 from {rest:#?}: {e}"),
+            },
+            "openevse" => {
+                let Some(rapi) = &self.rapi else {
+                    return String::from("error: the EVSE isn't an OpenEVSE");
+                };
+                match Self::describe_openevse(rapi).await {
+                    Ok(description) => {
+                        serde_json::to_string_pretty(&description).unwrap_or_default()
+                    }
+                    Err(e) => format!("error: {e:#}"),
+                }
+            }
+            "time_limit" => {
+                let Some(rapi) = &self.rapi else {
+                    return String::from("error: the EVSE isn't an OpenEVSE");
+                };
+                match u32::from_str(rest) {
+                    Ok(minutes) => match rapi.set_time_limit(minutes).await {
+                        Ok(()) => String::from("ok"),
+                        Err(e) => format!("error: {e:#}"),
+                    },
+                    Err(e) => format!("error: failed to parse u32 from {rest:#?}: {e}"),
+                }
+            }
+            "delay_timer" => {
+                let Some(rapi) = &self.rapi else {
+                    return String::from("error: the EVSE isn't an OpenEVSE");
+                };
+                let timer = match rest {
+                    "off" => None,
+                    window => match schedule::TimeWindow::from_str(window) {
+                        Ok(window) => Some(window),
+                        Err(e) => return format!("error: {e:#}"),
+                    },
+                };
+                match rapi.set_delay_timer(timer.as_ref()).await {
+                    Ok(()) => String::from("ok"),
+                    Err(e) => format!("error: {e:#}"),
+                }
+            }
+            "rapi" => {
+                let Some(rapi) = &self.rapi else {
+                    return String::from("error: the EVSE isn't an OpenEVSE");
+                };
+                let command: Vec<&str> = rest.trim_start_matches('$').split_whitespace().collect();
+                if command.is_empty() {
+                    return String::from("error: which RAPI command?");
+                }
+                match rapi.request(&command).await {
+                    Ok(reply) => reply.to_string(),
+                    Err(e) => format!("error: {e:#}"),
+                }
+            }
+            "reload" => match self.reload(rest) {
+                Ok(()) => {
+                    self.update_api_status();
+                    serde_json::to_string_pretty(&self.last_reload).unwrap_or_default()
+                }
+                Err(e) => format!("error: {e:#}"),
+            },
+            _ => match self.handle_command(command, rest) {
+                Ok(()) => {
+                    self.update_api_status();
+                    String::from("ok")
+                }
+                Err(e) => format!("error: {e:#}"),
+            },
+        }
+    }
+
+    /// Use `new`'s value of the long option `option` from now on, if
+    /// it's one the control loop reads every cycle.  Returns whether it
+    /// was.
+    fn apply_option(args: &mut Args, new: &Args, option: &str) -> bool {
+        match option {
+            "period" => args.period = new.period,
+            "target-export-current" => args.target_export_current = new.target_export_current,
+            "target-export-schedule" => {
+                args.target_export_schedule = new.target_export_schedule.clone()
+            }
+            "peak-shaving-max-import" => args.peak_shaving_max_import = new.peak_shaving_max_import,
+            "charge-window" => args.charge_window = new.charge_window,
+            // With --extra-openevse, they're each unit's default range,
+            // which has already been shared out between the units.
+            "evse-min-charge-current" | "evse-max-charge-current"
+                if !args.extra_openevse.is_empty() =>
+            {
+                return false
+            }
+            "evse-min-charge-current" => args.evse_min_charge_current = new.evse_min_charge_current,
+            "evse-max-charge-current" => args.evse_max_charge_current = new.evse_max_charge_current,
+            "max-discharge-current" => args.max_discharge_current = new.max_discharge_current,
+            "house-max-current" => args.house_max_current = new.house_max_current,
+            "fault-hold" => args.fault_hold = new.fault_hold,
+            "idle-period" => args.idle_period = new.idle_period,
+            "soft-start-cycles" => args.soft_start_cycles = new.soft_start_cycles,
+            "max-ramp-rate" => args.max_ramp_rate = new.max_ramp_rate,
+            "min-pilot-change-interval" => {
+                args.min_pilot_change_interval = new.min_pilot_change_interval
+            }
+            "min-on-seconds" => args.min_on_seconds = new.min_on_seconds,
+            "min-off-seconds" => args.min_off_seconds = new.min_off_seconds,
+            "dither" => args.dither = new.dither,
+            "deadband" => args.deadband = new.deadband,
+            "export-smoothing" => args.export_smoothing = new.export_smoothing,
+            "ev-stop-soc" => args.ev_stop_soc = new.ev_stop_soc,
+            "unplugged" => args.unplugged = new.unplugged,
+            "sensor" => args.sensor = new.sensor.clone(),
+            "trigger" => args.trigger = new.trigger.clone(),
+            "vehicle-profile" => args.vehicle_profile = new.vehicle_profile.clone(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Read the settings again (see reload.rs): "" to apply them,
+    /// "dry_run" to only say what would change, or "confirm" to apply
+    /// the ones waiting for --reload-confirm.
+    fn reload(&mut self, how: &str) -> Result<(), eyre::Report> {
+        let (options, new) = match how {
+            "" | "dry_run" => {
+                let options = config::expand(self.command_line.clone())?;
+                let new = Args::try_parse_from(&options)?;
+                new.check_currents()?;
+                (options, Box::new(new))
+            }
+            "confirm" => self
+                .pending_reload
+                .take()
+                .ok_or(eyre::eyre!("there's no reload waiting to be confirmed"))?,
+            _ => return Err(eyre::eyre!("unknown kind of reload {:?}", how)),
+        };
+        let command = <Args as clap::CommandFactory>::command();
+        let mut changes = reload::diff(&command, &self.options, &options)?;
+        // Find out which are live on a copy of the settings in effect.
+        let mut scratch = Args::try_parse_from(&self.options)?;
+        for change in &mut changes {
+            change.live = Self::apply_option(&mut scratch, &new, &change.option);
+        }
+
+        let held = how.is_empty()
+            && self.args.reload_confirm
+            && changes.iter().any(reload::Change::is_safety_relevant);
+        let applied = how != "dry_run" && !held;
+        println!(
+            "{} the settings: {} changed",
+            match (how, held) {
+                ("dry_run", _) => "dry run of reloading",
+                (_, true) => "holding back",
+                _ => "reloaded",
+            },
+            changes.len()
+        );
+        for change in &changes {
+            println!("config: {change}");
+        }
+        if held {
+            println!("confirm with POST /reload {{\"confirm\": true}}");
+            self.pending_reload = Some((options, new));
+        } else if applied {
+            for change in &changes {
+                Self::apply_option(&mut self.args, &new, &change.option);
+            }
+            self.options = options;
+            self.pending_reload = None;
+        }
+        self.last_reload = Some(serde_json::json!({
+            "time": chrono::Local::now().to_rfc3339(),
+            "changes": changes,
+            "applied": applied,
+            "waiting_for_confirmation": held,
+        }));
+        Ok(())
+    }
+
+    /// The EVSE charge current range can't be changed with more than
+    /// one OpenEVSE, it's been shared out between them.
+    fn check_single_evse(&self) -> Result<(), eyre::Report> {
+        if self.args.extra_openevse.is_empty() {
+            return Ok(());
+        }
+        Err(eyre::eyre!(
+            "the charge current range of more than one OpenEVSE can only change with a restart"
+        ))
+    }
+
+    /// Carry out a command from MQTT or the HTTP API.
+    fn handle_command(&mut self, command: &str, payload: &str) -> Result<(), eyre::Report> {
+        let payload = payload.trim();
+        let amps = || {
+            f64::from_str(payload)
+                .map_err(|e| eyre::eyre!("failed to parse f64 from {:#?}: {}", payload, e))
+        };
+        match command {
+            "mode" => {
+                let mode = <mode::Mode as clap::ValueEnum>::from_str(payload, true)
+                    .map_err(|_| eyre::eyre!("unknown mode {:#?}", payload))?;
+                if mode == mode::Mode::Scheduled && self.args.charge_window.is_none() {
+                    return Err(eyre::eyre!("scheduled mode needs a --charge-window"));
+                }
+                println!("mode: {} -> {}", self.mode, mode);
+                self.mode = mode;
+            }
+            "limit" => match payload {
+                "" | "none" => {
+                    println!("removing the charge current cap");
+                    self.charge_limit_cap = None;
+                }
+                _ => {
+                    let cap = amps()?;
+                    if !cap.is_finite() || cap < 0.0 {
+                        return Err(eyre::eyre!("charge current cap {} isn't a current", cap));
+                    }
+                    println!("capping the charge current limit at {:.3} A", cap);
+                    self.charge_limit_cap = Some(cap);
+                }
+            },
+            "boost" => {
+                let budget = boost::Budget::from_str(payload)?;
+                if budget.is_zero() {
+                    println!("cancelling the boost");
+                    self.boost = None;
+                } else {
+                    self.start_boost(budget);
+                }
+            }
+            "ev_soc" => {
+                let Some(soc) = &mut self.soc else {
+                    return Err(eyre::eyre!("ev_soc needs --ev-battery-kwh"));
+                };
+                let percent = f64::from_str(payload)
+                    .map_err(|e| eyre::eyre!("failed to parse f64 from {:#?}: {}", payload, e))?;
+                println!("EV state of charge: {:.0}%", percent);
+                soc.set(percent);
+            }
+            "grid_limit" => match payload {
+                "" | "none" => self.grid_limit.set(None),
+                _ => {
+                    let w = f64::from_str(payload).map_err(|e| {
+                        eyre::eyre!("failed to parse f64 from {:#?}: {}", payload, e)
+                    })?;
+                    self.grid_limit.set(Some(w));
+                }
+            },
+            "evse_min_charge_current" => {
+                self.check_single_evse()?;
+                let min = amps()?;
+                check_currents(
+                    min,
+                    self.args.evse_max_charge_current,
+                    self.args.target_export_current,
+                )?;
+                self.args.evse_min_charge_current = min;
+                println!(
+                    "EVSE min charge current: {:.3} A",
+                    self.args.evse_min_charge_current
+                );
+            }
+            "evse_max_charge_current" => {
+                self.check_single_evse()?;
+                let max = amps()?;
+                check_currents(
+                    self.args.evse_min_charge_current,
+                    max,
+                    self.args.target_export_current,
+                )?;
+                self.args.evse_max_charge_current = max;
+                println!(
+                    "EVSE max charge current: {:.3} A",
+                    self.args.evse_max_charge_current
+                );
+            }
+            "target_export_current" => {
+                let target = amps()?;
+                check_currents(
+                    self.args.evse_min_charge_current,
+                    self.args.evse_max_charge_current,
+                    target,
+                )?;
+                self.args.target_export_current = target;
+                println!(
+                    "target export current: {:.3} A",
+                    self.args.target_export_current
+                );
+            }
+            _ => return Err(eyre::eyre!("unknown command {:#?}", command)),
+        }
+        Ok(())
+    }
+
+    fn handle_mqtt_message(&mut self, topic: &str, payload: &str) {
+        if let (true, Some(prefix)) = (self.args.mqtt_commands, &self.args.mqtt_telemetry_prefix) {
+            if let Some(command) = topic.strip_prefix(&format!("{prefix}/cmd/")) {
+                let command = command.to_string();
+                if let Err(e) = self.handle_command(&command, payload) {
+                    println!("MQTT command {command}: {e:#}");
+                }
+                return;
+            }
+        }
+        if !self.args.extra_openevse.is_empty() && topic.starts_with("openevse/") {
+            // That's only one of the OpenEVSEs, we poll them all.
+            return;
+        }
+        match topic {
+            "openevse/amp" => match f64::from_str(payload) {
+                Ok(new_val) => {
+                    let current = new_val / 1000.0;
+                    self.reported_charge_current = Some((current, std::time::Instant::now()));
+                    if self.reported_charge_current_unreliable {
+                        println!(
+                            "EVSE reports active charge current: {:.3} (not trusted, polling instead)",
+                            current
+                        );
+                        return;
+                    }
+                    self.evse_charge_current = current;
+                    self.evse_charge_current_time = Some(std::time::Instant::now());
+                    println!(
+                        "EVSE reports active charge current: {:.3}",
+                        self.evse_charge_current
+                    );
+                    self.setpoint_latency.observe(self.evse_charge_current);
+                }
+                Err(e) => {
+                    // Don't feed a made-up charge current back into the
+                    // control loop, poll the EVSE for it next cycle.
+                    println!("failed to parse f64 from {:#?}: {:#?}", payload, e);
+                    self.evse_charge_current_time = None;
+                }
+            },
+            "openevse/pilot" => match f64::from_str(payload) {
+                Ok(new_val) => {
+                    println!("EVSE reports charge current limit: {:.3}", new_val);
+                    self.override_audit.check_pilot(new_val);
+                    if self.commanded_charge_limit != Some(new_val as isize) {
+                        // Someone else changed it, put it back next cycle.
+                        self.commanded_charge_limit = None;
+                    }
+                }
+                Err(e) => {
+                    println!("failed to parse f64 from {:#?}: {:#?}", payload, e);
+                }
+            },
+            "openevse/state" => match u8::from_str(payload) {
+                Ok(code) => {
+                    let evse_state = openevse::EvseState::from_code(code);
+                    if self.evse_state != Some(evse_state) {
+                        println!("EVSE reports state {:?}", evse_state);
+                    }
+                    if let Some(connected) = evse_state.is_vehicle_connected() {
+                        self.set_vehicle_connected(connected);
+                    }
+                    self.evse_state = Some(evse_state);
+                    self.evse_enabled = Some(evse_state.is_enabled());
+                    self.set_evse_fault(evse_state.is_fault().then(|| format!("{evse_state:?}")));
+                }
+                Err(e) => {
+                    println!("failed to parse u8 from {:#?}: {:#?}", payload, e);
+                }
+            },
+            "openevse/vehicle" => match payload {
+                "1" => self.set_vehicle_connected(true),
+                "0" => self.set_vehicle_connected(false),
+                _ => println!("unknown EVSE vehicle status {:#?}", payload),
+            },
+            topic
+                if self
+                    .args
+                    .teslamate_topic
+                    .as_deref()
+                    .and_then(|prefix| topic.strip_prefix(prefix))
+                    .is_some_and(|rest| rest.starts_with('/')) =>
+            {
+                let field = topic.rsplit('/').next().unwrap_or_default();
+                if let Some(vehicle) = &mut self.vehicle {
+                    if let Some(percent) = vehicle.handle_teslamate(field, payload) {
+                        self.vehicle_soc(percent);
+                    }
+                }
+            }
+            topic if Some(topic) == self.args.cold_temperature_topic.as_deref() => {
+                match (f64::from_str(payload), &mut self.cold) {
+                    (Ok(celsius), Some(cold)) => cold.set_temperature(celsius),
+                    (Err(e), _) => {
+                        println!("failed to parse f64 from {:#?}: {:#?}", payload, e)
+                    }
+                    (Ok(_), None) => {}
+                }
+            }
+            topic if Some(topic) == self.args.grid_limit_topic.as_deref() => {
+                if let Err(e) = self.handle_command("grid_limit", payload) {
+                    println!("grid limit: {e:#}");
+                }
+            }
+            topic if Some(topic) == self.args.battery_power_topic.as_deref() => {
+                match f64::from_str(payload) {
+                    Ok(new_val) => {
+                        let w = new_val * self.args.battery_power_scale;
+                        self.battery_power = Some((w, std::time::Instant::now()));
+                    }
+                    Err(e) => {
+                        println!("failed to parse f64 from {:#?}: {:#?}", payload, e);
+                    }
+                }
+            }
+            topic
+                if self
+                    .args
+                    .mqtt_input
+                    .iter()
+                    .any(|input| input.topic == topic) =>
+            {
+                for input in self
+                    .args
+                    .mqtt_input
+                    .iter()
+                    .filter(|input| input.topic == topic)
+                {
+                    match input.parse(payload) {
+                        Ok(value) => {
+                            self.mqtt_inputs
+                                .insert(input.name.clone(), (value, std::time::Instant::now()));
+                        }
+                        Err(e) => println!("MQTT input {}: {:#}", input.name, e),
+                    }
+                }
+            }
+            "openevse/status" => match payload {
+                "active" => self.evse_enabled = Some(true),
+                "disabled" => self.evse_enabled = Some(false),
+                _ => println!("unknown EVSE status {:#?}", payload),
+            },
+            topic => {
+                let plug = topic
+                    .strip_prefix(&self.args.zigbee2mqtt_prefix)
+                    .and_then(|topic| topic.strip_prefix('/'))
+                    .filter(|plug| self.args.zigbee_plug.iter().any(|name| name == plug));
+                if let Some(plug) = plug {
+                    let json: serde_json::Value = serde_json::from_str(payload).unwrap_or_default();
+                    match json["power"].as_f64() {
+                        Some(w) => {
+                            self.plug_power.insert(String::from(plug), w);
+                        }
+                        None => println!("plug {} reports no power: {:#?}", plug, payload),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Compute the new charge current limit from the surplus.
+    fn compute_charge_limit(&mut self) {
+        let gains = control::Gains {
+            kp: self.args.kp,
+            ki: self.args.ki,
+            kd: self.args.kd,
+            smoothing: self.args.export_smoothing,
+        };
+        let dt = self.reading_interval.unwrap_or(self.args.period as f64);
+        let export_current = self.controller.filter(&gains, self.export_current, dt);
+        let correction = self.controller.correction(
+            &gains,
+            export_current - self.target_export_current(),
+            dt,
+            self.args.evse_max_charge_current,
+        );
+        // A negative limit discharges the EV, if the EVSE can.
+        let max_discharge = if self.evse.is_bidirectional() {
+            self.args.max_discharge_current
+        } else {
+            0.0
+        };
+        self.evse_charge_limit = (self.evse_charge_current + correction)
+            .clamp(-max_discharge, self.args.evse_max_charge_current);
+        if self.evse_charge_limit.abs() < self.args.evse_min_charge_current {
+            self.evse_charge_limit = 0.0;
+        }
+        if let (Some(commanded), Some(true)) = (self.commanded_charge_limit, self.evse_enabled) {
+            let commanded = commanded as f64;
+            if self.evse_charge_limit != 0.0
+                && (self.evse_charge_limit - commanded).abs() < self.args.deadband
+            {
+                self.evse_charge_limit = commanded;
+            }
+        }
+
+        if let Some(soc) = self.battery_soc {
+            if soc < self.args.battery_reserve_soc {
+                // Don't start charging, or charge any faster than the EV
+                // is now.
+                let limit = if self.evse_enabled == Some(true) {
+                    self.evse_charge_current
+                        .max(self.args.evse_min_charge_current)
+                } else {
+                    0.0
+                };
+                if self.evse_charge_limit > limit {
+                    println!(
+                        "home battery is below its reserve, holding the charge current limit at {:.3} A",
+                        limit
+                    );
+                    self.evse_charge_limit = limit;
+                }
+            }
+        }
+
+        if let Some(curve) = &self.args.charger_efficiency {
+            if self.evse_charge_limit > 0.0 {
+                match curve.efficient_limit(
+                    self.evse_charge_limit,
+                    self.args.evse_min_charge_current,
+                    self.args.min_charger_efficiency,
+                ) {
+                    Some(limit) => {
+                        if limit != self.evse_charge_limit {
+                            println!(
+                                "lowering charge current limit from {:.3} A to {:.3} A for charger efficiency",
+                                self.evse_charge_limit, limit
+                            );
+                        }
+                        self.evse_charge_limit = limit;
+                        println!(
+                            "estimated charger efficiency: {:.0}%",
+                            100.0 * curve.at(limit)
+                        );
+                    }
+                    None => {
+                        println!(
+                            "charger is less than {:.0}% efficient at every current up to {:.3} A, exporting the surplus",
+                            100.0 * self.args.min_charger_efficiency,
+                            self.evse_charge_limit
+                        );
+                        self.evse_charge_limit = 0.0;
+                    }
+                }
+            }
+        }
+
+        if let (Some(export_price), Some(value)) = (self.export_price(), self.args.ev_energy_value)
+        {
+            if self.evse_charge_limit > 0.0 {
+                let efficiency = self
+                    .args
+                    .charger_efficiency
+                    .as_ref()
+                    .map_or(1.0, |curve| curve.at(self.evse_charge_limit));
+                if export_price > value * efficiency {
+                    println!(
+                        "exporting earns {:.3}/kWh, more than the {:.3}/kWh the surplus is worth in the EV, exporting it",
+                        export_price,
+                        value * efficiency
+                    );
+                    self.evse_charge_limit = 0.0;
+                }
+            }
+        }
+
+        if self
+            .boost
+            .is_some_and(|boost| boost.is_over(self.session_energy_wh))
+        {
+            println!("boost is over, back to {} mode", self.mode);
+            self.boost = None;
+        }
+        match (self.mode, self.boost) {
+            (_, Some(_)) | (mode::Mode::Fast, None) => {
+                self.evse_charge_limit = self.args.evse_max_charge_current;
+            }
+            (mode::Mode::Scheduled, None) if self.in_charge_window() => {
+                self.evse_charge_limit = self.args.evse_max_charge_current;
+            }
+            (mode::Mode::Eco | mode::Mode::Scheduled, None)
+                if self.current_price().is_some_and(|price| price < 0.0) =>
+            {
+                println!("electricity price is negative, charging at full power");
+                self.evse_charge_limit = self.args.evse_max_charge_current;
+            }
+            (mode::Mode::Eco | mode::Mode::Scheduled, None) if self.must_charge_for_deadline() => {
+                if let Some(deadline) = &self.deadline {
+                    println!(
+                        "charging at full power to deliver {:.3} kWh more by {}",
+                        deadline.remaining_wh() / 1000.0,
+                        deadline.next().format("%H:%M")
+                    );
+                }
+                self.evse_charge_limit = self.args.evse_max_charge_current;
+            }
+            (mode::Mode::Eco | mode::Mode::Scheduled, None) if self.cold_trickle() => {
+                let current = self
+                    .args
+                    .cold_charge_current
+                    .unwrap_or(self.args.evse_min_charge_current);
+                if self.evse_charge_limit < current {
+                    println!("trickle charging at {:.3} A for the cold", current);
+                    self.evse_charge_limit = current;
+                }
+            }
+            (mode::Mode::Off | mode::Mode::Pause, None) => {
+                self.evse_charge_limit = 0.0;
+            }
+            (mode::Mode::Eco | mode::Mode::Scheduled, None) => {}
+        }
+        self.apply_policies();
+        self.apply_ramp_rate();
+        let cap = self
+            .charge_limit_cap
+            .into_iter()
+            .chain(
+                self.vehicle_profile()
+                    .and_then(|profile| profile.max_current),
+            )
+            .reduce(f64::min);
+        if let Some(cap) = cap {
+            if self.evse_charge_limit > cap {
+                println!("capping the charge current limit at {:.3} A", cap);
+                self.evse_charge_limit = if cap < self.args.evse_min_charge_current {
+                    0.0
+                } else {
+                    cap
+                };
+            }
+        }
+        if let Some(limit_w) = self.grid_limit.current() {
+            let max = limit_w / self.watts_per_amp();
+            if self.evse_charge_limit > max {
+                println!(
+                    "grid operator{} limit of {:.0} W holds the charge current limit at {:.3} A",
+                    if self.grid_limit.is_failsafe() {
+                        "'s failsafe"
+                    } else {
+                        "'s"
+                    },
+                    limit_w,
+                    max
+                );
+                self.evse_charge_limit = if max < self.args.evse_min_charge_current {
+                    0.0
+                } else {
+                    max
+                };
+            }
+        }
+
+        self.apply_current_limits();
+        self.apply_house_limit();
+    }
+
+    /// Switch the EVSE between one phase and three if the surplus calls
+    /// for it.  The EV can't charge while the phases change, so the
+    /// EVSE is put to sleep first, and wakes up on the new phases next
+    /// cycle.
+    async fn check_phase_switch(&mut self) -> Result<(), eyre::Report> {
+        if !self.args.phase_switching && self.args.phase_switch_topic.is_none() {
+            return Ok(());
+        }
+        let stopped =
+            self.boost.is_none() && matches!(self.mode, mode::Mode::Off | mode::Mode::Pause);
+        if self.vehicle_connected != Some(true) || stopped {
+            self.phase_switch.reset();
+            return Ok(());
+        }
+        let available_w = (self.evse_charge_current + self.export_current
+            - self.target_export_current())
+            * self.watts_per_amp();
+        let three_phase_min_w = self.args.evse_min_charge_current * self.rms_voltage * 3.0;
+        let Some(phases) =
+            self.phase_switch
+                .check(self.active_phases, available_w, three_phase_min_w)
+        else {
+            return Ok(());
+        };
+        self.sleep_evse().await?;
+        if self.evse_enabled != Some(false) {
+            // The relay has to stay on a while longer, try again then.
+            return Ok(());
+        }
+        println!("switching the EVSE to {} phase(s)", phases);
+        match &self.args.phase_switch_topic {
+            Some(topic) => {
+                let Some(mqtt_client) = &self.mqtt_client else {
+                    return Err(eyre::eyre!("--phase-switch-topic needs an MQTT broker"));
+                };
+                mqtt_client
+                    .try_publish(topic, rumqttc::QoS::AtLeastOnce, true, phases.to_string())
+                    .map_err(|e| eyre::eyre!("failed to publish {}: {:?}", topic, e))?;
+            }
+            None => self.evse.set_phases(phases).await?,
+        }
+        self.active_phases = phases;
+        // The surplus in Amps means something else now.
+        self.controller.reset();
+        self.ramp_base = None;
+        self.evse_charge_limit = 0.0;
+        Ok(())
+    }
+
+    /// Let the policies have their say on the charge current limit (see
+    /// policy.rs), within what the EVSE can do.
+    fn apply_policies(&mut self) {
+        if self.policies.is_empty() {
+            return;
+        }
+        let mut inputs = self.sensor_inputs();
+        for sensor in &self.args.sensor {
+            if let Ok(value) = sensor.evaluate(&inputs) {
+                inputs.insert(sensor.name.clone(), value);
+            }
+        }
+        let max_discharge = if self.evse.is_bidirectional() {
+            self.args.max_discharge_current
+        } else {
+            0.0
+        };
+        for policy in &mut self.policies {
+            let mut limit = policy.limit(&inputs, self.evse_charge_limit);
+            if limit.is_nan() {
+                continue;
+            }
+            limit = limit.clamp(-max_discharge, self.args.evse_max_charge_current);
+            if limit.abs() < self.args.evse_min_charge_current {
+                limit = 0.0;
+            }
+            if limit != self.evse_charge_limit {
+                println!(
+                    "policy {} changed the charge current limit from {:.3} A to {:.3} A",
+                    policy.name(),
+                    self.evse_charge_limit,
+                    limit
+                );
+                self.evse_charge_limit = limit;
+            }
+        }
+    }
+
+    /// Move the charge current limit towards the new one no faster than
+    /// --max-ramp-rate.
+    fn apply_ramp_rate(&mut self) {
+        let Some(rate) = self.args.max_ramp_rate else {
+            return;
+        };
+        let now = std::time::Instant::now();
+        // Turning charging off should turn it off.
+        let stopping =
+            self.boost.is_none() && matches!(self.mode, mode::Mode::Off | mode::Mode::Pause);
+        if !stopping {
+            // Start from what the EV's drawing.
+            let (base, time) = self.ramp_base.unwrap_or((self.evse_charge_current, now));
+            let step = rate * (now - time).as_secs_f64() / 60.0;
+            let min = self.args.evse_min_charge_current;
+            // Charging starts at the minimum, not from nothing.
+            let from = if self.evse_charge_limit > base && base < min {
+                min
+            } else {
+                base
+            };
+            let limit = self
+                .evse_charge_limit
+                .clamp(from - step, (from + step).max(min));
+            if limit != self.evse_charge_limit {
+                println!(
+                    "ramping the charge current limit from {:.3} A towards {:.3} A, {:.3} A for now",
+                    base, self.evse_charge_limit, limit
+                );
+                self.evse_charge_limit = if limit.abs() < min { 0.0 } else { limit };
+            }
+        }
+        self.ramp_base = Some((self.evse_charge_limit, now));
+    }
+
+    /// How many more Amps the EV could draw before the house is over
+    /// --house-max-current, negative if it's over already.  None if
+    /// there's no limit or the meter doesn't measure consumption.
+    fn house_headroom(&self) -> Option<f64> {
+        let max = self.args.house_max_current?;
+        let reading = self.last_reading.as_ref()?;
+        let phase_amps = |phase: &meter::Phase| {
+            let volts = phase.rms_voltage.unwrap_or(self.args.nominal_voltage);
+            Some(phase.consumption_w? / volts)
+        };
+        // The most loaded of the phases the EV draws on.
+        let evse_phases: Vec<&meter::Phase> = match self.active_phases {
+            1 => reading
+                .phases
+                .get(self.args.evse_phase as usize - 1)
+                .into_iter()
+                .collect(),
+            _ => reading.phases.iter().collect(),
+        };
+        let per_phase: Option<Vec<f64>> = evse_phases.into_iter().map(phase_amps).collect();
+        let load = match per_phase {
+            Some(amps) if !amps.is_empty() => amps.into_iter().fold(f64::MIN, f64::max),
+            // Otherwise assume it's spread evenly over the phases.
+            _ => reading.consumption_w? / (self.rms_voltage * self.args.phases as f64),
+        };
+        Some(max - load)
+    }
+
+    /// Whether the house is drawing more than --house-max-current.
+    fn house_over_limit(&self) -> bool {
+        self.house_headroom().is_some_and(|headroom| headroom < 0.0)
+    }
+
+    /// Keep the EV's charge current plus the house's load under
+    /// --house-max-current.
+    fn apply_house_limit(&mut self) {
+        self.house_limited = false;
+        let Some(headroom) = self.house_headroom() else {
+            return;
+        };
+        let max = self.evse_charge_current + headroom;
+        if self.evse_charge_limit > max {
+            println!(
+                "house limit of {:.0} A holds the charge current limit at {:.3} A",
+                self.args.house_max_current.unwrap_or_default(),
+                max
+            );
+            self.house_limited = true;
+            self.evse_charge_limit = if max < self.args.evse_min_charge_current {
+                0.0
+            } else {
+                max
+            };
+        }
+    }
+
+    /// Keep the charge current limit under what every level of the
+    /// wiring can carry.
+    fn apply_current_limits(&mut self) {
+        if self.args.current_limit.is_empty() {
+            return;
+        }
+        let inputs = self.sensor_inputs();
+        for limit in &self.args.current_limit {
+            let max = match limit.headroom(&inputs) {
+                Ok(headroom) => self.evse_charge_current + headroom,
+                Err(e) => {
+                    // Play it safe, don't let the EV draw any more.
+                    println!("can't compute the load on {}: {:#}", limit.name, e);
+                    self.evse_charge_current
+                }
+            };
+            if self.evse_charge_limit > max {
+                println!(
+                    "{} limit of {:.0} A holds the charge current limit at {:.3} A",
+                    limit.name, limit.amps, max
+                );
+                self.evse_charge_limit = if max < self.args.evse_min_charge_current {
+                    0.0
+                } else {
+                    max
+                };
+            }
+        }
+    }
+
+    /// Update the OpenEVSE with a new charge limit, unless that's what
+    /// it's already got.
+    async fn set_charge_limit(&mut self, new_limit: isize) -> Result<(), eyre::Report> {
+        if self.commanded_charge_limit == Some(new_limit) {
+            return Ok(());
+        }
+        // Shedding current to protect the main breaker can't wait.
+        let shedding = self.house_over_limit()
+            && self
+                .commanded_charge_limit
+                .is_some_and(|limit| new_limit < limit);
+        if let (Some(t), false) = (self.commanded_charge_limit_time, shedding) {
+            let min_interval = std::time::Duration::from_secs(self.args.min_pilot_change_interval);
+            if t.elapsed() < min_interval {
+                println!(
+                    "not changing the EVSE charge limit to {} A for another {:.0} s",
+                    new_limit,
+                    min_interval.saturating_sub(t.elapsed()).as_secs_f64()
+                );
+                return Ok(());
+            }
+        }
+        self.evse.set_current_capacity(new_limit).await?;
+        self.setpoint_latency.commanded(
+            self.evse_charge_current,
+            self.commanded_charge_limit,
+            new_limit,
+        );
+        self.commanded_charge_limit = Some(new_limit);
+        self.commanded_charge_limit_time = Some(std::time::Instant::now());
+        self.emit(events::Event::SetpointChanged { amps: new_limit });
+        self.override_audit.commanded(new_limit);
+        self.evse.get_current_capacity().await?;
+        Ok(())
+    }
+
+    /// How much longer the EVSE has to stay awake (`enabled` true) or
+    /// asleep (false) before we switch it, to spare its relay.
+    fn relay_hold(&self, enabled: bool) -> Option<std::time::Duration> {
+        let min = std::time::Duration::from_secs(if enabled {
+            self.args.min_on_seconds
+        } else {
+            self.args.min_off_seconds
+        });
+        let elapsed = self.evse_enabled_time?.elapsed();
+        (self.evse_enabled == Some(enabled) && elapsed < min).then(|| min - elapsed)
+    }
+
+    async fn enable_evse(&mut self) -> Result<(), eyre::Report> {
+        if self.evse_enabled != Some(true) {
+            if let Some(hold) = self.relay_hold(false) {
+                println!(
+                    "not waking the EVSE for another {:.0} s, it was only just put to sleep",
+                    hold.as_secs_f64()
+                );
+                return Ok(());
+            }
+            self.evse.enable().await?;
+            self.evse_enabled = Some(true);
+            self.evse_enabled_time = Some(std::time::Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn sleep_evse(&mut self) -> Result<(), eyre::Report> {
+        if self.evse_enabled != Some(false) {
+            if let (Some(hold), false) = (self.relay_hold(true), self.house_over_limit()) {
+                println!(
+                    "not putting the EVSE to sleep for another {:.0} s, it was only just woken, charging at the minimum meanwhile",
+                    hold.as_secs_f64()
+                );
+                return self
+                    .set_charge_limit(self.args.evse_min_charge_current as isize)
+                    .await;
+            }
+            self.evse.sleep().await?;
+            self.evse_enabled = Some(false);
+            self.evse_enabled_time = Some(std::time::Instant::now());
+        }
+        Ok(())
+    }
+
+    /// What to do with the EVSE while there's no EV plugged in.
+    async fn handle_unplugged(&mut self) -> Result<(), eyre::Report> {
+        match self.args.unplugged {
+            UnpluggedAction::Sleep => {
+                println!("no EV plugged in, sleeping");
+                self.sleep_evse().await?;
+            }
+            UnpluggedAction::MinCurrent => {
+                println!("no EV plugged in, waiting at minimum charge current");
+                self.set_charge_limit(self.args.evse_min_charge_current as isize)
+                    .await?;
+                self.enable_evse().await?;
+            }
+            UnpluggedAction::Release => {
+                println!("no EV plugged in, leaving the EVSE alone");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the OpenEVSE's settings that would get in our way.
+    async fn check_openevse(&self) {
+        let Some(rapi) = &self.rapi else {
+            return;
+        };
+        match rapi.get_version().await {
+            Ok(version) => println!(
+                "OpenEVSE firmware {}, RAPI {}",
+                version.firmware, version.protocol
+            ),
+            Err(e) => println!("can't read the OpenEVSE's version: {e:#}"),
+        }
+        if let Ok(range) = rapi.get_current_range().await {
+            if self.args.evse_min_charge_current < range.min
+                || self.args.evse_max_charge_current > range.max
+            {
+                println!(
+                    "warning: the OpenEVSE can only be set from {:.0} A to {:.0} A, but --evse-min-charge-current and --evse-max-charge-current are {:.0} A and {:.0} A",
+                    range.min,
+                    range.max,
+                    self.args.evse_min_charge_current,
+                    self.args.evse_max_charge_current
+                );
+            }
+        }
+        if let Ok(Some(timer)) = rapi.get_delay_timer().await {
+            if self.args.openevse_claims {
+                println!("the OpenEVSE's delay timer only lets it charge {timer}");
+            } else {
+                println!(
+                    "warning: the OpenEVSE's delay timer only lets it charge {timer}, clear it with \"delay_timer off\" on the admin console"
+                );
+            }
+        }
+        if self.args.openevse_claims {
+            match rapi.get_override().await {
+                Ok(Some(manual)) => {
+                    println!("the OpenEVSE's manual override wins over our claim: {manual}")
+                }
+                Ok(None) => {}
+                Err(e) => println!("can't read the OpenEVSE's manual override: {e:#}"),
+            }
+        }
+    }
+
+    /// Everything the OpenEVSE says about itself, for the admin console.
+    async fn describe_openevse(
+        rapi: &openevse::OpenEVSE,
+    ) -> Result<serde_json::Value, eyre::Report> {
+        let state = rapi.get_state().await?;
+        Ok(serde_json::json!({
+            "version": rapi.get_version().await?,
+            "current_range": rapi.get_current_range().await?,
+            "state": {
+                "evse": format!("{:?}", state.evse),
+                "elapsed_s": state.elapsed.as_secs(),
+                "pilot": state.pilot,
+                "vflags": state.vflags.map(|vflags| format!("{vflags:04x}")),
+                "vehicle_connected": state.is_vehicle_connected(),
+            },
+            "charging": rapi.get_charging().await?,
+            "temperatures": rapi.get_temperatures().await?,
+            "energy": rapi.get_energy().await?,
+            "fault_counts": rapi.get_fault_counts().await?,
+            "delay_timer": rapi.get_delay_timer().await?.map(|timer| timer.to_string()),
+            "clock": rapi
+                .get_clock()
+                .await?
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string()),
+        }))
+    }
+
+    /// See if the EVSE has shown up on the network.
+    async fn try_attach_evse(&mut self) {
+        let timeout = std::time::Duration::from_secs(self.args.startup_timeout);
+        match probe::with_timeout(timeout, self.evse.get_active_charging_current()).await {
+            Ok(current) => {
+                println!("found the EVSE at {}", self.args.openevse);
+                self.evse_attached = true;
+                self.check_openevse().await;
+                self.evse_charge_current = current;
+                self.evse_charge_current_time = Some(std::time::Instant::now());
+                self.resume_session().await;
+            }
+            Err(e) => {
+                println!("EVSE at {} is not reachable: {e:#}", self.args.openevse);
+                self.metrics.lock().unwrap().evse_errors += 1;
+                self.set_error(format!("EVSE is not reachable: {e:#}"));
+            }
+        }
+    }
+
+    /// If the EV is already charging (because we were restarted in the
+    /// middle of a session), carry on with that session instead of
+    /// starting a new one.
+    async fn resume_session(&mut self) {
+        if self.session_start.is_some() || self.evse_charge_current <= 0.0 {
+            return;
+        }
+        let session = match self.evse.get_session().await {
+            Ok(Some(session)) => session,
+            Ok(None) => return,
+            Err(e) => {
+                println!("can't read the EVSE's session: {e:#}");
+                return;
+            }
+        };
+        let start =
+            chrono::Local::now() - chrono::Duration::from_std(session.elapsed).unwrap_or_default();
+        println!(
+            "EV is already charging, resuming the session that started at {} with {:.3} kWh",
+            start.format("%Y-%m-%d %H:%M:%S"),
+            session.energy_wh / 1000.0
+        );
+        self.session_start = Some(start);
+        self.session_energy_wh = session.energy_wh;
+        self.emit(events::Event::SessionStarted { start });
+        // We don't know where the energy so far came from.
+        self.session_solar_wh = 0.0;
+        self.session_cost = None;
+        self.session_savings = None;
+        self.vehicle_connected = Some(true);
+    }
+
+    /// Forget what we knew about the EVSE, it's gone.
+    fn detach_evse(&mut self) {
+        self.evse_attached = false;
+        self.evse_charge_current = 0.0;
+        self.evse_charge_current_time = None;
+        self.evse_state = None;
+        self.evse_enabled = None;
+        self.vehicle_connected = None;
+        self.commanded_charge_limit = None;
+        self.soft_start_cycle = None;
+    }
+
+    /// Don't count a discharging home battery as surplus, and (if the
+    /// EV has priority) count power the battery is charging with as
+    /// surplus.
+    fn account_for_battery(&mut self) {
+        let Some((w, time)) = self.battery_power else {
+            return;
+        };
+        if time.elapsed() > std::time::Duration::from_secs(2 * self.args.period) {
+            println!("home battery power is out of date, ignoring it");
+            return;
+        }
+        let battery_current = w / self.watts_per_amp();
+        let priority = match self.battery_soc {
+            Some(soc) if soc < self.args.battery_reserve_soc => {
+                println!(
+                    "home battery is at {:.1}%, below the {:.1}% reserve, it gets the surplus first",
+                    soc, self.args.battery_reserve_soc
+                );
+                BatteryPriority::Battery
+            }
+            _ => self.args.battery_priority,
+        };
+        let adjustment = match priority {
+            BatteryPriority::Ev => battery_current,
+            BatteryPriority::Battery => battery_current.max(0.0),
+        };
+        if adjustment != 0.0 {
+            self.export_current -= adjustment;
+            println!(
+                "home battery current {:.3} A (positive is discharging), surplus for the EV: {:.3} A",
+                battery_current, self.export_current
+            );
+        }
+    }
+
+    /// Compare the charge current the EVSE last reported over MQTT with
+    /// what we just polled, and stop (or go back to) trusting the
+    /// reports.
+    fn check_reported_charge_current(&mut self) {
+        let period = std::time::Duration::from_secs(self.args.period);
+        let Some((reported, _)) = self
+            .reported_charge_current
+            .filter(|(_, t)| t.elapsed() <= period)
+        else {
+            return;
+        };
+        self.cycles_since_charge_current_check = 0;
+        let difference = (reported - self.evse_charge_current).abs();
+        if difference > self.args.charge_current_tolerance {
+            if !self.reported_charge_current_unreliable {
+                println!(
+                    "EVSE reported charge current {:.3} A over MQTT but {:.3} A when polled, polling it from now on",
+                    reported, self.evse_charge_current
+                );
+                self.set_error(format!(
+                    "reported charge current {reported:.3} A disagrees with polled {:.3} A",
+                    self.evse_charge_current
+                ));
+                self.reported_charge_current_unreliable = true;
+            }
+        } else if self.reported_charge_current_unreliable {
+            println!(
+                "EVSE reported charge current agrees with polling again ({:.3} A vs {:.3} A), using the reports",
+                reported, self.evse_charge_current
+            );
+            self.reported_charge_current_unreliable = false;
+        }
+    }
+
+    /// Ask the EVSE whether an EV is plugged in and whether it's
+    /// faulted, if it can tell us.
+    async fn check_evse_status(&mut self) -> Result<(), eyre::Report> {
+        let status = self.evse.status().await?;
+        if let Some(connected) = status.vehicle_connected {
+            if self.vehicle_connected != Some(connected) {
+                self.set_vehicle_connected(connected);
+            }
+        }
+        if let (Some(_), Some(rapi)) = (&status.fault, &self.rapi) {
+            match rapi.get_fault_counts().await {
+                Ok(counts) => self.fault_counts = Some(counts),
+                Err(e) => println!("can't read the EVSE's fault counts: {e:#}"),
+            }
+        }
+        self.set_evse_fault(status.fault);
+        Ok(())
+    }
+
+    /// Notice the EVSE faulting, or its fault clearing.
+    fn set_evse_fault(&mut self, fault: Option<String>) {
+        if fault == self.evse_fault {
+            return;
+        }
+        match &fault {
+            Some(fault) => {
+                println!("EVSE fault: {fault}");
+                self.webhooks
+                    .fire("evse_fault", serde_json::json!({ "fault": fault }));
+            }
+            None => {
+                println!(
+                    "EVSE fault cleared, waiting {} s before charging again",
+                    self.args.fault_hold
+                );
+                self.fault_cleared = Some(std::time::Instant::now());
+                self.webhooks
+                    .fire("evse_fault_cleared", serde_json::json!({}));
+            }
+        }
+        self.evse_fault = fault;
+    }
+
+    /// Whether an EV's been plugged in while we were idling, so there's
+    /// no waiting for the next cycle.
+    fn plugged_in_while_idle(&self) -> bool {
+        self.controller_state == controller_state::ControllerState::Idle
+            && self.vehicle_connected == Some(true)
+    }
+
+    /// Read the EV's charge current from the EVSE (if we don't have a
+    /// recent reading from MQTT), and adjust the export current for
+    /// things that aren't really surplus.
+    async fn update_evse_charge_current(&mut self) -> Result<(), eyre::Report> {
+        let previous_evse_charge_current = self.evse_charge_current;
+
+        // Use the EV current-draw value from MQTT if the EVSE has
+        // reported it since the last cycle (and it's been right),
+        // otherwise poll the EVSE for the active charge current right
+        // now.  Poll every so often anyway to check the reports.
+        self.cycles_since_charge_current_check += 1;
+        let check_due = self.args.charge_current_check_cycles > 0
+            && self.reported_charge_current.is_some()
+            && self.cycles_since_charge_current_check >= self.args.charge_current_check_cycles;
+        let fresh = match self.evse_charge_current_time {
+            Some(t) => t.elapsed() <= std::time::Duration::from_secs(self.args.period),
+            None => false,
+        };
+        self.check_evse_status().await?;
+        if self.reported_charge_current_unreliable || !fresh || check_due {
+            self.evse_charge_current = self.evse.get_active_charging_current().await?;
+            self.evse_charge_current_time = Some(std::time::Instant::now());
+            self.setpoint_latency.observe(self.evse_charge_current);
+            self.check_reported_charge_current();
+        }
+        println!(
+            "active EVSE charge current: {:.3}",
+            self.evse_charge_current
+        );
+
+        self.account_for_battery();
+
+        // The average export over the last interval still partly
+        // reflects the EV's charge current from before the last change,
+        // for as long as it took the meter to see the change.
+        if let Some(interval) = self.reading_interval {
+            let delta_current = self.evse_charge_current - previous_evse_charge_current;
+            self.meter_latency.observe(
+                interval,
+                self.export_current,
+                self.export_current_now,
+                delta_current,
+            );
+            let compensation = self.meter_latency.compensation(interval, delta_current);
+            if compensation != 0.0 {
+                self.export_current -= compensation;
+                println!(
+                    "compensating for meter latency, export current: {:.3} A",
+                    self.export_current
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide what state the controller should be in, and why.
+    fn next_state(&self) -> (controller_state::ControllerState, String) {
+        use controller_state::ControllerState;
+        if !self.evse_attached {
+            return (ControllerState::Fault, String::from("EVSE is unreachable"));
+        }
+        if let Some(fault) = &self.evse_fault {
+            return (ControllerState::Fault, format!("EVSE reports {fault}"));
+        }
+        if self
+            .fault_cleared
+            .is_some_and(|t| t.elapsed() < std::time::Duration::from_secs(self.args.fault_hold))
+        {
+            return (
+                ControllerState::Fault,
+                String::from("waiting --fault-hold after the EVSE's fault cleared"),
+            );
+        }
+        if self.vehicle_connected == Some(false) {
+            return (ControllerState::Idle, String::from("no EV plugged in"));
+        }
+        if self.ev_full() {
+            return (ControllerState::Standby, String::from("the EV is full"));
+        }
+        if self.boost.is_none() && matches!(self.mode, mode::Mode::Off | mode::Mode::Pause) {
+            return (ControllerState::Standby, format!("{} mode", self.mode));
+        }
+        if self.house_limited && self.evse_charge_limit < self.args.evse_min_charge_current {
+            return (
+                ControllerState::Standby,
+                String::from("the house is at its --house-max-current"),
+            );
+        }
+        if self.evse_charge_limit.abs() < self.args.evse_min_charge_current {
+            return (
+                ControllerState::Standby,
+                String::from("not enough available current"),
+            );
+        }
+        if self.evse_charge_limit < 0.0 {
+            return (
+                ControllerState::Tracking,
+                String::from("discharging the EV into the house"),
+            );
+        }
+        if self.boost.is_some() {
+            (ControllerState::Boost, String::from("boosting"))
+        } else if self.mode == mode::Mode::Fast {
+            (ControllerState::Tracking, String::from("fast mode"))
+        } else if self.mode == mode::Mode::Scheduled && self.in_charge_window() {
+            (
+                ControllerState::Tracking,
+                String::from("in the charge window"),
+            )
+        } else if self.current_price().is_some_and(|price| price < 0.0) {
+            (
+                ControllerState::Tracking,
+                String::from("negative electricity price"),
+            )
+        } else if self.must_charge_for_deadline() {
+            (
+                ControllerState::Tracking,
+                String::from("topping up for the deadline"),
+            )
+        } else if self.cold_trickle() {
+            (
+                ControllerState::Tracking,
+                String::from("trickle charging for the cold"),
+            )
+        } else if self.args.peak_shaving_max_import.is_some() {
+            (
+                ControllerState::GridAssist,
+                String::from("room under the import limit"),
+            )
+        } else {
+            (ControllerState::Tracking, String::from("enough surplus"))
+        }
+    }
+
+    /// Move to a new state, and tell the world about it.
+    fn transition(&mut self, next: controller_state::ControllerState, reason: &str) {
+        if next == self.controller_state {
+            return;
+        }
+        println!(
+            "controller state: {} -> {} ({})",
+            self.controller_state, next, reason
+        );
+        self.webhooks.fire(
+            "state_change",
+            serde_json::json!({
+                "from": self.controller_state.to_string(),
+                "to": next.to_string(),
+                "reason": reason,
+            }),
+        );
+        self.controller_state = next;
+    }
+
+    /// Set the OpenEVSE's clock, if it's time to.
+    async fn sync_openevse_clock(&mut self) {
+        let (Some(rapi), true) = (&self.rapi, self.evse_attached) else {
+            return;
+        };
+        let interval = std::time::Duration::from_secs(self.args.openevse_clock_sync * 60 * 60);
+        if interval.is_zero() || self.clock_synced.is_some_and(|t| t.elapsed() < interval) {
+            return;
+        }
+        let now = chrono::Local::now();
+        if let Ok(Some(clock)) = rapi.get_clock().await {
+            println!(
+                "the OpenEVSE's clock is {} s off",
+                (clock - now.naive_local()).num_seconds()
+            );
+        }
+        match rapi.set_clock(now.naive_local()).await {
+            Ok(()) => println!(
+                "set the OpenEVSE's clock to {}",
+                now.format("%Y-%m-%d %H:%M:%S")
+            ),
+            Err(e) => println!("can't set the OpenEVSE's clock: {e:#}"),
+        }
+        // Don't keep trying every cycle if it doesn't take.
+        self.clock_synced = Some(std::time::Instant::now());
+    }
+
+    /// Curtail the inverter as needed to keep export under the limit,
+    /// but only once the EVSE can't take any more of the surplus.
+    async fn curtail_inverter(&mut self) -> Result<(), eyre::Report> {
+        use controller_state::ControllerState;
+        let watts_per_amp = self.watts_per_amp();
+        let (Some(inverter), Some(export_limit)) = (&mut self.inverter, self.args.export_limit)
+        else {
+            return Ok(());
+        };
+
+        // How much more the EVSE could take, in Watts.
+        let evse_headroom_w = match self.controller_state {
+            ControllerState::Tracking | ControllerState::Boost | ControllerState::GridAssist => {
+                (self.args.evse_max_charge_current - self.evse_charge_limit).max(0.0)
+                    * watts_per_amp
+            }
+            _ => 0.0,
+        };
+        let excess_w = (self.export_current_now - export_limit) * watts_per_amp;
+
+        // Let production up by whatever the EVSE could still take, and
+        // down by whatever's over the export limit.
+        let limit_w = inverter.production_w().await? - excess_w + evse_headroom_w;
+        inverter
+            .set_limit(
+                limit_w,
+                std::time::Duration::from_secs(3 * self.args.period),
+            )
+            .await
+    }
+
+    /// Run one cycle of the state machine: decide what state to be in,
+    /// then act on it.
+    async fn step(&mut self) -> Result<(), eyre::Report> {
+        use controller_state::ControllerState;
+
+        if self.evse_attached && self.vehicle_connected == Some(false) {
+            // Nothing to charge, just watch for an EV being plugged in.
+            self.check_evse_status().await?;
+        }
+        self.update_vehicle_profile();
+        if self.evse_attached && self.vehicle_connected == Some(false) {
+            self.evse_charge_current = 0.0;
+            self.evse_charge_limit = 0.0;
+        } else if self.evse_attached && self.ev_full() {
+            // The EV won't take any more, no need to ask the EVSE what
+            // it's drawing.
+            self.evse_charge_limit = 0.0;
+        } else if self.evse_attached {
+            self.update_evse_charge_current().await?;
+            if self.setpoint_latency.settling() && !self.house_over_limit() {
+                // Stay in whatever state we're in until the EVSE has
+                // responded to the last change.
+                println!("waiting for the EVSE to respond to the last charge limit change");
+                return Ok(());
+            }
+            self.compute_charge_limit();
+            self.check_phase_switch().await?;
+        }
+
+        let (next, reason) = self.next_state();
+        self.transition(next, &reason);
+        self.controller_state_reason = reason.clone();
+
+        match self.controller_state {
+            ControllerState::Fault => {
+                println!("not charging: {reason}");
+            }
+            ControllerState::Idle => {
+                self.handle_unplugged().await?;
+            }
+            ControllerState::Standby => {
+                println!("sleeping, waiting for more available current");
+                self.controller.reset();
+                self.dither_error = 0.0;
+                self.soft_start_cycle = None;
+                self.sleep_evse().await?;
+            }
+            ControllerState::Tracking | ControllerState::Boost | ControllerState::GridAssist => {
+                if self.evse_enabled != Some(true) {
+                    self.soft_start_cycle = Some(0);
+                }
+                self.apply_soft_start();
+                println!(
+                    "setting EVSE charge current limit to {:.3} A!",
+                    self.evse_charge_limit
+                );
+
+                let new_limit = self.charge_limit_setpoint();
+                self.set_charge_limit(new_limit).await?;
+                self.enable_evse().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(&mut self) -> Result<(), eyre::Report> {
+        // My OpenEVSE has a minimum charge current of 6A (1.5 kW).
+        // --min-on-seconds and --min-off-seconds keep us from clicking
+        // the relay on/off too much.
+        self.announce();
+        loop {
+            let cycle_start = std::time::Instant::now();
+            if let Err(e) = self.update_current_surplus().await {
+                self.metrics.lock().unwrap().meter_errors += 1;
+                return Err(e);
+            }
+            println!(
+                "export current: {:.3} A (target {:.3} A)",
+                self.export_current,
+                self.target_export_current()
+            );
+
+            if let Some(prices) = &mut self.prices {
+                if let Err(e) = prices.refresh().await {
+                    println!("can't fetch electricity prices: {e:#}");
+                }
+            }
+            if let Some(forecast) = &mut self.forecast {
+                if let Err(e) = forecast.refresh().await {
+                    println!("can't fetch the solar forecast: {e:#}");
+                }
+            }
+            if let (Some(source), Some(vehicle)) = (&mut self.vehicle_source, &mut self.vehicle) {
+                match source.refresh(vehicle).await {
+                    Ok(Some(percent)) => self.vehicle_soc(percent),
+                    Ok(None) => {}
+                    Err(e) => println!("can't ask {} about the EV: {e:#}", source.name()),
+                }
+            }
+
+            if let Some(topic) = &self.args.grid_ie_topic {
+                // The OpenEVSE does the rest.
+                self.publish_grid_ie(topic);
+            } else {
+                if !self.evse_attached {
+                    self.try_attach_evse().await;
+                }
+                self.sync_openevse_clock().await;
+                if let Err(e) = self.step().await {
+                    println!("lost contact with the EVSE: {e:#}");
+                    self.metrics.lock().unwrap().evse_errors += 1;
+                    self.set_error(format!("lost contact with the EVSE: {e:#}"));
+                    self.detach_evse();
+                    self.transition(
+                        controller_state::ControllerState::Fault,
+                        "lost contact with the EVSE",
+                    );
+                }
+            }
+
+            if let Err(e) = self.curtail_inverter().await {
+                println!("failed to curtail the inverter: {e:#}");
+                self.set_error(format!("failed to curtail the inverter: {e:#}"));
+            }
+
+            self.update_session_energy();
+            self.publish_sensors();
+            self.publish_telemetry();
+            self.check_triggers();
+            self.check_alerts();
+
+            if let Some(report) = self.override_audit.take_report() {
+                if let Some(text) = self.templates.render("override_report", &report) {
+                    println!("{text}");
+                }
+            }
+
+            {
+                let mut metrics = self.metrics.lock().unwrap();
+                metrics.export_current = self.export_current;
+                metrics.evse_charge_limit = self.evse_charge_limit;
+                metrics.evse_charge_current = self.evse_charge_current;
+                metrics.controller_state = Some(self.controller_state);
+                metrics.evse_fault = self.evse_fault.is_some();
+                metrics.fault_counts = self.fault_counts;
+                metrics.loop_seconds = cycle_start.elapsed().as_secs_f64();
+                metrics.uptime_seconds = self.restarts.uptime().as_secs_f64();
+                metrics.meter_reading_time = self
+                    .last_reading
+                    .as_ref()
+                    .map(|reading| reading.reading_time.timestamp() as f64);
+            }
+            self.update_api_status();
+            self.record_api_history();
+            self.emit(events::Event::CycleCompleted {
+                export_current: self.export_current,
+                charge_limit: self.evse_charge_limit,
+                charge_current: self.evse_charge_current,
+                state: self.controller_state,
+                reason: self.controller_state_reason.clone(),
+            });
+
+            let period = if self.controller_state == controller_state::ControllerState::Idle {
+                self.args.idle_period.max(self.args.period)
+            } else {
+                self.args.period
+            };
+            let timeout = tokio::time::sleep(std::time::Duration::from_secs(period));
+            tokio::pin!(timeout);
+
+            loop {
+                tokio::select! {
+                    _ = self.ctrl_c_rx.recv() => {
+                        println!("bye!");
+                        return Ok(());
+                    }
+
+                    notification = mqtt::poll(&mut self.mqtt_eventloop) => {
+                        match notification {
+                            Ok(rumqttc::Event::Incoming(rumqttc::mqttbytes::v4::Packet::Publish(msg))) => {
+                                let payload = String::from_utf8_lossy(&msg.payload);
+                                self.handle_mqtt_message(&msg.topic, &payload);
+                                if self.plugged_in_while_idle() {
+                                    break;
+                                }
+                            }
+                            Ok(rumqttc::Event::Incoming(rumqttc::mqttbytes::v4::Packet::ConnAck(_)))
+                                if self.mqtt_publish_client.is_none() =>
+                            {
+                                // Reconnected, maybe to a restarted broker
+                                // that's forgotten our retained messages.
+                                self.announce();
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    notification = mqtt::poll(&mut self.mqtt_publish_eventloop) => {
+                        match notification {
+                            Ok(rumqttc::Event::Incoming(rumqttc::mqttbytes::v4::Packet::Publish(msg))) => {
+                                let payload = String::from_utf8_lossy(&msg.payload);
+                                self.handle_mqtt_message(&msg.topic, &payload);
+                                if self.plugged_in_while_idle() {
+                                    break;
+                                }
+                            }
+                            Ok(rumqttc::Event::Incoming(rumqttc::mqttbytes::v4::Packet::ConnAck(_))) => {
+                                self.announce();
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    Some(()) = reload::next(&mut self.hangups) => {
+                        if let Err(e) = self.reload("") {
+                            println!("can't reload the settings: {e:#}");
+                        }
+                        self.update_api_status();
+                    }
+
+                    Some(command) = api::next(&mut self.api_commands) => {
+                        // Reloads come from the API (or the admin console),
+                        // not MQTT, so they can't be confirmed from there.
+                        let result = match command.name.as_str() {
+                            "reload" => self.reload(&command.value),
+                            _ => self.handle_command(&command.name, &command.value),
+                        };
+                        self.update_api_status();
+                        let _ = command.reply.send(result.map_err(|e| format!("{e:#}")));
+                    }
+
+                    Some(request) = admin::next(&mut self.admin_requests) => {
+                        let reply = self.handle_admin(&request.line).await;
+                        let _ = request.reply.send(reply);
+                        if std::mem::take(&mut self.cycle_now) {
+                            break;
+                        }
+                    }
+
+                    Some((topic, payload)) = openevse_events::next(&mut self.openevse_events) => {
+                        self.handle_mqtt_message(&topic, &payload);
+                        if self.plugged_in_while_idle() {
+                            break;
+                        }
+                    }
+
+                    _ = &mut timeout => {
+                        break;
+                    }
+                }
+            }
+
+            println!();
+        }
+    }
+}
+
+/// The `solar-evse` command.
+pub async fn cli() -> Result<(), eyre::Report> {
+    let args = Args::parse_from(config::args()?);
+    match &args.command {
+        Some(Command::ConfigSchema) => {
+            let schema = config::schema(&<Args as clap::CommandFactory>::command());
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            Ok(())
+        }
+        Some(Command::Sessions { year, format }) => {
+            let Some(filename) = &args.session_log else {
+                return Err(eyre::eyre!("which --session-log?"));
+            };
+            sessions::report(filename, *year, *format)
+        }
+        Some(Command::Invoice { month }) => {
+            let Some(filename) = &args.session_log else {
+                return Err(eyre::eyre!("which --session-log?"));
+            };
+            sessions::invoice(filename, *month)
+        }
+        Some(Command::Analytics) => {
+            let Some(filename) = &args.session_log else {
+                return Err(eyre::eyre!("which --session-log?"));
+            };
+            sessions::analytics(filename)
+        }
+        Some(Command::ImportCsv { file }) => {
+            let Some(filename) = &args.session_log else {
+                return Err(eyre::eyre!("which --session-log?"));
+            };
+            let contents = std::fs::read_to_string(file)
+                .map_err(|e| eyre::eyre!("can't read {}: {}", file.display(), e))?;
+            let days = sessions::days(
+                &enlighten::parse(&contents)?,
+                args.import_price.as_ref(),
+                args.export_price.as_ref(),
+            );
+            let (added, skipped) = sessions::import(filename, &days)?;
+            println!(
+                "added {added} days to {}, skipped {skipped}",
+                filename.display()
+            );
+            Ok(())
+        }
+        Some(Command::AlertRules {
+            job,
+            stale_after,
+            import_amps,
+            import_for,
+        }) => {
+            print!(
+                "{}",
+                alert_rules::render(job, *stale_after, *import_amps, *import_for)?
+            );
+            Ok(())
+        }
+        Some(Command::Snapshot { dir }) => take_snapshot(&args, dir).await,
+        Some(Command::Autotune { step, settle }) => {
+            let (step, settle) = (*step, *settle);
+            ControllerBuilder {
+                options: std::env::args_os().skip(1).collect(),
+                ..ControllerBuilder::with_args(args)
+            }
+            .build()
+            .await?
+            .autotune(step, std::time::Duration::from_secs(settle))
+            .await
+        }
+        None => {
+            ControllerBuilder {
+                options: std::env::args_os().skip(1).collect(),
+                ..ControllerBuilder::with_args(args)
+            }
+            .build()
+            .await?
+            .run_until_stopped()
+            .await
+        }
+    }
+}
+
+/// `solar-evse snapshot DIR`.
+async fn take_snapshot(args: &Args, dir: &std::path::Path) -> Result<(), eyre::Report> {
+    println!("config: {args:#?}");
+    let auth_token = args.auth_token().await?;
+    let capture = std::sync::Arc::new(capture::Capture::new(dir, u64::MAX, 0, &[&auth_token])?);
+    let address_book = args.address_book();
+    let envoy = args.envoy(&address_book, &auth_token, Some(capture.clone()))?;
+    let openevse = openevse::OpenEVSE::new(
+        &args.openevse,
+        address_book.client_builder().build()?,
+        Some(capture.clone()),
+    );
+    snapshot::take(&capture, &format!("{args:#?}"), &envoy, &openevse).await
+}
+
+/// Check the EVSE's charge current range and the export target make
+/// sense, as the control loop relies on (`f64::clamp()` panics on a
+/// range that's backwards or NaN), whether they come from the command
+/// line, a reload or a command.
+fn check_currents(min: f64, max: f64, target_export: f64) -> Result<(), eyre::Report> {
+    for (option, value) in [
+        ("--evse-min-charge-current", min),
+        ("--evse-max-charge-current", max),
+    ] {
+        if !value.is_finite() || value < 0.0 {
+            return Err(eyre::eyre!("{} {} isn't a current", option, value));
+        }
+    }
+    if !target_export.is_finite() {
+        return Err(eyre::eyre!(
+            "--target-export-current {} isn't a current",
+            target_export
+        ));
+    }
+    if min > max {
+        return Err(eyre::eyre!(
+            "--evse-min-charge-current {} is more than --evse-max-charge-current {}",
+            min,
+            max
+        ));
+    }
+    // The EVSE only takes whole Amps.
+    if min.ceil() > max.floor() {
+        return Err(eyre::eyre!(
+            "there's no whole Amp between --evse-min-charge-current {} and --evse-max-charge-current {}",
+            min,
+            max
+        ));
+    }
+    Ok(())
+}
+
+impl Args {
+    /// Check the current limits, the export target and the control
+    /// loop's settings make sense.
+    fn check_currents(&self) -> Result<(), eyre::Report> {
+        if !self.max_discharge_current.is_finite() || self.max_discharge_current < 0.0 {
+            return Err(eyre::eyre!(
+                "--max-discharge-current {} isn't a current",
+                self.max_discharge_current
+            ));
+        }
+        for (option, value) in [
+            ("--kp", self.kp),
+            ("--ki", self.ki),
+            ("--kd", self.kd),
+            ("--deadband", self.deadband),
+            ("--export-smoothing", self.export_smoothing),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(eyre::eyre!(
+                    "{} {} has to be a number, 0 or more",
+                    option,
+                    value
+                ));
+            }
+        }
+        if let Some(rate) = self.max_ramp_rate {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(eyre::eyre!(
+                    "--max-ramp-rate {} isn't a rate to ramp at",
+                    rate
+                ));
+            }
+        }
+        check_currents(
+            self.evse_min_charge_current,
+            self.evse_max_charge_current,
+            self.target_export_current,
+        )
+    }
+
+    fn address_book(&self) -> address_book::AddressBook {
+        address_book::AddressBook::new(
+            self.address_book.as_deref(),
+            &self.address,
+            std::time::Duration::from_secs(self.dns_ttl),
+            self.interface.as_deref(),
+            self.local_address,
+        )
+    }
+
+    async fn auth_token(&self) -> Result<String, eyre::Report> {
+        Ok(match &self.auth_token_filename {
+            Some(filename) => tokio::fs::read_to_string(filename).await?,
+            None => String::new(),
+        })
+    }
+
+    fn envoy(
+        &self,
+        address_book: &address_book::AddressBook,
+        auth_token: &str,
+        capture: Option<std::sync::Arc<capture::Capture>>,
+    ) -> Result<envoy::Envoy, eyre::Report> {
+        let envoy_url = match self.envoy.contains("://") {
+            true => self.envoy.clone(),
+            false => format!("https://{}", address_book::url_host(&self.envoy)),
+        };
+        envoy::Envoy::new(
+            reqwest::Url::parse(&envoy_url)?,
+            address_book,
+            auth_token,
+            std::time::Duration::from_secs(self.envoy_pool_idle_timeout),
+            std::time::Duration::from_secs(self.envoy_tcp_keepalive),
+            capture,
+            self.envoy_battery,
+        )
+    }
+}
+
+/// Builds a `Controller` (see the top of this file).
+pub struct ControllerBuilder {
+    // The options set so far, as they'd be given on the command line,
+    // and what they parse to.
+    options: Vec<std::ffi::OsString>,
+    args: Args,
+    meter: Option<(Box<dyn meter::Meter>, String)>,
+    evse: Option<(Box<dyn evse::Evse>, String)>,
+    vehicle_source: Option<Box<dyn vehicle::Source>>,
+    policies: Vec<Box<dyn policy::Policy>>,
+    stop: Option<tokio::sync::mpsc::Receiver<()>>,
+
+    // The first bad value given to one of the setters that don't
+    // return a Result, for build() to return.
+    error: Option<eyre::Report>,
+}
+
+impl Default for ControllerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControllerBuilder {
+    /// A builder with every option at its default, as if run with no
+    /// command line options.
+    pub fn new() -> Self {
+        Self::with_args(Args::parse_from(["solar-evse"]))
+    }
+
+    fn with_args(args: Args) -> Self {
+        Self {
+            options: Vec::new(),
+            args,
+            meter: None,
+            evse: None,
+            vehicle_source: None,
+            policies: Vec::new(),
+            stop: None,
+            error: None,
+        }
+    }
+
+    /// Set options the way they're given on the command line, like
+    /// `["--mqtt-broker", "mqtt.lan", "--period", "10"]`.  Any option
+    /// can be set this way, and they're checked the same way.
+    pub fn args<I, T>(mut self, args: I) -> Result<Self, eyre::Report>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString>,
+    {
+        self.add_args(args)?;
+        Ok(self)
+    }
+
+    /// Add options, leaving the ones set so far alone if they're bad.
+    fn add_args<I, T>(&mut self, args: I) -> Result<(), eyre::Report>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString>,
+    {
+        let mut options = self.options.clone();
+        options.extend(args.into_iter().map(Into::into));
+        // Later options override earlier ones.
+        self.args = Args::try_parse_from(config::expand(
+            std::iter::once(std::ffi::OsString::from("solar-evse"))
+                .chain(options.iter().cloned())
+                .collect(),
+        )?)?;
+        self.options = options;
+        Ok(())
+    }
+
+    /// Add options for one of the setters below, keeping the first
+    /// error for build().
+    fn set<T: Into<std::ffi::OsString>>(mut self, args: impl IntoIterator<Item = T>) -> Self {
+        if let Err(e) = self.add_args(args) {
+            self.error.get_or_insert(e);
+        }
+        self
+    }
+
+    /// How often to run the control loop (--period).
+    pub fn period(self, period: std::time::Duration) -> Self {
+        self.set([format!("--period={}", period.as_secs())])
+    }
+
+    /// The EVSE's charge current range, in Amps
+    /// (--evse-min-charge-current and --evse-max-charge-current).
+    /// build() fails if it isn't a range of currents.
+    pub fn charge_current_range(self, min: f64, max: f64) -> Self {
+        self.set([
+            format!("--evse-min-charge-current={min}"),
+            format!("--evse-max-charge-current={max}"),
+        ])
+    }
+
+    /// The charging mode to start in (--mode).
+    pub fn mode(self, mode: mode::Mode) -> Self {
+        let mode = clap::ValueEnum::to_possible_value(&mode).expect("modes all have names");
+        self.set([format!("--mode={}", mode.get_name())])
+    }
+
+    /// Read the house's import and export from `meter`, instead of the
+    /// Envoy (or the --sunspec or --powerwall meter).  `name` is what
+    /// to call it in the logs.
+    pub fn meter(mut self, meter: impl meter::Meter + 'static, name: &str) -> Self {
+        self.meter = Some((Box::new(meter), String::from(name)));
+        self
+    }
+
+    /// Charge the EV with `evse`, instead of the OpenEVSE (or the OCPP
+    /// charger or the Quasar).  `name` is what to call it in the logs.
+    pub fn evse(mut self, evse: impl evse::Evse + 'static, name: &str) -> Self {
+        self.evse = Some((Box::new(evse), String::from(name)));
+        self
+    }
+
+    /// Ask `source` about the EV every cycle, instead of the Tesla API.
+    pub fn vehicle(mut self, source: impl vehicle::Source + 'static) -> Self {
+        self.vehicle_source = Some(Box::new(source));
+        self
+    }
+
+    /// Have `policy` adjust the charge current limit every cycle, after
+    /// the ones added before it.
+    pub fn policy(mut self, policy: impl policy::Policy + 'static) -> Self {
+        self.policies.push(Box::new(policy));
+        self
+    }
+
+    /// Stop when something's sent on `stop`, instead of on Ctrl-C.
+    pub fn stop_signal(mut self, stop: tokio::sync::mpsc::Receiver<()>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Check the options make sense together, beyond what clap checks.
+    fn validate(&self) -> Result<(), eyre::Report> {
+        let args = &self.args;
+        if self.meter.is_none()
+            && args.sunspec.is_none()
+            && args.powerwall.is_none()
+            && args.auth_token_filename.is_none()
+        {
+            return Err(eyre::eyre!("the Envoy needs an --auth-token-filename"));
+        }
+        if args.period == 0 {
+            return Err(eyre::eyre!("--period must be at least 1 second"));
+        }
+        args.check_currents()?;
+        if args.deadline.is_some() && args.deadline_kwh.is_none() && args.ev_target_soc.is_none() {
+            return Err(eyre::eyre!(
+                "--deadline needs --deadline-kwh or --ev-target-soc"
+            ));
+        }
+        if args.evse_phase > args.phases {
+            return Err(eyre::eyre!(
+                "--evse-phase {} but the house only has {} phases",
+                args.evse_phase,
+                args.phases
+            ));
+        }
+        if (args.phase_switching || args.phase_switch_topic.is_some()) && args.evse_phases != 3 {
+            return Err(eyre::eyre!("phase switching needs --evse-phases 3"));
+        }
+        Ok(())
+    }
+
+    /// Set up everything the options ask for, and make sure we can
+    /// reach the meter and the MQTT brokers.
+    pub async fn build(mut self) -> Result<Controller, eyre::Report> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.validate()?;
+        let command_line: Vec<std::ffi::OsString> =
+            std::iter::once(std::ffi::OsString::from("solar-evse"))
+                .chain(self.options.iter().cloned())
+                .collect();
+        let options = config::expand(command_line.clone())?;
+        // SIGHUP reloads the settings, unless whoever's embedding us has
+        // their own signals.
+        let hangups = match self.stop {
+            Some(_) => None,
+            None => Some(reload::hangups()?),
+        };
+        let mut args = self.args;
+        println!("config: {args:#?}");
+
+        let auth_token = args.auth_token().await?;
+        let capture = match &args.capture_dir {
+            Some(dir) => Some(std::sync::Arc::new(capture::Capture::new(
+                dir,
+                args.capture_max_bytes,
+                args.capture_max_files,
+                &[&auth_token],
+            )?)),
+            None => None,
+        };
+
+        let address_book = args.address_book();
+        let openevse_client = address_book.client_builder().build()?;
+        let mut openevse =
+            openevse::OpenEVSE::new(&args.openevse, openevse_client.clone(), capture.clone());
+        if args.openevse_claims {
+            openevse = openevse.with_claims(args.openevse_claim_priority);
+        }
+
+        // Handle Ctrl-C and SIGTERM, unless whoever's embedding us has
+        // their own way of stopping us.  (SIGHUP is for reloading.)
+        let ctrl_c_rx = match self.stop {
+            Some(stop) => stop,
+            None => {
+                let (ctrl_c_tx, ctrl_c_rx) = tokio::sync::mpsc::channel::<()>(10);
+                let mut terminate =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                        .map_err(|e| eyre::eyre!("can't listen for SIGTERM: {e}"))?;
+                tokio::spawn(async move {
+                    loop {
+                        tokio::select! {
+                            _ = tokio::signal::ctrl_c() => {}
+                            _ = terminate.recv() => {}
+                        }
+                        if ctrl_c_tx.send(()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                ctrl_c_rx
+            }
+        };
+
+        // Set up MQTT.  The broker we publish to tells everyone we're gone
+        // if we go away.
+        let last_will = args.mqtt_telemetry_prefix.as_ref().map(|prefix| {
+            rumqttc::LastWill::new(
+                homeassistant::availability_topic(prefix),
+                "offline",
+                rumqttc::QoS::AtLeastOnce,
+                true,
+            )
+        });
+        let (mqtt_client, mut mqtt_eventloop) = match &args.mqtt_broker {
+            Some(broker) => {
+                let mut mqtt_options = mqtt::options(
+                    "rumqttc-async",
+                    broker,
+                    args.mqtt_username.as_deref(),
+                    args.mqtt_password_filename.as_deref(),
+                )?;
+                if let (Some(last_will), None) = (&last_will, &args.mqtt_publish_broker) {
+                    mqtt_options.set_last_will(last_will.clone());
+                }
+                let (mqtt_client, mut mqtt_eventloop) =
+                    rumqttc::AsyncClient::new(mqtt_options, 100);
+                mqtt::bind(&mut mqtt_eventloop, args.interface.as_deref());
+                mqtt_client
+                    .subscribe("openevse/amp", rumqttc::QoS::AtMostOnce)
+                    .await
+                    .unwrap();
+                mqtt_client
+                    .subscribe("openevse/pilot", rumqttc::QoS::AtMostOnce)
+                    .await
+                    .unwrap();
+                mqtt_client
+                    .subscribe("openevse/state", rumqttc::QoS::AtMostOnce)
+                    .await
+                    .unwrap();
+                mqtt_client
+                    .subscribe("openevse/status", rumqttc::QoS::AtMostOnce)
+                    .await
+                    .unwrap();
+                mqtt_client
+                    .subscribe("openevse/vehicle", rumqttc::QoS::AtMostOnce)
+                    .await
+                    .unwrap();
+                for plug in &args.zigbee_plug {
+                    mqtt_client
+                        .subscribe(
+                            format!("{}/{}", args.zigbee2mqtt_prefix, plug),
+                            rumqttc::QoS::AtMostOnce,
+                        )
+                        .await
+                        .unwrap();
+                }
+                for input in &args.mqtt_input {
+                    mqtt_client
+                        .subscribe(&input.topic, rumqttc::QoS::AtMostOnce)
+                        .await
+                        .unwrap();
+                }
+                if let Some(topic) = &args.teslamate_topic {
+                    mqtt_client
+                        .subscribe(format!("{topic}/+"), rumqttc::QoS::AtMostOnce)
+                        .await
+                        .unwrap();
+                }
+                for topic in [
+                    &args.battery_power_topic,
+                    &args.grid_limit_topic,
+                    &args.cold_temperature_topic,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    mqtt_client
+                        .subscribe(topic, rumqttc::QoS::AtMostOnce)
+                        .await
+                        .unwrap();
+                }
+                (Some(mqtt_client), Some(mqtt_eventloop))
+            }
+            None => (None, None),
+        };
+        let (mqtt_publish_client, mut mqtt_publish_eventloop) = match &args.mqtt_publish_broker {
+            Some(broker) => {
+                let mut mqtt_options = mqtt::options(
+                    "rumqttc-async-publish",
+                    broker,
+                    args.mqtt_publish_username.as_deref(),
+                    args.mqtt_publish_password_filename.as_deref(),
+                )?;
+                if let Some(last_will) = last_will {
+                    mqtt_options.set_last_will(last_will);
+                }
+                let (mqtt_client, mut mqtt_eventloop) =
+                    rumqttc::AsyncClient::new(mqtt_options, 100);
+                mqtt::bind(&mut mqtt_eventloop, args.interface.as_deref());
+                (Some(mqtt_client), Some(mqtt_eventloop))
+            }
+            None => (None, None),
+        };
+        if let (true, Some(prefix)) = (args.mqtt_commands, &args.mqtt_telemetry_prefix) {
+            // Commands come from wherever we publish to.
+            if let Some(client) = mqtt_publish_client.as_ref().or(mqtt_client.as_ref()) {
+                client
+                    .subscribe(format!("{prefix}/cmd/#"), rumqttc::QoS::AtMostOnce)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let injected_evse = self.evse.is_some();
+        let (evse, evse_name): (Box<dyn evse::Evse>, String) =
+            match (self.evse, &args.ocpp_listen, &args.quasar) {
+                (Some(evse), _, _) => evse,
+                (None, Some(address), _) => (
+                    Box::new(
+                        ocpp::Ocpp::listen(
+                            address,
+                            std::time::Duration::from_secs(args.period),
+                            &args.ocpp_id_tag,
+                        )
+                        .await?,
+                    ),
+                    format!("OCPP charger (listening on {address})"),
+                ),
+                (None, None, Some(address)) => (
+                    Box::new(quasar::Quasar::new(
+                        address,
+                        args.quasar_unit_id,
+                        &address_book,
+                    )),
+                    format!("Wallbox Quasar ({address})"),
+                ),
+                (None, None, None) if !args.extra_openevse.is_empty() => {
+                    let range = (args.evse_min_charge_current, args.evse_max_charge_current);
+                    let primary = multi_evse::UnitSpec {
+                        host: args.openevse.clone(),
+                        range: None,
+                    };
+                    let units: Vec<_> = std::iter::once(primary)
+                        .chain(args.extra_openevse.iter().cloned())
+                        .map(|spec| {
+                            let unit_range = spec.range.unwrap_or(range);
+                            (spec, unit_range)
+                        })
+                        .collect();
+                    let names: Vec<&str> =
+                        units.iter().map(|(spec, _)| spec.host.as_str()).collect();
+                    let name = format!("OpenEVSEs ({})", names.join(", "));
+                    args.evse_min_charge_current = units
+                        .iter()
+                        .map(|(_, (min, _))| *min)
+                        .fold(f64::MAX, f64::min);
+                    args.evse_max_charge_current = units.iter().map(|(_, (_, max))| *max).sum();
+                    println!(
+                        "sharing between {} OpenEVSEs, {:.0} A to {:.0} A in all",
+                        units.len(),
+                        args.evse_min_charge_current,
+                        args.evse_max_charge_current
+                    );
+                    (
+                        Box::new(multi_evse::MultiEvse::new(
+                            units,
+                            args.load_sharing,
+                            &openevse_client,
+                            capture.clone(),
+                        )),
+                        name,
+                    )
+                }
+                (None, None, None) => (
+                    Box::new(openevse.clone()),
+                    format!("OpenEVSE ({})", args.openevse),
+                ),
+            };
+        let rapi = (!injected_evse && args.ocpp_listen.is_none() && args.quasar.is_none())
+            .then_some(openevse);
+        if args.phase_switching && args.phase_switch_topic.is_none() && !evse.can_switch_phases() {
+            return Err(eyre::eyre!(
+                "{} can't switch phases, --phase-switching needs a --phase-switch-topic",
+                evse_name
+            ));
+        }
+
+        let (mut meter, meter_name): (Box<dyn meter::Meter>, String) =
+            match (self.meter, &args.sunspec) {
+                (Some(meter), _) => meter,
+                (None, Some(address)) => (
+                    Box::new(sunspec::SunSpecMeter::new(
+                        address,
+                        args.sunspec_unit_id,
+                        args.sunspec_invert,
+                        &address_book,
+                    )),
+                    format!("SunSpec meter ({address})"),
+                ),
+                (None, None) => match &args.powerwall {
+                    Some(host) => {
+                        let filename = args.powerwall_password_filename.as_ref().unwrap();
+                        let password = tokio::fs::read_to_string(filename).await?;
+                        (
+                            Box::new(powerwall::Powerwall::new(
+                                reqwest::Url::parse(&format!(
+                                    "https://{}",
+                                    address_book::url_host(host)
+                                ))?,
+                                &address_book,
+                                &password,
+                            )?),
+                            format!("Powerwall ({host})"),
+                        )
+                    }
+                    None => (
+                        Box::new(args.envoy(&address_book, &auth_token, capture.clone())?),
+                        format!("Envoy ({})", args.envoy),
+                    ),
+                },
+            };
+
+        // Make sure we can talk to everything before we start.
+        let startup_timeout = std::time::Duration::from_secs(args.startup_timeout);
+        let (meter_probe, openevse_probe, mqtt_probe, mqtt_publish_probe) = tokio::join!(
+            probe::with_timeout(startup_timeout, meter.export_power()),
+            probe::with_timeout(startup_timeout, async {
+                let active_charging_current = evse.get_active_charging_current().await?;
+                // FIXME: only if the charger's enabled, not sleeping
+                let charging_current_limit = evse.get_current_capacity().await?;
+                Ok((active_charging_current, charging_current_limit))
+            }),
+            probe::with_timeout(startup_timeout, async {
+                match &mut mqtt_eventloop {
+                    Some(eventloop) => probe::mqtt_connect(eventloop).await,
+                    None => Ok(()),
+                }
+            }),
+            probe::with_timeout(startup_timeout, async {
+                match &mut mqtt_publish_eventloop {
+                    Some(eventloop) => probe::mqtt_connect(eventloop).await,
+                    None => Ok(()),
+                }
+            }),
+        );
+        println!("startup checks:");
+        let meter_ok = probe::report(&meter_name, &meter_probe);
+        let openevse_ok = probe::report(&evse_name, &openevse_probe);
+        let mqtt_ok = match &args.mqtt_broker {
+            Some(broker) => probe::report(&format!("MQTT broker ({broker})"), &mqtt_probe),
+            None => true,
+        };
+        let mqtt_publish_ok = match &args.mqtt_publish_broker {
+            Some(broker) => probe::report(
+                &format!("MQTT publish broker ({broker})"),
+                &mqtt_publish_probe,
+            ),
+            None => true,
+        };
+        if !(meter_ok && mqtt_ok && mqtt_publish_ok) {
+            return Err(eyre::eyre!("can't reach all devices, giving up"));
+        }
+        if !openevse_ok {
+            // Carry on without it, and keep looking for it.
+            println!("starting without the EVSE");
+        }
+        let (active_charging_current, charging_current_limit) =
+            openevse_probe.unwrap_or((0.0, 0.0));
+
+        let solcast_api_key = match &args.solcast_api_key_filename {
+            Some(filename) => Some(
+                tokio::fs::read_to_string(filename)
+                    .await?
+                    .trim()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let vehicle_source: Option<Box<dyn vehicle::Source>> = match (
+            self.vehicle_source,
+            &args.tesla_vehicle_id,
+            &args.tesla_token_filename,
+        ) {
+            (Some(source), _, _) => Some(source),
+            (None, Some(id), Some(filename)) => Some(Box::new(vehicle::TeslaApi::new(
+                args.tesla_api_url.clone(),
+                id,
+                &tokio::fs::read_to_string(filename).await?,
+                std::time::Duration::from_secs(args.tesla_poll_interval),
+            ))),
+            _ => None,
+        };
+
+        let forecast = args
+            .solar_forecast_url
+            .clone()
+            .map(|url| forecast::Forecast::new(url, solcast_api_key));
+
+        let templates =
+            std::sync::Arc::new(templates::Templates::new(args.template_dir.as_deref())?);
+        let webhooks = webhook::Webhooks::new(&args.webhook, templates.clone());
+        let inverter = args.curtail_inverter.as_ref().map(|address| {
+            sunspec::SunSpecInverter::new(address, args.curtail_inverter_unit_id, &address_book)
+        });
+
+        let metrics = std::sync::Arc::new(std::sync::Mutex::new(metrics::Metrics::default()));
+        if let Some(listen) = &args.metrics_listen {
+            metrics::serve(listen, metrics.clone()).await?;
+        }
+
+        let (api_published, api_commands) = if args.http_listen.is_empty() {
+            (None, None)
+        } else {
+            let security = api::Security {
+                tls: match (&args.http_tls_cert, &args.http_tls_key) {
+                    (Some(cert), Some(key)) => Some(api::tls_acceptor(cert, key)?),
+                    _ => None,
+                },
+                credentials: match (&args.http_username, &args.http_password_filename) {
+                    (Some(username), Some(filename)) => Some((
+                        username.clone(),
+                        tokio::fs::read_to_string(filename)
+                            .await?
+                            .trim()
+                            .to_string(),
+                    )),
+                    _ => None,
+                },
+                tokens: match &args.http_token_file {
+                    Some(filename) => {
+                        api::parse_tokens(&tokio::fs::read_to_string(filename).await.map_err(
+                            |e| eyre::eyre!("can't read {}: {}", filename.display(), e),
+                        )?)?
+                    }
+                    None => std::collections::HashMap::new(),
+                },
+            };
+            let downsampler =
+                downsample::Downsampler::new(args.history_aggregate, args.history_file.as_deref())?;
+            let (published, commands) =
+                api::serve(&args.http_listen, security, downsampler).await?;
+            (Some(published), Some(commands))
+        };
+
+        let openevse_events = args
+            .openevse_events
+            .clone()
+            .map(|url| openevse_events::follow(url, openevse_client.clone()));
+
+        let admin_requests = args.admin_socket.as_deref().map(admin::serve).transpose()?;
+
+        let restarts = restarts::Restarts::start(args.state_file.as_deref())?;
+        restarts.catch_panics();
+        {
+            let mut metrics = metrics.lock().unwrap();
+            metrics.restarts = restarts.restarts;
+            metrics.last_exit_cause = restarts.last_exit_cause.clone();
+        }
+
+        // With --grid-ie-topic we don't drive the EVSE at all.
+        let evse_attached = openevse_ok && args.grid_ie_topic.is_none();
+
+        // The --policy ones first, then the builder's.
+        let policies = args
+            .policy
+            .iter()
+            .map(policy::Spec::build)
+            .chain(self.policies)
+            .collect();
+
+        Ok(Controller {
+            rms_voltage: args.nominal_voltage,
+            mode: args.mode,
+            energy_flows: energy_flow::EnergyFlows::default(),
+            deadline: args
+                .deadline
+                .map(|time| deadline::Deadline::new(time, args.deadline_kwh.unwrap_or(0.0))),
+            vehicle_profile: None,
+            set_aside_deadline: None,
+            soc: args.ev_battery_kwh.map(soc::SocEstimate::new),
+            vehicle: (args.teslamate_topic.is_some() || vehicle_source.is_some())
+                .then(vehicle::Vehicle::default),
+            vehicle_source,
+            cold: args
+                .cold_window
+                .map(|window| cold::ColdCharging::new(args.cold_below, window, args.cold_kwh)),
+            prices: args.octopus_agile_url.clone().map(prices::Prices::new),
+            forecast,
+            house_limited: false,
+            active_phases: args.evse_phases,
+            phase_switch: phase_switch::PhaseSwitch::new(
+                std::time::Duration::from_secs(args.phase_switch_delay),
+                args.phase_switch_hysteresis,
+            ),
+            alerts: alert::Alerts::new(&args.alert),
+            grid_limit: grid_limit::GridLimit::new(
+                args.grid_limit_failsafe,
+                std::time::Duration::from_secs(args.grid_limit_timeout),
+            ),
+            args,
+            meter,
+            evse,
+            rapi,
+            clock_synced: None,
+            ctrl_c_rx,
+            mqtt_client,
+            mqtt_eventloop,
+            mqtt_publish_client,
+            mqtt_publish_eventloop,
+            openevse_events,
+            last_reading: None,
+            recent_readings: std::collections::VecDeque::new(),
+            export_current: 0.0,
+            export_current_now: 0.0,
+            battery_power: None,
+            mqtt_inputs: std::collections::HashMap::new(),
+            battery_soc: None,
+            reading_interval: None,
+            evse_charge_current: active_charging_current,
+            evse_charge_limit: charging_current_limit,
+            evse_attached,
+            evse_charge_current_time: Some(std::time::Instant::now()),
+            reported_charge_current: None,
+            reported_charge_current_unreliable: false,
+            cycles_since_charge_current_check: 0,
+            evse_state: None,
+            evse_fault: None,
+            fault_cleared: None,
+            fault_counts: None,
+            evse_enabled: None,
+            evse_enabled_time: None,
+            commanded_charge_limit: None,
+            commanded_charge_limit_time: None,
+            controller: control::Controller::default(),
+            dither_error: 0.0,
+            soft_start_cycle: None,
+            ramp_base: None,
+            setpoint_latency: latency::SetpointLatency::default(),
+            meter_latency: latency::MeterLatency::default(),
+            vehicle_connected: None,
+            session_start: None,
+            session_energy_wh: 0.0,
+            session_energy_time: None,
+            session_solar_wh: 0.0,
+            session_cost: None,
+            session_savings: None,
+            webhooks,
+            active_triggers: std::collections::HashMap::new(),
+            plug_power: std::collections::HashMap::new(),
+            inverter,
+            templates,
+            controller_state: controller_state::ControllerState::Standby,
+            controller_state_reason: String::new(),
+            last_error: None,
+            charge_limit_cap: None,
+            boost: None,
+            override_audit: audit::OverrideAudit::new(std::time::Duration::from_secs(60 * 60)),
+            metrics,
+            api_published,
+            api_commands,
+            admin_requests,
+            injected_export_current: None,
+            cycle_now: false,
+            command_line,
+            options,
+            hangups,
+            pending_reload: None,
+            last_reload: None,
+            policies,
+            events: tokio::sync::broadcast::channel(events::CAPACITY).0,
+            restarts,
+        })
+    }
+}
+
+impl Controller {
+    /// Hear what the controller's doing from now on (see events.rs).
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<events::Event> {
+        self.events.subscribe()
+    }
+
+    /// Step the charge current limit by `step` Amps and print the
+    /// control loop settings that suit how the meter responds (see
+    /// autotune.rs), then hand the EVSE back.
+    pub async fn autotune(
+        mut self,
+        step: f64,
+        settle: std::time::Duration,
+    ) -> Result<(), eyre::Report> {
+        if !self.evse_attached {
+            return Err(eyre::eyre!("can't auto-tune without the EVSE"));
+        }
+        let current = self.evse.get_active_charging_current().await?;
+        let test = autotune::Step::new(
+            current,
+            step,
+            self.args.evse_min_charge_current,
+            self.args.evse_max_charge_current,
+        )?;
+        let r = self.step_test(test, settle).await;
+        if self.evse.release().await? {
+            println!("released the EVSE");
+        } else {
+            self.charge_at_full_blast().await?;
+        }
+        let response = r?.response()?;
+        println!(
+            "export current moved {:.2} A for a {:.0} A step: dead time {:.0} s, time constant {:.0} s, noise {:.2} A",
+            response.gain * step.abs(),
+            step.abs(),
+            response.dead_time,
+            response.time_constant,
+            response.noise
+        );
+        println!("suggested: {}", response.suggest(self.args.period as f64));
+        Ok(())
+    }
+
+    /// Hold the charge current limit at `test.from` for `settle`, then
+    /// step it to `test.to` and hold it for `settle` again, reading the
+    /// meter every --period.
+    async fn step_test(
+        &mut self,
+        mut test: autotune::Step,
+        settle: std::time::Duration,
+    ) -> Result<autotune::Step, eyre::Report> {
+        let (from, to) = (test.from, test.to);
+        let period = std::time::Duration::from_secs(self.args.period);
+        println!("holding the charge current limit at {from} A");
+        self.evse.set_current_capacity(from as isize).await?;
+        self.evse.enable().await?;
+        let start = std::time::Instant::now();
+        while start.elapsed() < settle {
+            tokio::time::sleep(period).await;
+            self.update_current_surplus().await?;
+            // Only the second half has settled.
+            if start.elapsed() > settle / 2 {
+                test.before.push(self.export_current_now);
+            }
+        }
+        println!("stepping the charge current limit to {to} A");
+        self.evse.set_current_capacity(to as isize).await?;
+        let start = std::time::Instant::now();
+        while start.elapsed() < settle {
+            tokio::time::sleep(period).await;
+            self.update_current_surplus().await?;
+            test.after
+                .push((start.elapsed().as_secs_f64(), self.export_current_now));
+        }
+        Ok(test)
+    }
+
+    /// Run the control loop until we're stopped (by Ctrl-C, or the
+    /// builder's `stop_signal()`) or something goes wrong, then leave
+    /// the EVSE charging at full blast (or to itself, with
+    /// --openevse-claims).
+    pub async fn run_until_stopped(mut self) -> Result<(), eyre::Report> {
+        if self.evse_attached {
+            self.check_openevse().await;
+            self.resume_session().await;
+        }
+        if let Some(topic) = &self.args.grid_ie_topic {
+            self.start_divert(topic).await;
+        }
+        if let Some(budget) = self.args.boost {
+            self.start_boost(budget);
+        }
+        let r = self.run().await;
+        match &r {
+            Ok(()) => self.restarts.stop("signal", None),
+            Err(e) => self.restarts.stop("error", Some(format!("{e:#}"))),
+        }
+
+        if let Err(e) = &r {
+            self.emit(events::Event::Error {
+                message: format!("{e:#}"),
+            });
+            self.webhooks
+                .send("error", serde_json::json!({ "message": format!("{e:#}") }))
+                .await;
+        }
+
+        // Always hand the EVSE back, or reset it to charge at full blast,
+        // when we exit.
+        if self.evse_attached {
+            if self.evse.release().await? {
+                println!("released the EVSE");
+            } else {
+                self.charge_at_full_blast().await?;
+            }
+        }
+
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn currents() {
+        assert!(check_currents(6.0, 32.0, 1.0).is_ok());
+        assert!(check_currents(6.0, 6.0, -1.0).is_ok());
+        assert!(check_currents(-1.0, 32.0, 1.0).is_err());
+        assert!(check_currents(6.0, f64::INFINITY, 1.0).is_err());
+        assert!(check_currents(6.0, 32.0, f64::NAN).is_err());
+        assert!(check_currents(32.0, 6.0, 1.0).is_err());
+        assert!(check_currents(6.5, 7.0, 1.0).is_ok());
+        assert!(check_currents(6.5, 6.8, 1.0).is_err());
+    }
+
+    #[test]
+    fn ramp_rate() {
+        let args =
+            |rate: &str| Args::parse_from(["solar-evse".into(), format!("--max-ramp-rate={rate}")]);
+        assert!(args("6").check_currents().is_ok());
+        for rate in ["0", "-6", "NaN", "inf"] {
+            assert!(args(rate).check_currents().is_err(), "{rate}");
+        }
+    }
+
+    #[test]
+    fn control_loop_settings() {
+        let args = |option: &str| Args::parse_from(["solar-evse", option]);
+        assert!(args("--ki=0.1").check_currents().is_ok());
+        for option in [
+            "--kp=NaN",
+            "--ki=NaN",
+            "--ki=-0.1",
+            "--kd=inf",
+            "--deadband=-1",
+            "--export-smoothing=NaN",
+        ] {
+            assert!(args(option).check_currents().is_err(), "{option}");
+        }
+    }
+
+    struct Meter;
+
+    #[async_trait::async_trait]
+    impl meter::Meter for Meter {
+        async fn export_power(&mut self) -> Result<meter::PowerReading, eyre::Report> {
+            Ok(meter::PowerReading {
+                reading_time: chrono::Utc::now(),
+                export_w: 0.0,
+                export_wh_lifetime: None,
+                rms_voltage: None,
+                phases: Vec::new(),
+                consumption_w: None,
+                production_w: None,
+                battery_w: None,
+                battery_soc: None,
+                details: Vec::new(),
+            })
+        }
+    }
+
+    struct Evse;
+
+    #[async_trait::async_trait]
+    impl evse::Evse for Evse {
+        async fn enable(&self) -> Result<(), eyre::Report> {
+            Ok(())
+        }
+
+        async fn sleep(&self) -> Result<(), eyre::Report> {
+            Ok(())
+        }
+
+        async fn get_active_charging_current(&self) -> Result<f64, eyre::Report> {
+            Ok(0.0)
+        }
+
+        async fn get_current_capacity(&self) -> Result<f64, eyre::Report> {
+            Ok(0.0)
+        }
+
+        async fn set_current_capacity(&self, _: isize) -> Result<(), eyre::Report> {
+            Ok(())
+        }
+    }
+
+    /// A policy that always chooses `limit`.
+    struct Fixed(f64);
+
+    impl policy::Policy for Fixed {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn limit(&mut self, _: &std::collections::HashMap<String, f64>, _: f64) -> f64 {
+            self.0
+        }
+    }
+
+    /// A controller with the fake meter and EVSE above.
+    async fn controller(builder: ControllerBuilder) -> Controller {
+        let (_, stop) = tokio::sync::mpsc::channel(1);
+        builder
+            .meter(Meter, "meter")
+            .evse(Evse, "evse")
+            .stop_signal(stop)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn policy_limits() {
+        for (policy, limit) in [(10.0, 10.0), (40.0, 30.0), (3.0, 0.0), (-10.0, 0.0)] {
+            let mut controller = controller(ControllerBuilder::new().policy(Fixed(policy))).await;
+            controller.evse_charge_limit = 16.0;
+            controller.apply_policies();
+            assert_eq!(controller.evse_charge_limit, limit, "{policy}");
+        }
+    }
+
+    #[tokio::test]
+    async fn boost_state() {
+        use controller_state::ControllerState;
+        let mut controller = controller(ControllerBuilder::new()).await;
+        controller.vehicle_connected = Some(true);
+        controller.evse_charge_limit = 30.0;
+        assert_eq!(controller.next_state().0, ControllerState::Tracking);
+        controller.boost = Some(boost::Boost::new(
+            boost::Budget::Time(std::time::Duration::from_secs(3600)),
+            0.0,
+        ));
+        assert_eq!(
+            controller.next_state(),
+            (ControllerState::Boost, String::from("boosting"))
+        );
+        controller.vehicle_connected = Some(false);
+        assert_eq!(controller.next_state().0, ControllerState::Idle);
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let filename = std::env::temp_dir().join(format!(
+            "solar-evse-reload-test-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&filename, "version = 2\nmax_ramp_rate = 6\n").unwrap();
+        let mut controller = controller(
+            ControllerBuilder::new()
+                .args(["--config".into(), filename.clone().into_os_string()])
+                .unwrap(),
+        )
+        .await;
+        let mut reload = |contents: &str, how: &str| {
+            std::fs::write(&filename, contents).unwrap();
+            let result = controller.reload(how);
+            (result, controller.args.max_ramp_rate)
+        };
+        // Bad values are turned away, keeping the settings in effect.
+        for bad in ["max_ramp_rate = -6", "max_ramp_rate = \"fast\"", "kp = nan"] {
+            let (result, rate) = reload(&format!("version = 2\n{bad}\n"), "");
+            assert!(result.is_err(), "{bad}");
+            assert_eq!(rate, Some(6.0), "{bad}");
+        }
+        let (result, rate) = reload("version = 2\nmax_ramp_rate = 12\n", "dry_run");
+        assert!(result.is_ok());
+        assert_eq!(rate, Some(6.0));
+        let (result, rate) = reload("version = 2\nmax_ramp_rate = 12\n", "");
+        let _ = std::fs::remove_file(&filename);
+        assert!(result.is_ok());
+        assert_eq!(rate, Some(12.0));
+    }
+
+    #[tokio::test]
+    async fn builder_bad_range() {
+        let builder = ControllerBuilder::new()
+            .args(["--auth-token-filename", "/nonexistent"])
+            .unwrap()
+            .charge_current_range(-1.0, 32.0)
+            .period(std::time::Duration::from_secs(10));
+        // The later setter doesn't hide the bad range.
+        assert_eq!(builder.args.period, 10);
+        match builder.build().await {
+            Ok(_) => panic!("built with a negative charge current"),
+            Err(e) => assert!(e.to_string().contains("isn't a current"), "{e}"),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// Current limits at each level of the house's wiring that the EVSE is
+// downstream of: its own circuit breaker, any subpanels, and the main
+// service.  Each limit is given as "name=amps:load", where the load is
+// an expression (like a --sensor's) for the current flowing through
+// that level right now, for example
+// "garage=50:evse_charge_current + plug_water_heater_w / rms_voltage".
+//
+// Two levels have a built-in load, so they can be given as just
+// "name=amps": "circuit" is the EVSE's own circuit (the EV's charge
+// current), and "service" is the main service (the current imported
+// from the grid).
+//
+// Every cycle the EV gets at most what it's drawing now plus the
+// smallest headroom left at any level, so when other loads on a shared
+// panel come on, the EV backs off to make room for them.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct CurrentLimit {
+    pub name: String,
+    pub amps: f64,
+    load: crate::sensor::Sensor,
+}
+
+impl CurrentLimit {
+    /// How many more Amps this level can carry right now.
+    pub fn headroom(
+        &self,
+        inputs: &std::collections::HashMap<String, f64>,
+    ) -> Result<f64, eyre::Report> {
+        Ok(self.amps - self.load.evaluate(inputs)?)
+    }
+}
+
+impl FromStr for CurrentLimit {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, rest)) = s.split_once('=') else {
+            return Err(eyre::eyre!(
+                "current limit {:?} is not of the form name=amps:load",
+                s
+            ));
+        };
+        let name = name.trim();
+        let (amps, load) = match rest.split_once(':') {
+            Some((amps, load)) => (amps, load),
+            None => match name {
+                "circuit" => (rest, "evse_charge_current"),
+                "service" => (rest, "-export_current_now"),
+                _ => {
+                    return Err(eyre::eyre!(
+                        "current limit {:?} needs a load, like {}=50:evse_charge_current",
+                        name,
+                        name
+                    ))
+                }
+            },
+        };
+        let amps = f64::from_str(amps.trim())
+            .map_err(|e| eyre::eyre!("bad amps {:?} in current limit {:?}: {}", amps, s, e))?;
+        Ok(Self {
+            name: String::from(name),
+            amps,
+            load: crate::sensor::Sensor::from_str(&format!("{name}={load}"))?,
+        })
+    }
+}
@@ -0,0 +1,70 @@
+// Where we get the house's grid import/export from.  The control loop
+// only talks to the `Meter` trait, so supporting a different kind of
+// household meter (Shelly EM, IotaWatt, P1/DSMR, ...) means
+// implementing the trait for it, not changing the control loop.
+
+/// One reading of the power flowing between the house and the grid.
+#[derive(Debug, Clone)]
+pub struct PowerReading {
+    /// When the meter took the reading.
+    pub reading_time: chrono::DateTime<chrono::Utc>,
+
+    /// Power being exported to the grid right now, in Watts.  Negative
+    /// when importing.
+    pub export_w: f64,
+
+    /// Lifetime energy exported minus energy imported, in Watt-hours,
+    /// if the meter keeps count.  This lets us compute the average
+    /// export since the previous reading, instead of relying on the
+    /// instantaneous power.
+    pub export_wh_lifetime: Option<f64>,
+
+    /// The grid voltage, if the meter measures it.  Some meters report
+    /// the sum of the line voltages here, so with --phases 3 only the
+    /// voltages in `phases` are used.
+    pub rms_voltage: Option<f64>,
+
+    /// Each phase's export power and voltage, if the meter measures
+    /// them separately, in line order.
+    pub phases: Vec<Phase>,
+
+    /// The house's total consumption in Watts, EV included, if the
+    /// meter measures it.
+    pub consumption_w: Option<f64>,
+
+    /// Solar production in Watts, if the meter measures it.
+    pub production_w: Option<f64>,
+
+    /// The home battery's power in Watts, positive when discharging, if
+    /// the meter knows about a battery.
+    pub battery_w: Option<f64>,
+
+    /// The home battery's state of charge in percent, if the meter
+    /// knows about a battery.
+    pub battery_soc: Option<f64>,
+
+    /// Anything else the meter reported, by name, for republishing to
+    /// MQTT.
+    pub details: Vec<(String, f64)>,
+}
+
+/// One phase's share of a `PowerReading`.
+#[derive(Debug, Clone)]
+pub struct Phase {
+    /// Power being exported on this phase, in Watts.  Negative when
+    /// importing.
+    pub export_w: f64,
+
+    /// The phase's line-to-neutral voltage, if the meter measures it.
+    pub rms_voltage: Option<f64>,
+
+    /// The house's consumption on this phase in Watts, if the meter
+    /// measures it.
+    pub consumption_w: Option<f64>,
+}
+
+#[async_trait::async_trait]
+pub trait Meter: Send {
+    /// Read the power the house is exporting to the grid.
+    async fn export_power(&mut self) -> Result<PowerReading, eyre::Report>;
+}
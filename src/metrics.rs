@@ -0,0 +1,190 @@
+// Serve the controller's numbers at `/metrics` in the Prometheus text
+// format, for graphing in Grafana next to the rest of the house's
+// energy data.
+//
+// ```text
+// $ curl http://localhost:9477/metrics
+// # HELP solar_evse_export_current_amps Current exported to the grid, negative when importing.
+// # TYPE solar_evse_export_current_amps gauge
+// solar_evse_export_current_amps 2.413
+// ...
+// ```
+//
+// The control loop updates a `Metrics` once per cycle, and the server
+// reads whatever the latest values are.
+//
+// `solar-evse alert-rules` prints Prometheus alerting rules that use
+// these names (see alert_rules.rs), so keep the two in step.
+
+use crate::controller_state::ControllerState;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub export_current: f64,
+
+    // When the meter took its last reading, in seconds since the epoch.
+    pub meter_reading_time: Option<f64>,
+    pub evse_charge_limit: f64,
+    pub evse_charge_current: f64,
+    pub controller_state: Option<ControllerState>,
+
+    // Errors talking to the meter (Envoy, or whichever) and the EVSE,
+    // since startup.
+    pub meter_errors: u64,
+    pub evse_errors: u64,
+
+    // Whether the EVSE is faulted, and how many times it's tripped on
+    // each kind of fault, if it says.
+    pub evse_fault: bool,
+    pub fault_counts: Option<crate::openevse::FaultCounts>,
+
+    // How long the last cycle's work took, not counting the wait for
+    // the next cycle.
+    pub loop_seconds: f64,
+
+    // Since we started, how many times we've restarted, and why the
+    // last run stopped (see --state-file).
+    pub uptime_seconds: f64,
+    pub restarts: u64,
+    pub last_exit_cause: Option<String>,
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            text += &format!("# HELP solar_evse_{name} {help}\n");
+            text += &format!("# TYPE solar_evse_{name} {kind}\n");
+            for (labels, value) in samples {
+                text += &format!("solar_evse_{name}{labels} {value}\n");
+            }
+        };
+        metric(
+            "export_current_amps",
+            "gauge",
+            "Current exported to the grid, negative when importing.",
+            &[(String::new(), self.export_current)],
+        );
+        if let Some(time) = self.meter_reading_time {
+            metric(
+                "meter_reading_timestamp_seconds",
+                "gauge",
+                "When the meter took its last reading, in seconds since the epoch.",
+                &[(String::new(), time)],
+            );
+        }
+        metric(
+            "evse_pilot_amps",
+            "gauge",
+            "Charge current limit the EVSE offers the EV.",
+            &[(String::new(), self.evse_charge_limit)],
+        );
+        metric(
+            "evse_current_amps",
+            "gauge",
+            "Current the EV is drawing.",
+            &[(String::new(), self.evse_charge_current)],
+        );
+        let states: Vec<(String, f64)> = ControllerState::ALL
+            .iter()
+            .map(|state| {
+                let value = (Some(*state) == self.controller_state) as u8 as f64;
+                (format!("{{state=\"{state}\"}}"), value)
+            })
+            .collect();
+        metric(
+            "controller_state",
+            "gauge",
+            "1 for the state the controller is in, 0 for the others.",
+            &states,
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Failed requests to the devices.",
+            &[
+                (String::from("{device=\"meter\"}"), self.meter_errors as f64),
+                (String::from("{device=\"evse\"}"), self.evse_errors as f64),
+            ],
+        );
+        metric(
+            "evse_fault",
+            "gauge",
+            "1 if the EVSE is reporting a fault, 0 if not.",
+            &[(String::new(), self.evse_fault as u8 as f64)],
+        );
+        if let Some(counts) = &self.fault_counts {
+            metric(
+                "evse_fault_trips_total",
+                "counter",
+                "How many times the EVSE has tripped on each kind of fault.",
+                &[
+                    (String::from("{fault=\"gfci\"}"), counts.gfci as f64),
+                    (
+                        String::from("{fault=\"no_ground\"}"),
+                        counts.no_ground as f64,
+                    ),
+                    (
+                        String::from("{fault=\"stuck_relay\"}"),
+                        counts.stuck_relay as f64,
+                    ),
+                ],
+            );
+        }
+        metric(
+            "loop_seconds",
+            "gauge",
+            "How long the last control cycle took.",
+            &[(String::new(), self.loop_seconds)],
+        );
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "How long the controller has been running.",
+            &[(String::new(), self.uptime_seconds)],
+        );
+        metric(
+            "restarts_total",
+            "counter",
+            "How many times the controller has restarted.",
+            &[(String::new(), self.restarts as f64)],
+        );
+        if let Some(cause) = &self.last_exit_cause {
+            metric(
+                "last_exit",
+                "gauge",
+                "Why the last run stopped: signal, error, panic or crash.",
+                &[(format!("{{cause=\"{cause}\"}}"), 1.0)],
+            );
+        }
+        text
+    }
+}
+
+/// Start serving `metrics` on `listen`, in the background.
+pub async fn serve(
+    listen: &str,
+    metrics: std::sync::Arc<std::sync::Mutex<Metrics>>,
+) -> Result<(), eyre::Report> {
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .map_err(|e| eyre::eyre!("can't listen for metrics on {listen}: {e}"))?;
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || async move {
+            (
+                [(
+                    axum::http::header::CONTENT_TYPE,
+                    "text/plain; version=0.0.4",
+                )],
+                metrics.lock().unwrap().render(),
+            )
+        }),
+    );
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            println!("metrics server failed: {e}");
+        }
+    });
+    Ok(())
+}
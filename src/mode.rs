@@ -0,0 +1,32 @@
+// How the user wants the EV charged, chosen with --mode and changeable
+// while we're running (see --mqtt-commands and --http-listen).
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Charge with surplus solar only.
+    Eco,
+
+    /// Charge at --evse-max-charge-current, solar or not.
+    #[value(alias = "full")]
+    Fast,
+
+    /// Don't charge.
+    Off,
+
+    /// Don't charge until the EV is unplugged, then go back to Eco.
+    Pause,
+
+    /// Charge at --evse-max-charge-current during --charge-window, and
+    /// with surplus solar only the rest of the time.
+    Scheduled,
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use clap::ValueEnum;
+        match self.to_possible_value() {
+            Some(value) => write!(f, "{}", value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
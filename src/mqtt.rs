@@ -0,0 +1,173 @@
+// Connecting to the MQTT brokers.  There can be two: the one the
+// OpenEVSE publishes its telemetry to (--mqtt-broker), and optionally a
+// different one where we publish our own telemetry for dashboards and
+// Home Assistant (--mqtt-publish-broker).  Each has its own
+// credentials.
+//
+// Other devices on the first broker can feed in sensor inputs with
+// --mqtt-input, for example a water heater's tank temperature from a
+// Shelly temperature add-on, so a --trigger can stop diverting surplus
+// into the tank once it's hot:
+//
+// ```text
+// --zigbee-plug water_heater
+// --mqtt-input "tank_temp=shelly1pm/status/temperature:100,/tC"
+// --trigger "water_heater=export_current > 10 && tank_temp < 60"
+// ```
+//
+// A reading older than `MAX_INPUT_AGE` is dropped, so the trigger's
+// condition can't be computed and the plug switches off rather than
+// heating the tank blind.
+
+use std::str::FromStr;
+
+/// How long an --mqtt-input reading is good for.
+pub const MAX_INPUT_AGE: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// A sensor input read from an MQTT topic, as "name=topic" for a
+/// numeric payload, or "name=topic,/json/pointer" for a number in a
+/// JSON payload.
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub name: String,
+    pub topic: String,
+    pointer: Option<String>,
+}
+
+impl Input {
+    /// The input's value in an MQTT message.
+    pub fn parse(&self, payload: &str) -> Result<f64, eyre::Report> {
+        let value = match &self.pointer {
+            Some(pointer) => {
+                let json: serde_json::Value = serde_json::from_str(payload)?;
+                json.pointer(pointer)
+                    .and_then(|value| value.as_f64())
+                    .ok_or(eyre::eyre!("no number at {pointer}"))?
+            }
+            None => f64::from_str(payload.trim())?,
+        };
+        if !value.is_finite() {
+            return Err(eyre::eyre!("{value} isn't a reading"));
+        }
+        Ok(value)
+    }
+}
+
+impl FromStr for Input {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, topic)) = s.split_once('=') else {
+            return Err(eyre::eyre!(
+                "MQTT input {s:?} is not of the form name=topic"
+            ));
+        };
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(eyre::eyre!(
+                "MQTT input name {name:?} isn't a variable name"
+            ));
+        }
+        let (topic, pointer) = match topic.split_once(',') {
+            Some((topic, pointer)) if pointer.starts_with('/') => {
+                (topic, Some(String::from(pointer)))
+            }
+            Some((_, pointer)) => {
+                return Err(eyre::eyre!(
+                    "JSON pointer {pointer:?} doesn't start with \"/\""
+                ))
+            }
+            None => (topic, None),
+        };
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(eyre::eyre!("MQTT input topic {topic:?} isn't a topic"));
+        }
+        Ok(Self {
+            name: String::from(name),
+            topic: String::from(topic),
+            pointer,
+        })
+    }
+}
+
+/// Connection options for a broker given as "host" or "host:port"
+/// (IPv6 addresses in brackets with a port), logging in if there's a
+/// username.
+pub fn options(
+    client_id: &str,
+    broker: &str,
+    username: Option<&str>,
+    password_filename: Option<&str>,
+) -> Result<rumqttc::MqttOptions, eyre::Report> {
+    let (host, port) = crate::address_book::host_port(broker, 1883)
+        .map_err(|e| eyre::eyre!("bad MQTT broker: {e}"))?;
+    let mut options = rumqttc::MqttOptions::new(client_id, host, port);
+    if let Some(username) = username {
+        let password = match password_filename {
+            Some(filename) => std::fs::read_to_string(filename)
+                .map_err(|e| eyre::eyre!("can't read MQTT password {filename}: {e}"))?
+                .trim()
+                .to_string(),
+            None => String::new(),
+        };
+        options.set_credentials(username, password);
+    }
+    Ok(options)
+}
+
+/// Connect to the broker out of `interface`, if there's one (Linux
+/// only).
+pub fn bind(eventloop: &mut rumqttc::EventLoop, interface: Option<&str>) {
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = interface {
+        let mut network_options = eventloop.network_options();
+        network_options.set_bind_device(interface);
+        eventloop.set_network_options(network_options);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (eventloop, interface);
+}
+
+/// The next event from a broker, or never if there's no broker.
+pub async fn poll(
+    eventloop: &mut Option<rumqttc::EventLoop>,
+) -> Result<rumqttc::Event, rumqttc::ConnectionError> {
+    match eventloop {
+        Some(eventloop) => eventloop.poll().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input() {
+        let input = Input::from_str("tank_temp=shelly/status/temperature:100,/tC").unwrap();
+        assert_eq!(input.name, "tank_temp");
+        assert_eq!(input.topic, "shelly/status/temperature:100");
+        assert_eq!(input.parse(r#"{"id": 100, "tC": 58.4}"#).unwrap(), 58.4);
+        assert!(input.parse(r#"{"id": 100}"#).is_err());
+        assert!(input.parse("58.4").is_err());
+
+        let input = Input::from_str("tank_temp=tank/temperature").unwrap();
+        assert_eq!(input.parse(" 61.5\n").unwrap(), 61.5);
+        assert!(input.parse("hot").is_err());
+        assert!(input.parse("NaN").is_err());
+    }
+
+    #[test]
+    fn input_bad() {
+        for s in [
+            "tank/temperature",
+            "=tank/temperature",
+            "tank temp=tank/temperature",
+            "tank_temp=",
+            "tank_temp=tank/+/temperature",
+            "tank_temp=tank/temperature,tC",
+        ] {
+            assert!(Input::from_str(s).is_err(), "{s}");
+        }
+    }
+}
@@ -0,0 +1,431 @@
+// More than one OpenEVSE sharing the surplus, for households with two
+// EVs.  The control loop still works out one charge current limit, for
+// all of them together, and this splits it between the units that have
+// an EV plugged in, keeping each unit within its own minimum and
+// maximum:
+//
+// - With "--load-sharing priority", in the order they're given, the
+//   --openevse first: each unit gets as much as it can take of what's
+//   left, and a unit that can't have its minimum gets nothing.
+//
+// - With "--load-sharing round-robin", evenly, if there's enough for
+//   every unit's minimum.  If there isn't, the units take turns having
+//   priority, an hour each.
+//
+// Each --extra-openevse is "host" or "host/MIN-MAX", like
+// "garage2.local/6-16", with the range in Amps defaulting to
+// --evse-min-charge-current and --evse-max-charge-current, which are
+// also the --openevse's own range.  The control loop's maximum becomes
+// the total of all the units' maximums.
+//
+// A sleeping unit's state doesn't say whether an EV is plugged in, so
+// we go by the "EV connected" bit of its vflags (firmware 5 and up).
+// A faulted unit gets no share, and the EVSEs only count as faulted
+// when every unit is.
+
+use crate::evse::Evse;
+use std::str::FromStr;
+
+/// How long each unit has priority for, with round-robin sharing.
+const TURN: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Sharing {
+    Priority,
+    RoundRobin,
+}
+
+/// An --extra-openevse.
+#[derive(Debug, Clone)]
+pub struct UnitSpec {
+    pub host: String,
+    pub range: Option<(f64, f64)>,
+}
+
+impl FromStr for UnitSpec {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((host, range)) = s.split_once('/') else {
+            return Ok(Self {
+                host: String::from(s),
+                range: None,
+            });
+        };
+        let parse = |amps: &str| {
+            f64::from_str(amps.trim())
+                .map_err(|e| eyre::eyre!("bad OpenEVSE {:?}: {}: {}", s, amps, e))
+        };
+        let Some((min, max)) = range.split_once('-') else {
+            return Err(eyre::eyre!(
+                "OpenEVSE {:?} is not of the form host[/MIN-MAX]",
+                s
+            ));
+        };
+        let (min, max) = (parse(min)?, parse(max)?);
+        if min > max {
+            return Err(eyre::eyre!(
+                "bad OpenEVSE {:?}: minimum is more than maximum",
+                s
+            ));
+        }
+        Ok(Self {
+            host: String::from(host),
+            range: Some((min, max)),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Unit {
+    host: String,
+    openevse: crate::openevse::OpenEVSE,
+    min: f64,
+    max: f64,
+}
+
+/// What a unit's been told to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Setting {
+    Awake(f64),
+    Asleep,
+}
+
+#[derive(Debug)]
+struct Shared {
+    // Whether each unit has an EV plugged in, as far as we know, and
+    // the fault it's in, if any.
+    connected: Vec<bool>,
+    faults: Vec<Option<String>>,
+
+    // The total charge current limit, and whether we're enabled.
+    limit: f64,
+    enabled: bool,
+
+    // What each unit was last told, None if we don't know.
+    applied: Vec<Option<Setting>>,
+
+    // Which unit has priority with round-robin sharing, and since when.
+    first: usize,
+    turn_start: std::time::Instant,
+}
+
+#[derive(Debug)]
+pub struct MultiEvse {
+    units: Vec<Unit>,
+    sharing: Sharing,
+    shared: std::sync::Mutex<Shared>,
+}
+
+impl MultiEvse {
+    /// `units` are each OpenEVSE and its range, in priority order.
+    pub fn new(
+        units: Vec<(UnitSpec, (f64, f64))>,
+        sharing: Sharing,
+        client: &reqwest::Client,
+        capture: Option<std::sync::Arc<crate::capture::Capture>>,
+    ) -> Self {
+        let n = units.len();
+        Self {
+            units: units
+                .into_iter()
+                .map(|(spec, (min, max))| Unit {
+                    openevse: crate::openevse::OpenEVSE::new(
+                        &spec.host,
+                        client.clone(),
+                        capture.clone(),
+                    ),
+                    host: spec.host,
+                    min,
+                    max,
+                })
+                .collect(),
+            sharing,
+            shared: std::sync::Mutex::new(Shared {
+                connected: vec![false; n],
+                faults: vec![None; n],
+                limit: 0.0,
+                enabled: true,
+                applied: vec![None; n],
+                first: 0,
+                turn_start: std::time::Instant::now(),
+            }),
+        }
+    }
+
+    /// How much of `total` each unit gets.
+    fn split(&self, total: f64, shared: &mut Shared) -> Vec<f64> {
+        let n = self.units.len();
+        if self.sharing == Sharing::RoundRobin && shared.turn_start.elapsed() >= TURN {
+            shared.first = (shared.first + 1) % n;
+            shared.turn_start = std::time::Instant::now();
+        }
+        let first = match self.sharing {
+            Sharing::Priority => 0,
+            Sharing::RoundRobin => shared.first,
+        };
+        let order: Vec<usize> = (0..n)
+            .map(|i| (first + i) % n)
+            .filter(|i| shared.connected[*i] && shared.faults[*i].is_none())
+            .collect();
+        let mut shares = vec![0.0; n];
+
+        let minimums: f64 = order.iter().map(|i| self.units[*i].min).sum();
+        if self.sharing == Sharing::RoundRobin && !order.is_empty() && total >= minimums {
+            // Everyone gets their minimum, and then an even share of the
+            // rest, as far as their maximums allow.
+            for i in &order {
+                shares[*i] = self.units[*i].min;
+            }
+            let mut left = total - minimums;
+            let mut open: Vec<usize> = order.clone();
+            while left > 0.001 && !open.is_empty() {
+                let each = left / open.len() as f64;
+                for i in &open {
+                    let more = each.min(self.units[*i].max - shares[*i]);
+                    shares[*i] += more;
+                    left -= more;
+                }
+                open.retain(|i| shares[*i] < self.units[*i].max);
+            }
+            return shares;
+        }
+
+        let mut left = total;
+        for i in order {
+            let share = left.min(self.units[i].max);
+            if share >= self.units[i].min {
+                shares[i] = share;
+                left -= share;
+            }
+        }
+        shares
+    }
+
+    /// Give each unit its share of the limit, or put them all to sleep.
+    async fn apply(&self) -> Result<(), eyre::Report> {
+        let changes: Vec<(usize, Setting)> = {
+            let mut shared = self.shared.lock().unwrap();
+            let shares = if shared.enabled {
+                let limit = shared.limit;
+                self.split(limit, &mut shared)
+            } else {
+                vec![0.0; self.units.len()]
+            };
+            shares
+                .into_iter()
+                .enumerate()
+                .map(|(i, share)| {
+                    if share > 0.0 {
+                        (i, Setting::Awake(share))
+                    } else {
+                        (i, Setting::Asleep)
+                    }
+                })
+                .filter(|(i, setting)| shared.applied[*i] != Some(*setting))
+                .collect()
+        };
+        for (i, setting) in changes {
+            let unit = &self.units[i];
+            match setting {
+                Setting::Awake(amps) => {
+                    println!("OpenEVSE {}: {:.0} A", unit.host, amps);
+                    unit.openevse.set_current_capacity(amps as isize).await?;
+                    unit.openevse.enable().await?;
+                }
+                Setting::Asleep => {
+                    println!("OpenEVSE {}: sleeping", unit.host);
+                    unit.openevse.sleep().await?;
+                }
+            }
+            self.shared.lock().unwrap().applied[i] = Some(setting);
+        }
+        Ok(())
+    }
+
+    /// Ask each unit whether it has an EV plugged in, and whether it's
+    /// faulted.
+    async fn refresh_status(&self) -> Result<(), eyre::Report> {
+        for (i, unit) in self.units.iter().enumerate() {
+            let status = unit
+                .openevse
+                .status()
+                .await
+                .map_err(|e| eyre::eyre!("OpenEVSE {}: {:#}", unit.host, e))?;
+            let mut shared = self.shared.lock().unwrap();
+            if shared.faults[i] != status.fault {
+                match &status.fault {
+                    Some(fault) => println!("OpenEVSE {}: {}", unit.host, fault),
+                    None => println!("OpenEVSE {}: fault cleared", unit.host),
+                }
+                shared.faults[i] = status.fault;
+                shared.applied = vec![None; self.units.len()];
+            }
+            // Older firmware doesn't say while it's asleep, assume
+            // nothing's changed.
+            if let Some(connected) = status.vehicle_connected {
+                if shared.connected[i] != connected {
+                    println!(
+                        "OpenEVSE {}: EV {}",
+                        unit.host,
+                        if connected { "plugged in" } else { "unplugged" }
+                    );
+                    shared.connected[i] = connected;
+                    // Its share has to be worked out again.
+                    shared.applied = vec![None; self.units.len()];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::evse::Evse for MultiEvse {
+    async fn enable(&self) -> Result<(), eyre::Report> {
+        self.shared.lock().unwrap().enabled = true;
+        self.apply().await
+    }
+
+    async fn sleep(&self) -> Result<(), eyre::Report> {
+        self.shared.lock().unwrap().enabled = false;
+        self.apply().await
+    }
+
+    async fn get_active_charging_current(&self) -> Result<f64, eyre::Report> {
+        let mut total = 0.0;
+        for unit in &self.units {
+            total += unit.openevse.get_active_charging_current().await?;
+        }
+        Ok(total)
+    }
+
+    async fn get_current_capacity(&self) -> Result<f64, eyre::Report> {
+        Ok(self.shared.lock().unwrap().limit)
+    }
+
+    async fn set_current_capacity(&self, charge_current_limit: isize) -> Result<(), eyre::Report> {
+        let enabled = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.limit = charge_current_limit as f64;
+            shared.enabled
+        };
+        if enabled {
+            self.apply().await?;
+        }
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<crate::evse::Status, eyre::Report> {
+        self.refresh_status().await?;
+        let shared = self.shared.lock().unwrap();
+        let fault = match shared.faults.iter().all(Option::is_some) {
+            true => Some(
+                self.units
+                    .iter()
+                    .zip(&shared.faults)
+                    .map(|(unit, fault)| {
+                        format!("{}: {}", unit.host, fault.as_deref().unwrap_or_default())
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+            false => None,
+        };
+        Ok(crate::evse::Status {
+            vehicle_connected: Some(shared.connected.iter().any(|connected| *connected)),
+            fault,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Units with the given ranges, all with an EV plugged in.
+    fn units(sharing: Sharing, ranges: &[(f64, f64)]) -> MultiEvse {
+        let units = ranges
+            .iter()
+            .enumerate()
+            .map(|(i, range)| {
+                let spec = UnitSpec::from_str(&format!("evse{i}.local")).unwrap();
+                (spec, *range)
+            })
+            .collect();
+        let multi = MultiEvse::new(units, sharing, &reqwest::Client::new(), None);
+        multi.shared.lock().unwrap().connected = vec![true; ranges.len()];
+        multi
+    }
+
+    fn split(multi: &MultiEvse, total: f64) -> Vec<f64> {
+        let mut shared = multi.shared.lock().unwrap();
+        multi.split(total, &mut shared)
+    }
+
+    #[test]
+    fn even_split() {
+        let multi = units(Sharing::RoundRobin, &[(6.0, 32.0), (6.0, 32.0)]);
+        assert_eq!(split(&multi, 20.0), [10.0, 10.0]);
+        // What one can't take goes to the other.
+        let multi = units(Sharing::RoundRobin, &[(6.0, 8.0), (6.0, 32.0)]);
+        assert_eq!(split(&multi, 30.0), [8.0, 22.0]);
+        assert_eq!(split(&multi, 100.0), [8.0, 32.0]);
+    }
+
+    #[test]
+    fn priority() {
+        let multi = units(Sharing::Priority, &[(6.0, 16.0), (6.0, 32.0)]);
+        assert_eq!(split(&multi, 40.0), [16.0, 24.0]);
+        assert_eq!(split(&multi, 24.0), [16.0, 8.0]);
+        assert_eq!(split(&multi, 10.0), [10.0, 0.0]);
+    }
+
+    #[test]
+    fn minimum_cutoff() {
+        // Not enough for the second unit's minimum.
+        let multi = units(Sharing::Priority, &[(6.0, 16.0), (6.0, 32.0)]);
+        assert_eq!(split(&multi, 20.0), [16.0, 0.0]);
+        // Nor for the first's, so none at all.
+        assert_eq!(split(&multi, 5.0), [0.0, 0.0]);
+        // A unit with a higher minimum is skipped for one that can
+        // take what's left.
+        let multi = units(Sharing::Priority, &[(10.0, 16.0), (6.0, 32.0)]);
+        assert_eq!(split(&multi, 8.0), [0.0, 8.0]);
+
+        // Round-robin with too little for both minimums goes by turns.
+        let multi = units(Sharing::RoundRobin, &[(6.0, 32.0), (6.0, 32.0)]);
+        assert_eq!(split(&multi, 10.0), [10.0, 0.0]);
+        // The second unit's turn.
+        multi.shared.lock().unwrap().first = 1;
+        assert_eq!(split(&multi, 10.0), [0.0, 10.0]);
+    }
+
+    #[test]
+    fn unplugged() {
+        let multi = units(Sharing::RoundRobin, &[(6.0, 32.0), (6.0, 32.0)]);
+        multi.shared.lock().unwrap().connected[0] = false;
+        assert_eq!(split(&multi, 20.0), [0.0, 20.0]);
+        // A faulted unit gets nothing either.
+        let multi = units(Sharing::Priority, &[(6.0, 32.0), (6.0, 32.0)]);
+        multi.shared.lock().unwrap().faults[0] = Some(String::from("GFCI fault"));
+        assert_eq!(split(&multi, 20.0), [0.0, 20.0]);
+        multi.shared.lock().unwrap().connected[1] = false;
+        assert_eq!(split(&multi, 20.0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn unit_spec() {
+        let spec = UnitSpec::from_str("garage2.local/6-16").unwrap();
+        assert_eq!(spec.host, "garage2.local");
+        assert_eq!(spec.range, Some((6.0, 16.0)));
+        assert_eq!(UnitSpec::from_str("garage2.local").unwrap().range, None);
+        for bad in [
+            "garage2.local/16",
+            "garage2.local/16-6",
+            "garage2.local/a-16",
+        ] {
+            assert!(UnitSpec::from_str(bad).is_err(), "{bad}");
+        }
+    }
+}
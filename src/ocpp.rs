@@ -0,0 +1,899 @@
+// A minimal OCPP 1.6J and 2.0.1 (JSON over WebSocket) central system,
+// so chargers that only speak OCPP can be driven instead of an
+// OpenEVSE.  The version is whichever the charge point offers in the
+// WebSocket handshake, preferring 2.0.1.
+//
+// In OCPP the charger (the "charge point") connects to the central
+// system, so we listen for it, and the charger has to be configured
+// with our address as its central system URL, for example
+// "ws://solar-evse.local:8887/".  Any charge point ID is accepted, and
+// if a second charge point connects it replaces the first.
+//
+// We only implement what the control loop needs:
+//
+// - The charge limit is set with SetChargingProfile, as a
+//   TxDefaultProfile on connector 0 (EVSE 0 in 2.0.1).  Unlike a
+//   TxProfile that applies to the current charging session only, this
+//   also covers sessions that haven't started yet.  Sleeping is a limit
+//   of 0 A.  A charge point that boots (or reboots) is sent the profile
+//   again, rather than going back to its own default.
+//
+// - With --phase-switching, the number of phases to charge on is the
+//   numberPhases of the charging profile's period.  Charge points
+//   that can't switch ignore it.
+//
+// - The EV's charge current is the Current.Import measurand from
+//   MeterValues (and, in 2.0.1, TransactionEvent).  An OCPP 1.6
+//   charger has to be configured to send it (MeterValuesSampledData),
+//   and to send it at least once per --period
+//   (MeterValueSampleInterval).  A 2.0.1 charger is configured to by
+//   setting those variables of its device model (the SampledDataCtrlr
+//   and AlignedDataCtrlr components) when it boots.
+//
+// - With --ocpp-id-tag, only the listed RFID cards (or other ID tokens)
+//   are authorized to start a session, and the charge point is told
+//   to stop any other; without, every one is.  Each authorization, and
+//   each session's start and end, is logged with its tag:
+//
+//   ```text
+//   OCPP idTag 04A2B1C3: Accepted (Authorize)
+//   OCPP idTag 04A2B1C3: transaction 1 started
+//   OCPP idTag 04A2B1C3: transaction 1 ended, 7342 Wh
+//   OCPP idTag DEADBEEF: Invalid (Authorize)
+//   ```
+//
+// - Whether an EV is plugged in, and whether the charger's faulted,
+//   come from StatusNotification, so sessions start and end (and are
+//   logged with their tag) as they do with an OpenEVSE.
+//
+// Everything else the charge point tells us is accepted.
+
+use futures_util::{SinkExt, StreamExt};
+
+// How long to wait for the charge point to answer a request.
+const CALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+type CallResult = Result<serde_json::Value, eyre::Report>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Version {
+    #[default]
+    V16,
+    V201,
+}
+
+#[derive(Default)]
+struct Inner {
+    // For sending messages to the connected charge point, if there is
+    // one.
+    tx: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+
+    // Requests we've sent and are waiting for the answer to, by
+    // message ID.
+    pending: std::collections::HashMap<String, tokio::sync::oneshot::Sender<CallResult>>,
+
+    next_message_id: u64,
+    next_transaction_id: i64,
+
+    // The OCPP version the connected charge point speaks.
+    version: Version,
+
+    // How often a 2.0.1 charge point should send meter values.
+    meter_interval: std::time::Duration,
+
+    // The EV's charge current in the last MeterValues.
+    charging_current: Option<f64>,
+
+    // The charge limit we've been asked for, and whether we're
+    // enabled.  The charge point gets `limit` when enabled, 0 A when
+    // not.
+    limit: f64,
+    enabled: bool,
+
+    // How many phases the charge point should charge on, if we've
+    // been asked to switch.
+    phases: Option<u8>,
+
+    // Whether we've been asked for a charge limit yet, and whether the
+    // charge point has just booted and needs to be sent it again.
+    configured: bool,
+    booted: bool,
+
+    // The ID tags allowed to start a session, or None for any.
+    id_tags: Option<std::collections::HashSet<String>>,
+
+    // The ID tag and starting meter reading (Wh) of each 1.6
+    // transaction in progress, by transaction ID.
+    transactions: std::collections::HashMap<i64, (String, f64)>,
+
+    // The ID tag the latest transaction was started with.
+    id_tag: Option<String>,
+
+    // Whether an EV's plugged in and the charge point's fault, from
+    // the last StatusNotifications.
+    status: crate::evse::Status,
+}
+
+impl Inner {
+    /// Whether `id_tag` may start a session, logging the answer, as
+    /// OCPP's authorization status.
+    fn authorize(&self, id_tag: &str, action: &str) -> &'static str {
+        let status = match &self.id_tags {
+            Some(id_tags) if !id_tags.contains(id_tag) => "Invalid",
+            _ => "Accepted",
+        };
+        println!("OCPP idTag {id_tag}: {status} ({action})");
+        status
+    }
+}
+
+pub struct Ocpp {
+    inner: std::sync::Arc<std::sync::Mutex<Inner>>,
+}
+
+impl Ocpp {
+    /// Start listening for the charge point to connect.  A 2.0.1
+    /// charge point is told to send meter values every
+    /// `meter_interval`.  Only `id_tags` may start a session, any if
+    /// it's empty.
+    pub async fn listen(
+        address: &str,
+        meter_interval: std::time::Duration,
+        id_tags: &[String],
+    ) -> Result<Self, eyre::Report> {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| eyre::eyre!("can't listen for OCPP on {}: {}", address, e))?;
+        let inner = std::sync::Arc::new(std::sync::Mutex::new(Inner {
+            enabled: true,
+            meter_interval,
+            id_tags: (!id_tags.is_empty()).then(|| id_tags.iter().cloned().collect()),
+            ..Default::default()
+        }));
+        tokio::spawn(accept(listener, inner.clone()));
+        Ok(Self { inner })
+    }
+
+    /// Send a request to the charge point and wait for its answer.
+    async fn call(&self, action: &str, payload: serde_json::Value) -> CallResult {
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let tx = inner
+                .tx
+                .clone()
+                .ok_or(eyre::eyre!("no OCPP charge point connected"))?;
+            inner.next_message_id += 1;
+            let id = inner.next_message_id.to_string();
+            tx.send(serde_json::json!([2, id, action, payload]).to_string())
+                .map_err(|_| eyre::eyre!("OCPP charge point disconnected"))?;
+            inner.pending.insert(id.clone(), result_tx);
+            id
+        };
+        match tokio::time::timeout(CALL_TIMEOUT, result_rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(eyre::eyre!("OCPP charge point disconnected")),
+            Err(_) => {
+                self.inner.lock().unwrap().pending.remove(&id);
+                Err(eyre::eyre!(
+                    "no answer to OCPP {} after {} seconds",
+                    action,
+                    CALL_TIMEOUT.as_secs()
+                ))
+            }
+        }
+    }
+
+    /// Send the charge point the charge limit it should have right now.
+    async fn send_charging_profile(&self) -> Result<(), eyre::Report> {
+        let (limit, version, phases) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.configured = true;
+            let limit = if inner.enabled { inner.limit } else { 0.0 };
+            (limit, inner.version, inner.phases)
+        };
+        let mut period = serde_json::json!({ "startPeriod": 0, "limit": limit });
+        if let Some(phases) = phases {
+            period["numberPhases"] = serde_json::json!(phases);
+        }
+        let schedule = serde_json::json!({
+            "startSchedule": chrono::Utc::now().to_rfc3339(),
+            "chargingRateUnit": "A",
+            "chargingSchedulePeriod": [period],
+        });
+        let request = match version {
+            Version::V16 => serde_json::json!({
+                "connectorId": 0,
+                "csChargingProfiles": {
+                    "chargingProfileId": 1,
+                    "stackLevel": 0,
+                    "chargingProfilePurpose": "TxDefaultProfile",
+                    "chargingProfileKind": "Absolute",
+                    "chargingSchedule": schedule,
+                },
+            }),
+            Version::V201 => {
+                // 2.0.1 profiles can have more than one schedule, each
+                // with an ID.
+                let mut schedule = schedule;
+                schedule["id"] = serde_json::json!(1);
+                serde_json::json!({
+                    "evseId": 0,
+                    "chargingProfile": {
+                        "id": 1,
+                        "stackLevel": 0,
+                        "chargingProfilePurpose": "TxDefaultProfile",
+                        "chargingProfileKind": "Absolute",
+                        "chargingSchedule": [schedule],
+                    },
+                })
+            }
+        };
+        let result = self.call("SetChargingProfile", request).await?;
+        match result.get("status").and_then(|v| v.as_str()) {
+            Some("Accepted") => Ok(()),
+            status => Err(eyre::eyre!(
+                "OCPP charge point didn't accept the charging profile: {:?}",
+                status
+            )),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::evse::Evse for Ocpp {
+    async fn enable(&self) -> Result<(), eyre::Report> {
+        self.inner.lock().unwrap().enabled = true;
+        self.send_charging_profile().await
+    }
+
+    async fn sleep(&self) -> Result<(), eyre::Report> {
+        self.inner.lock().unwrap().enabled = false;
+        self.send_charging_profile().await
+    }
+
+    async fn get_active_charging_current(&self) -> Result<f64, eyre::Report> {
+        let inner = self.inner.lock().unwrap();
+        if inner.tx.is_none() {
+            return Err(eyre::eyre!("no OCPP charge point connected"));
+        }
+        // Some charge points only send meter values while charging.
+        Ok(inner.charging_current.unwrap_or(0.0))
+    }
+
+    async fn get_current_capacity(&self) -> Result<f64, eyre::Report> {
+        Ok(self.inner.lock().unwrap().limit)
+    }
+
+    async fn set_current_capacity(&self, charge_current_limit: isize) -> Result<(), eyre::Report> {
+        let enabled = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.limit = charge_current_limit as f64;
+            inner.configured = true;
+            inner.enabled
+        };
+        if enabled {
+            self.send_charging_profile().await?;
+        }
+        Ok(())
+    }
+
+    fn can_switch_phases(&self) -> bool {
+        true
+    }
+
+    async fn set_phases(&self, phases: u8) -> Result<(), eyre::Report> {
+        self.inner.lock().unwrap().phases = Some(phases);
+        self.send_charging_profile().await
+    }
+
+    async fn status(&self) -> Result<crate::evse::Status, eyre::Report> {
+        Ok(self.inner.lock().unwrap().status.clone())
+    }
+
+    fn id_tag(&self) -> Option<String> {
+        self.inner.lock().unwrap().id_tag.clone()
+    }
+}
+
+/// Keep track of whether an EV's plugged in and whether the charge
+/// point's faulted, from a StatusNotification.
+fn update_status(inner: &std::sync::Mutex<Inner>, version: Version, payload: &serde_json::Value) {
+    let (status, fault) = match version {
+        Version::V16 => (
+            payload["status"].as_str().unwrap_or_default(),
+            payload["errorCode"].as_str().unwrap_or_default(),
+        ),
+        Version::V201 => (
+            payload["connectorStatus"].as_str().unwrap_or_default(),
+            "Faulted",
+        ),
+    };
+    let mut inner = inner.lock().unwrap();
+    inner.status.fault = (status == "Faulted").then(|| String::from(fault));
+    // 1.6's connector 0 is the charge point as a whole.
+    if payload["connectorId"].as_i64() == Some(0) {
+        return;
+    }
+    match status {
+        "Available" | "Reserved" | "Unavailable" => inner.status.vehicle_connected = Some(false),
+        "Preparing" | "Charging" | "SuspendedEV" | "SuspendedEVSE" | "Finishing" | "Occupied" => {
+            inner.status.vehicle_connected = Some(true)
+        }
+        _ => {}
+    }
+}
+
+async fn accept(listener: tokio::net::TcpListener, inner: std::sync::Arc<std::sync::Mutex<Inner>>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("OCPP accept failed: {e}");
+                continue;
+            }
+        };
+        println!("OCPP connection from {peer}");
+        let inner = inner.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, inner).await {
+                println!("OCPP connection from {peer} failed: {e:#}");
+            }
+        });
+    }
+}
+
+/// Talk to one connected charge point until it disconnects.
+// The handshake callback's error type is tungstenite's, not ours.
+#[allow(clippy::result_large_err)]
+async fn serve(
+    stream: tokio::net::TcpStream,
+    inner: std::sync::Arc<std::sync::Mutex<Inner>>,
+) -> Result<(), eyre::Report> {
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut version = Version::V16;
+    let ws = tokio_tungstenite::accept_hdr_async(
+        stream,
+        |request: &tokio_tungstenite::tungstenite::handshake::server::Request,
+         mut response: tokio_tungstenite::tungstenite::handshake::server::Response| {
+            let offers = |protocol: &str| {
+                request
+                    .headers()
+                    .get("Sec-WebSocket-Protocol")
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|protocols| protocols.split(',').any(|p| p.trim() == protocol))
+            };
+            let protocol = if offers("ocpp2.0.1") {
+                version = Version::V201;
+                Some("ocpp2.0.1")
+            } else if offers("ocpp1.6") {
+                Some("ocpp1.6")
+            } else {
+                None
+            };
+            if let Some(protocol) = protocol {
+                response.headers_mut().insert(
+                    "Sec-WebSocket-Protocol",
+                    tokio_tungstenite::tungstenite::http::HeaderValue::from_static(protocol),
+                );
+            }
+            Ok(response)
+        },
+    )
+    .await?;
+    println!(
+        "OCPP charge point speaks {}",
+        match version {
+            Version::V16 => "1.6",
+            Version::V201 => "2.0.1",
+        }
+    );
+    let (mut sink, mut stream) = ws.split();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    {
+        let mut inner = inner.lock().unwrap();
+        inner.tx = Some(tx.clone());
+        inner.version = version;
+    }
+
+    let result = async {
+        loop {
+            tokio::select! {
+                Some(message) = rx.recv() => {
+                    sink.send(Message::Text(message)).await?;
+                }
+
+                message = stream.next() => {
+                    match message {
+                        None | Some(Ok(Message::Close(_))) => return Ok(()),
+                        Some(Ok(Message::Text(text))) => {
+                            if let Some(reply) = handle_message(&inner, &text) {
+                                sink.send(Message::Text(reply)).await?;
+                            }
+                            resend_after_boot(&inner);
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return Err(eyre::Report::from(e)),
+                    }
+                }
+            }
+        }
+    }
+    .await;
+
+    // Forget about this charge point, unless another one has replaced
+    // it already.
+    let mut inner = inner.lock().unwrap();
+    if inner.tx.as_ref().is_some_and(|t| t.same_channel(&tx)) {
+        inner.tx = None;
+        inner.charging_current = None;
+        inner.pending.clear();
+    }
+    result
+}
+
+/// Send a charge point that's just booted the charge limit we've been
+/// asked for, in the background: it's sent by this connection's loop.
+fn resend_after_boot(inner: &std::sync::Arc<std::sync::Mutex<Inner>>) {
+    {
+        let mut inner = inner.lock().unwrap();
+        if !std::mem::take(&mut inner.booted) || !inner.configured {
+            return;
+        }
+    }
+    let ocpp = Ocpp {
+        inner: inner.clone(),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = ocpp.send_charging_profile().await {
+            println!("can't send the rebooted OCPP charge point its charging profile: {e:#}");
+        }
+    });
+}
+
+/// Handle one message from the charge point, returning the reply to
+/// send, if any.
+fn handle_message(inner: &std::sync::Mutex<Inner>, text: &str) -> Option<String> {
+    let message: serde_json::Value = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => {
+            println!("bad OCPP message {:?}: {}", text, e);
+            return None;
+        }
+    };
+    let id = message.get(1).and_then(|v| v.as_str()).unwrap_or_default();
+    match message.get(0).and_then(|v| v.as_u64()) {
+        // A request from the charge point.
+        Some(2) => {
+            let action = message.get(2).and_then(|v| v.as_str()).unwrap_or_default();
+            let payload = message.get(3).cloned().unwrap_or_default();
+            let reply = match handle_call(inner, action, &payload) {
+                Some(result) => serde_json::json!([3, id, result]),
+                None => serde_json::json!([
+                    4,
+                    id,
+                    "NotImplemented",
+                    format!("{action} is not supported"),
+                    {}
+                ]),
+            };
+            Some(reply.to_string())
+        }
+
+        // The answer to one of our requests.
+        Some(3) => {
+            if let Some(result_tx) = inner.lock().unwrap().pending.remove(id) {
+                let _ = result_tx.send(Ok(message.get(2).cloned().unwrap_or_default()));
+            }
+            None
+        }
+
+        // An error in answer to one of our requests.
+        Some(4) => {
+            if let Some(result_tx) = inner.lock().unwrap().pending.remove(id) {
+                let _ = result_tx.send(Err(eyre::eyre!(
+                    "OCPP error {}: {}",
+                    message.get(2).and_then(|v| v.as_str()).unwrap_or_default(),
+                    message.get(3).and_then(|v| v.as_str()).unwrap_or_default()
+                )));
+            }
+            None
+        }
+
+        _ => {
+            println!("bad OCPP message {:?}", text);
+            None
+        }
+    }
+}
+
+/// Handle a request from the charge point, returning the result, or
+/// None if we don't support the request.
+fn handle_call(
+    inner: &std::sync::Mutex<Inner>,
+    action: &str,
+    payload: &serde_json::Value,
+) -> Option<serde_json::Value> {
+    let now = chrono::Utc::now().to_rfc3339();
+    let version = inner.lock().unwrap().version;
+    match action {
+        "BootNotification" => {
+            let (vendor, model) = match version {
+                Version::V16 => (&payload["chargePointVendor"], &payload["chargePointModel"]),
+                Version::V201 => (
+                    &payload["chargingStation"]["vendorName"],
+                    &payload["chargingStation"]["model"],
+                ),
+            };
+            println!(
+                "OCPP charge point booted: {} {}",
+                vendor.as_str().unwrap_or_default(),
+                model.as_str().unwrap_or_default()
+            );
+            if version == Version::V201 {
+                configure_meter_values(inner);
+            }
+            inner.lock().unwrap().booted = true;
+            Some(serde_json::json!({
+                "status": "Accepted",
+                "currentTime": now,
+                "interval": 60,
+            }))
+        }
+        "Heartbeat" => Some(serde_json::json!({ "currentTime": now })),
+        "StatusNotification" => {
+            match version {
+                Version::V16 => println!(
+                    "OCPP connector {} status: {} ({})",
+                    payload["connectorId"],
+                    payload["status"].as_str().unwrap_or_default(),
+                    payload["errorCode"].as_str().unwrap_or_default()
+                ),
+                Version::V201 => println!(
+                    "OCPP EVSE {} connector {} status: {}",
+                    payload["evseId"],
+                    payload["connectorId"],
+                    payload["connectorStatus"].as_str().unwrap_or_default()
+                ),
+            }
+            update_status(inner, version, payload);
+            Some(serde_json::json!({}))
+        }
+        "MeterValues" => {
+            update_charging_current(inner, payload);
+            Some(serde_json::json!({}))
+        }
+        "Authorize" => Some(match version {
+            Version::V16 => {
+                let id_tag = payload["idTag"].as_str().unwrap_or_default();
+                let status = inner.lock().unwrap().authorize(id_tag, action);
+                serde_json::json!({ "idTagInfo": { "status": status } })
+            }
+            Version::V201 => {
+                let id_token = payload["idToken"]["idToken"].as_str().unwrap_or_default();
+                let status = inner.lock().unwrap().authorize(id_token, action);
+                serde_json::json!({ "idTokenInfo": { "status": status } })
+            }
+        }),
+        "TransactionEvent" => {
+            // 2.0.1's replacement for StartTransaction, StopTransaction
+            // and the meter values in between.  The ID token comes
+            // with the event it was presented in, if there was one.
+            update_charging_current(inner, payload);
+            let transaction = payload["transactionInfo"]["transactionId"]
+                .as_str()
+                .unwrap_or_default();
+            let event = payload["eventType"].as_str().unwrap_or_default();
+            let mut inner = inner.lock().unwrap();
+            if event == "Ended" {
+                inner.charging_current = Some(0.0);
+                println!("OCPP transaction {transaction} ended");
+            }
+            let id_token = payload["idToken"]["idToken"].as_str();
+            if event == "Started" {
+                inner.id_tag = id_token.map(String::from);
+            }
+            match id_token {
+                Some(id_token) => {
+                    let status = inner.authorize(id_token, action);
+                    if event == "Started" && status == "Accepted" {
+                        println!("OCPP idTag {id_token}: transaction {transaction} started");
+                    }
+                    Some(serde_json::json!({ "idTokenInfo": { "status": status } }))
+                }
+                None => Some(serde_json::json!({})),
+            }
+        }
+        "StartTransaction" => {
+            let mut inner = inner.lock().unwrap();
+            let id_tag = payload["idTag"].as_str().unwrap_or_default();
+            let status = inner.authorize(id_tag, action);
+            inner.next_transaction_id += 1;
+            let transaction = inner.next_transaction_id;
+            if status == "Accepted" {
+                println!("OCPP idTag {id_tag}: transaction {transaction} started");
+                inner.id_tag = Some(String::from(id_tag));
+                let meter_start = payload["meterStart"].as_f64().unwrap_or_default();
+                inner
+                    .transactions
+                    .insert(transaction, (String::from(id_tag), meter_start));
+            }
+            Some(serde_json::json!({
+                "idTagInfo": { "status": status },
+                "transactionId": transaction,
+            }))
+        }
+        "StopTransaction" => {
+            let mut inner = inner.lock().unwrap();
+            inner.charging_current = Some(0.0);
+            let transaction = payload["transactionId"].as_i64().unwrap_or_default();
+            if let Some((id_tag, meter_start)) = inner.transactions.remove(&transaction) {
+                let meter_stop = payload["meterStop"].as_f64().unwrap_or(meter_start);
+                println!(
+                    "OCPP idTag {}: transaction {} ended, {:.0} Wh",
+                    id_tag,
+                    transaction,
+                    meter_stop - meter_start
+                );
+            }
+            Some(serde_json::json!({ "idTagInfo": { "status": "Accepted" } }))
+        }
+        "DataTransfer" => Some(serde_json::json!({ "status": "UnknownVendorId" })),
+        "DiagnosticsStatusNotification"
+        | "FirmwareStatusNotification"
+        | "LogStatusNotification"
+        | "NotifyEvent"
+        | "NotifyReport"
+        | "SecurityEventNotification" => Some(serde_json::json!({})),
+        _ => None,
+    }
+}
+
+/// Take the EV's charge current from the Current.Import measurand in
+/// a MeterValues or TransactionEvent request, if it's there.
+fn update_charging_current(inner: &std::sync::Mutex<Inner>, payload: &serde_json::Value) {
+    // Multi-phase charge points report each phase separately, use the
+    // highest.  1.6 sends the values as strings, 2.0.1 as numbers.
+    let currents: Vec<f64> = payload["meterValue"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|meter_value| meter_value["sampledValue"].as_array().into_iter().flatten())
+        .filter(|sampled| sampled["measurand"].as_str() == Some("Current.Import"))
+        .filter_map(|sampled| {
+            sampled["value"]
+                .as_f64()
+                .or_else(|| sampled["value"].as_str()?.parse::<f64>().ok())
+        })
+        .collect();
+    if let Some(current) = currents.into_iter().reduce(f64::max) {
+        inner.lock().unwrap().charging_current = Some(current);
+    }
+}
+
+/// Tell a 2.0.1 charge point to send the EV's charge current every
+/// meter interval, during transactions and outside them, by setting
+/// variables in its device model.  The answer isn't waited for, if the
+/// charge point doesn't take the settings it has to be configured by
+/// hand.
+fn configure_meter_values(inner: &std::sync::Mutex<Inner>) {
+    let mut inner = inner.lock().unwrap();
+    let Some(tx) = inner.tx.clone() else {
+        return;
+    };
+    let interval = inner.meter_interval.as_secs().to_string();
+    let variables: Vec<serde_json::Value> = [
+        ("SampledDataCtrlr", "TxUpdatedMeasurands", "Current.Import"),
+        ("SampledDataCtrlr", "TxUpdatedInterval", interval.as_str()),
+        ("AlignedDataCtrlr", "Measurands", "Current.Import"),
+        ("AlignedDataCtrlr", "Interval", interval.as_str()),
+    ]
+    .iter()
+    .map(|(component, variable, value)| {
+        serde_json::json!({
+            "attributeValue": value,
+            "component": { "name": component },
+            "variable": { "name": variable },
+        })
+    })
+    .collect();
+    inner.next_message_id += 1;
+    let id = inner.next_message_id.to_string();
+    let message = serde_json::json!([2, id, "SetVariables", { "setVariableData": variables }]);
+    let _ = tx.send(message.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inner(version: Version, id_tags: Option<&[&str]>) -> std::sync::Mutex<Inner> {
+        std::sync::Mutex::new(Inner {
+            version,
+            id_tags: id_tags.map(|tags| tags.iter().map(|tag| String::from(*tag)).collect()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn charging_current() {
+        let inner = inner(Version::V16, None);
+        // 1.6 sends strings, one per phase, among other measurands.
+        let payload = serde_json::json!({
+            "connectorId": 1,
+            "meterValue": [{
+                "timestamp": "2026-05-01T12:00:00Z",
+                "sampledValue": [
+                    { "value": "7400", "measurand": "Power.Active.Import" },
+                    { "value": "15.2", "measurand": "Current.Import", "phase": "L1" },
+                    { "value": "15.9", "measurand": "Current.Import", "phase": "L2" },
+                    { "value": "15.4", "measurand": "Current.Import", "phase": "L3" },
+                ],
+            }],
+        });
+        update_charging_current(&inner, &payload);
+        assert_eq!(inner.lock().unwrap().charging_current, Some(15.9));
+
+        // 2.0.1 sends numbers.
+        let payload = serde_json::json!({
+            "meterValue": [{
+                "sampledValue": [{ "value": 8.5, "measurand": "Current.Import" }],
+            }],
+        });
+        update_charging_current(&inner, &payload);
+        assert_eq!(inner.lock().unwrap().charging_current, Some(8.5));
+
+        // Without the measurand it stays as it was.
+        let payload = serde_json::json!({
+            "meterValue": [{
+                "sampledValue": [{ "value": "7400", "measurand": "Power.Active.Import" }],
+            }],
+        });
+        update_charging_current(&inner, &payload);
+        assert_eq!(inner.lock().unwrap().charging_current, Some(8.5));
+        update_charging_current(&inner, &serde_json::json!({}));
+        assert_eq!(inner.lock().unwrap().charging_current, Some(8.5));
+    }
+
+    #[test]
+    fn authorize_v16() {
+        let inner = inner(Version::V16, Some(&["04A2B1C3"]));
+        let status = |action: &str, payload: serde_json::Value| {
+            handle_call(&inner, action, &payload).unwrap()["idTagInfo"]["status"].clone()
+        };
+        assert_eq!(
+            status("Authorize", serde_json::json!({ "idTag": "04A2B1C3" })),
+            "Accepted"
+        );
+        assert_eq!(
+            status("Authorize", serde_json::json!({ "idTag": "DEADBEEF" })),
+            "Invalid"
+        );
+        assert_eq!(
+            status(
+                "StartTransaction",
+                serde_json::json!({ "connectorId": 1, "idTag": "DEADBEEF", "meterStart": 0 })
+            ),
+            "Invalid"
+        );
+        assert!(inner.lock().unwrap().transactions.is_empty());
+        assert_eq!(
+            status(
+                "StartTransaction",
+                serde_json::json!({ "connectorId": 1, "idTag": "04A2B1C3", "meterStart": 1000 })
+            ),
+            "Accepted"
+        );
+        assert_eq!(
+            inner.lock().unwrap().transactions[&2],
+            (String::from("04A2B1C3"), 1000.0)
+        );
+        status(
+            "StopTransaction",
+            serde_json::json!({ "transactionId": 2, "meterStop": 8342 }),
+        );
+        assert!(inner.lock().unwrap().transactions.is_empty());
+    }
+
+    #[test]
+    fn authorize_v201() {
+        let inner = inner(Version::V201, Some(&["04A2B1C3"]));
+        let status = |action: &str, payload: serde_json::Value| {
+            handle_call(&inner, action, &payload).unwrap()["idTokenInfo"]["status"].clone()
+        };
+        let token = |id: &str| serde_json::json!({ "idToken": id, "type": "ISO14443" });
+        assert_eq!(
+            status(
+                "Authorize",
+                serde_json::json!({ "idToken": token("04A2B1C3") })
+            ),
+            "Accepted"
+        );
+        assert_eq!(
+            status(
+                "Authorize",
+                serde_json::json!({ "idToken": token("DEADBEEF") })
+            ),
+            "Invalid"
+        );
+        assert_eq!(
+            status(
+                "TransactionEvent",
+                serde_json::json!({
+                    "eventType": "Started",
+                    "transactionInfo": { "transactionId": "t1" },
+                    "idToken": token("DEADBEEF"),
+                })
+            ),
+            "Invalid"
+        );
+        // Events without a token get an empty answer.
+        assert_eq!(
+            handle_call(
+                &inner,
+                "TransactionEvent",
+                &serde_json::json!({
+                    "eventType": "Updated",
+                    "transactionInfo": { "transactionId": "t1" },
+                })
+            ),
+            Some(serde_json::json!({}))
+        );
+    }
+
+    #[test]
+    fn boot() {
+        let inner = inner(Version::V16, None);
+        let result = handle_call(
+            &inner,
+            "BootNotification",
+            &serde_json::json!({ "chargePointVendor": "V", "chargePointModel": "M" }),
+        );
+        assert_eq!(result.unwrap()["status"], "Accepted");
+        // The charging profile's sent again once it's answered.
+        assert!(inner.lock().unwrap().booted);
+    }
+
+    #[test]
+    fn status() {
+        let inner = inner(Version::V16, None);
+        let notify = |connector: i64, status: &str, error: &str| {
+            let payload = serde_json::json!({
+                "connectorId": connector,
+                "status": status,
+                "errorCode": error,
+            });
+            handle_call(&inner, "StatusNotification", &payload).unwrap();
+            inner.lock().unwrap().status.clone()
+        };
+        assert_eq!(
+            notify(1, "Preparing", "NoError").vehicle_connected,
+            Some(true)
+        );
+        let status = notify(0, "Faulted", "GroundFailure");
+        assert_eq!(status.vehicle_connected, Some(true));
+        assert_eq!(status.fault.as_deref(), Some("GroundFailure"));
+        let status = notify(1, "Available", "NoError");
+        assert_eq!(status.vehicle_connected, Some(false));
+        assert_eq!(status.fault, None);
+
+        let inner = self::inner(Version::V201, None);
+        let payload =
+            serde_json::json!({ "evseId": 1, "connectorId": 1, "connectorStatus": "Occupied" });
+        handle_call(&inner, "StatusNotification", &payload).unwrap();
+        assert_eq!(inner.lock().unwrap().status.vehicle_connected, Some(true));
+    }
+
+    #[test]
+    fn authorize_anyone() {
+        let inner = inner(Version::V16, None);
+        let result = handle_call(
+            &inner,
+            "Authorize",
+            &serde_json::json!({ "idTag": "DEADBEEF" }),
+        );
+        assert_eq!(result.unwrap()["idTagInfo"]["status"], "Accepted");
+    }
+}
@@ -8,9 +8,62 @@
 //   "ret": "$OK 30 0121^21"
 // }
 // ```
+//
+// Each command we use has a function that returns what the reply means,
+// rather than the reply itself.  Replies are "$OK" and the values,
+// separated by spaces, and maybe a "^" and a checksum; or "$NK" if the
+// EVSE didn't like the command.  `request()` takes them apart into a
+// `RapiResponse`, so a "$NK" or a reply that's too short is an error
+// saying so, not a value that failed to parse.
+//
+// The checksum is the XOR of the bytes before the "^", in hex ("$GE" is
+// "$GE^26").  We put one on every command, so the OpenEVSE ignores one
+// that got mangled on the way, and check the one on every reply, so we
+// don't act on a mangled one: a reply that fails is asked for again, and
+// if they all fail it's a `ChecksumMismatch` error.
+//
+// WiFi firmware 4 and up also answers `/status` and `/config` with JSON,
+// which has most of what we ask for every cycle in one request:
+//
+// ```text
+// $ curl --silent http://openevse/status | jq .
+// {
+//   "amp": 12340,
+//   "pilot": 16,
+//   "state": 3,
+//   "vehicle": 1,
+//   "elapsed": 1234,
+//   "session_energy": 2468.5,
+//   ...
+// }
+// ```
+//
+// So we ask that first, and keep the answer for a moment so a cycle's
+// questions are one request, not one each.  Firmware without it gets a
+// 404 (or something that isn't the JSON we want), and from then on it's
+// all RAPI.  Commands, and anything `/status` doesn't say, are RAPI
+// regardless.
+//
+// Except with --openevse-claims, where the charge current, and whether
+// to charge at all, are a claim on the WiFi firmware's `/claims` API
+// instead of `$SC`, `$FE` and `$FS`:
+//
+// ```text
+// $ curl -X POST -d '{"state": "active", "charge_current": 16, "priority": 10}' http://openevse/claims/327681
+// {"msg": "done"}
+// ```
+//
+// The EVSE weighs our claim against its own timers and the manual
+// override (`/override`, its front panel's "charge now"), which have
+// higher priorities, so they win instead of being undone by us every
+// cycle.  When we stop, the claim is released, and the EVSE goes back
+// to its own settings.
 
 use std::str::FromStr;
 
+/// The "EV connected" bit of the vflags in a `$GS` reply.
+const ECVF_EV_CONNECTED: u32 = 0x0100;
+
 #[derive(Debug, serde::Deserialize, Clone)]
 pub struct RapiReply {
     #[allow(dead_code)]
@@ -18,103 +71,558 @@ pub struct RapiReply {
     ret: String,
 }
 
+/// The EVSE state, as reported by RAPI `$GS` or the `openevse/state`
+/// MQTT topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvseState {
+    NotConnected,
+    Connected,
+    Charging,
+    VentRequired,
+    DiodeCheckFailed,
+    GfciFault,
+    NoGround,
+    StuckRelay,
+    GfciSelfTestFailed,
+    OverTemperature,
+    OverCurrent,
+    Sleeping,
+    Disabled,
+    Unknown(u8),
+}
+
+impl EvseState {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Self::NotConnected,
+            2 => Self::Connected,
+            3 => Self::Charging,
+            4 => Self::VentRequired,
+            5 => Self::DiodeCheckFailed,
+            6 => Self::GfciFault,
+            7 => Self::NoGround,
+            8 => Self::StuckRelay,
+            9 => Self::GfciSelfTestFailed,
+            10 => Self::OverTemperature,
+            11 => Self::OverCurrent,
+            254 => Self::Sleeping,
+            255 => Self::Disabled,
+            _ => Self::Unknown(code),
+        }
+    }
+
+    /// True if the EVSE will offer current to a connected EV, false if
+    /// it's been put to sleep or disabled.
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Sleeping | Self::Disabled)
+    }
+
+    /// True if the EVSE is reporting an error condition.
+    pub fn is_fault(&self) -> bool {
+        matches!(
+            self,
+            Self::VentRequired
+                | Self::DiodeCheckFailed
+                | Self::GfciFault
+                | Self::NoGround
+                | Self::StuckRelay
+                | Self::GfciSelfTestFailed
+                | Self::OverTemperature
+                | Self::OverCurrent
+        )
+    }
+
+    /// Whether an EV is plugged in, if this state tells us.  (The EVSE
+    /// doesn't report this while it's asleep or faulted.)
+    pub fn is_vehicle_connected(&self) -> Option<bool> {
+        match self {
+            Self::NotConnected => Some(false),
+            Self::Connected | Self::Charging => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// What `$GS` says.
+#[derive(Debug, Clone, Copy)]
+pub struct State {
+    pub evse: EvseState,
+
+    // How long the EV's been charging, or charged for last time.
+    pub elapsed: std::time::Duration,
+
+    // The pilot state and the vflags, from firmware 5 and up.
+    pub pilot: Option<u8>,
+    pub vflags: Option<u32>,
+}
+
+impl State {
+    /// Whether an EV is plugged in, if the EVSE says.  A sleeping
+    /// unit's state doesn't, but the vflags do.
+    pub fn is_vehicle_connected(&self) -> Option<bool> {
+        self.evse
+            .is_vehicle_connected()
+            .or(self.vflags.map(|vflags| vflags & ECVF_EV_CONNECTED != 0))
+    }
+}
+
+/// The firmware and RAPI protocol versions, from `$GV`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Version {
+    pub firmware: String,
+    pub protocol: String,
+}
+
+/// The range the charge current can be set in, in Amps, from `$GC`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct CurrentRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+/// The charge current and voltage, from `$GG`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Charging {
+    pub amps: f64,
+
+    // None without a voltmeter.
+    pub volts: Option<f64>,
+}
+
+/// The temperatures of whichever sensors the EVSE has, in degrees
+/// Celsius, from `$GP`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Temperatures {
+    pub ds3231: Option<f64>,
+    pub mcp9808: Option<f64>,
+    pub tmp007: Option<f64>,
+}
+
+/// Energy delivered, from `$GU`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct EnergyCounters {
+    // This session, and ever.
+    pub session_wh: f64,
+    pub total_wh: f64,
+}
+
+/// How many times the EVSE has tripped on each kind of fault, from
+/// RAPI `$GF`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct FaultCounts {
+    pub gfci: u64,
+    pub no_ground: u64,
+    pub stuck_relay: u64,
+}
+
+/// The RAPI checksum of `text`.
+pub fn checksum(text: &str) -> u8 {
+    text.bytes().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// A reply whose checksum doesn't match what came before it.
 #[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub reply: String,
+    pub expected: u8,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bad checksum in RAPI reply {:?}, expected {:02X}",
+            self.reply, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Check the checksum on a reply, if it has one.
+fn verify(reply: &str) -> Result<(), ChecksumMismatch> {
+    let Some((text, sum)) = reply.rsplit_once('^') else {
+        return Ok(());
+    };
+    let expected = checksum(text);
+    match u8::from_str_radix(sum.trim(), 16) {
+        Ok(sum) if sum == expected => Ok(()),
+        _ => Err(ChecksumMismatch {
+            reply: String::from(reply),
+            expected,
+        }),
+    }
+}
+
+/// A RAPI reply, taken apart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RapiResponse {
+    /// "$OK" and the values after it.
+    Ok(Vec<String>),
+
+    /// "$NK": the EVSE didn't like the command.
+    Nk,
+
+    /// Anything else, as it came.
+    ParseError(String),
+}
+
+impl RapiResponse {
+    /// Take apart a reply, with its checksum (if any) already checked.
+    pub fn parse(reply: &str) -> Self {
+        // Some RAPI commands return a string like "$OK 26400 -1^0C"
+        // that we can split on whitespace, but some return a string
+        // like "$OK^20" that we can not, so the checksum goes first.
+        let text = reply.split('^').next().unwrap_or_default();
+        let mut tokens = text.split_whitespace();
+        match tokens.next() {
+            Some("$OK") => Self::Ok(tokens.map(String::from).collect()),
+            Some("$NK") => Self::Nk,
+            _ => Self::ParseError(String::from(reply)),
+        }
+    }
+
+    /// The values, or an error if it isn't "$OK".
+    pub fn values(&self) -> Result<&[String], eyre::Report> {
+        match self {
+            Self::Ok(values) => Ok(values),
+            Self::Nk => Err(eyre::eyre!("the OpenEVSE said $NK")),
+            Self::ParseError(reply) => Err(eyre::eyre!("can't parse RAPI reply {:?}", reply)),
+        }
+    }
+
+    fn get(&self, i: usize) -> Result<&str, eyre::Report> {
+        self.values()?
+            .get(i)
+            .map(String::as_str)
+            .ok_or_else(|| eyre::eyre!("RAPI reply \"{}\" is too short", self))
+    }
+
+    /// The `i`th value.
+    pub fn value<T: FromStr>(&self, i: usize) -> Result<T, eyre::Report> {
+        let value = self.get(i)?;
+        T::from_str(value)
+            .map_err(|_| eyre::eyre!("can't parse {:?} in RAPI reply \"{}\"", value, self))
+    }
+
+    /// The `i`th value, in hex.
+    pub fn hex_value(&self, i: usize) -> Result<u64, eyre::Report> {
+        let value = self.get(i)?;
+        u64::from_str_radix(value, 16)
+            .map_err(|_| eyre::eyre!("can't parse {:?} in RAPI reply \"{}\"", value, self))
+    }
+}
+
+impl std::fmt::Display for RapiResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok(values) => {
+                write!(f, "$OK")?;
+                for value in values {
+                    write!(f, " {value}")?;
+                }
+                Ok(())
+            }
+            Self::Nk => write!(f, "$NK"),
+            Self::ParseError(reply) => write!(f, "{reply}"),
+        }
+    }
+}
+
+/// The parts of the WiFi firmware's `/status` we use.  Older firmware
+/// leaves some out.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct JsonStatus {
+    /// In milliamps.
+    pub amp: f64,
+    /// The current offered, in amps.
+    pub pilot: f64,
+    /// The EVSE state, the same number as `$GS`.
+    pub state: u8,
+    pub vehicle: Option<u8>,
+    /// Seconds.
+    pub elapsed: Option<u64>,
+    /// Watt-hours this session, or Watt-seconds from older firmware.
+    pub session_energy: Option<f64>,
+    pub wattsec: Option<f64>,
+}
+
+impl JsonStatus {
+    pub fn session_wh(&self) -> Option<f64> {
+        self.session_energy
+            .or(self.wattsec.map(|wattsec| wattsec / 3600.0))
+    }
+}
+
+/// The parts of the WiFi firmware's `/config` we use.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct JsonConfig {
+    firmware: Option<String>,
+    protocol: Option<String>,
+    min_current_hard: Option<f64>,
+    max_current_hard: Option<f64>,
+}
+
+/// How long a `/status` answers questions for.
+const STATUS_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Who our claims are from, in the EVSE's list of claims: an ID of our
+/// own, so as not to be mistaken for one of the firmware's.
+const CLAIM_CLIENT: u32 = 0x0005_0001;
+
+/// What we've claimed so far, all of which goes in every claim.
+#[derive(Debug, Default)]
+struct Claim {
+    priority: u32,
+    state: Option<&'static str>,
+    charge_current: Option<isize>,
+}
+
+#[derive(Debug, Default)]
+struct JsonApi {
+    // None until we've asked, then whether the firmware has it.
+    available: Option<bool>,
+    // The last `/status`, and when we got it.
+    status: Option<(std::time::Instant, JsonStatus)>,
+}
+
+#[derive(Debug, Clone)]
 pub struct OpenEVSE {
     openevse_hostname: String,
+    client: reqwest::Client,
+    capture: Option<std::sync::Arc<crate::capture::Capture>>,
+    json: std::sync::Arc<std::sync::Mutex<JsonApi>>,
+    // With --openevse-claims.
+    claim: Option<std::sync::Arc<std::sync::Mutex<Claim>>>,
 }
 
 impl OpenEVSE {
-    pub fn new(openevse_hostname: &str) -> Self {
+    pub fn new(
+        openevse_hostname: &str,
+        client: reqwest::Client,
+        capture: Option<std::sync::Arc<crate::capture::Capture>>,
+    ) -> Self {
         Self {
             openevse_hostname: String::from(openevse_hostname),
+            client,
+            capture,
+            json: Default::default(),
+            claim: None,
         }
     }
 
-    pub async fn enable(&self) -> Result<(), eyre::Report> {
-        let _data = self.request(&["FE"]).await?;
-        // println!("enable: {}", data);
+    /// Set the current, enable and sleep with a claim at `priority`
+    /// rather than with RAPI.
+    pub fn with_claims(mut self, priority: u32) -> Self {
+        self.claim = Some(std::sync::Arc::new(std::sync::Mutex::new(Claim {
+            priority,
+            ..Claim::default()
+        })));
+        self
+    }
+
+    fn claim_url(&self) -> String {
+        format!(
+            "http://{}/claims/{}",
+            crate::address_book::url_host(&self.openevse_hostname),
+            CLAIM_CLIENT
+        )
+    }
+
+    /// Change our claim, and make it again with the change.
+    async fn update_claim(
+        &self,
+        claim: &std::sync::Mutex<Claim>,
+        change: impl FnOnce(&mut Claim),
+    ) -> Result<(), eyre::Report> {
+        let body = {
+            let mut claim = claim.lock().unwrap();
+            change(&mut claim);
+            let mut body = serde_json::json!({
+                "priority": claim.priority,
+                "auto_release": false,
+            });
+            if let Some(state) = claim.state {
+                body["state"] = serde_json::json!(state);
+            }
+            if let Some(amps) = claim.charge_current {
+                body["charge_current"] = serde_json::json!(amps);
+            }
+            body
+        };
+        self.forget_json_status();
+        let url = self.claim_url();
+        let reply = self
+            .client
+            .post(&url)
+            .json(&body)
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| eyre::eyre!("OpenEVSE claim failed (it needs WiFi firmware 4): {e}"))?
+            .text()
+            .await?;
+        if let Some(capture) = &self.capture {
+            capture.record("openevse", &url, &reply);
+        }
         Ok(())
     }
 
-    pub async fn sleep(&self) -> Result<(), eyre::Report> {
-        let _data = self.request(&["FS"]).await?;
-        // println!("sleep: {}", data);
+    /// Put the WiFi firmware's own solar divert in Eco mode, following
+    /// the grid import/export it's told about over MQTT, or back in
+    /// Normal mode.
+    pub async fn set_divert_mode(&self, eco: bool) -> Result<(), eyre::Report> {
+        let url = format!(
+            "http://{}/divertmode",
+            crate::address_book::url_host(&self.openevse_hostname)
+        );
+        let body = format!("divertmode={}", if eco { 2 } else { 1 });
+        let reply = self
+            .client
+            .post(&url)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(body)
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if let Some(capture) = &self.capture {
+            capture.record("openevse", &url, &reply);
+        }
         Ok(())
     }
 
-    /// Read amount of current currently being drawn by the EV, in amps.
-    pub async fn get_active_charging_current(&self) -> Result<f64, eyre::Report> {
-        // `reply` will be a string like "$OK 1234 -1^0C", where the
-        // 1234 is the current in milliamps.
-        let reply = self.request(&["GG"]).await?;
+    /// Whoever's set the manual override (the front panel, the app), and
+    /// what it says, if anyone has.
+    pub async fn get_override(&self) -> Result<Option<serde_json::Value>, eyre::Report> {
+        let value: serde_json::Value = self.get_json("override").await?;
+        Ok(value
+            .as_object()
+            .is_some_and(|o| !o.is_empty())
+            .then_some(value))
+    }
 
-        let mut tokens = reply.split_whitespace();
-        match tokens.next() {
-            Some("$OK") => {
-                let i = f64::from_str(tokens.next().unwrap())? / 1000.0;
-                return Ok(i);
+    /// GET `path` from the WiFi firmware's JSON API.
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, eyre::Report> {
+        let url = format!(
+            "http://{}/{}",
+            crate::address_book::url_host(&self.openevse_hostname),
+            path
+        );
+        let body = self
+            .client
+            .get(&url)
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if let Some(capture) = &self.capture {
+            capture.record("openevse", &url, &body);
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// GET `path`, unless we know the firmware hasn't got the JSON API.
+    /// None means ask with RAPI instead.
+    async fn try_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Option<T> {
+        if self.json.lock().unwrap().available == Some(false) {
+            return None;
+        }
+        let result = self.get_json(path).await;
+        let mut json = self.json.lock().unwrap();
+        match result {
+            Ok(value) => {
+                if json.available.is_none() {
+                    println!("using the OpenEVSE's JSON API");
+                }
+                json.available = Some(true);
+                Some(value)
             }
-            _ => {
-                return Err(eyre::Report::msg(format!("{:#?}", reply)));
+            Err(e) => {
+                // Not being able to reach it says nothing about its
+                // firmware, and RAPI won't do any better.
+                let unreachable = e
+                    .downcast_ref::<reqwest::Error>()
+                    .is_some_and(|e| e.is_connect() || e.is_timeout());
+                if !unreachable && json.available.is_none() {
+                    println!("the OpenEVSE has no JSON API ({e:#}), using RAPI");
+                    json.available = Some(false);
+                } else {
+                    println!("OpenEVSE /{path} failed, using RAPI: {e:#}");
+                }
+                None
             }
         }
     }
 
-    /// Read amount of current currently being offered by the EVSE to
-    /// the EV, in amps.
-    ///
-    /// Note: This does not take into account the EVSE state, e.g. when
-    /// it's in Sleep mode it will not offer any current but this function
-    /// will report what it *would* offer if it was Enabled.
-    pub async fn get_current_capacity(&self) -> Result<f64, eyre::Report> {
-        let reply = self.request(&["GE"]).await?;
-
-        let mut tokens = reply.split_whitespace();
-        match tokens.next() {
-            Some("$OK") => {
-                let i = f64::from_str(tokens.next().unwrap())?;
-                return Ok(i);
-            }
-            _ => {
-                return Err(eyre::Report::msg(format!("{:#?}", reply)));
+    /// The WiFi firmware's `/status`, at most STATUS_MAX_AGE old.
+    pub async fn get_json_status(&self) -> Option<JsonStatus> {
+        if let Some((time, status)) = &self.json.lock().unwrap().status {
+            if time.elapsed() < STATUS_MAX_AGE {
+                return Some(status.clone());
             }
         }
+        let status: JsonStatus = self.try_json("status").await?;
+        self.json.lock().unwrap().status = Some((std::time::Instant::now(), status.clone()));
+        Some(status)
     }
 
-    pub async fn set_current_capacity(
-        &self,
-        charge_current_limit: isize,
-    ) -> Result<(), eyre::Report> {
-        let _data = self
-            .request(&["SC", &format!("{}", charge_current_limit)])
-            .await?;
-        // println!("set_current_capacity({}): {}", charge_current_limit, data);
-        Ok(())
+    /// Forget the last `/status`, for when we've just changed what it
+    /// would say.
+    fn forget_json_status(&self) {
+        self.json.lock().unwrap().status = None;
     }
 
-    pub async fn request(&self, command: &[&str]) -> Result<String, eyre::Report> {
+    pub async fn request(&self, command: &[&str]) -> Result<RapiResponse, eyre::Report> {
         const NUM_RETRIES: usize = 18;
         const RETRY_DELAY_SECONDS: u64 = 10;
+        const CHECKSUM_RETRIES: usize = 3;
 
         let mut url = format!(
             "http://{}/r?json=1&rapi=%24{}",
-            self.openevse_hostname, command[0]
+            crate::address_book::url_host(&self.openevse_hostname),
+            command[0]
         );
         for arg in command[1..].iter() {
             url += &format!("+{arg}");
         }
+        url += &format!("%5E{:02X}", checksum(&format!("${}", command.join(" "))));
 
+        let mut mismatches = 0;
         for _ in 0..NUM_RETRIES {
-            match reqwest::get(&url).await {
+            match self.client.get(&url).send().await {
                 Ok(response) => {
                     match response.text().await {
                         Ok(body) => {
+                            if let Some(capture) = &self.capture {
+                                capture.record("openevse", &url, &body);
+                            }
                             let rapi_reply: RapiReply = serde_json::from_str(&body)?;
-                            // Some RAPI commands return a string like
-                            // "$OK 26400 -1^0C" that we can split on
-                            // whitespace, but some return a string like
-                            // "$OK^20" that we can not. :-(
-                            return Ok(rapi_reply.ret);
+                            match verify(&rapi_reply.ret) {
+                                Ok(()) => return Ok(RapiResponse::parse(&rapi_reply.ret)),
+                                Err(e) => {
+                                    println!("{e}");
+                                    mismatches += 1;
+                                    if mismatches == CHECKSUM_RETRIES {
+                                        return Err(e.into());
+                                    }
+                                    // Ask again right away, it was
+                                    // the reply that got mangled, not
+                                    // the connection.
+                                    continue;
+                                }
+                            }
                         }
                         Err(e) => {
                             println!("OpenEVSE request text failed: {:?}", e);
@@ -129,9 +637,347 @@ impl OpenEVSE {
             // a bit then retry (or give up).
             tokio::time::sleep(tokio::time::Duration::from_secs(RETRY_DELAY_SECONDS)).await;
         }
-        return Err(eyre::Report::msg(format!(
+        Err(eyre::Report::msg(format!(
             "giving up after {} OpenEVSE Request failures",
             NUM_RETRIES
-        )));
+        )))
+    }
+
+    /// Send a command that only says $OK or not.
+    async fn command(&self, command: &[&str]) -> Result<(), eyre::Report> {
+        self.request(command).await?.values()?;
+        Ok(())
+    }
+
+    pub async fn get_version(&self) -> Result<Version, eyre::Report> {
+        if let Some(JsonConfig {
+            firmware: Some(firmware),
+            protocol: Some(protocol),
+            ..
+        }) = self.try_json("config").await
+        {
+            return Ok(Version { firmware, protocol });
+        }
+        // `reply` will be a string like "$OK 7.1.3 5.0.1^21".
+        let reply = self.request(&["GV"]).await?;
+        Ok(Version {
+            firmware: reply.value(0)?,
+            protocol: reply.value(1)?,
+        })
+    }
+
+    pub async fn get_current_range(&self) -> Result<CurrentRange, eyre::Report> {
+        if let Some(JsonConfig {
+            min_current_hard: Some(min),
+            max_current_hard: Some(max),
+            ..
+        }) = self.try_json("config").await
+        {
+            return Ok(CurrentRange { min, max });
+        }
+        // `reply` will be a string like "$OK 6 32^17".
+        let reply = self.request(&["GC"]).await?;
+        Ok(CurrentRange {
+            min: reply.value(0)?,
+            max: reply.value(1)?,
+        })
+    }
+
+    pub async fn get_charging(&self) -> Result<Charging, eyre::Report> {
+        // `reply` will be a string like "$OK 1234 -1^38", where the
+        // 1234 is the current in milliamps, and -1 the voltage in
+        // millivolts if there were a voltmeter.
+        let reply = self.request(&["GG"]).await?;
+        let millivolts: f64 = reply.value(1).unwrap_or(-1.0);
+        Ok(Charging {
+            amps: reply.value::<f64>(0)? / 1000.0,
+            volts: (millivolts >= 0.0).then_some(millivolts / 1000.0),
+        })
+    }
+
+    pub async fn get_state(&self) -> Result<State, eyre::Report> {
+        // `reply` will be a string like "$OK 254 1234 2 0100^24",
+        // where the 254 is the EVSE state, 1234 the seconds the EV's
+        // been charging, 2 the pilot state, and 0100 the vflags (in
+        // hex).  Older firmware sends only the first two.
+        let reply = self.request(&["GS"]).await?;
+        let code = match reply.value::<u8>(0) {
+            Ok(code) => code,
+            Err(_) => reply.hex_value(0)? as u8,
+        };
+        Ok(State {
+            evse: EvseState::from_code(code),
+            elapsed: std::time::Duration::from_secs(reply.value(1)?),
+            pilot: reply.hex_value(2).ok().map(|pilot| pilot as u8),
+            vflags: reply.hex_value(3).ok().map(|vflags| vflags as u32),
+        })
+    }
+
+    pub async fn get_temperatures(&self) -> Result<Temperatures, eyre::Report> {
+        // `reply` will be a string like "$OK 230 -2560 -2560^31", in
+        // tenths of a degree, -2560 for a missing sensor.
+        let reply = self.request(&["GP"]).await?;
+        let celsius = |i| -> Result<Option<f64>, eyre::Report> {
+            let tenths: f64 = reply.value(i)?;
+            Ok((tenths > -2560.0).then_some(tenths / 10.0))
+        };
+        Ok(Temperatures {
+            ds3231: celsius(0)?,
+            mcp9808: celsius(1)?,
+            tmp007: celsius(2)?,
+        })
+    }
+
+    pub async fn get_energy(&self) -> Result<EnergyCounters, eyre::Report> {
+        // `reply` will be a string like "$OK 123456 7890123", the
+        // Watt-seconds this session and the Watt-hours ever.
+        let reply = self.request(&["GU"]).await?;
+        Ok(EnergyCounters {
+            session_wh: reply.value::<f64>(0)? / 3600.0,
+            total_wh: reply.value(1)?,
+        })
+    }
+
+    /// How many times the EVSE has tripped on each kind of fault.
+    pub async fn get_fault_counts(&self) -> Result<FaultCounts, eyre::Report> {
+        // `reply` will be a string like "$OK 2 0 1^33", the counts in
+        // hex.
+        let reply = self.request(&["GF"]).await?;
+        Ok(FaultCounts {
+            gfci: reply.hex_value(0)?,
+            no_ground: reply.hex_value(1)?,
+            stuck_relay: reply.hex_value(2)?,
+        })
+    }
+
+    /// When the EVSE's own delay timer lets it charge, None if it
+    /// isn't set.
+    pub async fn get_delay_timer(
+        &self,
+    ) -> Result<Option<crate::schedule::TimeWindow>, eyre::Report> {
+        // `reply` will be a string like "$OK 22 0 6 0^16", the start and
+        // end hours and minutes, all 0 if there's no timer.
+        let reply = self.request(&["GD"]).await?;
+        let time = |i| -> Result<chrono::NaiveTime, eyre::Report> {
+            chrono::NaiveTime::from_hms_opt(reply.value(i)?, reply.value(i + 1)?, 0)
+                .ok_or_else(|| eyre::eyre!("bad time in RAPI reply \"{}\"", reply))
+        };
+        let (start, end) = (time(0)?, time(2)?);
+        Ok((start != end).then_some(crate::schedule::TimeWindow { start, end }))
+    }
+
+    /// Set the EVSE's own delay timer, or clear it with None.
+    pub async fn set_delay_timer(
+        &self,
+        timer: Option<&crate::schedule::TimeWindow>,
+    ) -> Result<(), eyre::Report> {
+        use chrono::Timelike;
+        let times = match timer {
+            Some(timer) => [
+                timer.start.hour(),
+                timer.start.minute(),
+                timer.end.hour(),
+                timer.end.minute(),
+            ],
+            None => [0; 4],
+        };
+        let times = times.map(|t| t.to_string());
+        self.command(&["ST", &times[0], &times[1], &times[2], &times[3]])
+            .await
+    }
+
+    /// Stop charging after `minutes` (rounded up to a quarter of an
+    /// hour), this session only, or never with 0.
+    pub async fn set_time_limit(&self, minutes: u32) -> Result<(), eyre::Report> {
+        self.command(&["S3", &minutes.div_ceil(15).to_string()])
+            .await
+    }
+
+    /// The OpenEVSE's real-time clock, None if it doesn't have one.
+    pub async fn get_clock(&self) -> Result<Option<chrono::NaiveDateTime>, eyre::Report> {
+        // `reply` will be a string like "$OK 24 6 1 12 30 0^11", or
+        // "$OK 165 165 165 165 165 85" without a clock.
+        let reply = self.request(&["GT"]).await?;
+        let field = |i| reply.value::<u32>(i);
+        Ok(
+            chrono::NaiveDate::from_ymd_opt(2000 + field(0)? as i32, field(1)?, field(2)?)
+                .and_then(|date| date.and_hms_opt(field(3).ok()?, field(4).ok()?, field(5).ok()?)),
+        )
+    }
+
+    /// Set the OpenEVSE's real-time clock, which keeps local time (it
+    /// has no idea of time zones), for its timers and session logs.
+    pub async fn set_clock(&self, time: chrono::NaiveDateTime) -> Result<(), eyre::Report> {
+        use chrono::{Datelike, Timelike};
+        self.command(&[
+            "S1",
+            &(time.year() % 100).to_string(),
+            &time.month().to_string(),
+            &time.day().to_string(),
+            &time.hour().to_string(),
+            &time.minute().to_string(),
+            &time.second().to_string(),
+        ])
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::evse::Evse for OpenEVSE {
+    async fn enable(&self) -> Result<(), eyre::Report> {
+        if let Some(claim) = &self.claim {
+            return self
+                .update_claim(claim, |claim| claim.state = Some("active"))
+                .await;
+        }
+        self.forget_json_status();
+        self.command(&["FE"]).await
+    }
+
+    async fn sleep(&self) -> Result<(), eyre::Report> {
+        if let Some(claim) = &self.claim {
+            return self
+                .update_claim(claim, |claim| claim.state = Some("disabled"))
+                .await;
+        }
+        self.forget_json_status();
+        self.command(&["FS"]).await
+    }
+
+    /// Read amount of current currently being drawn by the EV, in amps.
+    async fn get_active_charging_current(&self) -> Result<f64, eyre::Report> {
+        if let Some(status) = self.get_json_status().await {
+            return Ok(status.amp / 1000.0);
+        }
+        Ok(self.get_charging().await?.amps)
+    }
+
+    /// Read amount of current currently being offered by the EVSE to
+    /// the EV, in amps.
+    ///
+    /// Note: This does not take into account the EVSE state, e.g. when
+    /// it's in Sleep mode it will not offer any current but this function
+    /// will report what it *would* offer if it was Enabled.
+    async fn get_current_capacity(&self) -> Result<f64, eyre::Report> {
+        if let Some(status) = self.get_json_status().await {
+            return Ok(status.pilot);
+        }
+        // `reply` will be a string like "$OK 30 0121^21", the current
+        // and the flags.
+        let reply = self.request(&["GE"]).await?;
+        reply.value(0)
+    }
+
+    async fn set_current_capacity(&self, charge_current_limit: isize) -> Result<(), eyre::Report> {
+        if let Some(claim) = &self.claim {
+            return self
+                .update_claim(claim, |claim| {
+                    claim.charge_current = Some(charge_current_limit)
+                })
+                .await;
+        }
+        self.forget_json_status();
+        self.command(&["SC", &format!("{}", charge_current_limit)])
+            .await
+    }
+
+    async fn status(&self) -> Result<crate::evse::Status, eyre::Report> {
+        if let Some(status) = self.get_json_status().await {
+            if let Some(vehicle) = status.vehicle {
+                let evse = EvseState::from_code(status.state);
+                return Ok(crate::evse::Status {
+                    vehicle_connected: Some(vehicle != 0),
+                    fault: evse.is_fault().then(|| format!("{:?}", evse)),
+                });
+            }
+        }
+        let state = self.get_state().await?;
+        Ok(crate::evse::Status {
+            vehicle_connected: state.is_vehicle_connected(),
+            fault: state.evse.is_fault().then(|| format!("{:?}", state.evse)),
+        })
+    }
+
+    async fn get_session(&self) -> Result<Option<crate::evse::Session>, eyre::Report> {
+        if let Some(status) = self.get_json_status().await {
+            if let (Some(elapsed), Some(energy_wh)) = (status.elapsed, status.session_wh()) {
+                if EvseState::from_code(status.state) != EvseState::Charging {
+                    return Ok(None);
+                }
+                return Ok(Some(crate::evse::Session {
+                    elapsed: std::time::Duration::from_secs(elapsed),
+                    energy_wh,
+                }));
+            }
+        }
+        let state = self.get_state().await?;
+        if state.evse != EvseState::Charging {
+            return Ok(None);
+        }
+        Ok(Some(crate::evse::Session {
+            elapsed: state.elapsed,
+            energy_wh: self.get_energy().await?.session_wh,
+        }))
+    }
+
+    async fn release(&self) -> Result<bool, eyre::Report> {
+        if self.claim.is_none() {
+            return Ok(false);
+        }
+        self.forget_json_status();
+        let url = self.claim_url();
+        let reply = self
+            .client
+            .delete(&url)
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if let Some(capture) = &self.capture {
+            capture.record("openevse", &url, &reply);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum("$GE"), 0x26);
+        assert_eq!(checksum("$OK 30 0121"), 0x21);
+        assert_eq!(checksum(""), 0);
+    }
+
+    #[test]
+    fn verify_reply() {
+        assert!(verify("$OK 30 0121^21").is_ok());
+        assert!(verify("$OK^20").is_ok());
+        // Replies from firmware that doesn't send checksums.
+        assert!(verify("$OK 30 0121").is_ok());
+
+        let e = verify("$OK 31 0121^21").unwrap_err();
+        assert_eq!(e.expected, checksum("$OK 31 0121"));
+        assert!(verify("$OK 30 0121^").is_err());
+        assert!(verify("$OK 30 0121^ZZ").is_err());
+    }
+
+    #[test]
+    fn parse_reply() {
+        let reply = RapiResponse::parse("$OK 26400 -1^0C");
+        assert_eq!(reply.value::<u32>(0).unwrap(), 26400);
+        assert_eq!(reply.value::<i32>(1).unwrap(), -1);
+        assert!(reply.value::<u32>(2).is_err());
+        assert_eq!(
+            RapiResponse::parse("$OK 30 0121^21").hex_value(1).unwrap(),
+            0x121
+        );
+        assert_eq!(RapiResponse::parse("$OK^20"), RapiResponse::Ok(Vec::new()));
+        assert!(RapiResponse::parse("$NK^21").values().is_err());
+        assert!(RapiResponse::parse("garbage").values().is_err());
     }
 }
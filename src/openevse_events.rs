@@ -0,0 +1,122 @@
+// Get the OpenEVSE's state changes pushed to us over HTTP, for setups
+// without an MQTT broker (or where the charger's WebSocket isn't
+// reachable), so we hear about them promptly instead of at the next
+// cycle.
+//
+// The URL can be a Server-Sent Events stream, where each event's data
+// is a JSON object of changed fields:
+//
+// ```text
+// data: {"amp": 15800, "pilot": 16}
+//
+// data: {"state": 254, "status": "disabled"}
+// ```
+//
+// or a long-poll URL that holds the request open until something
+// changes and then replies with such a JSON object, in which case we ask
+// again right away.
+//
+// The fields have the same names as the OpenEVSE's MQTT topics, so each
+// one becomes an ("openevse/<field>", value) message, handled just like
+// the MQTT message would be.
+
+pub type Event = (String, String);
+
+/// Start following the events at `url`, forever, reconnecting when the
+/// connection drops.
+pub fn follow(url: reqwest::Url, client: reqwest::Client) -> tokio::sync::mpsc::Receiver<Event> {
+    const RETRY_DELAY_SECONDS: u64 = 10;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = read_events(&client, &url, &tx).await {
+                println!("OpenEVSE event stream {url}: {e:#}");
+                tokio::time::sleep(std::time::Duration::from_secs(RETRY_DELAY_SECONDS)).await;
+            }
+            if tx.is_closed() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// The next event, or never if we're not following any.
+pub async fn next(rx: &mut Option<tokio::sync::mpsc::Receiver<Event>>) -> Option<Event> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Make one request and pass on its events, until the reply ends.
+async fn read_events(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    tx: &tokio::sync::mpsc::Sender<Event>,
+) -> Result<(), eyre::Report> {
+    let mut response = client
+        .get(url.clone())
+        .header(
+            reqwest::header::ACCEPT,
+            "text/event-stream, application/json",
+        )
+        .send()
+        .await?
+        .error_for_status()?;
+    let is_stream = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+
+    if !is_stream {
+        // Long-poll: the whole reply is one event.
+        let body = response.text().await?;
+        return send_fields(&body, tx).await;
+    }
+
+    let mut buffer = Vec::new();
+    let mut data = String::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                // A blank line ends the event.
+                if !data.is_empty() {
+                    send_fields(&data, tx).await?;
+                    data.clear();
+                }
+            } else if let Some(value) = line.strip_prefix("data:") {
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(value.strip_prefix(' ').unwrap_or(value));
+            }
+            // Ignore comments, and the event, id and retry fields.
+        }
+    }
+    Ok(())
+}
+
+async fn send_fields(
+    json: &str,
+    tx: &tokio::sync::mpsc::Sender<Event>,
+) -> Result<(), eyre::Report> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    let Some(fields) = json.as_object() else {
+        return Err(eyre::eyre!("event isn't a JSON object: {json}"));
+    };
+    for (name, value) in fields {
+        let payload = match value {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        tx.send((format!("openevse/{name}"), payload)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+// Switching a three-phase EVSE between charging on one phase and on
+// all three, with --phase-switching.  On three phases the EV can't
+// charge at less than three times the minimum charge current, which
+// leaves a lot of surplus unused on a cloudy day, while on one phase it
+// can't use more than the maximum.  So we charge on one phase while the
+// surplus is small, and on three once there's enough for the minimum
+// on all three, plus --phase-switch-hysteresis so we don't flap between
+// them.
+//
+// The EV has to stop charging while the phases change, so we only
+// switch once we've wanted to for --phase-switch-delay seconds.  The
+// switching is done by the EVSE if it can (OCPP charge points that
+// honor numberPhases in a charging profile), or by an external
+// contactor listening to --phase-switch-topic for "1" or "3".
+
+#[derive(Debug)]
+pub struct PhaseSwitch {
+    delay: std::time::Duration,
+    hysteresis_w: f64,
+
+    // The phases we'd like to switch to, and since when.
+    pending: Option<(u8, std::time::Instant)>,
+}
+
+impl PhaseSwitch {
+    pub fn new(delay: std::time::Duration, hysteresis_w: f64) -> Self {
+        Self {
+            delay,
+            hysteresis_w,
+            pending: None,
+        }
+    }
+
+    /// How many phases to switch to, if it's time to switch away from
+    /// charging on `active` phases.  `available_w` is the power the EV
+    /// could have, and `three_phase_min_w` what it takes to charge at
+    /// the minimum current on three phases.
+    pub fn check(&mut self, active: u8, available_w: f64, three_phase_min_w: f64) -> Option<u8> {
+        let wanted = if active == 1 && available_w >= three_phase_min_w + self.hysteresis_w {
+            3
+        } else if active == 3 && available_w < three_phase_min_w {
+            1
+        } else {
+            self.pending = None;
+            return None;
+        };
+        let now = std::time::Instant::now();
+        match self.pending {
+            Some((phases, since)) if phases == wanted => {
+                if now - since < self.delay {
+                    return None;
+                }
+                self.pending = None;
+                Some(wanted)
+            }
+            _ => {
+                println!(
+                    "{:.0} W available, switching to {} phase{} in {} s if it stays that way",
+                    available_w,
+                    wanted,
+                    if wanted == 1 { "" } else { "s" },
+                    self.delay.as_secs()
+                );
+                self.pending = Some((wanted, now));
+                None
+            }
+        }
+    }
+
+    /// Forget about switching, for when the EV isn't charging.
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The minimum on three phases at 6 A and 230 V.
+    const MIN_W: f64 = 3.0 * 6.0 * 230.0;
+
+    #[test]
+    fn hysteresis() {
+        // With no delay, each switch takes two checks in a row.
+        let mut switch = PhaseSwitch::new(std::time::Duration::ZERO, 500.0);
+        for available_w in [MIN_W, MIN_W + 499.0] {
+            assert_eq!(switch.check(1, available_w, MIN_W), None);
+            assert_eq!(switch.check(1, available_w, MIN_W), None);
+        }
+        assert_eq!(switch.check(1, MIN_W + 500.0, MIN_W), None);
+        assert_eq!(switch.check(1, MIN_W + 500.0, MIN_W), Some(3));
+
+        // Back to one phase only below the minimum, without the
+        // hysteresis.
+        for available_w in [MIN_W + 500.0, MIN_W] {
+            assert_eq!(switch.check(3, available_w, MIN_W), None);
+            assert_eq!(switch.check(3, available_w, MIN_W), None);
+        }
+        assert_eq!(switch.check(3, MIN_W - 1.0, MIN_W), None);
+        assert_eq!(switch.check(3, MIN_W - 1.0, MIN_W), Some(1));
+    }
+
+    #[test]
+    fn delay() {
+        let delay = std::time::Duration::from_millis(50);
+        let mut switch = PhaseSwitch::new(delay, 500.0);
+        assert_eq!(switch.check(1, 6000.0, MIN_W), None);
+        assert_eq!(switch.check(1, 6000.0, MIN_W), None);
+
+        // A dip starts the wait over.
+        std::thread::sleep(delay);
+        assert_eq!(switch.check(1, 4000.0, MIN_W), None);
+        assert_eq!(switch.check(1, 6000.0, MIN_W), None);
+        assert_eq!(switch.check(1, 6000.0, MIN_W), None);
+        std::thread::sleep(delay);
+        assert_eq!(switch.check(1, 6000.0, MIN_W), Some(3));
+
+        // And so does not charging.
+        assert_eq!(switch.check(3, 3000.0, MIN_W), None);
+        std::thread::sleep(delay);
+        switch.reset();
+        assert_eq!(switch.check(3, 3000.0, MIN_W), None);
+        std::thread::sleep(delay);
+        assert_eq!(switch.check(3, 3000.0, MIN_W), Some(1));
+    }
+}
@@ -0,0 +1,378 @@
+// Charging policies from outside: a program embedding the controller
+// (see `ControllerBuilder::policy()`) can have its say on the charge
+// current limit every cycle, after the control loop and the built-in
+// rules (the mode, prices, the deadline, ...), but before the charge
+// current cap (the "limit" command), the grid operator's limit, and
+// the house's current limits, which always win.  What a policy chooses
+// is held to --evse-max-charge-current, and to --max-discharge-current
+// the other way if the EVSE can discharge the EV (or 0 if it can't);
+// below --evse-min-charge-current it's 0.  A policy that chooses NaN
+// leaves the limit alone.
+//
+// A policy sees the same numbers the --sensor expressions do
+// (`export_current`, `evse_charge_limit`, `ev_soc`, ...), and the
+// --sensors themselves, and the policies go in the order they were
+// added, each getting the limit the one before it chose.
+//
+// Policies can be put together out of others:
+//
+// - `MinOf`: the lowest limit any of them chooses,
+// - `Scheduled`: one policy in a time of day window, another outside it,
+// - `Override`: one policy while a condition holds, laid over another
+//   the rest of the time.
+//
+// and those can be written in the --config file (or with --policy) as
+// "name=policy", where a policy is one of
+//
+// ```text
+// min(POLICY, POLICY, ...)
+// schedule(HH:MM-HH:MM, POLICY, OTHERWISE)
+// override(CONDITION, POLICY, OTHERWISE)
+// EXPRESSION
+// ```
+//
+// An expression (and a condition, which holds when it isn't 0) is a
+// --sensor expression, which can use `limit`, the limit chosen so far,
+// `boost` (1 while boosting), and `grid_limit_w` while the grid operator
+// has set a limit.  So "solar only, but no more than 10 A at night, and
+// no more than 16 A unless boosting":
+//
+// ```text
+// policy = [
+//     "quiet=schedule(22:00-07:00, min(limit, 10), limit)",
+//     "capped=override(boost, limit, min(limit, 16))",
+// ]
+// ```
+
+use std::str::FromStr;
+
+pub trait Policy: Send + Sync {
+    /// What to call it in the logs.
+    fn name(&self) -> &str;
+
+    /// The charge current limit to use, in Amps, given `limit`, the one
+    /// chosen so far.  0 means don't charge.
+    fn limit(&mut self, inputs: &std::collections::HashMap<String, f64>, limit: f64) -> f64;
+}
+
+/// The lowest limit any of the policies chooses, each given the same
+/// limit to start from.
+pub struct MinOf(pub Vec<Box<dyn Policy>>);
+
+impl Policy for MinOf {
+    fn name(&self) -> &str {
+        "min"
+    }
+
+    fn limit(&mut self, inputs: &std::collections::HashMap<String, f64>, limit: f64) -> f64 {
+        self.0
+            .iter_mut()
+            .map(|policy| policy.limit(inputs, limit))
+            .fold(limit, f64::min)
+    }
+}
+
+/// `during` in `window`, and `otherwise` the rest of the day.
+pub struct Scheduled {
+    pub window: crate::schedule::TimeWindow,
+    pub during: Box<dyn Policy>,
+    pub otherwise: Box<dyn Policy>,
+}
+
+impl Policy for Scheduled {
+    fn name(&self) -> &str {
+        "schedule"
+    }
+
+    fn limit(&mut self, inputs: &std::collections::HashMap<String, f64>, limit: f64) -> f64 {
+        if self.window.now() {
+            self.during.limit(inputs, limit)
+        } else {
+            self.otherwise.limit(inputs, limit)
+        }
+    }
+}
+
+/// Whether an `Override` is on, given the inputs.
+pub type Condition = Box<dyn Fn(&std::collections::HashMap<String, f64>) -> bool + Send + Sync>;
+
+/// `layer` while `active` says so, and `base` the rest of the time.
+pub struct Override {
+    pub active: Condition,
+    pub layer: Box<dyn Policy>,
+    pub base: Box<dyn Policy>,
+}
+
+impl Policy for Override {
+    fn name(&self) -> &str {
+        "override"
+    }
+
+    fn limit(&mut self, inputs: &std::collections::HashMap<String, f64>, limit: f64) -> f64 {
+        if (self.active)(inputs) {
+            self.layer.limit(inputs, limit)
+        } else {
+            self.base.limit(inputs, limit)
+        }
+    }
+}
+
+/// A --sensor expression for the limit, which can use `limit`.  If it
+/// can't be computed the limit stays as it was.
+pub struct Expression(crate::sensor::Sensor);
+
+impl Policy for Expression {
+    fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    fn limit(&mut self, inputs: &std::collections::HashMap<String, f64>, limit: f64) -> f64 {
+        let mut inputs = inputs.clone();
+        inputs.insert(String::from("limit"), limit);
+        match self.0.evaluate(&inputs) {
+            Ok(value) if value.is_finite() => value.max(0.0),
+            Ok(value) => {
+                println!("policy expression {} gave {}", self.0.name, value);
+                limit
+            }
+            Err(e) => {
+                println!("can't compute policy expression {}: {:#}", self.0.name, e);
+                limit
+            }
+        }
+    }
+}
+
+/// A policy put together in the --config file, with --policy.
+#[derive(Debug, Clone)]
+pub struct Spec {
+    pub name: String,
+    node: Node,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    MinOf(Vec<Node>),
+    Scheduled(crate::schedule::TimeWindow, Box<Node>, Box<Node>),
+    Override(crate::sensor::Sensor, Box<Node>, Box<Node>),
+    Expression(crate::sensor::Sensor),
+}
+
+impl Spec {
+    /// A new policy made to the spec, for the controller.
+    pub fn build(&self) -> Box<dyn Policy> {
+        Box::new(Named {
+            name: self.name.clone(),
+            policy: self.node.build(),
+        })
+    }
+}
+
+impl Node {
+    fn build(&self) -> Box<dyn Policy> {
+        match self {
+            Self::MinOf(nodes) => Box::new(MinOf(nodes.iter().map(Node::build).collect())),
+            Self::Scheduled(window, during, otherwise) => Box::new(Scheduled {
+                window: *window,
+                during: during.build(),
+                otherwise: otherwise.build(),
+            }),
+            Self::Override(condition, layer, base) => {
+                let condition = condition.clone();
+                Box::new(Override {
+                    active: Box::new(move |inputs| match condition.evaluate(inputs) {
+                        Ok(value) => value != 0.0,
+                        Err(e) => {
+                            println!(
+                                "can't compute override condition {}: {:#}",
+                                condition.name, e
+                            );
+                            false
+                        }
+                    }),
+                    layer: layer.build(),
+                    base: base.build(),
+                })
+            }
+            Self::Expression(sensor) => Box::new(Expression(sensor.clone())),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, eyre::Report> {
+        let s = s.trim();
+        let call = s
+            .split_once('(')
+            .and_then(|(function, rest)| Some((function.trim(), rest.strip_suffix(')')?)));
+        let Some((function, args)) =
+            call.filter(|(function, _)| ["min", "schedule", "override"].contains(function))
+        else {
+            return Ok(Self::Expression(crate::sensor::Sensor::new(s, s)?));
+        };
+        let args = split_args(args)?;
+        let wrong =
+            |expected: &str| eyre::eyre!("policy {:?}: {}() takes {}", s, function, expected);
+        match (function, args.as_slice()) {
+            ("min", []) => Err(wrong("at least one policy")),
+            ("min", args) => Ok(Self::MinOf(
+                args.iter()
+                    .map(|arg| Node::parse(arg))
+                    .collect::<Result<_, _>>()?,
+            )),
+            ("schedule", [window, during, otherwise]) => Ok(Self::Scheduled(
+                crate::schedule::TimeWindow::from_str(window.trim())?,
+                Box::new(Node::parse(during)?),
+                Box::new(Node::parse(otherwise)?),
+            )),
+            ("schedule", _) => Err(wrong("a time window and two policies")),
+            ("override", [condition, layer, base]) => Ok(Self::Override(
+                crate::sensor::Sensor::new(condition, condition)?,
+                Box::new(Node::parse(layer)?),
+                Box::new(Node::parse(base)?),
+            )),
+            _ => Err(wrong("a condition and two policies")),
+        }
+    }
+}
+
+/// Split a combinator's arguments at the commas that aren't in
+/// parentheses.
+fn split_args(s: &str) -> Result<Vec<&str>, eyre::Report> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(eyre::eyre!("unexpected \")\" in {:?}", s)),
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(eyre::eyre!("missing \")\" in {:?}", s));
+    }
+    if !s[start..].trim().is_empty() || !args.is_empty() {
+        args.push(&s[start..]);
+    }
+    Ok(args)
+}
+
+impl FromStr for Spec {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, policy)) = s.split_once('=') else {
+            return Err(eyre::eyre!("policy {:?} is not of the form name=policy", s));
+        };
+        Ok(Self {
+            name: String::from(name.trim()),
+            node: Node::parse(policy)?,
+        })
+    }
+}
+
+/// A --policy, by the name it was given.
+struct Named {
+    name: String,
+    policy: Box<dyn Policy>,
+}
+
+impl Policy for Named {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn limit(&mut self, inputs: &std::collections::HashMap<String, f64>, limit: f64) -> f64 {
+        self.policy.limit(inputs, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(spec: &str, inputs: &[(&str, f64)], limit: f64) -> f64 {
+        let inputs = inputs
+            .iter()
+            .map(|(name, value)| (String::from(*name), *value))
+            .collect();
+        Spec::from_str(spec).unwrap().build().limit(&inputs, limit)
+    }
+
+    #[test]
+    fn expression() {
+        assert_eq!(limit("half=limit / 2", &[], 16.0), 8.0);
+        assert_eq!(limit("fixed=10", &[], 16.0), 10.0);
+        assert_eq!(limit("neg=limit - 20", &[], 16.0), 0.0);
+        // What can't be computed leaves the limit alone.
+        assert_eq!(limit("soc=ev_soc", &[], 16.0), 16.0);
+        assert_eq!(limit("inf=1 / 0", &[], 16.0), 16.0);
+    }
+
+    #[test]
+    fn min_of() {
+        assert_eq!(limit("m=min(limit, 10)", &[], 16.0), 10.0);
+        assert_eq!(limit("m=min(limit, 10)", &[], 6.0), 6.0);
+        assert_eq!(limit("m=min(20, 12, limit - 1)", &[], 16.0), 12.0);
+        // Never more than it's given.
+        assert_eq!(limit("m=min(20)", &[], 16.0), 16.0);
+    }
+
+    #[test]
+    fn override_condition() {
+        let spec = "capped=override(boost, limit, min(limit, 16))";
+        assert_eq!(limit(spec, &[("boost", 1.0)], 32.0), 32.0);
+        assert_eq!(limit(spec, &[("boost", 0.0)], 32.0), 16.0);
+        // A condition that can't be computed doesn't hold.
+        assert_eq!(limit(spec, &[], 32.0), 16.0);
+        let spec = "soc=override(ev_soc >= 80 && ev_soc < 100, 6, limit)";
+        assert_eq!(limit(spec, &[("ev_soc", 85.0)], 32.0), 6.0);
+        assert_eq!(limit(spec, &[("ev_soc", 50.0)], 32.0), 32.0);
+    }
+
+    #[test]
+    fn schedule() {
+        // A window around now, and one that's never on.
+        let now = chrono::Local::now().time();
+        let hour = chrono::Duration::hours(1);
+        let around_now = format!(
+            "s=schedule({}-{}, min(limit, 10), limit)",
+            (now - hour).format("%H:%M"),
+            (now + hour).format("%H:%M")
+        );
+        assert_eq!(limit(&around_now, &[], 16.0), 10.0);
+        assert_eq!(
+            limit("s=schedule(12:00-12:00, min(limit, 10), limit)", &[], 16.0),
+            16.0
+        );
+    }
+
+    #[test]
+    fn parse_bad() {
+        for spec in [
+            "no equals sign",
+            "m=min()",
+            "m=min(limit, 10",
+            "m=min(limit))",
+            "s=schedule(limit, 10)",
+            "s=schedule(noon, limit, 10)",
+            "o=override(boost, limit)",
+            "e=limit +",
+        ] {
+            assert!(Spec::from_str(spec).is_err(), "{spec:?} parsed");
+        }
+    }
+
+    #[test]
+    fn args() {
+        assert_eq!(
+            split_args("a, min(b, c), d").unwrap(),
+            ["a", " min(b, c)", " d"]
+        );
+        assert!(split_args("").unwrap().is_empty());
+    }
+}
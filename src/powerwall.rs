@@ -0,0 +1,163 @@
+// Read grid import/export and the home battery's state from a Tesla
+// Energy Gateway (Powerwall) over its local API, as an alternative to
+// the Enphase Envoy.
+//
+// The gateway wants a login with the "customer" password before it
+// answers anything, and the login expires, so we log in again whenever
+// it says we're not authorized.
+//
+// ```
+// $ curl -k https://powerwall/api/meters/aggregates
+// {"site": {"last_communication_time": "...", "instant_power": -21.4,
+//           "energy_exported": 1136916.6, "energy_imported": 3276432.6,
+//           "instant_average_voltage": 239.8, ...},
+//  "battery": {"instant_power": -2350.0, ...}, ...}
+// $ curl -k https://powerwall/api/system_status/soe
+// {"percentage": 69.1}
+// ```
+//
+// The site's power is positive when importing, the battery's is
+// positive when discharging.  The state of charge is as the gateway
+// reports it, which is a bit higher than what the Tesla app shows
+// (the app hides a 5% reserve).
+
+#[derive(Debug)]
+pub struct Powerwall {
+    base_url: reqwest::Url,
+    password: String,
+    client: reqwest::Client,
+    auth_token: Option<String>,
+}
+
+impl Powerwall {
+    pub fn new(
+        base_url: reqwest::Url,
+        address_book: &crate::address_book::AddressBook,
+        password: &str,
+    ) -> Result<Self, eyre::Report> {
+        let client = address_book
+            .client_builder()
+            // The gateway uses a self-signed certificate.
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        Ok(Self {
+            base_url,
+            password: String::from(password.trim()),
+            client,
+            auth_token: None,
+        })
+    }
+
+    async fn login(&mut self) -> Result<String, eyre::Report> {
+        let reply: serde_json::Value = self
+            .client
+            .post(self.base_url.join("api/login/Basic")?)
+            .json(&serde_json::json!({
+                "username": "customer",
+                "password": self.password,
+                "email": "",
+                "force_sm_off": false,
+            }))
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(|e| eyre::eyre!("Powerwall login failed: {}", e))?
+            .json()
+            .await?;
+        let token = reply
+            .get("token")
+            .and_then(|v| v.as_str())
+            .ok_or(eyre::eyre!("Powerwall login reply has no token"))?;
+        Ok(String::from(token))
+    }
+
+    /// GET a JSON document from the gateway, logging in if needed.
+    async fn get(&mut self, path: &str) -> Result<serde_json::Value, eyre::Report> {
+        let url = self.base_url.join(path)?;
+        for _ in 0..2 {
+            let token = match &self.auth_token {
+                Some(token) => token.clone(),
+                None => {
+                    let token = self.login().await?;
+                    self.auth_token = Some(token.clone());
+                    token
+                }
+            };
+            let response = self
+                .client
+                .get(url.clone())
+                .header(reqwest::header::COOKIE, format!("AuthCookie={token}"))
+                .bearer_auth(&token)
+                .send()
+                .await?;
+            if matches!(
+                response.status(),
+                reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+            ) {
+                // The login expired, try again with a new one.
+                self.auth_token = None;
+                continue;
+            }
+            return Ok(response.error_for_status()?.json().await?);
+        }
+        Err(eyre::eyre!("Powerwall won't let us read {}", path))
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::meter::Meter for Powerwall {
+    async fn export_power(&mut self) -> Result<crate::meter::PowerReading, eyre::Report> {
+        let aggregates = self.get("api/meters/aggregates").await?;
+        let soe = self.get("api/system_status/soe").await?;
+
+        let site = &aggregates["site"];
+        let import_w = site["instant_power"]
+            .as_f64()
+            .ok_or(eyre::eyre!("Powerwall reports no site power"))?;
+        let reading_time = site["last_communication_time"]
+            .as_str()
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&chrono::Utc))
+            .unwrap_or_else(chrono::Utc::now);
+        let wh_exported = site["energy_exported"].as_f64();
+        let wh_imported = site["energy_imported"].as_f64();
+        let battery_w = aggregates["battery"]["instant_power"].as_f64();
+        let battery_soc = soe["percentage"].as_f64();
+
+        let mut details = Vec::new();
+        for (name, value) in [
+            ("powerwall/site_w", Some(import_w)),
+            ("powerwall/battery_w", battery_w),
+            ("powerwall/battery_soc", battery_soc),
+            (
+                "powerwall/solar_w",
+                aggregates["solar"]["instant_power"].as_f64(),
+            ),
+            (
+                "powerwall/load_w",
+                aggregates["load"]["instant_power"].as_f64(),
+            ),
+        ] {
+            if let Some(value) = value {
+                details.push((String::from(name), value));
+            }
+        }
+
+        Ok(crate::meter::PowerReading {
+            reading_time,
+            export_w: -import_w,
+            export_wh_lifetime: wh_exported
+                .zip(wh_imported)
+                .map(|(exported, imported)| exported - imported),
+            rms_voltage: site["instant_average_voltage"]
+                .as_f64()
+                .filter(|v| *v > 0.0),
+            phases: Vec::new(),
+            consumption_w: aggregates["load"]["instant_power"].as_f64(),
+            production_w: aggregates["solar"]["instant_power"].as_f64(),
+            battery_w,
+            battery_soc,
+            details,
+        })
+    }
+}
@@ -0,0 +1,230 @@
+// Dynamic electricity prices, from Octopus Energy's Agile tariff, which
+// publishes the next day's half-hourly prices every afternoon:
+//
+// ```text
+// $ curl https://api.octopus.energy/v1/products/AGILE-24-10-01/electricity-tariffs/E-1R-AGILE-24-10-01-C/standard-unit-rates/
+// {"count": 1234, "results": [{"value_exc_vat": 12.3, "value_inc_vat": 12.915,
+//   "valid_from": "2024-10-01T22:30:00Z", "valid_to": "2024-10-01T23:00:00Z", ...}, ...]}
+// ```
+//
+// With a --deadline, the grid charging it needs happens in the cheapest
+// slots before the deadline, rather than just before it.  When the
+// price is negative we get paid to use electricity, so the EV charges
+// at full power as if there were unlimited surplus.
+
+/// How often to fetch the prices.
+const REFRESH: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(Debug, serde::Deserialize)]
+struct Rates {
+    results: Vec<Rate>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Rate {
+    value_inc_vat: f64,
+
+    // RFC 3339 times.  The last slot of a tariff has no end.
+    valid_from: String,
+    valid_to: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub start: chrono::DateTime<chrono::Local>,
+    pub end: chrono::DateTime<chrono::Local>,
+
+    // Per kWh, in the tariff's currency (pence, for Octopus).
+    pub price: f64,
+}
+
+#[derive(Debug)]
+pub struct Prices {
+    url: reqwest::Url,
+    client: reqwest::Client,
+
+    // Sorted by start time.
+    slots: Vec<Slot>,
+    fetched: Option<std::time::Instant>,
+}
+
+impl Prices {
+    pub fn new(url: reqwest::Url) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+            slots: Vec::new(),
+            fetched: None,
+        }
+    }
+
+    /// Fetch the prices, if it's been a while.
+    pub async fn refresh(&mut self) -> Result<(), eyre::Report> {
+        if self.fetched.is_some_and(|t| t.elapsed() < REFRESH) {
+            return Ok(());
+        }
+        let rates: Rates = self
+            .client
+            .get(self.url.clone())
+            .timeout(std::time::Duration::from_secs(30))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let mut slots: Vec<Slot> = rates
+            .results
+            .iter()
+            .filter_map(|rate| {
+                let time = |s: &str| {
+                    chrono::DateTime::parse_from_rfc3339(s)
+                        .ok()
+                        .map(|t| t.with_timezone(&chrono::Local))
+                };
+                Some(Slot {
+                    start: time(&rate.valid_from)?,
+                    end: time(rate.valid_to.as_ref()?)?,
+                    price: rate.value_inc_vat,
+                })
+            })
+            .collect();
+        slots.sort_by_key(|slot| slot.start);
+        println!(
+            "fetched {} electricity prices, up to {}",
+            slots.len(),
+            slots
+                .last()
+                .map(|slot| slot.end.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        );
+        self.slots = slots;
+        self.fetched = Some(std::time::Instant::now());
+        Ok(())
+    }
+
+    /// The slot we're in, if we know its price.
+    pub fn current(&self, now: chrono::DateTime<chrono::Local>) -> Option<&Slot> {
+        self.slots
+            .iter()
+            .find(|slot| slot.start <= now && now < slot.end)
+    }
+
+    /// Whether we're in one of the cheapest slots between now and
+    /// `deadline` that together last at least `needed`, out of the
+    /// slots we know the prices of.
+    pub fn is_cheapest(
+        &self,
+        now: chrono::DateTime<chrono::Local>,
+        deadline: chrono::DateTime<chrono::Local>,
+        needed: std::time::Duration,
+    ) -> bool {
+        let Some(current) = self.current(now) else {
+            return false;
+        };
+        let mut candidates: Vec<&Slot> = self
+            .slots
+            .iter()
+            .filter(|slot| slot.end > now && slot.start < deadline)
+            .collect();
+        candidates.sort_by(|a, b| a.price.total_cmp(&b.price));
+        let mut total = std::time::Duration::ZERO;
+        for slot in candidates {
+            if total >= needed {
+                return false;
+            }
+            if slot.start == current.start {
+                return true;
+            }
+            // Only the part of the slot that's still to come, and
+            // before the deadline, counts.
+            let start = slot.start.max(now);
+            let end = slot.end.min(deadline);
+            total += (end - start).to_std().unwrap_or_default();
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(day: u32, hour: u32, minute: u32) -> chrono::DateTime<chrono::Local> {
+        chrono::Local
+            .with_ymd_and_hms(2026, 3, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    /// Half-hourly slots from `start` at `prices`.
+    fn prices(start: chrono::DateTime<chrono::Local>, prices: &[f64]) -> Prices {
+        let mut p = Prices::new(reqwest::Url::parse("http://localhost/").unwrap());
+        let half_hour = chrono::Duration::minutes(30);
+        p.slots = prices
+            .iter()
+            .enumerate()
+            .map(|(i, price)| Slot {
+                start: start + half_hour * i as i32,
+                end: start + half_hour * (i as i32 + 1),
+                price: *price,
+            })
+            .collect();
+        p
+    }
+
+    const HOUR: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+    #[test]
+    fn empty() {
+        let p = prices(time(1, 12, 0), &[]);
+        assert!(p.current(time(1, 12, 0)).is_none());
+        assert!(!p.is_cheapest(time(1, 12, 0), time(1, 18, 0), HOUR));
+    }
+
+    #[test]
+    fn cheapest() {
+        // 12:00 to 14:00.
+        let p = prices(time(1, 12, 0), &[20.0, 5.0, 10.0, 30.0]);
+        let deadline = time(1, 14, 0);
+        assert_eq!(p.current(time(1, 12, 45)).unwrap().price, 5.0);
+        assert!(!p.is_cheapest(time(1, 12, 0), deadline, HOUR));
+        assert!(p.is_cheapest(time(1, 12, 30), deadline, HOUR));
+        assert!(p.is_cheapest(time(1, 13, 0), deadline, HOUR));
+        // What's left is the cheapest there is.
+        assert!(p.is_cheapest(time(1, 13, 30), deadline, HOUR));
+        // Nothing needed, nothing's cheap enough.
+        assert!(!p.is_cheapest(time(1, 12, 30), deadline, std::time::Duration::ZERO));
+        // Not knowing the price now.
+        assert!(!p.is_cheapest(time(1, 15, 0), time(1, 16, 0), HOUR));
+    }
+
+    #[test]
+    fn ties() {
+        // The earlier of two slots at the same price comes first.
+        let p = prices(time(1, 12, 0), &[10.0, 10.0]);
+        assert!(p.is_cheapest(time(1, 12, 0), time(1, 13, 0), HOUR / 2));
+        // After the cheapest, when it takes more than that.
+        let p = prices(time(1, 12, 0), &[10.0, 5.0, 10.0]);
+        let deadline = time(1, 13, 30);
+        assert!(p.is_cheapest(time(1, 12, 0), deadline, HOUR));
+        assert!(!p.is_cheapest(time(1, 12, 0), deadline, HOUR / 2));
+    }
+
+    #[test]
+    fn past_midnight() {
+        // 23:00 to 01:00, with the deadline at 00:45.
+        let p = prices(time(1, 23, 0), &[20.0, 30.0, 5.0, 10.0]);
+        let deadline = time(2, 0, 45);
+        assert!(!p.is_cheapest(time(1, 23, 0), deadline, HOUR / 2));
+        assert!(p.is_cheapest(time(2, 0, 0), deadline, HOUR / 2));
+        // Only a quarter of an hour of the 00:30 slot is before the
+        // deadline, so the 23:00 slot is needed too.
+        let needed = std::time::Duration::from_secs(50 * 60);
+        assert!(p.is_cheapest(time(1, 23, 0), deadline, needed));
+        assert!(p.is_cheapest(time(2, 0, 30), deadline, needed));
+        assert!(!p.is_cheapest(time(1, 23, 0), deadline, HOUR * 3 / 4));
+        assert!(!p.is_cheapest(time(1, 23, 30), deadline, HOUR * 3 / 4));
+        // Half of the 00:00 slot has gone by.
+        assert!(p.is_cheapest(time(2, 0, 15), deadline, HOUR / 2));
+    }
+}
@@ -0,0 +1,43 @@
+// Check at startup that the devices we depend on are reachable.  The
+// checks run concurrently, each with its own timeout, so one
+// unreachable device doesn't hold up finding out about the others.
+
+/// Run a startup check, giving up if it takes longer than `timeout`.
+pub async fn with_timeout<T>(
+    timeout: std::time::Duration,
+    check: impl std::future::Future<Output = Result<T, eyre::Report>>,
+) -> Result<T, eyre::Report> {
+    match tokio::time::timeout(timeout, check).await {
+        Ok(result) => result,
+        Err(_) => Err(eyre::eyre!(
+            "no response after {} seconds",
+            timeout.as_secs()
+        )),
+    }
+}
+
+/// Wait for the MQTT broker to accept our connection.
+pub async fn mqtt_connect(eventloop: &mut rumqttc::EventLoop) -> Result<(), eyre::Report> {
+    loop {
+        if let rumqttc::Event::Incoming(rumqttc::mqttbytes::v4::Packet::ConnAck(_)) =
+            eventloop.poll().await?
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Print the result of one startup check, and return true if it
+/// succeeded.
+pub fn report<T>(name: &str, result: &Result<T, eyre::Report>) -> bool {
+    match result {
+        Ok(_) => {
+            println!("    {name}: ok");
+            true
+        }
+        Err(e) => {
+            println!("    {name}: FAILED: {e:#}");
+            false
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Per-vehicle profiles, for households with more than one EV.  The OCPP
+// charger tells us which RFID card (ID token) started the session, and a
+// profile for that card overrides a few settings until the EV is
+// unplugged:
+//
+// ```text
+// --vehicle-profile "CAFE0001:max_current=16,stop_soc=80"
+// --vehicle-profile "CAFE0002:ready_by=07:00,ready_kwh=20"
+// ```
+//
+// max_current caps the charge current limit like the "limit" command,
+// stop_soc replaces --ev-stop-soc, and ready_by with ready_kwh replaces
+// --deadline and --deadline-kwh.  There's no priority between vehicles:
+// the OCPP charger is the only EVSE that reports a card, and it can't be
+// combined with --extra-openevse, so there's only ever one vehicle
+// charging.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub id_tag: String,
+    pub max_current: Option<f64>,
+    pub stop_soc: Option<f64>,
+    pub ready_by: Option<chrono::NaiveTime>,
+    pub ready_kwh: Option<f64>,
+}
+
+impl FromStr for Profile {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((id_tag, settings)) = s.split_once(':') else {
+            return Err(eyre::eyre!(
+                "vehicle profile {:?} is not of the form TAG:setting=value,...",
+                s
+            ));
+        };
+        let mut profile = Self {
+            id_tag: String::from(id_tag.trim()),
+            max_current: None,
+            stop_soc: None,
+            ready_by: None,
+            ready_kwh: None,
+        };
+        if profile.id_tag.is_empty() {
+            return Err(eyre::eyre!("vehicle profile {:?} has no ID tag", s));
+        }
+        for setting in settings.split(',') {
+            let Some((name, value)) = setting.split_once('=') else {
+                return Err(eyre::eyre!(
+                    "vehicle profile setting {:?} is not of the form name=value",
+                    setting
+                ));
+            };
+            let value = value.trim();
+            let number = |max: f64| {
+                f64::from_str(value)
+                    .ok()
+                    .filter(|n| (0.0..=max).contains(n))
+                    .ok_or(eyre::eyre!(
+                        "bad {} {:?} in vehicle profile",
+                        name.trim(),
+                        value
+                    ))
+            };
+            match name.trim() {
+                "max_current" => profile.max_current = Some(number(f64::MAX)?),
+                "stop_soc" => profile.stop_soc = Some(number(100.0)?),
+                "ready_kwh" => profile.ready_kwh = Some(number(f64::MAX)?),
+                "ready_by" => {
+                    profile.ready_by = Some(
+                        chrono::NaiveTime::parse_from_str(value, "%H:%M").map_err(|e| {
+                            eyre::eyre!("bad time {:?} in vehicle profile: {}", value, e)
+                        })?,
+                    )
+                }
+                name => return Err(eyre::eyre!("unknown vehicle profile setting {:?}", name)),
+            }
+        }
+        if profile.ready_by.is_some() != profile.ready_kwh.is_some() {
+            return Err(eyre::eyre!(
+                "vehicle profile {:?} needs both ready_by and ready_kwh",
+                s
+            ));
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let profile = Profile::from_str("CAFE0001:max_current=16, stop_soc=80").unwrap();
+        assert_eq!(profile.id_tag, "CAFE0001");
+        assert_eq!(profile.max_current, Some(16.0));
+        assert_eq!(profile.stop_soc, Some(80.0));
+        assert_eq!(profile.ready_by, None);
+
+        let profile = Profile::from_str("CAFE0002:ready_by=07:00,ready_kwh=20").unwrap();
+        assert_eq!(
+            profile.ready_by,
+            Some(chrono::NaiveTime::from_hms_opt(7, 0, 0).unwrap())
+        );
+        assert_eq!(profile.ready_kwh, Some(20.0));
+    }
+
+    #[test]
+    fn parse_bad() {
+        for s in [
+            "CAFE0001",
+            ":max_current=16",
+            "CAFE0001:max_current",
+            "CAFE0001:max_current=-1",
+            "CAFE0001:max_current=NaN",
+            "CAFE0001:stop_soc=101",
+            "CAFE0001:ready_by=7am,ready_kwh=20",
+            "CAFE0001:ready_by=07:00",
+            "CAFE0001:priority=1",
+        ] {
+            assert!(Profile::from_str(s).is_err(), "{s}");
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// Reloading the settings while we run, on SIGHUP or `POST /reload`: the
+// --config file is read again, the command line is put together with
+// it the same way as at startup, and the options that changed are
+// logged, with what they'll do:
+//
+// ```text
+// $ kill -HUP $(pidof solar-evse)
+// config: evse-max-charge-current 30 -> 24 (the EV will be offered at most 24 A, not 30 A)
+// config: mqtt-broker mqtt.lan -> mqtt2.lan (after a restart)
+// ```
+//
+// Options the control loop reads every cycle (the current limits, the
+// export target, the timings, ...) take effect right away, the rest (the
+// devices, the brokers, the listening addresses, ...) at the next
+// restart.  With --extra-openevse, the EVSE charge current range is one
+// of the rest, since it's been shared out between the units.
+//
+// `POST /reload` with `{"dry_run": true}` only says what would change,
+// in the "reload" part of the status it replies with.  With
+// --reload-confirm, a reload that changes one of the safety-relevant
+// options (the current limits, ...) isn't applied until it's confirmed
+// with `{"confirm": true}`.
+
+/// Options that change how much current we can draw.
+const SAFETY_RELEVANT: &[&str] = &[
+    "evse-min-charge-current",
+    "evse-max-charge-current",
+    "max-discharge-current",
+    "house-max-current",
+    "current-limit",
+    "peak-shaving-max-import",
+    "fault-hold",
+];
+
+/// An option whose value changed.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Change {
+    pub option: String,
+
+    // The values, separated by commas if there's more than one, or
+    // None if it wasn't set.
+    pub old: Option<String>,
+    pub new: Option<String>,
+
+    // Whether it takes effect without a restart.
+    pub live: bool,
+}
+
+impl Change {
+    pub fn is_safety_relevant(&self) -> bool {
+        SAFETY_RELEVANT.contains(&self.option.as_str())
+    }
+
+    /// What it'll do, in words, for the options where that's not
+    /// obvious from the values.
+    pub fn effect(&self) -> Option<String> {
+        let (Some(old), Some(new)) = (&self.old, &self.new) else {
+            return None;
+        };
+        Some(match self.option.as_str() {
+            "evse-max-charge-current" => {
+                format!("the EV will be offered at most {new} A, not {old} A")
+            }
+            "evse-min-charge-current" => {
+                format!("charging will stop below {new} A, not {old} A")
+            }
+            "max-discharge-current" => {
+                format!("the EV will discharge at most {new} A, not {old} A")
+            }
+            "house-max-current" => format!("the house will be held to {new} A, not {old} A"),
+            "target-export-current" => format!("we'll aim to export {new} A, not {old} A"),
+            "period" => format!("a cycle every {new} s, not every {old} s"),
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let value = |value: &Option<String>| value.clone().unwrap_or(String::from("(unset)"));
+        write!(
+            f,
+            "{} {} -> {}",
+            self.option,
+            value(&self.old),
+            value(&self.new)
+        )?;
+        match (self.effect(), self.live) {
+            (Some(effect), true) => write!(f, " ({effect})"),
+            (Some(effect), false) => write!(f, " ({effect}, after a restart)"),
+            (None, true) => Ok(()),
+            (None, false) => write!(f, " (after a restart)"),
+        }
+    }
+}
+
+/// The options whose values differ between the `old` and `new` command
+/// lines, as `command` parses them, defaults and all.  None of them are
+/// live yet.
+pub fn diff(
+    command: &clap::Command,
+    old: &[std::ffi::OsString],
+    new: &[std::ffi::OsString],
+) -> Result<Vec<Change>, eyre::Report> {
+    let old = command.clone().try_get_matches_from(old)?;
+    let new = command.clone().try_get_matches_from(new)?;
+    let value = |matches: &clap::ArgMatches, id: &str| -> Option<String> {
+        let values: Vec<String> = matches
+            .get_raw(id)?
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+        Some(values.join(","))
+    };
+    let mut changes = Vec::new();
+    for arg in command.get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches!(long, "config" | "help" | "version") {
+            continue;
+        }
+        let id = arg.get_id().as_str();
+        let (old, new) = (value(&old, id), value(&new, id));
+        if old != new {
+            changes.push(Change {
+                option: String::from(long),
+                old,
+                new,
+                live: false,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Start listening for SIGHUP.
+pub fn hangups() -> Result<tokio::signal::unix::Signal, eyre::Report> {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(|e| eyre::eyre!("can't listen for SIGHUP: {e}"))
+}
+
+/// The next SIGHUP, or never if we're not listening for them.
+pub async fn next(signal: &mut Option<tokio::signal::unix::Signal>) -> Option<()> {
+    match signal {
+        Some(signal) => signal.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(old: &[&str], new: &[&str]) -> Vec<Change> {
+        let command = <crate::Args as clap::CommandFactory>::command();
+        let args = |args: &[&str]| {
+            std::iter::once("solar-evse")
+                .chain(args.iter().copied())
+                .map(std::ffi::OsString::from)
+                .collect::<Vec<_>>()
+        };
+        super::diff(&command, &args(old), &args(new)).unwrap()
+    }
+
+    #[test]
+    fn changes() {
+        assert!(diff(&["--period=5"], &["--period", "5"]).is_empty());
+        let changes = diff(
+            &["--config=a.toml", "--evse-max-charge-current=30"],
+            &[
+                "--config=b.toml",
+                "--evse-max-charge-current=24",
+                "--sensor=a=1",
+            ],
+        );
+        assert_eq!(changes.len(), 2, "{changes:?}");
+        let mut max = changes[0].clone();
+        assert!(max.is_safety_relevant());
+        assert_eq!(
+            max.to_string(),
+            "evse-max-charge-current 30 -> 24 (the EV will be offered at most 24 A, not 30 A, after a restart)"
+        );
+        max.live = true;
+        assert_eq!(
+            max.to_string(),
+            "evse-max-charge-current 30 -> 24 (the EV will be offered at most 24 A, not 30 A)"
+        );
+        assert!(!changes[1].is_safety_relevant());
+        assert_eq!(
+            changes[1].to_string(),
+            "sensor (unset) -> a=1 (after a restart)"
+        );
+    }
+}
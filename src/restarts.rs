@@ -0,0 +1,142 @@
+// Keeping count of our restarts, and why we stopped last time, in a
+// small JSON file (--state-file), so that a crash-looping install shows
+// up in the metrics and the status API and not just in the logs.
+//
+// The file says we're running while we run, and why we stopped once we
+// stop: a signal, an error, or a panic.  If it still says we're running
+// when we start, the last run died without a chance to say why (killed,
+// out of memory, the power went out, ...), which we call a crash.
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+struct Saved {
+    // How many times we've started.
+    starts: u64,
+    running: bool,
+
+    // Why the last run stopped ("signal", "error", "panic"), with the
+    // details, and when.
+    exit_cause: Option<String>,
+    exit_message: Option<String>,
+    exit_time: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Restarts {
+    filename: Option<std::path::PathBuf>,
+    started: std::time::Instant,
+
+    // How many times we started before this one.
+    pub restarts: u64,
+
+    // Why the last run stopped, and the details.
+    pub last_exit_cause: Option<String>,
+    pub last_exit_message: Option<String>,
+}
+
+impl Restarts {
+    /// Read what happened last time from `filename` (if there is one),
+    /// and note that we're running.
+    pub fn start(filename: Option<&std::path::Path>) -> Result<Self, eyre::Report> {
+        let Some(filename) = filename else {
+            return Ok(Self {
+                filename: None,
+                started: std::time::Instant::now(),
+                restarts: 0,
+                last_exit_cause: None,
+                last_exit_message: None,
+            });
+        };
+        let saved: Saved = match std::fs::read_to_string(filename) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| eyre::eyre!("can't parse state file {}: {}", filename.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => {
+                return Err(eyre::eyre!(
+                    "can't read state file {}: {}",
+                    filename.display(),
+                    e
+                ))
+            }
+        };
+        let (last_exit_cause, last_exit_message) = if saved.running {
+            (Some(String::from("crash")), None)
+        } else {
+            (saved.exit_cause, saved.exit_message)
+        };
+        if let Some(cause) = &last_exit_cause {
+            println!(
+                "restart #{}, the last run ended: {}{}",
+                saved.starts,
+                cause,
+                last_exit_message
+                    .as_ref()
+                    .map(|m| format!(": {m}"))
+                    .unwrap_or_default()
+            );
+        }
+
+        let restarts = Self {
+            filename: Some(filename.to_path_buf()),
+            started: std::time::Instant::now(),
+            restarts: saved.starts,
+            last_exit_cause,
+            last_exit_message,
+        };
+        write(
+            filename,
+            &Saved {
+                starts: saved.starts + 1,
+                running: true,
+                ..Saved::default()
+            },
+        )?;
+        Ok(restarts)
+    }
+
+    pub fn uptime(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+
+    /// Note why we're stopping.
+    pub fn stop(&self, cause: &str, message: Option<String>) {
+        if let Some(filename) = &self.filename {
+            let saved = Saved {
+                starts: self.restarts + 1,
+                running: false,
+                exit_cause: Some(String::from(cause)),
+                exit_message: message,
+                exit_time: Some(chrono::Local::now().to_rfc3339()),
+            };
+            if let Err(e) = write(filename, &saved) {
+                println!("{e:#}");
+            }
+        }
+    }
+
+    /// Note it if we panic, on top of the usual panic message.
+    pub fn catch_panics(&self) {
+        let Some(filename) = self.filename.clone() else {
+            return;
+        };
+        let starts = self.restarts + 1;
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous_hook(info);
+            let saved = Saved {
+                starts,
+                running: false,
+                exit_cause: Some(String::from("panic")),
+                exit_message: Some(info.to_string()),
+                exit_time: Some(chrono::Local::now().to_rfc3339()),
+            };
+            if let Err(e) = write(&filename, &saved) {
+                println!("{e:#}");
+            }
+        }));
+    }
+}
+
+fn write(filename: &std::path::Path, saved: &Saved) -> Result<(), eyre::Report> {
+    std::fs::write(filename, serde_json::to_string_pretty(saved)?)
+        .map_err(|e| eyre::eyre!("can't write state file {}: {}", filename.display(), e))
+}
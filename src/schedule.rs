@@ -0,0 +1,114 @@
+// A daily schedule of values that change at certain times of day, for
+// example "06:00=5,12:00=1" for 5 A from 06:00 until noon and 1 A from
+// noon until 06:00 the next morning.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct Schedule<T> {
+    // Sorted by start time.
+    entries: Vec<(chrono::NaiveTime, T)>,
+}
+
+impl<T: Clone> Schedule<T> {
+    /// The value in effect at the specified time of day.
+    pub fn at(&self, time: chrono::NaiveTime) -> T {
+        // Before the first entry of the day, the last entry from the
+        // previous day is still in effect.
+        self.entries
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= time)
+            .unwrap_or(self.entries.last().unwrap())
+            .1
+            .clone()
+    }
+
+    /// The value in effect right now.
+    pub fn now(&self) -> T {
+        self.at(chrono::Local::now().time())
+    }
+}
+
+/// A time of day window, like "23:00-06:00", which may wrap around
+/// midnight.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeWindow {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl TimeWindow {
+    /// Whether the specified time of day is in the window.
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// Whether it's in the window right now.
+    pub fn now(&self) -> bool {
+        self.contains(chrono::Local::now().time())
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((start, end)) = s.split_once('-') else {
+            return Err(eyre::eyre!(
+                "time window {:?} is not of the form HH:MM-HH:MM",
+                s
+            ));
+        };
+        let parse = |time: &str| {
+            chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|e| eyre::eyre!("bad time {:?} in time window: {}", time, e))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl<T> FromStr for Schedule<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = Vec::new();
+        for entry in s.split(',') {
+            let Some((time, value)) = entry.trim().split_once('=') else {
+                return Err(eyre::eyre!(
+                    "schedule entry {:?} is not of the form HH:MM=value",
+                    entry
+                ));
+            };
+            let time = chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|e| eyre::eyre!("bad time {:?} in schedule: {}", time, e))?;
+            let value = T::from_str(value.trim())
+                .map_err(|e| eyre::eyre!("bad value {:?} in schedule: {}", value, e))?;
+            entries.push((time, value));
+        }
+        entries.sort_by_key(|(time, _)| *time);
+        Ok(Self { entries })
+    }
+}
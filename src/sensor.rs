@@ -0,0 +1,276 @@
+// User-defined sensors computed from the values we already have, for
+// example "house_load=production_w_now - export_w".  Expressions can use
+// numbers, variables, + - * /, and parentheses.  They can also compare
+// (< > <= >=) and combine comparisons (&& ||), which give 1 for true
+// and 0 for false, for writing the conditions of triggers.
+//
+// The variables are the values listed by `Controller::sensor_inputs()`
+// in lib.rs, and everything the meter reports, with "/" and "-" in the
+// names turned into "_" (so the Envoy's "net-consumption/w_now" is
+// "net_consumption_w_now").
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    pub name: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+}
+
+impl Sensor {
+    /// Compute the sensor's value from the named inputs.
+    pub fn evaluate(
+        &self,
+        inputs: &std::collections::HashMap<String, f64>,
+    ) -> Result<f64, eyre::Report> {
+        self.expr.evaluate(inputs)
+    }
+}
+
+impl Expr {
+    fn evaluate(
+        &self,
+        inputs: &std::collections::HashMap<String, f64>,
+    ) -> Result<f64, eyre::Report> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Variable(name) => inputs
+                .get(name)
+                .copied()
+                .ok_or(eyre::eyre!("no value for {:?}", name)),
+            Expr::Negate(e) => Ok(-e.evaluate(inputs)?),
+            Expr::Binary(a, op, b) => {
+                let a = a.evaluate(inputs)?;
+                let b = b.evaluate(inputs)?;
+                let truth = |t: bool| if t { 1.0 } else { 0.0 };
+                Ok(match *op {
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    "/" => a / b,
+                    "<" => truth(a < b),
+                    ">" => truth(a > b),
+                    "<=" => truth(a <= b),
+                    ">=" => truth(a >= b),
+                    "&&" => truth(a != 0.0 && b != 0.0),
+                    _ => truth(a != 0.0 || b != 0.0),
+                })
+            }
+        }
+    }
+}
+
+/// A recursive-descent parser over the expression's characters.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    s: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        while let Some((_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                return Some(*c);
+            }
+            self.chars.next();
+        }
+        None
+    }
+
+    /// Consume the first of `ops` that's next, if any.
+    fn op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        self.peek()?;
+        let start = self.chars.peek().unwrap().0;
+        let op = ops.iter().find(|op| self.s[start..].starts_with(**op))?;
+        for _ in 0..op.len() {
+            self.chars.next();
+        }
+        Some(op)
+    }
+
+    // or := and ("||" and)*
+    fn or(&mut self) -> Result<Expr, eyre::Report> {
+        let mut expr = self.and()?;
+        while let Some(op) = self.op(&["||"]) {
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    // and := comparison ("&&" comparison)*
+    fn and(&mut self) -> Result<Expr, eyre::Report> {
+        let mut expr = self.comparison()?;
+        while let Some(op) = self.op(&["&&"]) {
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    // comparison := sum (("<=" | ">=" | "<" | ">") sum)?
+    fn comparison(&mut self) -> Result<Expr, eyre::Report> {
+        let expr = self.sum()?;
+        match self.op(&["<=", ">=", "<", ">"]) {
+            Some(op) => Ok(Expr::Binary(Box::new(expr), op, Box::new(self.sum()?))),
+            None => Ok(expr),
+        }
+    }
+
+    // sum := product (("+" | "-") product)*
+    fn sum(&mut self) -> Result<Expr, eyre::Report> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.op(&["+", "-"]) {
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    // product := term (("*" | "/") term)*
+    fn product(&mut self) -> Result<Expr, eyre::Report> {
+        let mut expr = self.term()?;
+        while let Some(op) = self.op(&["*", "/"]) {
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    // term := number | variable | "-" term | "(" or ")"
+    fn term(&mut self) -> Result<Expr, eyre::Report> {
+        match self.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(Expr::Negate(Box::new(self.term()?)))
+            }
+            Some('(') => {
+                self.chars.next();
+                let expr = self.or()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(expr)
+                    }
+                    _ => Err(eyre::eyre!("missing \")\" in {:?}", self.s)),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let token = self.token(|c| c.is_ascii_digit() || c == '.');
+                Ok(Expr::Number(f64::from_str(token).map_err(|e| {
+                    eyre::eyre!("bad number {:?} in {:?}: {}", token, self.s, e)
+                })?))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let token = self.token(|c| c.is_ascii_alphanumeric() || c == '_');
+                Ok(Expr::Variable(String::from(token)))
+            }
+            Some(c) => Err(eyre::eyre!("unexpected {:?} in {:?}", c, self.s)),
+            None => Err(eyre::eyre!("unexpected end of {:?}", self.s)),
+        }
+    }
+
+    fn token(&mut self, in_token: impl Fn(char) -> bool) -> &'a str {
+        let start = self.chars.peek().unwrap().0;
+        let mut end = start;
+        while let Some((i, c)) = self.chars.peek() {
+            if !in_token(*c) {
+                break;
+            }
+            end = i + c.len_utf8();
+            self.chars.next();
+        }
+        &self.s[start..end]
+    }
+}
+
+impl Sensor {
+    /// A sensor called `name` computed with `expr`.
+    pub fn new(name: &str, expr: &str) -> Result<Self, eyre::Report> {
+        let mut parser = Parser {
+            chars: expr.char_indices().peekable(),
+            s: expr,
+        };
+        let expr = parser.or()?;
+        if let Some(c) = parser.peek() {
+            return Err(eyre::eyre!("unexpected {:?} in {:?}", c, parser.s));
+        }
+        Ok(Self {
+            name: String::from(name.trim()),
+            expr,
+        })
+    }
+}
+
+impl FromStr for Sensor {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, expr)) = s.split_once('=') else {
+            return Err(eyre::eyre!(
+                "sensor {:?} is not of the form name=expression",
+                s
+            ));
+        };
+        Self::new(name, expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(s: &str) -> Result<f64, eyre::Report> {
+        let inputs = std::collections::HashMap::from([
+            (String::from("production_w_now"), 5000.0),
+            (String::from("export_w"), 1200.0),
+            (String::from("vehicle_connected"), 1.0),
+        ]);
+        Sensor::from_str(s)?.evaluate(&inputs)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(evaluate("x=production_w_now - export_w").unwrap(), 3800.0);
+        assert_eq!(evaluate("x=1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("x=(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("x=10 - 4 - 3").unwrap(), 3.0);
+        assert_eq!(evaluate("x=-export_w / 4").unwrap(), -300.0);
+        assert_eq!(evaluate("x=.5").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(evaluate("x=export_w > 1000").unwrap(), 1.0);
+        assert_eq!(evaluate("x=export_w <= 1000").unwrap(), 0.0);
+        assert_eq!(
+            evaluate("x=vehicle_connected && export_w >= 1200").unwrap(),
+            1.0
+        );
+        assert_eq!(evaluate("x=export_w < 0 || export_w > 1000").unwrap(), 1.0);
+        assert_eq!(evaluate("x=export_w < 0 || 0 && 1").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn parse() {
+        let sensor = Sensor::from_str(" house_load = production_w_now - export_w").unwrap();
+        assert_eq!(sensor.name, "house_load");
+        for bad in [
+            "house_load",
+            "x=",
+            "x=1 +",
+            "x=(1 + 2",
+            "x=1 2",
+            "x=1.2.3",
+            "x=export_w % 2",
+        ] {
+            assert!(Sensor::from_str(bad).is_err(), "{bad:?} parsed");
+        }
+        // A missing input is an error when evaluating, not parsing.
+        assert!(evaluate("x=tank_temp < 60").is_err());
+    }
+}
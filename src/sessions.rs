@@ -0,0 +1,724 @@
+// A record of every charging session, for reimbursing the electricity
+// a company car used and the like.  With --session-log, each session is
+// appended to the file as one line of JSON when the EV is unplugged:
+//
+// ```text
+// {"start":"2026-03-01T17:02:11+01:00","end":"2026-03-02T07:30:40+01:00","energy_kwh":18.412,"solar_kwh":6.203,"grid_kwh":12.209,"solar_fraction":0.337,"cost":2.931,"tag":"04A2B1C3","savings":1.488}
+// ```
+//
+// - `start`, `end`: when the EV was plugged in and unplugged, RFC 3339
+//   local time.
+// - `energy_kwh`: the energy delivered to the EV.
+// - `solar_kwh`, `grid_kwh`: how much of it came from solar surplus,
+//   and how much from the grid.
+// - `solar_fraction`: `solar_kwh / energy_kwh`, 0 for no energy.
+// - `cost`: what the grid energy cost, at the --octopus-agile-url or
+//   --import-price price when it was used, in that price's currency;
+//   null if we didn't know the price for all of it.
+// - `tag`: the RFID card (or other ID token) the session was started
+//   with, for chargers that say (OCPP); null if we don't know.
+// - `savings`: what the solar energy would have cost from the grid,
+//   less what exporting it would have earned (--export-price); null if
+//   we didn't know the price for all of it.
+// - `imported`: true for a day of the whole house's energy from
+//   `solar-evse import-csv` (see below) rather than a charging session,
+//   left out otherwise.
+//
+// New fields may be added, existing ones won't change meaning.
+// `solar-evse sessions --year 2026 --format csv` prints a year's
+// sessions as CSV (with a header line and the columns in the order
+// above), or as a JSON array.
+//
+// For sharing a driveway, `solar-evse invoice --month 2026-03` adds up
+// a month's sessions (those that started in it) for each tag, as CSV to
+// invoice with, the sessions without a tag on a line of their own:
+//
+// ```text
+// tag,sessions,energy_kwh,solar_kwh,grid_kwh,cost
+// 04A2B1C3,12,183.520,61.114,122.406,29.37
+// DEADBEEF,3,40.100,30.075,10.025,
+// ,1,5.210,5.210,0.000,0.00
+// ```
+//
+// The cost is empty if we didn't know the price of some of the grid
+// energy.
+//
+// `solar-evse analytics` adds up each month's sessions, oldest first,
+// as CSV for charting in a spreadsheet:
+//
+// ```text
+// month,sessions,energy_kwh,solar_fraction,savings,average_hours,imported_days
+// 2026-02,14,201.330,0.412,19.87,9.6,0
+// 2026-03,16,223.620,0.533,,10.2,0
+// ```
+//
+// The savings are empty if a session's are unknown (for sessions logged
+// before they were).
+//
+// So the statistics can go back to before solar-evse was charging the
+// EV, `solar-evse import-csv FILE` adds the days in an Enlighten CSV
+// export (see enlighten.rs) to the log, each as a record of the whole
+// house's energy with `imported` set: `energy_kwh` is what the house
+// consumed, `solar_kwh` the solar it used rather than exported, and
+// `grid_kwh` what it imported.  The cost and savings are worked out
+// with --import-price and --export-price at the time of day each row
+// of the export starts, so a whole day is at the midnight price.  Days
+// from the first logged session on, and days that are already there,
+// are skipped.  `sessions` and `invoice` leave the imported days out,
+// and `analytics` adds them into their month without counting them as
+// sessions, saying how many there were in an `imported_days` column.
+
+use std::io::Write;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+    pub start: String,
+    pub end: String,
+    pub energy_kwh: f64,
+    pub solar_kwh: f64,
+    pub grid_kwh: f64,
+    pub solar_fraction: f64,
+    pub cost: Option<f64>,
+
+    // Not in logs from before there were tags.
+    #[serde(default)]
+    pub tag: Option<String>,
+
+    // Not in logs from before there were savings.
+    #[serde(default)]
+    pub savings: Option<f64>,
+
+    // A day of the house's energy from an Enlighten export, not a
+    // session.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub imported: bool,
+}
+
+impl Record {
+    pub fn new(
+        start: chrono::DateTime<chrono::Local>,
+        end: chrono::DateTime<chrono::Local>,
+        energy_wh: f64,
+        solar_wh: f64,
+        cost: Option<f64>,
+        savings: Option<f64>,
+        tag: Option<String>,
+    ) -> Self {
+        let solar_wh = solar_wh.clamp(0.0, energy_wh.max(0.0));
+        Self {
+            start: start.to_rfc3339(),
+            end: end.to_rfc3339(),
+            energy_kwh: energy_wh / 1000.0,
+            solar_kwh: solar_wh / 1000.0,
+            grid_kwh: (energy_wh - solar_wh) / 1000.0,
+            solar_fraction: if energy_wh > 0.0 {
+                solar_wh / energy_wh
+            } else {
+                0.0
+            },
+            cost,
+            tag,
+            savings,
+            imported: false,
+        }
+    }
+
+    /// The year and month the session started in.
+    fn month(&self) -> Option<Month> {
+        let start = chrono::DateTime::parse_from_rfc3339(&self.start).ok()?;
+        Some(Month {
+            year: chrono::Datelike::year(&start),
+            month: chrono::Datelike::month(&start),
+        })
+    }
+
+    /// How long the EV was plugged in, in hours.
+    fn hours(&self) -> f64 {
+        let start = chrono::DateTime::parse_from_rfc3339(&self.start);
+        let end = chrono::DateTime::parse_from_rfc3339(&self.end);
+        match (start, end) {
+            (Ok(start), Ok(end)) => (end - start).num_seconds() as f64 / 3600.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/// A month of a year, like "2026-03".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl std::str::FromStr for Month {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let date = chrono::NaiveDate::parse_from_str(&format!("{}-01", s.trim()), "%Y-%m-%d")
+            .map_err(|_| eyre::eyre!("month {:?} is not of the form YYYY-MM", s))?;
+        Ok(Self {
+            year: chrono::Datelike::year(&date),
+            month: chrono::Datelike::month(&date),
+        })
+    }
+}
+
+impl std::fmt::Display for Month {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// A tag's sessions in a month, added up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub tag: Option<String>,
+    pub sessions: usize,
+    pub energy_kwh: f64,
+    pub solar_kwh: f64,
+    pub grid_kwh: f64,
+    pub cost: Option<f64>,
+}
+
+/// Add up each tag's sessions that started in `month`, in the order the
+/// tags first come up.
+pub fn invoices(records: &[Record], month: Month) -> Vec<Invoice> {
+    let mut invoices: Vec<Invoice> = Vec::new();
+    for record in records
+        .iter()
+        .filter(|r| !r.imported && r.month() == Some(month))
+    {
+        let invoice = match invoices.iter().position(|i| i.tag == record.tag) {
+            Some(i) => &mut invoices[i],
+            None => {
+                invoices.push(Invoice {
+                    tag: record.tag.clone(),
+                    sessions: 0,
+                    energy_kwh: 0.0,
+                    solar_kwh: 0.0,
+                    grid_kwh: 0.0,
+                    cost: Some(0.0),
+                });
+                invoices.last_mut().unwrap()
+            }
+        };
+        invoice.sessions += 1;
+        invoice.energy_kwh += record.energy_kwh;
+        invoice.solar_kwh += record.solar_kwh;
+        invoice.grid_kwh += record.grid_kwh;
+        invoice.cost = invoice.cost.zip(record.cost).map(|(a, b)| a + b);
+    }
+    invoices
+}
+
+/// A month's sessions, added up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub month: Month,
+    pub sessions: usize,
+    pub energy_kwh: f64,
+    pub solar_kwh: f64,
+    pub savings: Option<f64>,
+    pub hours: f64,
+    pub imported_days: usize,
+}
+
+impl Summary {
+    /// How much of the energy came from solar, 0 for no energy.
+    pub fn solar_fraction(&self) -> f64 {
+        if self.energy_kwh > 0.0 {
+            self.solar_kwh / self.energy_kwh
+        } else {
+            0.0
+        }
+    }
+
+    /// How long the EV was plugged in for each session, on average, in
+    /// hours.
+    pub fn average_hours(&self) -> f64 {
+        if self.sessions > 0 {
+            self.hours / self.sessions as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Add up each month's sessions, oldest month first.
+pub fn summaries(records: &[Record]) -> Vec<Summary> {
+    let mut summaries: Vec<Summary> = Vec::new();
+    for record in records {
+        let Some(month) = record.month() else {
+            continue;
+        };
+        let summary = match summaries.iter().position(|s| s.month == month) {
+            Some(i) => &mut summaries[i],
+            None => {
+                summaries.push(Summary {
+                    month,
+                    sessions: 0,
+                    energy_kwh: 0.0,
+                    solar_kwh: 0.0,
+                    savings: Some(0.0),
+                    hours: 0.0,
+                    imported_days: 0,
+                });
+                summaries.last_mut().unwrap()
+            }
+        };
+        if record.imported {
+            summary.imported_days += 1;
+        } else {
+            summary.sessions += 1;
+            summary.hours += record.hours();
+        }
+        summary.energy_kwh += record.energy_kwh;
+        summary.solar_kwh += record.solar_kwh;
+        summary.savings = summary.savings.zip(record.savings).map(|(a, b)| a + b);
+    }
+    summaries.sort_by_key(|s| s.month);
+    summaries
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+/// Add a session to the end of the log.
+pub fn append(filename: &std::path::Path, record: &Record) -> Result<(), eyre::Report> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(filename)
+        .map_err(|e| eyre::eyre!("can't open session log {}: {}", filename.display(), e))?;
+    writeln!(file, "{}", serde_json::to_string(record)?)
+        .map_err(|e| eyre::eyre!("can't write session log {}: {}", filename.display(), e))
+}
+
+/// The days in an Enlighten export, added up, as records of the
+/// house's energy, priced with `import_price` and `export_price`.
+pub fn days(
+    rows: &[crate::enlighten::Row],
+    import_price: Option<&crate::schedule::Schedule<f64>>,
+    export_price: Option<&crate::schedule::Schedule<f64>>,
+) -> Vec<Record> {
+    // Consumed, self-consumed solar, cost and savings, by day.
+    let mut days: std::collections::BTreeMap<
+        chrono::NaiveDate,
+        (f64, f64, Option<f64>, Option<f64>),
+    > = std::collections::BTreeMap::new();
+    for row in rows {
+        let solar_wh = (row.produced_wh - row.exported_wh).clamp(0.0, row.consumed_wh.max(0.0));
+        let import_price = import_price.map(|price| price.at(row.time.time()));
+        let export_price = export_price.map_or(0.0, |price| price.at(row.time.time()));
+        let day = days
+            .entry(row.time.date())
+            .or_insert((0.0, 0.0, Some(0.0), Some(0.0)));
+        day.0 += row.consumed_wh;
+        day.1 += solar_wh;
+        day.2 = day
+            .2
+            .zip(import_price)
+            .map(|(cost, price)| cost + price * row.imported_wh / 1000.0);
+        day.3 = day
+            .3
+            .zip(import_price)
+            .map(|(savings, price)| savings + (price - export_price) * solar_wh / 1000.0);
+    }
+    let midnight = |date: chrono::NaiveDate| {
+        chrono::TimeZone::from_local_datetime(
+            &chrono::Local,
+            &date.and_time(chrono::NaiveTime::MIN),
+        )
+        .earliest()
+    };
+    days.into_iter()
+        .filter_map(|(date, (consumed_wh, solar_wh, cost, savings))| {
+            let start = midnight(date)?;
+            let end = midnight(date.succ_opt()?)?;
+            Some(Record {
+                imported: true,
+                ..Record::new(start, end, consumed_wh, solar_wh, cost, savings, None)
+            })
+        })
+        .collect()
+}
+
+/// Add the days in an Enlighten export to the log, except those from
+/// the first session on and those already there.  Returns how many
+/// were added and how many skipped.
+pub fn import(filename: &std::path::Path, days: &[Record]) -> Result<(usize, usize), eyre::Report> {
+    let records = match filename.exists() {
+        true => read(filename)?,
+        false => Vec::new(),
+    };
+    let first_session = records
+        .iter()
+        .filter(|r| !r.imported)
+        .filter_map(|r| chrono::DateTime::parse_from_rfc3339(&r.start).ok())
+        .min();
+    let (mut added, mut skipped) = (0, 0);
+    for day in days {
+        let start = chrono::DateTime::parse_from_rfc3339(&day.start)?;
+        let end = chrono::DateTime::parse_from_rfc3339(&day.end)?;
+        if first_session.is_some_and(|first| end > first)
+            || records.iter().any(|r| r.imported && r.start == day.start)
+        {
+            println!("skipping {}", start.date_naive());
+            skipped += 1;
+            continue;
+        }
+        append(filename, day)?;
+        added += 1;
+    }
+    Ok((added, skipped))
+}
+
+/// Read the sessions in the log.
+fn read(filename: &std::path::Path) -> Result<Vec<Record>, eyre::Report> {
+    let contents = std::fs::read_to_string(filename)
+        .map_err(|e| eyre::eyre!("can't read session log {}: {}", filename.display(), e))?;
+    let mut records = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(line).map_err(|e| {
+            eyre::eyre!(
+                "can't parse session log {} line {}: {}",
+                filename.display(),
+                n + 1,
+                e
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Print the sessions in the log that started in `year` (all of them
+/// if None).
+pub fn report(
+    filename: &std::path::Path,
+    year: Option<i32>,
+    format: Format,
+) -> Result<(), eyre::Report> {
+    let mut records = read(filename)?;
+    records.retain(|record| !record.imported);
+    if let Some(year) = year {
+        records.retain(|record| record.month().is_some_and(|m| m.year == year));
+    }
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&records)?),
+        Format::Csv => {
+            println!("start,end,energy_kwh,solar_kwh,grid_kwh,solar_fraction,cost,tag,savings");
+            for r in &records {
+                println!(
+                    "{},{},{:.3},{:.3},{:.3},{:.3},{},{},{}",
+                    r.start,
+                    r.end,
+                    r.energy_kwh,
+                    r.solar_kwh,
+                    r.grid_kwh,
+                    r.solar_fraction,
+                    r.cost.map(|cost| format!("{cost:.2}")).unwrap_or_default(),
+                    r.tag.as_deref().unwrap_or_default(),
+                    r.savings
+                        .map(|savings| format!("{savings:.2}"))
+                        .unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print each tag's sessions in the log that started in `month`, added
+/// up, as CSV.
+pub fn invoice(filename: &std::path::Path, month: Month) -> Result<(), eyre::Report> {
+    println!("tag,sessions,energy_kwh,solar_kwh,grid_kwh,cost");
+    for i in invoices(&read(filename)?, month) {
+        println!(
+            "{},{},{:.3},{:.3},{:.3},{}",
+            i.tag.as_deref().unwrap_or_default(),
+            i.sessions,
+            i.energy_kwh,
+            i.solar_kwh,
+            i.grid_kwh,
+            i.cost.map(|cost| format!("{cost:.2}")).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Print each month's sessions in the log, added up, as CSV.
+pub fn analytics(filename: &std::path::Path) -> Result<(), eyre::Report> {
+    println!("month,sessions,energy_kwh,solar_fraction,savings,average_hours,imported_days");
+    for s in summaries(&read(filename)?) {
+        println!(
+            "{},{},{:.3},{:.3},{},{:.1},{}",
+            s.month,
+            s.sessions,
+            s.energy_kwh,
+            s.solar_fraction(),
+            s.savings
+                .map(|savings| format!("{savings:.2}"))
+                .unwrap_or_default(),
+            s.average_hours(),
+            s.imported_days
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(start: &str, energy_kwh: f64, cost: Option<f64>, tag: Option<&str>) -> Record {
+        let start = chrono::DateTime::parse_from_rfc3339(start)
+            .unwrap()
+            .with_timezone(&chrono::Local);
+        Record::new(
+            start,
+            start + chrono::Duration::hours(2),
+            energy_kwh * 1000.0,
+            energy_kwh * 250.0,
+            cost,
+            cost.map(|cost| cost / 2.0),
+            tag.map(String::from),
+        )
+    }
+
+    #[test]
+    fn new() {
+        let r = record("2026-03-15T12:00:00+00:00", 8.0, Some(1.5), None);
+        assert_eq!(r.energy_kwh, 8.0);
+        assert_eq!(r.solar_kwh, 2.0);
+        assert_eq!(r.grid_kwh, 6.0);
+        assert_eq!(r.solar_fraction, 0.25);
+        assert_eq!(
+            r.month(),
+            Some(Month {
+                year: 2026,
+                month: 3
+            })
+        );
+    }
+
+    #[test]
+    fn old_log() {
+        let r: Record = serde_json::from_str(
+            r#"{"start":"2026-03-01T17:02:11+01:00","end":"2026-03-02T07:30:40+01:00","energy_kwh":18.412,"solar_kwh":6.203,"grid_kwh":12.209,"solar_fraction":0.337,"cost":2.931}"#,
+        )
+        .unwrap();
+        assert_eq!(r.tag, None);
+    }
+
+    #[test]
+    fn month() {
+        assert_eq!(
+            "2026-03".parse::<Month>().unwrap(),
+            Month {
+                year: 2026,
+                month: 3
+            }
+        );
+        for bad in ["2026", "2026-13", "March", "2026-03-01"] {
+            assert!(bad.parse::<Month>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn invoice() {
+        let records = [
+            record("2026-02-27T08:00:00+00:00", 10.0, Some(3.0), Some("A")),
+            record("2026-03-03T12:00:00+00:00", 10.0, Some(3.0), Some("A")),
+            record("2026-03-02T08:00:00+00:00", 4.0, None, Some("B")),
+            record("2026-03-05T08:00:00+00:00", 2.0, Some(0.5), None),
+            record("2026-03-09T08:00:00+00:00", 6.0, Some(1.0), Some("A")),
+            record("2026-03-10T08:00:00+00:00", 1.0, Some(0.5), Some("B")),
+            record("2026-04-03T12:00:00+00:00", 7.0, Some(2.0), Some("A")),
+        ];
+        let invoices = invoices(
+            &records,
+            Month {
+                year: 2026,
+                month: 3,
+            },
+        );
+        assert_eq!(
+            invoices,
+            [
+                Invoice {
+                    tag: Some(String::from("A")),
+                    sessions: 2,
+                    energy_kwh: 16.0,
+                    solar_kwh: 4.0,
+                    grid_kwh: 12.0,
+                    cost: Some(4.0),
+                },
+                // A session whose cost we don't know leaves it unknown.
+                Invoice {
+                    tag: Some(String::from("B")),
+                    sessions: 2,
+                    energy_kwh: 5.0,
+                    solar_kwh: 1.25,
+                    grid_kwh: 3.75,
+                    cost: None,
+                },
+                Invoice {
+                    tag: None,
+                    sessions: 1,
+                    energy_kwh: 2.0,
+                    solar_kwh: 0.5,
+                    grid_kwh: 1.5,
+                    cost: Some(0.5),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn summaries() {
+        let records = [
+            record("2026-03-03T12:00:00+00:00", 10.0, Some(3.0), Some("A")),
+            record("2026-02-10T08:00:00+00:00", 8.0, Some(2.0), Some("A")),
+            record("2026-03-09T08:00:00+00:00", 6.0, None, Some("B")),
+        ];
+        let summaries = super::summaries(&records);
+        assert_eq!(
+            summaries,
+            [
+                Summary {
+                    month: Month {
+                        year: 2026,
+                        month: 2,
+                    },
+                    sessions: 1,
+                    energy_kwh: 8.0,
+                    solar_kwh: 2.0,
+                    savings: Some(1.0),
+                    hours: 2.0,
+                    imported_days: 0,
+                },
+                // A session whose savings we don't know leaves them
+                // unknown.
+                Summary {
+                    month: Month {
+                        year: 2026,
+                        month: 3,
+                    },
+                    sessions: 2,
+                    energy_kwh: 16.0,
+                    solar_kwh: 4.0,
+                    savings: None,
+                    hours: 4.0,
+                    imported_days: 0,
+                },
+            ]
+        );
+        assert_eq!(summaries[1].solar_fraction(), 0.25);
+        assert_eq!(summaries[1].average_hours(), 2.0);
+        assert_eq!(summaries[1].month.to_string(), "2026-03");
+    }
+
+    fn row(
+        time: &str,
+        produced_wh: f64,
+        consumed_wh: f64,
+        exported_wh: f64,
+    ) -> crate::enlighten::Row {
+        crate::enlighten::Row {
+            time: chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap(),
+            produced_wh,
+            consumed_wh,
+            exported_wh,
+            imported_wh: consumed_wh - produced_wh + exported_wh,
+        }
+    }
+
+    #[test]
+    fn imported_days() {
+        let rows = [
+            row("2026-01-30 00:00", 0.0, 2000.0, 0.0),
+            row("2026-01-30 12:00", 6000.0, 3000.0, 4000.0),
+            row("2026-01-31 12:00", 1000.0, 5000.0, 0.0),
+        ];
+        // 10 until noon, 30 after.
+        let import_price = "00:00=10,12:00=30".parse().unwrap();
+        let export_price = "00:00=5".parse().unwrap();
+        let days = days(&rows, Some(&import_price), Some(&export_price));
+        assert_eq!(days.len(), 2);
+        assert!(days[0].imported);
+        assert_eq!(days[0].energy_kwh, 5.0);
+        assert_eq!(days[0].solar_kwh, 2.0);
+        assert_eq!(days[0].grid_kwh, 3.0);
+        // 2 kWh at 10 before noon, 1 kWh at 30 after.
+        assert_eq!(days[0].cost, Some(50.0));
+        // 2 kWh of solar at 30, less the 5 it would have earned.
+        assert_eq!(days[0].savings, Some(50.0));
+        assert_eq!(days[1].solar_kwh, 1.0);
+        assert_eq!(
+            days[1].month(),
+            Some(Month {
+                year: 2026,
+                month: 1
+            })
+        );
+        assert_eq!(super::days(&rows, None, None)[0].cost, None);
+
+        // They count in the month, but not as sessions.
+        let records = [
+            days[0].clone(),
+            days[1].clone(),
+            record("2026-01-31T20:00:00+00:00", 8.0, Some(2.0), Some("A")),
+        ];
+        let summary = &super::summaries(&records)[0];
+        assert_eq!(summary.sessions, 1);
+        assert_eq!(summary.imported_days, 2);
+        assert_eq!(summary.energy_kwh, 18.0);
+        assert_eq!(summary.hours, 2.0);
+        let month = Month {
+            year: 2026,
+            month: 1,
+        };
+        assert_eq!(invoices(&records, month)[0].sessions, 1);
+        assert_eq!(invoices(&records, month).len(), 1);
+    }
+
+    #[test]
+    fn import() {
+        let filename = std::env::temp_dir().join(format!(
+            "solar-evse-sessions-test-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&filename);
+        let rows: Vec<_> = (1..=5)
+            .map(|day| row(&format!("2026-02-0{day} 00:00"), 1000.0, 1000.0, 0.0))
+            .collect();
+        let days = days(&rows, None, None);
+        // The first session is on the 4th.
+        append(
+            &filename,
+            &record("2026-02-04T12:00:00+00:00", 8.0, None, None),
+        )
+        .unwrap();
+        let first = super::import(&filename, &days[..2]);
+        // Again, and on into the sessions.
+        let second = super::import(&filename, &days);
+        let records = read(&filename);
+        let _ = std::fs::remove_file(&filename);
+        assert_eq!(first.unwrap(), (2, 0));
+        assert_eq!(second.unwrap(), (1, 4));
+        let records = records.unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records.iter().filter(|r| r.imported).count(), 3);
+        // Read back as they were written.
+        assert_eq!(records[1].start, days[0].start);
+        assert!(records[1].imported);
+        assert!(!records[0].imported);
+    }
+}
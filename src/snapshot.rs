@@ -0,0 +1,44 @@
+// Capture a one-shot snapshot of a site: the Envoy's meter readings,
+// what the EVSE reports about itself, and our configuration.  The
+// snapshot directory can be attached to a bug report, and the payloads
+// in it used to reproduce problems with real-world installs.
+//
+// The snapshot is written with the same `Capture` used by
+// `--capture-dir`, so it has the same format, and the Envoy auth token
+// is redacted the same way.
+
+/// Read-only RAPI queries that describe the EVSE: firmware version,
+/// current capacity range, state, settings, and charge current.
+const RAPI_QUERIES: &[&str] = &["GV", "GC", "GS", "GE", "GG"];
+
+pub async fn take(
+    capture: &crate::capture::Capture,
+    config: &str,
+    envoy: &crate::envoy::Envoy,
+    openevse: &crate::openevse::OpenEVSE,
+) -> Result<(), eyre::Report> {
+    capture.record("config", "command line", config);
+
+    // Record whatever we can get, and report what we couldn't.
+    let mut failures = Vec::<String>::new();
+
+    if let Err(e) = envoy.production().await {
+        failures.push(format!("Envoy: {e:#}"));
+    }
+
+    for query in RAPI_QUERIES {
+        if let Err(e) = openevse.request(&[query]).await {
+            failures.push(format!("OpenEVSE ${query}: {e:#}"));
+            // If one query failed the EVSE is probably unreachable, and
+            // each query takes minutes to give up.
+            break;
+        }
+    }
+
+    if failures.is_empty() {
+        println!("snapshot complete");
+        Ok(())
+    } else {
+        Err(eyre::eyre!("incomplete snapshot: {}", failures.join("; ")))
+    }
+}
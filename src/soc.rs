@@ -0,0 +1,87 @@
+// An estimate of the EV battery's state of charge, for when we can't
+// ask the vehicle.  With --ev-battery-kwh, the EV is taken to be at
+// --ev-plug-in-soc when it's plugged in, unless we're told its actual
+// SoC with the "ev_soc" command (from the car's app, the dashboard, a
+// Home Assistant automation, ...), and the energy delivered to it since
+// is added on, less the onboard charger's losses.
+//
+// The estimate comes with an uncertainty in percentage points: what
+// the starting SoC was uncertain by, plus a tenth of what's been added
+// since, since we don't know the charger's losses or the battery's
+// capacity exactly.  With --ev-target-soc, --deadline plans for the
+// low end of the estimate, so the EV is ready even if it's worse than
+// we think.
+
+/// How far off a SoC we've been told is, in percentage points.  Cars
+/// round it, and it takes a while to reach us.
+const REPORTED_UNCERTAINTY: f64 = 2.0;
+
+/// How much of the energy added the estimate could be off by.
+const ADDED_UNCERTAINTY: f64 = 0.1;
+
+/// The onboard charger's efficiency, without --charger-efficiency.
+pub const DEFAULT_EFFICIENCY: f64 = 0.9;
+
+#[derive(Debug)]
+pub struct SocEstimate {
+    capacity_wh: f64,
+
+    // The SoC when we started counting, and how uncertain it was, in
+    // percent and percentage points.  None while the EV is unplugged.
+    start: Option<(f64, f64)>,
+
+    // Energy into the battery since we started counting.
+    added_wh: f64,
+}
+
+impl SocEstimate {
+    pub fn new(capacity_kwh: f64) -> Self {
+        Self {
+            capacity_wh: capacity_kwh * 1000.0,
+            start: None,
+            added_wh: 0.0,
+        }
+    }
+
+    /// The EV's just been plugged in, and we're assuming it's at `soc`,
+    /// give or take `uncertainty`.
+    pub fn plugged_in(&mut self, soc: f64, uncertainty: f64) {
+        self.start = Some((soc.clamp(0.0, 100.0), uncertainty.max(0.0)));
+        self.added_wh = 0.0;
+    }
+
+    /// We've been told the EV's SoC.
+    pub fn set(&mut self, soc: f64) {
+        self.plugged_in(soc, REPORTED_UNCERTAINTY);
+    }
+
+    /// The EV's been unplugged, who knows what it'll be at next time.
+    pub fn unplugged(&mut self) {
+        self.start = None;
+        self.added_wh = 0.0;
+    }
+
+    /// Count energy that went into the battery.
+    pub fn add(&mut self, battery_wh: f64) {
+        self.added_wh += battery_wh;
+    }
+
+    /// The estimated SoC and its uncertainty, in percent and percentage
+    /// points, if the EV is plugged in.
+    pub fn estimate(&self) -> Option<(f64, f64)> {
+        let (start, uncertainty) = self.start?;
+        let added = 100.0 * self.added_wh / self.capacity_wh;
+        Some((
+            (start + added).min(100.0),
+            uncertainty + ADDED_UNCERTAINTY * added.abs(),
+        ))
+    }
+
+    /// How much more energy has to go into the battery to be sure of
+    /// reaching `target` percent, in Watt-hours.
+    pub fn needed_wh(&self, target: f64) -> Option<f64> {
+        let (soc, uncertainty) = self.estimate()?;
+        let low = (soc - uncertainty).max(0.0);
+        Some((target - low).max(0.0) * self.capacity_wh / 100.0)
+    }
+}
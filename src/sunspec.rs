@@ -0,0 +1,591 @@
+// Talk to SunSpec devices over Modbus TCP.  Fronius, SMA, SolarEdge
+// and others expose their grid meters and inverters this way.
+//
+// `SunSpecMeter` reads grid import/export from a meter, as an
+// alternative to the Enphase Envoy.  It uses the first meter model
+// (201 single phase, 202 split phase, 203 wye, 204 delta, which all
+// have the same layout).  Inverter models only know about production,
+// not what goes to the grid, so they're no use as a meter.
+//
+// `SunSpecInverter` curtails an inverter's production with the
+// immediate controls model (123), for when export to the grid is
+// limited and nothing else can use the surplus.
+//
+// The Modbus client is just enough to read and write holding
+// registers, it's not worth a dependency.  The Wallbox Quasar (see
+// quasar.rs) uses it too.
+
+// Where the SunSpec register map might start.
+const BASE_ADDRESSES: &[u16] = &[40000, 0, 50000];
+
+// "SunS"
+const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6e53];
+
+// Offsets of the points we use in the meter models, from the start of
+// the model's data (after its ID and length).
+const POINT_PHASE_VOLTAGE: usize = 5;
+const POINT_PHASE_A_VOLTAGE: usize = 6;
+const POINT_LINE_VOLTAGE: usize = 9;
+const POINT_V_SF: usize = 13;
+const POINT_W: usize = 16;
+const POINT_PHASE_A_W: usize = 17;
+const POINT_W_SF: usize = 20;
+const POINT_TOT_WH_EXP: usize = 36;
+const POINT_TOT_WH_IMP: usize = 44;
+const POINT_TOT_WH_SF: usize = 52;
+
+// Offsets of the points we use in the inverter models (101-103), the
+// nameplate model (120), and the immediate controls model (123).
+const POINT_INVERTER_W: usize = 12;
+const POINT_INVERTER_W_SF: usize = 13;
+const POINT_W_RTG: usize = 1;
+const POINT_W_RTG_SF: usize = 2;
+const POINT_WMAXLIMPCT: u16 = 3;
+const POINT_WMAXLIMPCT_RVRTTMS: u16 = 5;
+const POINT_WMAXLIM_ENA: u16 = 7;
+const POINT_WMAXLIMPCT_SF: u16 = 21;
+
+// How long to wait for a device to answer.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct Modbus {
+    address: String,
+    address_book: crate::address_book::AddressBook,
+    unit_id: u8,
+    stream: Option<tokio::net::TcpStream>,
+    transaction_id: u16,
+}
+
+impl Modbus {
+    pub fn new(
+        address: &str,
+        unit_id: u8,
+        address_book: &crate::address_book::AddressBook,
+    ) -> Self {
+        Self {
+            address: String::from(address),
+            address_book: address_book.clone(),
+            unit_id,
+            stream: None,
+            transaction_id: 0,
+        }
+    }
+
+    /// Send a request (function code and data), and return the data
+    /// of the reply.
+    async fn request(&mut self, function: u8, data: &[u8]) -> Result<Vec<u8>, eyre::Report> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        if self.stream.is_none() {
+            let stream =
+                tokio::time::timeout(TIMEOUT, self.address_book.connect(&self.address, 502))
+                    .await
+                    .map_err(|_| eyre::eyre!("timed out connecting to {}", self.address))??;
+            self.stream = Some(stream);
+        }
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let transaction_id = self.transaction_id;
+
+        let mut request = Vec::with_capacity(8 + data.len());
+        request.extend_from_slice(&transaction_id.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes()); // protocol
+        request.extend_from_slice(&(2 + data.len() as u16).to_be_bytes()); // length of the rest
+        request.push(self.unit_id);
+        request.push(function);
+        request.extend_from_slice(data);
+
+        let stream = self.stream.as_mut().unwrap();
+        let result = tokio::time::timeout(TIMEOUT, async {
+            stream.write_all(&request).await?;
+            let mut header = [0u8; 7];
+            stream.read_exact(&mut header).await?;
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if length < 2 {
+                return Err(eyre::eyre!("short Modbus reply"));
+            }
+            let mut body = vec![0u8; length - 1];
+            stream.read_exact(&mut body).await?;
+            Ok((u16::from_be_bytes([header[0], header[1]]), body))
+        })
+        .await
+        .unwrap_or_else(|_| Err(eyre::eyre!("no answer from {}", self.address)));
+
+        let (reply_transaction_id, body) = match result {
+            Ok(reply) => reply,
+            Err(e) => {
+                // Start over with a new connection next time.
+                self.stream = None;
+                return Err(e);
+            }
+        };
+        if reply_transaction_id != transaction_id {
+            self.stream = None;
+            return Err(eyre::eyre!("Modbus reply is for another request"));
+        }
+        if body[0] == function | 0x80 {
+            return Err(eyre::eyre!(
+                "Modbus exception {}",
+                body.get(1).copied().unwrap_or_default()
+            ));
+        }
+        if body[0] != function {
+            return Err(eyre::eyre!("bad Modbus reply"));
+        }
+        Ok(body[1..].to_vec())
+    }
+
+    /// Read holding registers.
+    pub async fn read_registers(
+        &mut self,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, eyre::Report> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&start.to_be_bytes());
+        data.extend_from_slice(&count.to_be_bytes());
+        let reply = self
+            .request(3, &data)
+            .await
+            .map_err(|e| eyre::eyre!("reading {} registers at {}: {:#}", count, start, e))?;
+        if reply.len() != 1 + 2 * count as usize {
+            return Err(eyre::eyre!(
+                "bad Modbus reply reading {} registers at {}",
+                count,
+                start
+            ));
+        }
+        Ok(reply[1..]
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+
+    /// Write holding registers.
+    pub async fn write_registers(
+        &mut self,
+        start: u16,
+        values: &[u16],
+    ) -> Result<(), eyre::Report> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&start.to_be_bytes());
+        data.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        data.push(2 * values.len() as u8);
+        for value in values {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        self.request(16, &data)
+            .await
+            .map_err(|e| eyre::eyre!("writing {} registers at {}: {:#}", values.len(), start, e))?;
+        Ok(())
+    }
+
+    /// Find the first of the SunSpec models `ids` in the register map,
+    /// and return its ID, where its data starts, and how many registers
+    /// long it is.
+    async fn find_model(&mut self, ids: &[u16]) -> Result<(u16, u16, u16), eyre::Report> {
+        let mut base = None;
+        for address in BASE_ADDRESSES {
+            if let Ok(marker) = self.read_registers(*address, 2).await {
+                if marker == SUNSPEC_MARKER {
+                    base = Some(*address);
+                    break;
+                }
+            }
+        }
+        let Some(base) = base else {
+            return Err(eyre::eyre!(
+                "no SunSpec register map found at {}",
+                self.address
+            ));
+        };
+
+        let mut address = base + 2;
+        loop {
+            let header = self.read_registers(address, 2).await?;
+            let (id, length) = (header[0], header[1]);
+            if id == 0xffff {
+                return Err(eyre::eyre!(
+                    "no SunSpec model {:?} found at {}",
+                    ids,
+                    self.address
+                ));
+            }
+            if ids.contains(&id) {
+                println!(
+                    "found SunSpec model {} at {} register {}",
+                    id, self.address, address
+                );
+                return Ok((id, address + 2, length));
+            }
+            address += 2 + length;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SunSpecMeter {
+    modbus: Modbus,
+
+    // The meter reports importing as positive power, unless inverted.
+    invert: bool,
+
+    // The meter model we found: its ID, where its data starts, and how
+    // many registers long it is.
+    model: Option<(u16, u16, u16)>,
+}
+
+impl SunSpecMeter {
+    pub fn new(
+        address: &str,
+        unit_id: u8,
+        invert: bool,
+        address_book: &crate::address_book::AddressBook,
+    ) -> Self {
+        Self {
+            modbus: Modbus::new(address, unit_id, address_book),
+            invert,
+            model: None,
+        }
+    }
+}
+
+/// A SunSpec int16 value, None if it's "not implemented".
+fn int16(value: u16) -> Option<f64> {
+    match value {
+        0x8000 => None,
+        value => Some(value as i16 as f64),
+    }
+}
+
+/// A SunSpec acc32 value, None if it's "not implemented".
+fn acc32(high: u16, low: u16) -> Option<f64> {
+    match ((high as u32) << 16) | low as u32 {
+        0 => None,
+        value => Some(value as f64),
+    }
+}
+
+/// Apply a SunSpec scale factor.
+fn scaled(value: Option<f64>, sf: u16) -> Option<f64> {
+    Some(value? * 10f64.powi(int16(sf)? as i32))
+}
+
+#[async_trait::async_trait]
+impl crate::meter::Meter for SunSpecMeter {
+    async fn export_power(&mut self) -> Result<crate::meter::PowerReading, eyre::Report> {
+        let (id, start, length) = match self.model {
+            Some(model) => model,
+            None => {
+                let model = self.modbus.find_model(&[201, 202, 203, 204]).await?;
+                self.model = Some(model);
+                model
+            }
+        };
+        let points = self
+            .modbus
+            .read_registers(start, length.min(POINT_TOT_WH_SF as u16 + 1))
+            .await?;
+        reading(id, &points, self.invert)
+    }
+}
+
+/// Decode the points of meter model `id`, importing as positive power
+/// unless `invert`.
+fn reading(
+    id: u16,
+    points: &[u16],
+    invert: bool,
+) -> Result<crate::meter::PowerReading, eyre::Report> {
+    let point = |offset: usize| points.get(offset).copied().unwrap_or(0x8000);
+
+    let import_w = scaled(int16(point(POINT_W)), point(POINT_W_SF))
+        .ok_or(eyre::eyre!("SunSpec meter doesn't report power"))?;
+    // Split phase and delta meters are between the lines, wye and
+    // single phase between line and neutral.
+    let voltage_point = match id {
+        202 | 204 => POINT_LINE_VOLTAGE,
+        _ => POINT_PHASE_VOLTAGE,
+    };
+    let rms_voltage = scaled(int16(point(voltage_point)), point(POINT_V_SF)).filter(|v| *v > 0.0);
+    let wh_exported = scaled(
+        acc32(point(POINT_TOT_WH_EXP), point(POINT_TOT_WH_EXP + 1)),
+        point(POINT_TOT_WH_SF),
+    );
+    let wh_imported = scaled(
+        acc32(point(POINT_TOT_WH_IMP), point(POINT_TOT_WH_IMP + 1)),
+        point(POINT_TOT_WH_SF),
+    );
+
+    let sign = if invert { -1.0 } else { 1.0 };
+
+    // The three-phase wye meter measures each phase on its own.
+    let mut phases = Vec::new();
+    if id == 203 {
+        for line in 0..3 {
+            let Some(w) = scaled(int16(point(POINT_PHASE_A_W + line)), point(POINT_W_SF)) else {
+                phases.clear();
+                break;
+            };
+            phases.push(crate::meter::Phase {
+                export_w: -sign * w,
+                rms_voltage: scaled(
+                    int16(point(POINT_PHASE_A_VOLTAGE + line)),
+                    point(POINT_V_SF),
+                )
+                .filter(|v| *v > 0.0),
+                consumption_w: None,
+            });
+        }
+    }
+
+    let mut details = vec![(String::from("sunspec/w"), import_w)];
+    for (name, value) in [
+        ("sunspec/rms_voltage", rms_voltage),
+        ("sunspec/wh_exported", wh_exported),
+        ("sunspec/wh_imported", wh_imported),
+    ] {
+        if let Some(value) = value {
+            details.push((String::from(name), value));
+        }
+    }
+    Ok(crate::meter::PowerReading {
+        reading_time: chrono::Utc::now(),
+        export_w: -sign * import_w,
+        export_wh_lifetime: wh_exported
+            .zip(wh_imported)
+            .map(|(exported, imported)| sign * (exported - imported)),
+        rms_voltage,
+        phases,
+        consumption_w: None,
+        production_w: None,
+        battery_w: None,
+        battery_soc: None,
+        details,
+    })
+}
+
+#[derive(Debug)]
+pub struct SunSpecInverter {
+    modbus: Modbus,
+
+    // Where the inverter model's and the immediate controls model's
+    // data start, and the inverter's rated power in Watts, once we've
+    // found them.
+    models: Option<(u16, u16, f64)>,
+
+    // The production limit we last set, in percent of the rated power.
+    limit_pct: f64,
+}
+
+impl SunSpecInverter {
+    pub fn new(
+        address: &str,
+        unit_id: u8,
+        address_book: &crate::address_book::AddressBook,
+    ) -> Self {
+        Self {
+            modbus: Modbus::new(address, unit_id, address_book),
+            models: None,
+            limit_pct: 100.0,
+        }
+    }
+
+    async fn models(&mut self) -> Result<(u16, u16, f64), eyre::Report> {
+        if let Some(models) = self.models {
+            return Ok(models);
+        }
+        let (_, inverter, _) = self.modbus.find_model(&[101, 102, 103]).await?;
+        let (_, nameplate, _) = self.modbus.find_model(&[120]).await?;
+        let points = self
+            .modbus
+            .read_registers(nameplate, POINT_W_RTG_SF as u16 + 1)
+            .await?;
+        let rated_w = scaled(int16(points[POINT_W_RTG]), points[POINT_W_RTG_SF])
+            .filter(|w| *w > 0.0)
+            .ok_or(eyre::eyre!(
+                "SunSpec inverter doesn't report its rated power"
+            ))?;
+        let (_, controls, _) = self.modbus.find_model(&[123]).await?;
+        self.models = Some((inverter, controls, rated_w));
+        Ok((inverter, controls, rated_w))
+    }
+
+    /// The inverter's production right now, in Watts.
+    pub async fn production_w(&mut self) -> Result<f64, eyre::Report> {
+        let (inverter, _, _) = self.models().await?;
+        let points = self
+            .modbus
+            .read_registers(inverter, POINT_INVERTER_W_SF as u16 + 1)
+            .await?;
+        scaled(int16(points[POINT_INVERTER_W]), points[POINT_INVERTER_W_SF])
+            .ok_or(eyre::eyre!("SunSpec inverter doesn't report its power"))
+    }
+
+    /// Limit the inverter's production to `limit_w` Watts.  The limit
+    /// reverts after `revert` unless it's set again, so the inverter
+    /// isn't left curtailed if we die.
+    pub async fn set_limit(
+        &mut self,
+        limit_w: f64,
+        revert: std::time::Duration,
+    ) -> Result<(), eyre::Report> {
+        let (_, controls, rated_w) = self.models().await?;
+        let limit_pct = (100.0 * limit_w / rated_w).clamp(0.0, 100.0);
+        if limit_pct >= 100.0 && self.limit_pct >= 100.0 {
+            // Not curtailing, and weren't before.
+            return Ok(());
+        }
+
+        let sf = self
+            .modbus
+            .read_registers(controls + POINT_WMAXLIMPCT_SF, 1)
+            .await?[0];
+        let sf = int16(sf).unwrap_or(0.0) as i32;
+        let raw_pct = (limit_pct / 10f64.powi(sf)).round() as i16 as u16;
+        let revert_s = revert.as_secs().min(u16::MAX as u64) as u16;
+        self.modbus
+            .write_registers(controls + POINT_WMAXLIMPCT, &[raw_pct])
+            .await?;
+        self.modbus
+            .write_registers(controls + POINT_WMAXLIMPCT_RVRTTMS, &[revert_s])
+            .await?;
+        self.modbus
+            .write_registers(controls + POINT_WMAXLIM_ENA, &[1])
+            .await?;
+        if limit_pct != self.limit_pct {
+            println!(
+                "inverter production limit: {:.1}% of {:.0} W",
+                limit_pct, rated_w
+            );
+        }
+        self.limit_pct = limit_pct;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The points of a meter model, all "not implemented" but the ones
+    // given.
+    fn points(set: &[(usize, u16)]) -> Vec<u16> {
+        let mut points = vec![0x8000; POINT_TOT_WH_SF + 1];
+        for (offset, value) in set {
+            points[*offset] = *value;
+        }
+        points
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(int16(1234), Some(1234.0));
+        assert_eq!(int16(-2i16 as u16), Some(-2.0));
+        assert_eq!(int16(0x8000), None);
+        assert_eq!(acc32(0x0001, 0x86a0), Some(100000.0));
+        assert_eq!(acc32(0, 0), None);
+        assert_eq!(scaled(Some(1234.0), 0), Some(1234.0));
+        assert_eq!(scaled(Some(1234.0), 2), Some(123400.0));
+        assert_eq!(scaled(Some(1234.0), -2i16 as u16), Some(12.34));
+        assert_eq!(scaled(None, 0), None);
+        assert_eq!(scaled(Some(1234.0), 0x8000), None);
+    }
+
+    #[test]
+    fn wye() {
+        let reading = reading(
+            203,
+            &points(&[
+                (POINT_PHASE_VOLTAGE, 2400),
+                (POINT_PHASE_A_VOLTAGE, 2400),
+                (POINT_PHASE_A_VOLTAGE + 1, 2410),
+                (POINT_PHASE_A_VOLTAGE + 2, 2390),
+                (POINT_V_SF, -1i16 as u16),
+                (POINT_W, -150i16 as u16),
+                (POINT_PHASE_A_W, -50i16 as u16),
+                (POINT_PHASE_A_W + 1, -70i16 as u16),
+                (POINT_PHASE_A_W + 2, -30i16 as u16),
+                (POINT_W_SF, 1),
+                (POINT_TOT_WH_EXP, 0x0001),
+                (POINT_TOT_WH_EXP + 1, 0x86a0),
+                (POINT_TOT_WH_IMP, 0),
+                (POINT_TOT_WH_IMP + 1, 40000),
+                (POINT_TOT_WH_SF, 0),
+            ]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(reading.export_w, 1500.0);
+        assert_eq!(reading.rms_voltage, Some(240.0));
+        assert_eq!(reading.export_wh_lifetime, Some(60000.0));
+        let phases: Vec<_> = reading
+            .phases
+            .iter()
+            .map(|phase| (phase.export_w, phase.rms_voltage))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                (500.0, Some(240.0)),
+                (700.0, Some(241.0)),
+                (300.0, Some(239.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn split_phase() {
+        // Reading the line to line voltage, inverted, without the
+        // lifetime energy.
+        let reading = reading(
+            202,
+            &points(&[
+                (POINT_PHASE_VOLTAGE, 120),
+                (POINT_LINE_VOLTAGE, 240),
+                (POINT_V_SF, 0),
+                (POINT_W, 1500),
+                (POINT_PHASE_A_W, 750),
+                (POINT_W_SF, 0),
+                (POINT_TOT_WH_EXP, 0),
+                (POINT_TOT_WH_EXP + 1, 0),
+                (POINT_TOT_WH_SF, 0),
+            ]),
+            true,
+        )
+        .unwrap();
+        assert_eq!(reading.export_w, 1500.0);
+        assert_eq!(reading.rms_voltage, Some(240.0));
+        assert_eq!(reading.export_wh_lifetime, None);
+        assert!(reading.phases.is_empty());
+    }
+
+    #[test]
+    fn not_implemented() {
+        // No power, or no scale factor for it.
+        assert!(reading(201, &points(&[(POINT_W_SF, 0)]), false).is_err());
+        assert!(reading(201, &points(&[(POINT_W, 100)]), false).is_err());
+        // A short model.
+        assert!(reading(201, &[], false).is_err());
+
+        // Everything else is optional, and a wye meter missing one
+        // phase's power doesn't report any.
+        let reading = reading(
+            203,
+            &points(&[
+                (POINT_W, 100),
+                (POINT_PHASE_A_W, 50),
+                (POINT_PHASE_A_W + 1, 50),
+                (POINT_W_SF, 0),
+                (POINT_V_SF, 0),
+            ]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(reading.export_w, -100.0);
+        assert_eq!(reading.rms_voltage, None);
+        assert_eq!(reading.export_wh_lifetime, None);
+        assert!(reading.phases.is_empty());
+        assert_eq!(reading.details, [(String::from("sunspec/w"), 100.0)]);
+    }
+}
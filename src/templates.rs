@@ -0,0 +1,80 @@
+// The human-readable text of notifications, rendered from minijinja
+// templates so it can be customized or translated without changing the
+// code.
+//
+// Each notification has a built-in template, named after the event.  A
+// file named `<event>.txt` in the --template-dir directory replaces the
+// built-in template.  The template gets the same fields as the
+// webhook's JSON payload, for example `{{ reason }}` in
+// `state_change.txt`.
+
+const BUILT_IN: &[(&str, &str)] = &[
+    ("session_start", "EV plugged in."),
+    (
+        "session_end",
+        "EV unplugged after {{ (duration_s / 60) | round | int }} minutes.",
+    ),
+    (
+        "state_change",
+        "Charging {{ from }} -> {{ to }}: {{ reason }}.",
+    ),
+    ("error", "solar-evse stopped: {{ message }}"),
+    ("trigger", "Trigger {{ name }} is on."),
+    (
+        "alert",
+        "Alert {{ name }}: {{ condition }}{% if duration_s %} for {{ (duration_s / 60) | round | int }} minutes{% endif %}.",
+    ),
+    ("alert_cleared", "Alert {{ name }} cleared."),
+    ("evse_fault", "EVSE fault: {{ fault }}, not charging."),
+    ("evse_fault_cleared", "EVSE fault cleared."),
+    (
+        "override_report",
+        "{{ events | length }} out-of-band EVSE change(s) since last report:\
+{% for event in events %}
+    {{ event.time }}: EVSE charge current limit {{ event.reported_limit | round | int }} A, we had set {{ event.commanded_limit }} A\
+{% endfor %}",
+    ),
+];
+
+#[derive(Debug)]
+pub struct Templates {
+    env: minijinja::Environment<'static>,
+}
+
+impl Templates {
+    pub fn new(dir: Option<&std::path::Path>) -> Result<Self, eyre::Report> {
+        let mut env = minijinja::Environment::new();
+        for (name, source) in BUILT_IN {
+            env.add_template(name, source)?;
+        }
+        if let Some(dir) = dir {
+            for (name, _) in BUILT_IN {
+                let path = dir.join(format!("{name}.txt"));
+                match std::fs::read_to_string(&path) {
+                    Ok(source) => {
+                        // Editors like to end files with a newline.
+                        let source = source.trim_end_matches('\n').to_string();
+                        env.add_template_owned(*name, source)
+                            .map_err(|e| eyre::eyre!("bad template {}: {}", path.display(), e))?;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(eyre::eyre!("can't read template {}: {}", path.display(), e))
+                    }
+                }
+            }
+        }
+        Ok(Self { env })
+    }
+
+    /// Render the named template with the event's fields.  Rendering
+    /// problems are reported in the text, since a notification with a
+    /// broken message is more useful than none.
+    pub fn render(&self, name: &str, fields: &serde_json::Value) -> Option<String> {
+        let template = self.env.get_template(name).ok()?;
+        Some(match template.render(fields) {
+            Ok(text) => text,
+            Err(e) => format!("{name} (template error: {e})"),
+        })
+    }
+}
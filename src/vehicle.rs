@@ -0,0 +1,220 @@
+// What the EV itself says about its battery: its state of charge and
+// the charge limit set in the car.  Once it's reached --ev-stop-soc, or
+// failing that its own charge limit, there's no point diverting the
+// surplus to it, or asking the EVSE what it's drawing.
+//
+// It comes from TeslaMate, which publishes each car's state to MQTT
+// under --teslamate-topic:
+//
+// ```text
+// teslamate/cars/1/battery_level 78
+// teslamate/cars/1/charge_limit_soc 80
+// teslamate/cars/1/state asleep
+// ```
+//
+// or from the Tesla API with --tesla-vehicle-id.  Asking a sleeping
+// car for its charge state wakes it up (and drains its battery), so we
+// only ask once the vehicle list says it's online, and otherwise go on
+// what it said last.
+//
+// ```text
+// $ curl -H "Authorization: Bearer $TOKEN" https://owner-api.teslamotors.com/api/1/vehicles/1234
+// {"response": {"id": 1234, "state": "asleep", ...}}
+// $ curl -H "Authorization: Bearer $TOKEN" https://owner-api.teslamotors.com/api/1/vehicles/1234/vehicle_data?endpoints=charge_state
+// {"response": {"charge_state": {"battery_level": 78, "charge_limit_soc": 80, ...}, ...}}
+// ```
+
+use std::str::FromStr;
+
+#[derive(Debug, Default)]
+pub struct Vehicle {
+    // In percent, None until we've heard.
+    soc: Option<f64>,
+    charge_limit: Option<f64>,
+
+    // "online", "asleep", "offline", ...
+    state: Option<String>,
+}
+
+impl Vehicle {
+    pub fn soc(&self) -> Option<f64> {
+        self.soc
+    }
+
+    pub fn charge_limit(&self) -> Option<f64> {
+        self.charge_limit
+    }
+
+    pub fn state(&self) -> Option<&str> {
+        self.state.as_deref()
+    }
+
+    pub fn set_soc(&mut self, percent: f64) {
+        self.soc = Some(percent);
+    }
+
+    pub fn set_charge_limit(&mut self, percent: f64) {
+        self.charge_limit = Some(percent);
+    }
+
+    pub fn set_state(&mut self, state: &str) {
+        self.state = Some(String::from(state));
+    }
+
+    /// Whether the EV has charged as far as `stop` percent, or its own
+    /// charge limit without one.
+    pub fn is_full(&self, stop: Option<f64>) -> bool {
+        match (self.soc, stop.or(self.charge_limit)) {
+            (Some(soc), Some(stop)) => soc >= stop,
+            _ => false,
+        }
+    }
+
+    /// Forget the SoC, for when the EV's been unplugged: it could be
+    /// anything when it's plugged in again, and until it tells us, it
+    /// isn't full.
+    pub fn unplugged(&mut self) {
+        self.soc = None;
+    }
+
+    /// Handle a TeslaMate message, `field` being the last part of the
+    /// topic.  Returns the SoC if that's what it was.
+    pub fn handle_teslamate(&mut self, field: &str, payload: &str) -> Option<f64> {
+        let percent = || match f64::from_str(payload) {
+            Ok(percent) => Some(percent),
+            Err(e) => {
+                println!("failed to parse f64 from {:#?}: {:#?}", payload, e);
+                None
+            }
+        };
+        match field {
+            "battery_level" => {
+                self.soc = percent();
+                return self.soc;
+            }
+            "charge_limit_soc" => self.charge_limit = percent(),
+            "state" => self.state = Some(String::from(payload)),
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Somewhere to ask about the EV every cycle: the Tesla API, or an
+/// embedder's own (see `ControllerBuilder::vehicle()`).
+#[async_trait::async_trait]
+pub trait Source: Send + Sync {
+    /// What to call it in the logs.
+    fn name(&self) -> &str;
+
+    /// Update `vehicle`, if it's time to.  Returns the SoC, if there's
+    /// a new one.
+    async fn refresh(&mut self, vehicle: &mut Vehicle) -> Result<Option<f64>, eyre::Report>;
+
+    /// Ask again at the next refresh, for when the EV's just been
+    /// plugged in.
+    fn poll_soon(&mut self) {}
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Reply<T> {
+    response: T,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct VehicleSummary {
+    state: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct VehicleData {
+    charge_state: ChargeState,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChargeState {
+    battery_level: f64,
+    charge_limit_soc: f64,
+}
+
+#[derive(Debug)]
+pub struct TeslaApi {
+    base_url: reqwest::Url,
+    vehicle_id: String,
+    token: String,
+    interval: std::time::Duration,
+    client: reqwest::Client,
+    polled: Option<std::time::Instant>,
+}
+
+impl TeslaApi {
+    pub fn new(
+        base_url: reqwest::Url,
+        vehicle_id: &str,
+        token: &str,
+        interval: std::time::Duration,
+    ) -> Self {
+        Self {
+            base_url,
+            vehicle_id: String::from(vehicle_id),
+            token: String::from(token.trim()),
+            interval,
+            client: reqwest::Client::new(),
+            polled: None,
+        }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T, eyre::Report> {
+        let reply: Reply<T> = self
+            .client
+            .get(self.base_url.join(path)?)
+            .bearer_auth(&self.token)
+            .timeout(std::time::Duration::from_secs(30))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(reply.response)
+    }
+}
+
+#[async_trait::async_trait]
+impl Source for TeslaApi {
+    fn name(&self) -> &str {
+        "the Tesla API"
+    }
+
+    /// Update `vehicle` from the Tesla API, if it's been a while, without
+    /// waking the car.  Returns the SoC, if we got it.
+    async fn refresh(&mut self, vehicle: &mut Vehicle) -> Result<Option<f64>, eyre::Report> {
+        if self.polled.is_some_and(|t| t.elapsed() < self.interval) {
+            return Ok(None);
+        }
+        self.polled = Some(std::time::Instant::now());
+        let summary: VehicleSummary = self
+            .get(&format!("api/1/vehicles/{}", self.vehicle_id))
+            .await?;
+        vehicle.state = Some(summary.state.clone());
+        if summary.state != "online" {
+            return Ok(None);
+        }
+        let data: VehicleData = self
+            .get(&format!(
+                "api/1/vehicles/{}/vehicle_data?endpoints=charge_state",
+                self.vehicle_id
+            ))
+            .await?;
+        vehicle.soc = Some(data.charge_state.battery_level);
+        vehicle.charge_limit = Some(data.charge_state.charge_limit_soc);
+        println!(
+            "EV is at {:.0}%, charge limit {:.0}%",
+            data.charge_state.battery_level, data.charge_state.charge_limit_soc
+        );
+        Ok(vehicle.soc)
+    }
+
+    fn poll_soon(&mut self) {
+        self.polled = None;
+    }
+}
@@ -0,0 +1,67 @@
+// Send notifications of interesting events (EV plugged in, EV
+// unplugged, errors) to user-configured URLs, for integrating with
+// IFTTT, n8n, home-grown scripts, etc.
+//
+// Each event is POSTed as a JSON object with at least "event" and
+// "time" fields, and a human-readable "text" rendered from the event's
+// template (see templates.rs).  Any "{event}" in the URL is replaced by
+// the event name, so one endpoint can be used per event type if
+// desired.
+
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    urls: Vec<String>,
+    client: reqwest::Client,
+    templates: std::sync::Arc<crate::templates::Templates>,
+}
+
+impl Webhooks {
+    pub fn new(urls: &[String], templates: std::sync::Arc<crate::templates::Templates>) -> Self {
+        Self {
+            urls: urls.to_vec(),
+            client: reqwest::Client::new(),
+            templates,
+        }
+    }
+
+    /// Send the event to all the webhooks, and wait for them to finish.
+    pub async fn send(&self, event: &str, details: serde_json::Value) {
+        let mut payload = serde_json::json!({
+            "event": event,
+            "time": chrono::Local::now().to_rfc3339(),
+        });
+        if let (Some(payload), serde_json::Value::Object(details)) =
+            (payload.as_object_mut(), details)
+        {
+            payload.extend(details);
+        }
+        if let Some(text) = self.templates.render(event, &payload) {
+            payload["text"] = serde_json::Value::String(text);
+        }
+
+        for url in &self.urls {
+            let url = url.replace("{event}", event);
+            match self.client.post(&url).json(&payload).send().await {
+                Ok(response) => {
+                    if let Err(e) = response.error_for_status() {
+                        println!("webhook {} failed: {}", url, e);
+                    }
+                }
+                Err(e) => {
+                    println!("webhook {} failed: {}", url, e);
+                }
+            }
+        }
+    }
+
+    /// Send the event to all the webhooks in the background, so the
+    /// control loop doesn't wait on slow endpoints.
+    pub fn fire(&self, event: &str, details: serde_json::Value) {
+        if self.urls.is_empty() {
+            return;
+        }
+        let webhooks = self.clone();
+        let event = event.to_string();
+        tokio::spawn(async move { webhooks.send(&event, details).await });
+    }
+}